@@ -11,7 +11,8 @@ use std::process::exit;
 
 use try_from::{TryFrom, TryInto};
 
-use client::new_client;
+use client::new_xid;
+use client::pool::{ConnPool, PoolConfig};
 
 use zippyrpc::*;
 
@@ -20,12 +21,14 @@ use zippyrpc::*;
 /// There is an impl of `TryFrom<&str>` so that we can easily attempt
 /// to parse command line args.
 enum NfsCommand {
+    Hello(i32), // protocol version
     Null,
     MkDir(u64, String), // (did, filename)
     Remove(u64, String), // (did, filename)
     RmDir(u64, String), // (did, filename)
     Lookup(u64, String), // (did, filename)
-    ReadDir(u64, u64), // did, offset
+    ReadDir(u64, u64, u64, u64), // did, cookie, cookieverf, maxcount
+    ReadDirPlus(u64, u64, u64, u64), // did, cookie, cookieverf, maxcount
     GetAttr(u64), // fid
     SetAttr(u64, u64, u64, u64), // fid, size, atime, mtime
     Read(u64, u64, u64), // fid, offset, count
@@ -33,6 +36,7 @@ enum NfsCommand {
     Create(u64, String), // did, name
     Rename(u64, String, u64, String), // from_did, from_name, to_did, to_name
     StatFs,
+    FsInfo,
     Commit(u64, u64, u64), // fid, offset, count
 }
 
@@ -47,6 +51,16 @@ impl<'a> TryFrom<&'a str> for NfsCommand {
         }
 
         match parts[0] {
+            "HELLO" => {
+                // The version is optional
+                if parts.len() < 2 {
+                    Ok(NfsCommand::Hello(PROTOCOL_VERSION))
+                } else {
+                    Ok(NfsCommand::Hello(
+                        parts[1].parse().map_err(|e| format!("{}", e))?,
+                    ))
+                }
+            }
             "NULL" => Ok(NfsCommand::Null),
             "MKDIR" => {
                 if parts.len() < 3 {
@@ -90,11 +104,43 @@ impl<'a> TryFrom<&'a str> for NfsCommand {
             }
             "READDIR" => {
                 if parts.len() < 3 {
-                    Err("Readdir without dir name".into())
+                    Err("Readdir without did, cookie".into())
                 } else {
                     Ok(NfsCommand::ReadDir(
                         parts[1].parse().map_err(|e| format!("{}", e))?,
                         parts[2].parse().map_err(|e| format!("{}", e))?,
+                        // The verifier and maxcount are optional
+                        if parts.len() < 4 {
+                            0
+                        } else {
+                            parts[3].parse().map_err(|e| format!("{}", e))?
+                        },
+                        if parts.len() < 5 {
+                            0
+                        } else {
+                            parts[4].parse().map_err(|e| format!("{}", e))?
+                        },
+                    ))
+                }
+            }
+            "READDIRPLUS" => {
+                if parts.len() < 3 {
+                    Err("Readdirplus without did, cookie".into())
+                } else {
+                    Ok(NfsCommand::ReadDirPlus(
+                        parts[1].parse().map_err(|e| format!("{}", e))?,
+                        parts[2].parse().map_err(|e| format!("{}", e))?,
+                        // The verifier and maxcount are optional
+                        if parts.len() < 4 {
+                            0
+                        } else {
+                            parts[3].parse().map_err(|e| format!("{}", e))?
+                        },
+                        if parts.len() < 5 {
+                            0
+                        } else {
+                            parts[4].parse().map_err(|e| format!("{}", e))?
+                        },
                     ))
                 }
             }
@@ -166,6 +212,7 @@ impl<'a> TryFrom<&'a str> for NfsCommand {
                 }
             }
             "STATFS" => Ok(NfsCommand::StatFs),
+            "FSINFO" => Ok(NfsCommand::FsInfo),
             "COMMIT" => {
                 if parts.len() < 4 {
                     Err("Commit without fid, offset, count".into())
@@ -198,29 +245,43 @@ fn is_addr(arg: String) -> Result<(), String> {
 /// This creates an RPC client to the appropriate server and attempts to
 /// execute the given command.
 fn run(server_addr: &str, command: NfsCommand) -> Result<(), ZipError> {
-    // build a rpc client
-    let mut client = new_client(server_addr)?;
+    // build a pool of rpc clients, which retries commands if the server goes away
+    let pool = ConnPool::new(server_addr, PoolConfig::default())?;
 
     // Attempt to execute the appropriate command
-    match command {
+    let result = match command {
+        NfsCommand::Hello(version) => {
+            println!("Executing HELLO {}", version);
+            let res = pool.call(|client| client.hello(ZipHelloArgs::new(version)));
+            println!("Received response: {:?}", res);
+            res.map(|_| ())
+        }
+
         NfsCommand::Null => {
             println!("Executing NULL");
-            client.null()?;
+            pool.call(|client| client.null())?;
             Ok(())
         }
 
         NfsCommand::StatFs => {
             println!("Executing STATFS");
-            client.statfs(ZipFileHandle::new(1))?;
+            pool.call(|client| client.statfs(ZipFileHandle::new(1)))?;
             Ok(())
         }
 
+        NfsCommand::FsInfo => {
+            println!("Executing FSINFO");
+            let res = pool.call(|client| client.fsinfo(ZipFileHandle::new(1)));
+            println!("Received response: {:?}", res);
+            res.map(|_| ())
+        }
+
         NfsCommand::MkDir(did, new_dir) => {
             println!("Executing Mkdir {} {}", did, new_dir);
 
             // Create the RPC arguments
             let args = ZipCreateArgs::new(
-                ZipDirOpArgs::new(ZipFileHandle::new(did as i64), new_dir),
+                ZipDirOpArgs::new(ZipFileHandle::new(did as i64), new_dir, new_xid()),
                 ZipSattr::new(
                     None, // mode
                     None, // size
@@ -232,7 +293,7 @@ fn run(server_addr: &str, command: NfsCommand) -> Result<(), ZipError> {
             );
 
             // Send the RPC
-            let res = client.mkdir(args);
+            let res = pool.call(|client| client.mkdir(args.clone()));
 
             // Check the result
             println!("Received response: {:?}", res);
@@ -244,10 +305,10 @@ fn run(server_addr: &str, command: NfsCommand) -> Result<(), ZipError> {
             println!("Executing Lookup {} {}", did, fname);
 
             // Create the RPC args
-            let args = ZipDirOpArgs::new(ZipFileHandle::new(did as i64), fname);
+            let args = ZipDirOpArgs::new(ZipFileHandle::new(did as i64), fname, None);
 
             // Send the RPC
-            let res = client.lookup(args);
+            let res = pool.call(|client| client.lookup(args.clone()));
 
             // Check the result
             println!("Received response: {:?}", res);
@@ -259,10 +320,10 @@ fn run(server_addr: &str, command: NfsCommand) -> Result<(), ZipError> {
             println!("Executing Remove {} {}", did, fname);
 
             // Create the RPC args
-            let args = ZipDirOpArgs::new(ZipFileHandle::new(did as i64), fname);
+            let args = ZipDirOpArgs::new(ZipFileHandle::new(did as i64), fname, new_xid());
 
             // Send the RPC
-            let res = client.remove(args);
+            let res = pool.call(|client| client.remove(args.clone()));
 
             // Check the result
             println!("Received response: {:?}", res);
@@ -274,10 +335,36 @@ fn run(server_addr: &str, command: NfsCommand) -> Result<(), ZipError> {
             println!("Executing RmDir {} {}", did, fname);
 
             // Create the RPC args
-            let args = ZipDirOpArgs::new(ZipFileHandle::new(did as i64), fname);
+            let args = ZipDirOpArgs::new(ZipFileHandle::new(did as i64), fname, new_xid());
+
+            // Send the RPC
+            let res = pool.call(|client| client.rmdir(args.clone()));
+
+            // Check the result
+            println!("Received response: {:?}", res);
+
+            res.map(|_| ())
+        }
+
+        NfsCommand::ReadDir(did, cookie, cookieverf, maxcount) => {
+            println!(
+                "Executing ReadDir {} {} {} {}",
+                did,
+                cookie,
+                cookieverf,
+                maxcount
+            );
+
+            // Create the RPC args
+            let args = ZipReadDirArgs::new(
+                ZipFileHandle::new(did as i64),
+                cookie as i64,
+                cookieverf as i64,
+                maxcount as i64,
+            );
 
             // Send the RPC
-            let res = client.rmdir(args);
+            let res = pool.call(|client| client.readdir(args.clone()));
 
             // Check the result
             println!("Received response: {:?}", res);
@@ -285,14 +372,25 @@ fn run(server_addr: &str, command: NfsCommand) -> Result<(), ZipError> {
             res.map(|_| ())
         }
 
-        NfsCommand::ReadDir(did, offset) => {
-            println!("Executing ReadDir {} {}", did, offset);
+        NfsCommand::ReadDirPlus(did, cookie, cookieverf, maxcount) => {
+            println!(
+                "Executing ReadDirPlus {} {} {} {}",
+                did,
+                cookie,
+                cookieverf,
+                maxcount
+            );
 
             // Create the RPC args
-            let args = ZipReadDirArgs::new(ZipFileHandle::new(did as i64), offset as i64);
+            let args = ZipReadDirArgs::new(
+                ZipFileHandle::new(did as i64),
+                cookie as i64,
+                cookieverf as i64,
+                maxcount as i64,
+            );
 
             // Send the RPC
-            let res = client.readdir(args);
+            let res = pool.call(|client| client.readdirplus(args.clone()));
 
             // Check the result
             println!("Received response: {:?}", res);
@@ -307,7 +405,7 @@ fn run(server_addr: &str, command: NfsCommand) -> Result<(), ZipError> {
             let args = ZipFileHandle::new(fid as i64);
 
             // Send the RPC
-            let res = client.getattr(args);
+            let res = pool.call(|client| client.getattr(args.clone()));
 
             // Check the result
             println!("Received response: {:?}", res);
@@ -329,10 +427,11 @@ fn run(server_addr: &str, command: NfsCommand) -> Result<(), ZipError> {
                     ZipTimeVal::new(atime as i64, 0),
                     ZipTimeVal::new(mtime as i64, 0),
                 ),
+                None,
             );
 
             // Send the RPC
-            let res = client.setattr(args);
+            let res = pool.call(|client| client.setattr(args.clone()));
 
             // Check the result
             println!("Received response: {:?}", res);
@@ -344,11 +443,15 @@ fn run(server_addr: &str, command: NfsCommand) -> Result<(), ZipError> {
             println!("Executing Read {} {} {}", fid, offset, count);
 
             // Create the RPC args
-            let args =
-                ZipReadArgs::new(ZipFileHandle::new(fid as i64), offset as i64, count as i64);
+            let args = ZipReadArgs::new(
+                ZipFileHandle::new(fid as i64),
+                offset as i64,
+                count as i64,
+                None,
+            );
 
             // Send the RPC
-            let res = client.read(args);
+            let res = pool.call(|client| client.read(args.clone()));
 
             // Check the result
             println!("Received response: {:?}", res);
@@ -382,10 +485,11 @@ fn run(server_addr: &str, command: NfsCommand) -> Result<(), ZipError> {
                 } else {
                     ZipWriteStable::UNSTABLE
                 },
+                None,
             );
 
             // Send the RPC
-            let res = client.write(args);
+            let res = pool.call(|client| client.write(args.clone()));
 
             // Check the result
             println!("Received response: {:?}", res);
@@ -398,12 +502,12 @@ fn run(server_addr: &str, command: NfsCommand) -> Result<(), ZipError> {
 
             // Create the RPC args
             let args = ZipCreateArgs::new(
-                ZipDirOpArgs::new(ZipFileHandle::new(did as i64), fname),
+                ZipDirOpArgs::new(ZipFileHandle::new(did as i64), fname, new_xid()),
                 ZipSattr::new(None, None, None, None, None, None),
             );
 
             // Send the RPC
-            let res = client.create(args);
+            let res = pool.call(|client| client.create(args.clone()));
 
             // Check the result
             println!("Received response: {:?}", res);
@@ -416,12 +520,13 @@ fn run(server_addr: &str, command: NfsCommand) -> Result<(), ZipError> {
 
             // Create the RPC args
             let args = ZipRenameArgs::new(
-                ZipDirOpArgs::new(ZipFileHandle::new(fdid as i64), ffname),
-                ZipDirOpArgs::new(ZipFileHandle::new(tdid as i64), tfname),
+                ZipDirOpArgs::new(ZipFileHandle::new(fdid as i64), ffname, new_xid()),
+                ZipDirOpArgs::new(ZipFileHandle::new(tdid as i64), tfname, None),
+                None,
             );
 
             // Send the RPC
-            let res = client.rename(args);
+            let res = pool.call(|client| client.rename(args.clone()));
 
             // Check the result
             println!("Received response: {:?}", res);
@@ -433,18 +538,26 @@ fn run(server_addr: &str, command: NfsCommand) -> Result<(), ZipError> {
             println!("Executing Rename {} {} {}", fid, offset, count);
 
             // Create the RPC args
-            let args =
-                ZipCommitArgs::new(ZipFileHandle::new(fid as i64), offset as i64, count as i64);
+            let args = ZipCommitArgs::new(
+                ZipFileHandle::new(fid as i64),
+                offset as i64,
+                count as i64,
+                None,
+            );
 
             // Send the RPC
-            let res = client.commit(args);
+            let res = pool.call(|client| client.commit(args.clone()));
 
             // Check the result
             println!("Received response: {:?}", res);
 
             res.map(|_| ())
         }
-    }.map_err(|e| e.into())
+    };
+
+    println!("Connection pool: {}", pool.metrics());
+
+    result
 }
 
 /// The main entry point of the CLI client
@@ -4,151 +4,249 @@ extern crate clap;
 extern crate libc;
 extern crate fuse;
 extern crate time;
+extern crate thrift;
 
 extern crate client;
 extern crate zippyrpc;
 
 use std::collections::HashMap;
-use std::time::Duration;
+use std::mem;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
 use std::process::exit;
-use std::thread::sleep;
 use std::ffi::OsStr;
+use std::io;
+use std::net::{TcpListener, TcpStream};
 use std::string::String;
 use std::option::Option;
 use std::vec::Vec;
 use std::path::Path;
-use std::cmp::min;
+use std::cmp::{max, min};
+use std::i64;
 
 use time::{Timespec, get_time};
+use thrift::protocol::{TCompactInputProtocol, TCompactOutputProtocol};
+use thrift::server::TProcessor;
+use thrift::transport::{TIoChannel, TTcpChannel};
 use fuse::{FileAttr, FileType, Filesystem, Request, ReplyAttr, ReplyCreate, ReplyData,
-           ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyStatfs, ReplyWrite, ReplyOpen};
+           ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyLock, ReplyStatfs, ReplyWrite, ReplyOpen,
+           ReplyXattr};
 
-use libc::{ENOENT, ENOTEMPTY, ENOTDIR, EISDIR, EEXIST, ENAMETOOLONG, EIO, EAGAIN, c_int};
+use libc::{ENOENT, ENOTEMPTY, ENOTDIR, EISDIR, EEXIST, ENAMETOOLONG, EIO, EAGAIN, ENODATA, ERANGE,
+           EINVAL, ENOSYS, EINTR, F_RDLCK, F_WRLCK, F_UNLCK, O_ACCMODE, O_RDONLY, c_int};
 
 use zippyrpc::*;
-use client::{new_client, ZnfsClient};
+use zippyrpc::transport::{read_transport, write_transport};
+use client::{new_xid, ZnfsClient};
+use client::pipeline::{Pending, Pipeline, MAX_IN_FLIGHT};
+use client::diskcache::DiskCache;
+use client::pagecache::{Block, PageCache, Version, BLOCK_SIZE};
+use client::pool::{ConnPool, PoolConfig};
+
+/// Set to true to turn on asynchronous writes (if the server supports them)
+const ASYNC_WRITES: bool = true;
 
-/// The Time-To-Live of attributes received from the server
-const TTL: Timespec = Timespec { sec: 1, nsec: 0 }; // 1 second
+/// The number of threads handling requests from the kernel, by default
+const DEFAULT_THREADS: usize = 8;
 
-/// The maximum number of retries (with exponential backoff) before giving up
-const MAX_TRIES: usize = 5;
+/// How often the background flusher looks for files that have been dirty for too long
+const FLUSH_INTERVAL_SECS: u64 = 5;
 
-/// Set to true to turn on asynchronous writes
-const ASYNC_WRITES: bool = true;
+/// How long to assume a lease lasts until the server says
+const DEFAULT_LEASE_SECS: u64 = 90;
+
+/// How long to wait before asking for a lock again, at first and at most, when waiting for it
+const LOCK_POLL_MIN_MS: u64 = 10;
+const LOCK_POLL_MAX_MS: u64 = 1000;
+
+/// The number of threads waiting for locks for processes that asked to wait. Any more requests
+/// like that wait for one of them to be free.
+const LOCK_WAITERS: usize = 8;
+
+/// The extended attribute that shows a file's uncommitted writes, or all of them for the root
+const DIRTY_XATTR: &'static str = "user.zippy.dirty";
+
+/// The server capabilities we can't do without
+const REQUIRED_CAPS: i64 = CAP_READDIRPLUS;
 
 /// A type representing a File ID (FID)
 type Fid = usize;
 
-/// A macro for the repeated error handling that everyone does...
+/// The error we use internally when the server rejects a READDIR cookie verifier. This is the
+/// same value as Linux's kernel-internal `EBADCOOKIE`, which is never returned to userspace.
+const EBADCOOKIE: c_int = 523;
+
+/// The LIBC error for an error from the server.
 ///
-/// In particular, it attempts to reconnect to the server in case of some failures that should be
-/// handled automatically.
-macro_rules! errors {
-    ($e:ident, $s:ident) =>{ {
-        match $e {
-            ZipError::Nfs(ZipErrorType::NFSERR_STALE, msg) =>{
-                println!("NFS stale file handle: {}", msg);
-                (false, Some(ENOENT))
-            }
+/// Transport and protocol errors have already been retried by the connection pool by the time
+/// they get here, so they mean that we gave up on the server.
+fn errno(e: ZipError) -> c_int {
+    match e {
+        ZipError::Nfs(ZipErrorType::NFSERR_STALE, msg) => {
+            println!("NFS stale file handle: {}", msg);
+            ENOENT
+        }
 
-            ZipError::Nfs(ZipErrorType::NFSERR_NOENT, msg) =>{
-                println!("NFS no such dir or file: {}",msg);
-                (false, Some(ENOENT))
-            }
+        ZipError::Nfs(ZipErrorType::NFSERR_NOENT, msg) => {
+            println!("NFS no such dir or file: {}", msg);
+            ENOENT
+        }
 
-            ZipError::Nfs(ZipErrorType::NFSERR_NOTEMPTY, msg) =>{
-                println!("NFS Directory not empty: {}", msg);
-                (false, Some(ENOTEMPTY))
-            }
+        ZipError::Nfs(ZipErrorType::NFSERR_NOTEMPTY, msg) => {
+            println!("NFS Directory not empty: {}", msg);
+            ENOTEMPTY
+        }
 
-            ZipError::Nfs(ZipErrorType::NFSERR_NAMETOOLONG, msg) =>{
-                println!("NFS File name too long: {}", msg);
-                (false, Some(ENAMETOOLONG))
-            }
+        ZipError::Nfs(ZipErrorType::NFSERR_NAMETOOLONG, msg) => {
+            println!("NFS File name too long: {}", msg);
+            ENAMETOOLONG
+        }
 
-            ZipError::Nfs(ZipErrorType::NFSERR_ISDIR, msg) =>{
-                println!("NFS Is a directory: {}", msg);
-                (false, Some(EISDIR))
-            }
+        ZipError::Nfs(ZipErrorType::NFSERR_ISDIR, msg) => {
+            println!("NFS Is a directory: {}", msg);
+            EISDIR
+        }
 
-            ZipError::Nfs(ZipErrorType::NFSERR_NOTDIR, msg) =>{
-                println!("NFS Not a directory: {}", msg);
-                (false, Some(ENOTDIR))
-            }
+        ZipError::Nfs(ZipErrorType::NFSERR_NOTDIR, msg) => {
+            println!("NFS Not a directory: {}", msg);
+            ENOTDIR
+        }
 
-            ZipError::Nfs(ZipErrorType::NFSERR_EXIST, msg) =>{
-                println!("NFS File exists: {}", msg);
-                (false, Some(EEXIST))
-            }
+        ZipError::Nfs(ZipErrorType::NFSERR_EXIST, msg) => {
+            println!("NFS File exists: {}", msg);
+            EEXIST
+        }
 
-            ZipError::Transport(te) => {
-                println!("Transport error... {:?}", te);
-                match new_client(&$s.server_addr) {
-                    Ok(client) => { $s.znfs = client; },
-                    Err(_) => {}
-                }
-                (true, None)
-            }
+        ZipError::Nfs(ZipErrorType::NFSERR_BAD_COOKIE, msg) => {
+            println!("NFS Bad readdir cookie: {}", msg);
+            EBADCOOKIE
+        }
 
-            err => {
-                println!("Some other error: {:?}", err);
-                (false, Some(EAGAIN))
-            }
+        ZipError::Nfs(ZipErrorType::NFSERR_GRACE, msg) => {
+            println!("NFS Server in grace period: {}", msg);
+            EAGAIN
         }
-    } }
-}
 
-/// Attempts to run the given expression via RPC and handles retries appropriately.
-///
-/// This macro "returns" a `Result<T, c_int>` where `T` depends on the return type of the RPC. The
-/// `c_int` is a LIBC error if one occurs.
-macro_rules! do_with_retry {
-    ($self:ident, $rpc:expr) => { {
-        let mut should_try = true;
-        let mut tries = 0;
-        let mut result: Option<Result<_, c_int>> = None;
-
-        while should_try && tries < MAX_TRIES {
-            // Attempt to make the RPC call
-            match $rpc {
-                Ok(val) => {
-                    result = Some(Ok(val));
-                    should_try = false;
-                }
-                Err(e) => {
-                    let (st, le) = errors!(e, $self);
+        ZipError::Nfs(ZipErrorType::NFSERR_INVAL, msg) => {
+            println!("NFS Invalid argument: {}", msg);
+            EINVAL
+        }
 
-                    // XOR: should retry if there was an err returned,
-                    // but if no error is returned, then we should retry.
-                    assert!(st || le.is_some());
-                    assert!(!st || !le.is_some());
+        ZipError::Transport(te) => {
+            println!("Transport error, giving up: {:?}", te);
+            EIO
+        }
 
-                    should_try = st;
+        ZipError::Protocol(pe) => {
+            println!("Protocol error, giving up: {:?}", pe);
+            EIO
+        }
 
-                    if let Some(err) = le {
-                        result = Some(Err(err));
-                    }
-                }
-            }
+        err => {
+            println!("Some other error: {:?}", err);
+            EAGAIN
+        }
+    }
+}
 
-            // Backoff before retrying
-            if should_try {
-                sleep(Duration::from_secs(1 << tries));
-            }
+/// Convert a lock from the kernel, whose range ends with its last byte, into one for the server.
+fn to_zip_lock(owner: u64, start: u64, end: u64, typ: u32, pid: u32) -> Result<ZipLock, c_int> {
+    let kind = match typ as c_int {
+        F_RDLCK => ZipLockType::READ,
+        F_WRLCK => ZipLockType::WRITE,
+        F_UNLCK => ZipLockType::UNLOCK,
+        _ => return Err(EINVAL),
+    };
+
+    // The kernel says "up to EOF and beyond" with the largest offset
+    let end = if end >= i64::MAX as u64 {
+        -1
+    } else {
+        end as i64 + 1
+    };
+
+    Ok(ZipLock::new(kind, start as i64, end, owner as i64, pid as i64))
+}
 
-            tries += 1;
-        }
+/// Convert a lock from the server into (start, end, type, pid) for the kernel.
+fn from_zip_lock(lock: &ZipLock) -> (u64, u64, u32, u32) {
+    let typ = match lock.kind {
+        ZipLockType::READ => F_RDLCK,
+        ZipLockType::WRITE => F_WRLCK,
+        ZipLockType::UNLOCK => F_UNLCK,
+    };
+    let end = if lock.end < 0 {
+        i64::MAX as u64
+    } else {
+        lock.end as u64 - 1
+    };
+
+    (lock.start as u64, end, typ as u32, lock.pid as u32)
+}
 
-        if should_try { // Too many reties
-            result = Some(Err(EIO));
-        }
+/// Whether two ranges of bytes overlap.
+fn overlaps(offset1: usize, size1: usize, offset2: usize, size2: usize) -> bool {
+    offset1 < offset2 + size2 && offset2 < offset1 + size1
+}
+
+/// Add a write to a file's extents of dirty data, merging it with any that it overlaps or
+/// touches, with the new data winning where they overlap. The extents are sorted and disjoint.
+fn merge_extent(extents: &mut Vec<(usize, usize, Vec<u8>)>, offset: usize, data: &[u8]) {
+    let end = offset + data.len();
+
+    // The extents that overlap or touch the new data, which are all replaced by one
+    let first = extents
+        .iter()
+        .position(|&(o, s, _)| o + s >= offset)
+        .unwrap_or(extents.len());
+    let count = extents[first..]
+        .iter()
+        .take_while(|&&(o, _, _)| o <= end)
+        .count();
+    let mut old: Vec<_> = extents.drain(first..first + count).collect();
+
+    // Build on the first one if it starts no later than the new data. This is the usual case of
+    // sequential writes, which would otherwise copy the whole extent each time.
+    let reuse = old.first().map_or(false, |&(o, _, _)| o <= offset);
+    let (start, mut buf) = if reuse {
+        let (o, _, data) = old.remove(0);
+        (o, data)
+    } else {
+        (offset, Vec::new())
+    };
+
+    let merged_end = old.last().map_or(end, |&(o, s, _)| max(o + s, end));
+    let merged_end = max(merged_end, start + buf.len());
+    buf.resize(merged_end - start, 0);
+
+    for (o, s, data) in old {
+        buf[o - start..o - start + s].copy_from_slice(&data);
+    }
+    buf[offset - start..end - start].copy_from_slice(data);
+
+    extents.insert(first, (start, buf.len(), buf));
+}
 
-        // At this point `result` should contain _something_
-        assert!(result.is_some());
+/// Split extents of dirty data into writes of at most `wsize` bytes.
+fn split_extents(
+    extents: &[(usize, usize, Vec<u8>)],
+    wsize: usize,
+) -> Vec<(usize, usize, Vec<u8>)> {
+    let mut writes = Vec::new();
+    for &(offset, _, ref data) in extents.iter() {
+        for (i, chunk) in data.chunks(wsize).enumerate() {
+            writes.push((offset + i * wsize, chunk.len(), chunk.to_vec()));
+        }
+    }
+    writes
+}
 
-        result.unwrap()
-    } }
+/// The number of bytes in extents of dirty data.
+fn extents_len(extents: &[(usize, usize, Vec<u8>)]) -> usize {
+    extents.iter().map(|&(_, size, _)| size).sum()
 }
 
 /// Convert a `ZipTimeVal` used by NFS/Thrift into a `Timespec` used by FUSE.
@@ -167,327 +265,2197 @@ fn to_zip_time(s_time: Timespec) -> ZipTimeVal {
     }
 }
 
-/// A stateful FUSE implementation of NFS, which interacts with a remote server via Thrift RPC.
-struct ZippyFileSystem {
-    znfs: ZnfsClient, // Thrift client
-    server_addr: String, // Needed to reconnect
-    server_epoch: u64, // Server's generation number
+/// Convert a `ZipFtype` used by NFS/Thrift into a `FileType` used by FUSE.
+fn to_file_type(z_type: ZipFtype) -> FileType {
+    match z_type {
+        ZipFtype::NFREG => FileType::RegularFile,
+        ZipFtype::NFDIR => FileType::Directory,
+        ZipFtype::NFNON => FileType::NamedPipe,
+        ZipFtype::NFBLK => FileType::BlockDevice,
+        ZipFtype::NFCHR => FileType::CharDevice,
+        ZipFtype::NFLNK => FileType::Symlink,
+    }
+}
 
-    // buffers for the client to store data that has been unstablely written until commit.
-    // Fid -> [(offset, size, data)]
-    async_bufs: HashMap<Fid, Vec<(usize, usize, Vec<u8>)>>,
+/// Convert a `ZipFattr` used by NFS/Thrift into a `FileAttr` used by FUSE.
+fn to_file_attr(lres: ZipFattr) -> FileAttr {
+    let my_time = to_sys_time(lres.ctime);
+    FileAttr {
+        ino: lres.fid as u64,
+        size: lres.size as u64,
+        blocks: lres.blocks as u64,
+        atime: to_sys_time(lres.atime),
+        mtime: to_sys_time(lres.mtime),
+        ctime: my_time,
+        crtime: my_time,
+        kind: to_file_type(lres.type_),
+        perm: lres.mode as u16,
+        nlink: lres.nlink as u32,
+        uid: lres.uid as u32,
+        gid: lres.gid as u32,
+        rdev: lres.rdev as u32,
+        flags: 0,
+    }
 }
 
-impl ZippyFileSystem {
-    /// Read 0 or more bytes into the given buffer and returns the size.
-    ///
-    /// We will read 0 bytes if EOF.
-    fn read_part(
-        &mut self,
-        _req: &Request,
-        ino: u64,
-        _fh: u64,
-        offset: u64,
-        size: u32,
-        buf: &mut Vec<u8>,
-    ) -> Result<usize, c_int> {
-        println!(
-            "read(ino={}, _fh={}, off={}, _size={})",
-            ino,
-            _fh,
-            offset,
-            size
+/// How long we (and the kernel) may cache attributes without asking the server again, and how
+/// much data we may cache. This is mostly set with the same mount options as the Linux NFS client.
+#[derive(Clone, Copy, Debug)]
+struct CacheConfig {
+    // The bounds of the TTL of a file's attributes. It starts at the minimum, and doubles each
+    // time they turn out not to have changed, up to the maximum.
+    acregmin: Duration,
+    acregmax: Duration,
+
+    // The same, for directories
+    acdirmin: Duration,
+    acdirmax: Duration,
+
+    // Whether to check the attributes with the server on every open (close-to-open consistency)
+    cto: bool,
+
+    // Whether to ask the server for delegations of the files we open, so that we can cache them
+    // for as long as nobody else changes them, without checking with the server
+    deleg: bool,
+
+    // The most file data to cache, in bytes
+    cache_size: usize,
+
+    // The most data to read ahead of a sequential reader, in bytes
+    readahead: usize,
+
+    // The most file data to keep in the disk cache, if there is one, in bytes
+    disk_size: u64,
+
+    // How long a file's writes may go uncommitted before the background flusher commits them
+    dirty_expire: Duration,
+
+    // The most uncommitted data before the background flusher commits files, oldest first, until
+    // there is at most half this much. Writers that get to twice this much commit their own files.
+    dirty_bytes: usize,
+}
+
+impl Default for CacheConfig {
+    fn default() -> CacheConfig {
+        CacheConfig {
+            acregmin: Duration::from_secs(3),
+            acregmax: Duration::from_secs(60),
+            acdirmin: Duration::from_secs(30),
+            acdirmax: Duration::from_secs(60),
+            cto: true,
+            deleg: true,
+            cache_size: 64 << 20, // 64MB
+            readahead: 2 << 20, // 2MB
+            disk_size: 1 << 30, // 1GB
+            dirty_expire: Duration::from_secs(30),
+            dirty_bytes: 64 << 20, // 64MB
+        }
+    }
+}
+
+impl CacheConfig {
+    /// The bounds of the TTL of the attributes of the given kind of file.
+    fn ttl_bounds(&self, kind: FileType) -> (Duration, Duration) {
+        match kind {
+            FileType::Directory => (self.acdirmin, self.acdirmax),
+            _ => (self.acregmin, self.acregmax),
+        }
+    }
+}
+
+/// Whether the file hasn't changed between two sets of attributes.
+fn unchanged(old: &FileAttr, new: &FileAttr) -> bool {
+    old.mtime == new.mtime && old.ctime == new.ctime && old.size == new.size
+}
+
+/// The version of the file's data, for checking what is cached of it.
+fn version_of(attr: &FileAttr) -> Version {
+    Version::new((attr.mtime.sec, (attr.mtime.nsec / 1000) as i64), attr.size)
+}
+
+/// Everything we remember about files and directories between requests, mostly to save RPCs.
+/// It is never kept locked during an RPC.
+#[derive(Default)]
+struct Meta {
+    // The cookie verifier the server returned with the last READDIR page of each directory.
+    // Directory Fid -> verifier
+    readdir_verfs: HashMap<Fid, i64>,
+
+    // The entries of the last READDIR page of each directory that didn't fit in the reply to the
+    // kernel, and whether that page reached the end of the directory.
+    // Directory Fid -> (cookie of the last entry the kernel got, entries, eof)
+    readdir_rest: HashMap<Fid, (u64, Vec<ZipDirEntryPlus>, bool)>,
+
+    // Attributes and names we have recently heard about from the server, so that the LOOKUPs
+    // and GETATTRs the kernel does (e.g. for every entry of a listing, or every `stat` of the
+    // same file) don't each need an RPC. Attributes are good for their own TTL, which is what we
+    // hand to the kernel too, and names for `acdirmin`.
+    // ino -> (attributes, when we got them, TTL)
+    attr_cache: HashMap<u64, (FileAttr, Instant, Duration)>,
+    // (parent ino, name) -> (ino, when we got it)
+    entry_cache: HashMap<(u64, String), (u64, Instant)>,
+
+    // The delegations the server gave us. The attributes of these files stay good until the
+    // delegation is recalled, whatever their TTL, and so does their cached data.
+    // ino -> kind
+    delegations: HashMap<u64, ZipDelegationType>,
+
+    // The server epoch the delegations were given in
+    delegation_epoch: u64,
+
+    // The number of recalls so far, to tell whether one came in while we were asking for a
+    // delegation, and so might have been of the one we were being given
+    recalls: u64,
+
+    config: CacheConfig,
+}
+
+impl Meta {
+    /// Remember the attributes of the given file.
+    fn prime_attr(&mut self, attr: FileAttr) {
+        let (min_ttl, max_ttl) = self.config.ttl_bounds(attr.kind);
+        let old = self.attr_cache.get(&attr.ino).map(
+            |&(old, _, ttl)| (old, ttl),
         );
 
-        let args = ZipReadArgs::new(ZipFileHandle::new(ino as i64), offset as i64, size as i64);
+        let ttl = match old {
+            // It hasn't changed since we last looked, so it probably won't change soon either
+            Some((old, ttl)) if unchanged(&old, &attr) => min(max(ttl * 2, min_ttl), max_ttl),
+            Some(_) => {
+                self.changed(attr.ino);
+                min_ttl
+            }
+            None => min_ttl,
+        };
+
+        self.attr_cache.insert(attr.ino, (attr, Instant::now(), ttl));
+    }
 
-        let result =
-            do_with_retry! {
-                self,
-                self.znfs.read(args.clone()).map_err(|e| e.into())
-            };
+    /// Forget anything that depends on the contents of the given file, which have changed.
+    fn changed(&mut self, ino: u64) {
+        self.entry_cache.retain(|&(parent, _), _| parent != ino);
+        self.readdir_rest.remove(&(ino as Fid));
+    }
+
+    /// The TTL of the given file's attributes, or zero if we don't have any.
+    fn ttl(&self, ino: u64) -> Duration {
+        match self.attr_cache.get(&ino) {
+            Some(&(_, _, ttl)) => ttl,
+            None => Duration::new(0, 0),
+        }
+    }
+
+    /// Remember that `name` in `parent` is the file with the given attributes.
+    fn prime_entry(&mut self, parent: u64, name: String, attr: FileAttr) {
+        self.entry_cache.insert((parent, name), (attr.ino, Instant::now()));
+        self.prime_attr(attr);
+    }
 
-        match result {
-            Ok(mut resattr) => {
-                let data_len = resattr.data.len();
+    /// Returns the remembered attributes of the given file, if they are still fresh, which they
+    /// always are while we hold a delegation of it.
+    fn cached_attr(&self, ino: u64) -> Option<FileAttr> {
+        match self.attr_cache.get(&ino) {
+            Some(&(attr, primed, ttl)) if primed.elapsed() < ttl => Some(attr),
+            Some(&(attr, _, _)) if self.delegations.contains_key(&ino) => Some(attr),
+            _ => None,
+        }
+    }
 
-                println!("Recv {} B", data_len);
+    /// Returns whether we hold a WRITE delegation of the file, so that nobody else can open it
+    /// without recalling it first.
+    fn write_delegated(&self, ino: u64) -> bool {
+        self.delegations.get(&ino) == Some(&ZipDelegationType::WRITE)
+    }
 
-                // Return the data
-                buf.append(&mut resattr.data);
+    /// Forget our delegation of the file, along with the attributes it kept good.
+    fn forget_delegation(&mut self, ino: u64) {
+        if self.delegations.remove(&ino).is_some() {
+            self.attr_cache.remove(&ino);
+        }
+    }
 
-                // Return the number of bytes
-                Ok(data_len)
+    /// Returns the remembered attributes of `name` in `parent`, if both the name and the
+    /// attributes are still fresh.
+    fn cached_entry(&self, parent: u64, name: &str) -> Option<FileAttr> {
+        match self.entry_cache.get(&(parent, name.to_owned())) {
+            Some(&(ino, primed)) if primed.elapsed() < self.config.acdirmin => {
+                self.cached_attr(ino)
             }
-            Err(err) => Err(err),
+            _ => None,
         }
     }
 
-    /// Fully write up to MAX_BUF_LEN bytes from the buffer to the given file.
-    fn write_part(
-        &mut self,
-        fid: u64,
-        offset: u64,
-        data_vec: Vec<u8>,
-        stable: ZipWriteStable,
-    ) -> Result<u64, c_int> {
-        let data_len = min(data_vec.len(), MAX_BUF_LEN);
+    /// Forget anything we know about `name` in `parent`.
+    fn forget_entry(&mut self, parent: u64, name: &str) {
+        if let Some((ino, _)) = self.entry_cache.remove(&(parent, name.to_owned())) {
+            self.attr_cache.remove(&ino);
+        }
+    }
 
-        let args = ZipWriteArgs::new(
-            ZipFileHandle::new(fid as i64),
-            offset as i64,
-            data_len as i64,
-            data_vec,
-            stable,
+    /// Throw away everything that is no longer fresh, so the caches don't grow forever.
+    ///
+    /// Attributes are kept for their longest TTL, even if they are stale, so that their TTL can
+    /// keep growing if they turn out not to have changed, and for as long as they are delegated.
+    fn expire_cache(&mut self) {
+        let config = self.config;
+        let delegations = &self.delegations;
+        self.attr_cache.retain(|ino, &mut (attr, primed, _)| {
+            delegations.contains_key(ino) || primed.elapsed() < config.ttl_bounds(attr.kind).1
+        });
+        self.entry_cache.retain(|_, &mut (_, primed)| {
+            primed.elapsed() < config.acdirmin
+        });
+    }
+}
+
+/// A file or directory the kernel has open.
+struct Handle {
+    ino: u64,
+
+    // The flags it was opened with
+    flags: u32,
+
+    // Whether it is a directory, which only we know is open, not the server
+    is_dir: bool,
+
+    // Whether anything has been written through it since it was last flushed
+    written: bool,
+}
+
+/// The files and directories the kernel has open, by file handle. Each open gets its own handle,
+/// so that we can tell when the last one for a file is closed.
+#[derive(Default)]
+struct Handles {
+    // The last file handle given out
+    last: u64,
+
+    handles: HashMap<u64, Handle>,
+
+    // ino -> number of handles
+    opens: HashMap<u64, usize>,
+
+    // The open files that were unlinked, and have to be removed when the last handle is closed:
+    // ino -> (parent, silly name)
+    silly: HashMap<u64, (u64, String)>,
+
+    // The lock owners waiting for locks on files, and how many times they have flushed them since,
+    // which they do whenever they close a descriptor: (ino, owner) -> (requests waiting, flushes)
+    waiting: HashMap<(u64, u64), (usize, u64)>,
+}
+
+impl Handles {
+    /// Make a handle for a new open of the file or directory, and return it.
+    fn open(&mut self, ino: u64, flags: u32, is_dir: bool) -> u64 {
+        self.last += 1;
+        self.handles.insert(
+            self.last,
+            Handle {
+                ino,
+                flags,
+                is_dir,
+                written: false,
+            },
         );
+        *self.opens.entry(ino).or_insert(0) += 1;
+        self.last
+    }
 
-        let result =
-            do_with_retry! {
-                self,
-                self.znfs.write(args.clone()).map_err(|e| e.into())
-            };
+    /// Return whether the file or directory has any handles.
+    fn is_open(&self, ino: u64) -> bool {
+        self.opens.contains_key(&ino)
+    }
 
-        match result {
-            Ok(result) => {
-                assert_eq!(result.count as usize, data_len);
+    /// Note a write through the handle.
+    fn wrote(&mut self, fh: u64) {
+        if let Some(handle) = self.handles.get_mut(&fh) {
+            handle.written = true;
+        }
+    }
+
+    /// Return whether anything was written through the handle since it was last flushed, and
+    /// start over.
+    fn flushed(&mut self, fh: u64) -> bool {
+        match self.handles.get_mut(&fh) {
+            Some(handle) => mem::replace(&mut handle.written, false),
+            None => false,
+        }
+    }
+
+    /// Note that the owner is waiting for a lock on the file, and return how many times it has
+    /// flushed the file, for `closed_since`.
+    fn start_wait(&mut self, ino: u64, owner: u64) -> u64 {
+        let waiting = self.waiting.entry((ino, owner)).or_insert((0, 0));
+        waiting.0 += 1;
+        waiting.1
+    }
 
-                Ok(result.verf as u64)
+    /// Note that the owner is done waiting for a lock on the file.
+    fn end_wait(&mut self, ino: u64, owner: u64) {
+        let done = match self.waiting.get_mut(&(ino, owner)) {
+            Some(waiting) => {
+                waiting.0 -= 1;
+                waiting.0 == 0
             }
-            Err(err) => Err(err),
+            None => false,
+        };
+        if done {
+            self.waiting.remove(&(ino, owner));
+        }
+    }
+
+    /// Note that the owner flushed the file.
+    fn flushed_by(&mut self, ino: u64, owner: u64) {
+        if let Some(waiting) = self.waiting.get_mut(&(ino, owner)) {
+            waiting.1 += 1;
         }
+    }
+
+    /// Return whether the owner, waiting for a lock on the file through the handle, has closed it
+    /// since `start_wait` returned `since`, or the handle is gone altogether.
+    fn closed_since(&self, fh: u64, ino: u64, owner: u64, since: u64) -> bool {
+        let flushes = self.waiting.get(&(ino, owner)).map(|&(_, flushes)| flushes);
+        !self.handles.contains_key(&fh) || flushes != Some(since)
+    }
 
+    /// Describe the handles for the file, for debugging.
+    fn describe(&self, ino: u64) -> String {
+        let mut fhs: Vec<_> = self.handles
+            .iter()
+            .filter(|&(_, handle)| handle.ino == ino)
+            .collect();
+        fhs.sort_by_key(|&(fh, _)| *fh);
+
+        fhs.into_iter()
+            .map(|(fh, handle)| {
+                format!(
+                    "fh={} flags={:#o} written={}\n",
+                    fh,
+                    handle.flags,
+                    handle.written
+                )
+            })
+            .collect()
     }
 
-    /// Attempt to write async until we succeed without epoch changes
-    fn write_async_handle_epochs(&mut self, fid: Fid, mut pos: usize) -> Result<(), c_int> {
-        // If there are no writes, success!
-        if !self.async_bufs.contains_key(&fid) {
-            return Ok(());
+    /// Forget the handle, and return it, along with whether it was the file's last one.
+    fn release(&mut self, fh: u64) -> Option<(Handle, bool)> {
+        let handle = match self.handles.remove(&fh) {
+            Some(handle) => handle,
+            None => return None,
+        };
+
+        let last = match self.opens.get_mut(&handle.ino) {
+            Some(count) => {
+                *count -= 1;
+                *count == 0
+            }
+            None => true,
+        };
+        if last {
+            self.opens.remove(&handle.ino);
         }
 
-        // Get the set of writes
-        let writes_len = self.async_bufs.get(&fid).unwrap().len();
+        Some((handle, last))
+    }
+}
+
+/// The locks we hold, so that we can take them back if the server restarts.
+struct HeldLocks {
+    // The server epoch they were taken in
+    epoch: u64,
 
-        // Keep trying until we succeed without an epoch change
-        while pos < writes_len {
-            // Get a write
-            let (offset, size, data) = {
-                let (offset, size, ref data) = self.async_bufs.get(&fid).unwrap()[pos];
-                (offset, size, data.clone())
-            };
+    // ino -> locks
+    files: HashMap<u64, LockSet>,
+}
 
-            // Sanity
-            assert_eq!(size, data.len());
+/// The unstable writes to one file that haven't been committed yet.
+struct Dirty {
+    // The server epoch the writes were taken in. If the server's epoch is any different, it may
+    // have lost them.
+    epoch: u64,
 
-            // Attempt to write it
-            let epoch = self.write_part(
-                fid as u64,
-                offset as u64,
-                data,
-                ZipWriteStable::UNSTABLE,
-            )?;
+    // When the oldest uncommitted write was made
+    since: Instant,
 
-            // Sanity
-            assert!(epoch >= self.server_epoch);
+    // buffers for the client to store data that has been unstablely written until commit.
+    // Overlapping and adjacent writes are merged, so these are sorted and disjoint.
+    // [(offset, size, data)]
+    bufs: Vec<(usize, usize, Vec<u8>)>,
 
-            // If the epoch changed, then we need to start over
-            if epoch != self.server_epoch {
-                println!(
-                    "EPOCH Mismatch: Expected {} Got {}",
-                    self.server_epoch,
-                    epoch
-                );
-                self.server_epoch = epoch;
-                pos = 0;
-            } else {
-                pos += 1;
+    // Contiguous writes that haven't been sent yet, gathered into one of up to `wsize` bytes.
+    // [(offset, data)]
+    gather: Option<(usize, Vec<u8>)>,
+
+    // The writes that have been sent but not yet acknowledged by the server.
+    // [(offset, size, reply)]
+    in_flight: Vec<(usize, usize, Pending<ZipWriteRes>)>,
+}
+
+/// A stateful FUSE implementation of NFS, which interacts with a remote server via Thrift RPC.
+///
+/// This is shared by all of the threads handling requests from the kernel, so everything that
+/// changes is behind a lock.
+struct ZippyFs {
+    server_addr: String, // Needed to reconnect the pipeline
+    caps: i64, // Server's capabilities when we mounted; see `caps()`
+
+    // The sizes of transfers, as agreed with the server before mounting.
+    rsize: usize, // most data per READ
+    wsize: usize, // most data per WRITE
+    dsize: usize, // size of READDIR replies
+
+    // Connections to the server. Each RPC borrows one for as long as it takes.
+    pool: ConnPool,
+
+    // The connection with many RPCs in flight at once, for reads and writes. Everyone shares it,
+    // until it fails and is replaced.
+    pipe: Mutex<Arc<Pipeline>>,
+
+    // The newest server epoch (generation number) we have seen
+    server_epoch: Mutex<u64>,
+
+    // The files with unstable writes that haven't been committed yet. Each has its own lock, so
+    // that only requests for the same file wait for each other.
+    dirty: Mutex<HashMap<Fid, Arc<Mutex<Dirty>>>>,
+
+    // The total size of the uncommitted writes to all files
+    dirty_bytes: AtomicUsize,
+
+    // Set to wake the background flusher early, because there is too much dirty data
+    flush_wanted: Mutex<bool>,
+    flush_wake: Condvar,
+
+    meta: Mutex<Meta>,
+
+    // The data of files, and what is being read ahead
+    pages: Mutex<PageCache>,
+
+    // The data of files on local disk, under `pages`, if we were given a directory for it
+    disk: Mutex<Option<DiskCache>>,
+
+    // The files and directories the kernel has open
+    handles: Mutex<Handles>,
+
+    // Identifies us to the server in OPEN, CLOSE and LOCK. It is new for each mount.
+    client_id: i64,
+
+    // The locks we hold
+    locks: Mutex<HeldLocks>,
+
+    // The "IP:Port" address we take callbacks from the server at, if we ask for delegations
+    callback_addr: Mutex<Option<String>>,
+}
+
+impl ZippyFs {
+    /// Connect to the server, and agree on the epoch and transfer sizes.
+    fn connect(
+        server_addr: &str,
+        config: PoolConfig,
+        cache_config: CacheConfig,
+        cache_dir: Option<&str>,
+    ) -> Result<ZippyFs, String> {
+        // build a rpc client
+        let pool = ConnPool::new(server_addr, config).map_err(|e| format!("{:?}", e))?;
+        let caps = pool.capabilities();
+
+        if caps & REQUIRED_CAPS != REQUIRED_CAPS {
+            return Err(format!(
+                "Server is missing required capabilities {:#x}",
+                REQUIRED_CAPS & !caps
+            ));
+        }
+
+        let (pipe, _) = Pipeline::connect(server_addr).map_err(|e| format!("{}", e))?;
+
+        let server_epoch = pool.call(|znfs| znfs.null()).map_err(
+            |e| format!("{:?}", e),
+        )? as u64;
+        let fsinfo = pool.call(|znfs| znfs.fsinfo(ZipFileHandle::new(1)))
+            .map_err(|e| format!("{:?}", e))?;
+
+        // Never send or ask for more than either of us can handle
+        let rsize = min(fsinfo.rtmax as usize, MAX_IO_LEN);
+        let wsize = min(fsinfo.wtmax as usize, MAX_IO_LEN);
+        let dsize = min(fsinfo.dtpref as usize, MAX_MSG_LEN);
+
+        println!("rsize={}, wsize={}, dsize={}", rsize, wsize, dsize);
+
+        let block_size = min(BLOCK_SIZE, rsize);
+        let disk = match cache_dir {
+            Some(dir) => {
+                Some(DiskCache::open(dir, block_size, cache_config.disk_size)
+                    .map_err(|e| format!("Unable to open the disk cache: {}", e))?)
             }
+            None => None,
+        };
+
+        Ok(ZippyFs {
+            server_addr: server_addr.to_owned(),
+            caps,
+            rsize,
+            wsize,
+            dsize,
+            pool,
+            pipe: Mutex::new(Arc::new(pipe)),
+            server_epoch: Mutex::new(server_epoch),
+            dirty: Mutex::new(HashMap::new()),
+            dirty_bytes: AtomicUsize::new(0),
+            flush_wanted: Mutex::new(false),
+            flush_wake: Condvar::new(),
+            meta: Mutex::new(Meta {
+                delegation_epoch: server_epoch,
+                config: cache_config,
+                ..Meta::default()
+            }),
+            pages: Mutex::new(PageCache::new(
+                block_size,
+                cache_config.cache_size,
+                cache_config.readahead,
+            )),
+            disk: Mutex::new(disk),
+            handles: Mutex::new(Handles::default()),
+            client_id: new_xid(),
+            locks: Mutex::new(HeldLocks {
+                epoch: server_epoch,
+                files: HashMap::new(),
+            }),
+            callback_addr: Mutex::new(None),
+        })
+    }
+
+    /// Run an RPC on a connection from the pool, which retries it if the connection fails.
+    fn rpc<T, F>(&self, rpc: F) -> Result<T, c_int>
+    where
+        F: FnMut(&mut ZnfsClient) -> thrift::Result<T>,
+    {
+        self.pool.call(rpc).map_err(errno)
+    }
+
+    /// Like `rpc`, for RPCs on the pipeline.
+    fn pipelined<T, F>(&self, f: F) -> Result<T, c_int>
+    where
+        F: FnMut() -> Result<T, ZipError>,
+    {
+        self.pool.retry(f).map_err(errno)
+    }
+
+    /// The server's capabilities. If the server loses any after we mount (e.g. it is downgraded),
+    /// we stop using them, but we don't start using new ones, which we haven't set up for.
+    fn caps(&self) -> i64 {
+        self.caps & self.pool.capabilities()
+    }
+
+    /// The pipeline, which is replaced first if it has failed.
+    fn pipe(&self) -> Result<Arc<Pipeline>, ZipError> {
+        let mut pipe = self.pipe.lock().unwrap();
+        if pipe.is_closed() {
+            let (new_pipe, _) = Pipeline::connect(&self.server_addr)?;
+            *pipe = Arc::new(new_pipe);
         }
 
-        Ok(())
+        let current = pipe.clone();
+        Ok(current)
     }
 
-    /// A helper to in sending async writes to the server.
-    ///
-    /// It handles all of the weirdness of dealing with errors and server epoch numbers.
-    ///
-    /// It assumes we will never get a write of more than MAX_BUF_LEN bytes to send.
-    fn write_async_part(
-        &mut self,
-        fid: Fid,
-        offset: u64,
-        size: u64,
-        data: Vec<u8>,
-    ) -> Result<u64, c_int> {
-        // Append to the appropriate set of async bufs
-        let pos = if self.async_bufs.contains_key(&fid) {
-            let async_bufs = self.async_bufs.get_mut(&fid).unwrap();
-            async_bufs.push((offset as usize, size as usize, data));
-            async_bufs.len() - 1
-        } else {
-            let buf = vec![(offset as usize, size as usize, data)];
-            self.async_bufs.insert(fid, buf);
-            0
+    fn meta(&self) -> MutexGuard<Meta> {
+        self.meta.lock().unwrap()
+    }
+
+    fn handles(&self) -> MutexGuard<Handles> {
+        self.handles.lock().unwrap()
+    }
+
+    fn pages(&self) -> MutexGuard<PageCache> {
+        self.pages.lock().unwrap()
+    }
+
+    /// Run `f` on the disk cache, if there is one. It is only there to save RPCs, so an error is
+    /// just reported, and counts as the disk cache not having what we wanted.
+    fn disk<T, F>(&self, f: F) -> Option<T>
+    where
+        F: FnOnce(&mut DiskCache) -> io::Result<T>,
+    {
+        let mut disk = self.disk.lock().unwrap();
+        let result = match *disk {
+            Some(ref mut disk) => {
+                match f(disk) {
+                    Ok(res) => Some(res),
+                    Err(e) => {
+                        println!("Disk cache error: {}", e);
+                        None
+                    }
+                }
+            }
+            None => None,
         };
+        result
+    }
 
-        // Then attempt to write frome the given position
-        self.write_async_handle_epochs(fid, pos).map(|_| size)
+    /// Take a block from the disk cache, if it is there, and put it in the page cache too.
+    fn disk_block(&self, fid: Fid, version: Version, block: u64) -> Option<Vec<u8>> {
+        match self.disk(|disk| disk.get(fid as u64, version, block)) {
+            Some(Some(data)) => {
+                self.pages().insert(fid as u64, version, block, data.clone());
+                Some(data)
+            }
+            _ => None,
+        }
     }
 
-    /// A helper for running a COMMIT
-    fn commit(&mut self, fid: Fid) -> Result<(), c_int> {
-        // Keep trying until we succeed without an epoch change
-        loop {
-            // Try to send a COMMIT message and get the epoch #
-            let epoch = {
-                // Commit the whole file
-                let args = ZipCommitArgs::new(ZipFileHandle::new(fid as i64), 0, 0);
+    /// Forget whatever is cached of the file's data, because we are changing it.
+    fn forget_data(&self, ino: u64) {
+        self.pages().forget(ino);
+        self.disk(|disk| disk.forget(ino));
+    }
 
-                // Try to do the operation
-                let result =
-                    do_with_retry! {
-                        self,
-                        self.znfs.commit(args.clone()).map_err(|e| e.into())
-                    };
+    /// Remember the server's epoch, if it is newer than any we have seen.
+    fn note_epoch(&self, epoch: u64) {
+        let mut server_epoch = self.server_epoch.lock().unwrap();
+        *server_epoch = max(*server_epoch, epoch);
+    }
 
-                // Extract the epoch number
-                result.map(|r| r.verf as u64)?
-            };
+    /// The uncommitted writes to the file, if there are any.
+    fn dirty_file(&self, fid: Fid) -> Option<Arc<Mutex<Dirty>>> {
+        self.dirty.lock().unwrap().get(&fid).cloned()
+    }
 
-            // Epoch sanity
-            assert!(epoch >= self.server_epoch);
+    /// The uncommitted writes to the file, which may be none so far.
+    fn file(&self, fid: Fid) -> Arc<Mutex<Dirty>> {
+        let epoch = *self.server_epoch.lock().unwrap();
+
+        self.dirty
+            .lock()
+            .unwrap()
+            .entry(fid)
+            .or_insert_with(|| {
+                Arc::new(Mutex::new(Dirty {
+                    epoch,
+                    since: Instant::now(),
+                    bufs: Vec::new(),
+                    gather: None,
+                    in_flight: Vec::new(),
+                }))
+            })
+            .clone()
+    }
 
-            // If the epoch number matches, then we are done. Otherwise, redo...
-            if epoch == self.server_epoch {
-                // Cleanup!
-                self.async_bufs.remove(&fid);
-                break;
-            } else {
-                println!(
-                    "EPOCH Mismatch: Expected {} Got {}",
-                    self.server_epoch,
-                    epoch
-                );
-                self.server_epoch = epoch;
-                self.write_async_handle_epochs(fid, 0)?;
+    /// Forget about the file's writes if they have all been committed, and nobody else is
+    /// looking at them.
+    fn forget_clean(&self, fid: Fid) {
+        let mut dirty = self.dirty.lock().unwrap();
+
+        let clean = match dirty.get(&fid) {
+            // If we hold the only reference, nobody can have it locked
+            Some(file) => {
+                Arc::strong_count(file) == 1 && {
+                    let file = file.lock().unwrap();
+                    file.bufs.is_empty() && file.in_flight.is_empty()
+                }
             }
-        }
+            None => false,
+        };
 
+        if clean {
+            dirty.remove(&fid);
+        }
+    }
+
+    /// Get the page of the directory after the given cookie from the server, and whether it
+    /// reaches the end of the directory.
+    fn readdir_page(&self, ino: u64, cookie: u64) -> Result<(Vec<ZipDirEntryPlus>, bool), c_int> {
+        let verf = if cookie == 0 {
+            0
+        } else {
+            self.meta()
+                .readdir_verfs
+                .get(&(ino as Fid))
+                .cloned()
+                .unwrap_or(0)
+        };
+
+        let args = ZipReadDirArgs::new(
+            ZipFileHandle::new(ino as i64),
+            cookie as i64,
+            verf,
+            self.dsize as i64,
+        );
+
+        let result = self.rpc(|znfs| znfs.readdirplus(args.clone()));
+
+        // The directory changed under the cookie since the last page, so we can't tell where to
+        // resume, and the caller has to start over from cookie 0, which doesn't need a verifier.
+        if let Err(EBADCOOKIE) = result {
+            println!("Stale readdir verifier for ino={}", ino);
+            self.meta().readdir_verfs.remove(&(ino as Fid));
+        }
+
+        let dir_list = result?;
+        println!("resp: {:?}", dir_list);
+        self.meta().readdir_verfs.insert(
+            ino as Fid,
+            dir_list.cookieverf,
+        );
+
+        Ok((dir_list.entries, dir_list.eof))
+    }
+
+    /// Read up to `size` bytes from the given offset of the given version of the file, through
+    /// the page and disk caches. Whatever blocks aren't cached are asked for all at once on the
+    /// pipeline, along with any read-ahead. The result is short only at EOF.
+    fn read_cached(
+        &self,
+        fid: Fid,
+        version: Version,
+        offset: u64,
+        size: u64,
+    ) -> Result<Vec<u8>, ZipError> {
+        let end = min(offset + size, version.size());
+        if offset >= end {
+            return Ok(Vec::new());
+        }
+
+        let block_size = self.pages().block_size() as u64;
+        let first = offset / block_size;
+        let last = (end - 1) / block_size;
+
+        let (mut blocks, ahead) = {
+            let mut pages = self.pages();
+            let blocks: Vec<_> = (first..last + 1)
+                .map(|block| pages.get(fid as u64, version, block))
+                .collect();
+            let ahead = pages.readahead(fid as u64, version, offset, end);
+            (blocks, ahead)
+        };
+
+        let pipe = self.pipe()?;
+        let client_id = self.client_id;
+        let read_block = |block: u64| {
+            let args = ZipReadArgs::new(
+                ZipFileHandle::new(fid as i64),
+                (block * block_size) as i64,
+                block_size as i64,
+                client_id,
+            );
+            pipe.read(args)
+        };
+
+        for (i, block) in blocks.iter_mut().enumerate() {
+            if let Block::Missing = *block {
+                let number = first + i as u64;
+                *block = match self.disk_block(fid, version, number) {
+                    Some(data) => Block::Cached(data),
+                    None => Block::Coming(read_block(number)?),
+                };
+            }
+        }
+
+        for block in ahead {
+            if self.disk_block(fid, version, block).is_some() {
+                continue;
+            }
+
+            let reply = read_block(block)?;
+            self.pages().reading_ahead(fid as u64, version, block, reply);
+        }
+
+        let mut buf = Vec::with_capacity((last - first + 1) as usize * block_size as usize);
+        for (i, block) in blocks.into_iter().enumerate() {
+            let data = match block {
+                Block::Cached(data) => data,
+                Block::Coming(reply) => {
+                    let res = reply.wait()?;
+
+                    // The file changed since we looked, so our attributes are out of date too
+                    let got = Version::of(&res.attributes);
+                    if got != version {
+                        self.meta().prime_attr(to_file_attr(res.attributes.clone()));
+                    }
+
+                    let number = first + i as u64;
+                    self.pages().insert(fid as u64, got, number, res.data.clone());
+                    self.disk(|disk| disk.insert(fid as u64, got, number, &res.data));
+                    res.data
+                }
+                Block::Missing => unreachable!(),
+            };
+
+            let got = data.len();
+            buf.extend_from_slice(&data);
+
+            // EOF, so anything after this is empty
+            if (got as u64) < block_size {
+                break;
+            }
+        }
+
+        let skip = (offset - first * block_size) as usize;
+        if buf.len() <= skip {
+            return Ok(Vec::new());
+        }
+        buf.drain(..skip);
+        buf.truncate((end - offset) as usize);
+
+        Ok(buf)
+    }
+
+    /// Fully write up to `wsize` bytes from the buffer to the given file. If the server writes
+    /// less than we sent, the rest is sent again. Returns the verifier of the first write, so
+    /// that a reboot while we were at it shows.
+    fn write_part(
+        &self,
+        fid: u64,
+        offset: u64,
+        mut data_vec: Vec<u8>,
+        stable: ZipWriteStable,
+    ) -> Result<u64, c_int> {
+        data_vec.truncate(self.wsize);
+
+        let mut written = 0;
+        let mut verf = None;
+        while written < data_vec.len() {
+            let args = ZipWriteArgs::new(
+                ZipFileHandle::new(fid as i64),
+                (offset + written as u64) as i64,
+                (data_vec.len() - written) as i64,
+                data_vec[written..].to_vec(),
+                stable,
+                self.client_id,
+            );
+
+            let result = self.rpc(|znfs| znfs.write(args.clone()))?;
+
+            // Writing nothing at all would have us go around forever
+            if result.count <= 0 || result.count as usize > data_vec.len() - written {
+                println!(
+                    "Server wrote {} of {} bytes to {}",
+                    result.count,
+                    data_vec.len() - written,
+                    fid
+                );
+                return Err(EIO);
+            }
+
+            written += result.count as usize;
+            verf = verf.or(Some(result.verf as u64));
+        }
+
+        Ok(verf.unwrap_or(0))
+    }
+
+    /// Send the given unstable writes to the file, and return the verifier that each one came
+    /// back with.
+    ///
+    /// The writes are pipelined, rather than each waiting for the one before. Writes in flight
+    /// at the same time may land in any order, though, so we wait for the earlier ones before
+    /// sending one that overlaps them.
+    fn write_pipelined(
+        &self,
+        fid: Fid,
+        writes: &[(usize, usize, Vec<u8>)],
+    ) -> Result<Vec<u64>, ZipError> {
+        let pipe = self.pipe()?;
+
+        let mut verfs = Vec::with_capacity(writes.len());
+        let mut pending: Vec<Pending<ZipWriteRes>> = Vec::new();
+        let mut first_pending = 0;
+
+        for (i, &(offset, size, ref data)) in writes.iter().enumerate() {
+            if writes[first_pending..i].iter().any(|&(o, s, _)| {
+                overlaps(o, s, offset, size)
+            })
+            {
+                for reply in pending.drain(..) {
+                    verfs.push(reply.wait()?.verf as u64);
+                }
+                first_pending = i;
+            }
+
+            let args = ZipWriteArgs::new(
+                ZipFileHandle::new(fid as i64),
+                offset as i64,
+                size as i64,
+                data.clone(),
+                ZipWriteStable::UNSTABLE,
+                self.client_id,
+            );
+            pending.push(pipe.write(args)?);
+        }
+
+        for reply in pending {
+            verfs.push(reply.wait()?.verf as u64);
+        }
+
+        Ok(verfs)
+    }
+
+    /// Send all of the file's uncommitted writes again, until they have all been taken in the
+    /// same server epoch. Overlapping writes were merged as they were made, so each byte is only
+    /// sent once, in as few writes as will do.
+    fn write_async_handle_epochs(&self, fid: Fid, dirty: &mut Dirty) -> Result<(), c_int> {
+        // Whatever was gathered is in `bufs` too, so it gets sent here
+        dirty.gather = None;
+        let writes = split_extents(&dirty.bufs, self.wsize);
+
+        // Keep trying until we succeed without an epoch change
+        loop {
+            let result = self.pipelined(|| self.write_pipelined(fid, &writes));
+            let verfs = result?;
+
+            let epoch = match verfs.iter().cloned().max() {
+                Some(epoch) => epoch,
+                None => return Ok(()),
+            };
+            self.note_epoch(epoch);
+
+            // If the epoch changed in the middle, then we need to start over
+            if verfs.iter().all(|&verf| verf == epoch) {
+                dirty.epoch = epoch;
+                return Ok(());
+            }
+
+            println!("EPOCH changed while resending writes to fid={}", fid);
+        }
+    }
+
+    /// Check the reply to a pipelined unstable write, and return whether the server took the
+    /// write in the same epoch as the file's other writes.
+    fn check_write(&self, dirty: &Dirty, reply: Pending<ZipWriteRes>) -> bool {
+        match reply.wait() {
+            Ok(res) => {
+                let epoch = res.verf as u64;
+                if epoch != dirty.epoch {
+                    println!("EPOCH Mismatch: Expected {} Got {}", dirty.epoch, epoch);
+                    self.note_epoch(epoch);
+                    return false;
+                }
+                true
+            }
+            Err(e) => {
+                println!("Pipelined write failed: {:?}", ZipError::from(e));
+                false
+            }
+        }
+    }
+
+    /// Wait for all of the writes to the file that are in flight, and return whether the server
+    /// took all of them in the same epoch as the file's other writes.
+    fn wait_in_flight(&self, dirty: &mut Dirty) -> bool {
+        let in_flight = mem::replace(&mut dirty.in_flight, Vec::new());
+
+        let mut ok = true;
+        for (_, _, reply) in in_flight {
+            ok &= self.check_write(dirty, reply);
+        }
+        ok
+    }
+
+    /// Make sure all of the unstable writes to the file have reached the server. If any of them
+    /// went wrong, or the server restarted, we send all of them again.
+    fn finish_writes(&self, fid: Fid, dirty: &mut Dirty) -> Result<(), c_int> {
+        self.send_gathered(fid, dirty)?;
+
+        if self.wait_in_flight(dirty) {
+            Ok(())
+        } else {
+            self.write_async_handle_epochs(fid, dirty)
+        }
+    }
+
+    /// Like `finish_writes`, for anything that depends on the writes to the file, as the server
+    /// might otherwise handle it before writes that are still in flight.
+    fn sync_file(&self, fid: Fid) -> Result<(), c_int> {
+        let file = match self.dirty_file(fid) {
+            Some(file) => file,
+            None => return Ok(()),
+        };
+
+        let mut dirty = file.lock().unwrap();
+        let result = self.finish_writes(fid, &mut dirty);
+        result
+    }
+
+    /// A helper to in sending async writes to the server.
+    ///
+    /// The data is kept until it is committed, and contiguous or overlapping writes are gathered
+    /// into one of up to `wsize` bytes before being sent, so that a stream of small writes takes
+    /// few RPCs. What was gathered is sent once it is full, when a write elsewhere in the file
+    /// comes along, or before anything that needs the writes to have landed (`finish_writes`).
+    ///
+    /// A write of more than `wsize` bytes is split up first, as gathering relies on each one
+    /// fitting in a single WRITE.
+    fn write_async_part(
+        &self,
+        fid: Fid,
+        offset: u64,
+        size: u64,
+        data: Vec<u8>,
+    ) -> Result<u64, c_int> {
+        let (offset, size) = (offset as usize, size as usize);
+        assert_eq!(size, data.len());
+
+        if size > self.wsize {
+            for (i, chunk) in data.chunks(self.wsize).enumerate() {
+                let chunk_offset = (offset + i * self.wsize) as u64;
+                self.write_async_part(fid, chunk_offset, chunk.len() as u64, chunk.to_vec())?;
+            }
+            return Ok(size as u64);
+        }
+
+        let file = self.file(fid);
+        let mut guard = file.lock().unwrap();
+        let dirty = &mut *guard;
+
+        // Keep the data until it is committed, in case we need to send it again
+        if dirty.bufs.is_empty() {
+            dirty.since = Instant::now();
+        }
+        // Merging never loses bytes, so this only grows
+        let before = extents_len(&dirty.bufs);
+        merge_extent(&mut dirty.bufs, offset, &data);
+        let after = extents_len(&dirty.bufs);
+        self.dirty_bytes.fetch_add(after - before, Ordering::SeqCst);
+
+        // Add it to what was gathered, if it touches it and fits
+        let fits = match dirty.gather {
+            Some((start, ref buf)) => {
+                let end = start + buf.len();
+                start <= offset && offset <= end && max(end, offset + size) - start <= self.wsize
+            }
+            None => false,
+        };
+
+        if fits {
+            let full = match dirty.gather {
+                Some((start, ref mut buf)) => {
+                    let end = max(buf.len(), offset - start + size);
+                    buf.resize(end, 0);
+                    buf[offset - start..offset - start + size].copy_from_slice(&data);
+                    buf.len() == self.wsize
+                }
+                None => false,
+            };
+
+            if full {
+                self.send_gathered(fid, dirty)?;
+            }
+        } else {
+            // Start gathering again from this write
+            self.send_gathered(fid, dirty)?;
+            dirty.gather = Some((offset, data));
+            if size == self.wsize {
+                self.send_gathered(fid, dirty)?;
+            }
+        }
+
+        Ok(size as u64)
+    }
+
+    /// Send whatever writes to the file have been gathered, without waiting for the server to
+    /// acknowledge them, so that a stream of writes doesn't wait a round trip for each one. The
+    /// replies are checked later, in `finish_writes`, or when there are too many in flight.
+    fn send_gathered(&self, fid: Fid, dirty: &mut Dirty) -> Result<(), c_int> {
+        let (offset, data) = match dirty.gather.take() {
+            Some(gathered) => gathered,
+            None => return Ok(()),
+        };
+        let size = data.len();
+
+        // This has to land after any earlier write to the same bytes
+        if dirty.in_flight.iter().any(
+            |&(o, s, _)| overlaps(o, s, offset, size),
+        )
+        {
+            self.finish_writes(fid, dirty)?;
+        }
+
+        let args = ZipWriteArgs::new(
+            ZipFileHandle::new(fid as i64),
+            offset as i64,
+            size as i64,
+            data,
+            ZipWriteStable::UNSTABLE,
+            self.client_id,
+        );
+
+        let sent = self.pipe().and_then(|pipe| Ok(pipe.write(args)?));
+        let reply = match sent {
+            Ok(reply) => reply,
+            Err(e) => {
+                println!("Pipelined write failed: {:?}", e);
+                self.wait_in_flight(dirty);
+                return self.write_async_handle_epochs(fid, dirty);
+            }
+        };
+
+        // Check on the oldest write once there are enough in flight
+        dirty.in_flight.push((offset, size, reply));
+        if dirty.in_flight.len() > MAX_IN_FLIGHT {
+            let (_, _, oldest) = dirty.in_flight.remove(0);
+            if !self.check_write(dirty, oldest) {
+                self.wait_in_flight(dirty);
+                self.write_async_handle_epochs(fid, dirty)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// A helper for running a COMMIT
+    fn commit(&self, fid: Fid) -> Result<(), c_int> {
+        let file = match self.dirty_file(fid) {
+            Some(file) => file,
+
+            // Nothing to commit
+            None => return Ok(()),
+        };
+
+        let result = {
+            let mut dirty = file.lock().unwrap();
+            let result = self.commit_locked(fid, &mut dirty);
+            result
+        };
+
+        drop(file);
+        self.forget_clean(fid);
+
+        result
+    }
+
+    /// Commit the file's writes, with the file locked.
+    fn commit_locked(&self, fid: Fid, dirty: &mut Dirty) -> Result<(), c_int> {
+        self.finish_writes(fid, dirty)?;
+
+        // Keep trying until we succeed without an epoch change
+        while !dirty.bufs.is_empty() {
+            // Try to send a COMMIT message and get the epoch #
+            let epoch = {
+                // Commit the whole file
+                let args =
+                    ZipCommitArgs::new(ZipFileHandle::new(fid as i64), 0, 0, self.client_id);
+
+                // Try to do the operation
+                let result = self.rpc(|znfs| znfs.commit(args.clone()));
+
+                // Extract the epoch number
+                result.map(|r| r.verf as u64)?
+            };
+
+            // If the epoch number matches, then we are done. Otherwise, redo...
+            if epoch == dirty.epoch {
+                // Cleanup!
+                self.dirty_bytes.fetch_sub(extents_len(&dirty.bufs), Ordering::SeqCst);
+                dirty.bufs.clear();
+            } else {
+                println!("EPOCH Mismatch: Expected {} Got {}", dirty.epoch, epoch);
+                self.note_epoch(epoch);
+                self.write_async_handle_epochs(fid, dirty)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The main loop of the lease renewer, which keeps our lease with the server, so that it
+    /// doesn't take us for dead and clean up after us, e.g. remove our silly renamed files.
+    fn renewer(&self) {
+        let mut lease = Duration::from_secs(DEFAULT_LEASE_SECS);
+        let mut renewed = Instant::now();
+
+        loop {
+            let client_id = self.client_id;
+            match self.rpc(|znfs| znfs.renew(client_id)) {
+                Ok(secs) => {
+                    lease = Duration::from_secs(max(secs, 3) as u64);
+                    renewed = Instant::now();
+                }
+                Err(err) => println!("Unable to renew our lease: {}", err),
+            }
+
+            // Once our lease has run out, the server has dropped our delegations
+            if renewed.elapsed() >= lease {
+                self.drop_delegations();
+            }
+
+            self.set_callback();
+            self.check_locks();
+            self.check_delegations();
+
+            // Renew well before the lease runs out
+            thread::sleep(lease / 3);
+        }
+    }
+
+    /// Look for a lock held by someone else that keeps the owner from taking the given one.
+    fn getlk(
+        &self,
+        ino: u64,
+        owner: u64,
+        start: u64,
+        end: u64,
+        typ: u32,
+        pid: u32,
+    ) -> Result<Option<ZipLock>, c_int> {
+        // Without the server, the kernel can only lock for processes on this machine
+        if self.caps() & CAP_LOCKS == 0 {
+            return Err(ENOSYS);
+        }
+
+        let lock = to_zip_lock(owner, start, end, typ, pid)?;
+        let args = ZipLockArgs::new(ZipFileHandle::new(ino as i64), self.client_id, lock, false);
+
+        let result = self.rpc(|znfs| znfs.testlock(args.clone()));
+        Ok(result?.conflict)
+    }
+
+    /// Take or release a lock on a range of the file for the owner. If `wait` gives the handle it
+    /// was asked for through, this waits until the lock is free, asking the server again and
+    /// again, with longer and longer waits in between, since the server never makes anyone wait.
+    /// It gives up with EINTR once the owner closes the file, which would have released the lock
+    /// anyway, and if the lock comes through just then, releases it again right away.
+    ///
+    /// Like with close-to-open consistency, whatever was written is committed before unlocking,
+    /// and the file's attributes (and so which cached data is good) are checked after locking, so
+    /// that processes taking turns with a lock see each other's writes.
+    fn setlk(
+        &self,
+        ino: u64,
+        owner: u64,
+        start: u64,
+        end: u64,
+        typ: u32,
+        pid: u32,
+        wait: Option<u64>,
+    ) -> Result<(), c_int> {
+        if self.caps() & CAP_LOCKS == 0 {
+            return Err(ENOSYS);
+        }
+
+        let lock = to_zip_lock(owner, start, end, typ, pid)?;
+        let args = ZipLockArgs::new(
+            ZipFileHandle::new(ino as i64),
+            self.client_id,
+            lock.clone(),
+            false,
+        );
+
+        let unlock = lock.kind == ZipLockType::UNLOCK;
+        if unlock {
+            self.commit(ino as Fid)?;
+        }
+
+        let waiting = wait.map(|fh| (fh, self.handles().start_wait(ino, owner)));
+        let closed = || match waiting {
+            Some((fh, since)) => self.handles().closed_since(fh, ino, owner, since),
+            None => false,
+        };
+
+        let result = match self.poll_lock(&args, waiting.is_some(), &closed) {
+            Ok(()) if closed() => {
+                self.setlk(ino, owner, start, end, F_UNLCK as u32, 0, None)
+                    .and(Err(EINTR))
+            }
+            Ok(()) if !unlock => self.getattr_uncached(ino).map(|_| ()),
+            result => result,
+        };
+        if waiting.is_some() {
+            self.handles().end_wait(ino, owner);
+        }
+        result
+    }
+
+    /// Ask the server for the lock, and if `wait`, again and again until it is granted, or until
+    /// `closed` says that the owner has closed the file.
+    fn poll_lock(
+        &self,
+        args: &ZipLockArgs,
+        wait: bool,
+        closed: &Fn() -> bool,
+    ) -> Result<(), c_int> {
+        let ino = args.file.fid as u64;
+
+        let mut delay = Duration::from_millis(LOCK_POLL_MIN_MS);
+        loop {
+            if closed() {
+                return Err(EINTR);
+            }
+
+            // EAGAIN means the server is in its grace period
+            match self.rpc(|znfs| znfs.lock(args.clone())) {
+                Ok(ref res) if res.granted => {
+                    self.note_lock(ino, res.epoch as u64, &args.lock);
+                    return Ok(());
+                }
+                Ok(_) | Err(EAGAIN) if wait => {}
+                Ok(_) => return Err(EAGAIN),
+                Err(err) => return Err(err),
+            }
+
+            thread::sleep(delay);
+            delay = min(delay * 2, Duration::from_millis(LOCK_POLL_MAX_MS));
+        }
+    }
+
+    /// Release all of the owner's locks on the file, which POSIX says happens whenever it closes
+    /// any descriptor for the file.
+    fn unlock_owner(&self, ino: u64, owner: u64) -> Result<(), c_int> {
+        let holds = match self.locks.lock().unwrap().files.get(&ino) {
+            Some(locks) => locks.holds(self.client_id, owner as i64),
+            None => false,
+        };
+
+        if holds {
+            self.setlk(ino, owner, 0, i64::MAX as u64, F_UNLCK as u32, 0, None)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Note a lock or unlock that the server granted in the given epoch.
+    fn note_lock(&self, ino: u64, epoch: u64, lock: &ZipLock) {
+        // The locks from before a restart have to be taken back before they are changed
+        self.reclaim_locks(epoch);
+
+        let mut held = self.locks.lock().unwrap();
+        let empty = {
+            let locks = held.files.entry(ino).or_insert_with(LockSet::new);
+            locks.set(self.client_id, lock);
+            locks.is_empty()
+        };
+        if empty {
+            held.files.remove(&ino);
+        }
+    }
+
+    /// If we hold any locks, check whether the server has restarted, and take them back if it has.
+    fn check_locks(&self) {
+        if self.caps() & CAP_LOCKS == 0 || self.locks.lock().unwrap().files.is_empty() {
+            return;
+        }
+
+        match self.rpc(|znfs| znfs.null()) {
+            Ok(epoch) => self.reclaim_locks(epoch as u64),
+            Err(err) => println!("Unable to check the server epoch: {}", err),
+        }
+    }
+
+    /// If the server's epoch has moved on from the one our locks were taken in, it has restarted
+    /// and forgotten them, so take them back. Locks that someone else got first are lost. The
+    /// locks aren't kept locked while asking the server, so that nobody else waits on it.
+    fn reclaim_locks(&self, epoch: u64) {
+        let mine: Vec<(u64, ZipLock)> = {
+            let mut held = self.locks.lock().unwrap();
+            if epoch <= held.epoch {
+                return;
+            }
+            held.epoch = epoch;
+
+            let mut mine = vec![];
+            for (&ino, locks) in held.files.iter() {
+                for &(_, ref lock) in locks.locks() {
+                    mine.push((ino, lock.clone()));
+                }
+            }
+            mine
+        };
+
+        let mut lost = vec![];
+        for (ino, lock) in mine {
+            let args = ZipLockArgs::new(
+                ZipFileHandle::new(ino as i64),
+                self.client_id,
+                lock.clone(),
+                true,
+            );
+
+            let result = self.rpc(|znfs| znfs.lock(args.clone()));
+            match result {
+                Ok(ref res) if res.granted => {}
+                _ => {
+                    println!("Lost lock {:?} on {} after the server restarted", lock, ino);
+                    lost.push((ino, lock));
+                }
+            }
+        }
+        if lost.is_empty() {
+            return;
+        }
+
+        let mut held = self.locks.lock().unwrap();
+        for (ino, mut unlock) in lost {
+            unlock.kind = ZipLockType::UNLOCK;
+            if let Some(locks) = held.files.get_mut(&ino) {
+                locks.set(self.client_id, &unlock);
+            }
+        }
+        held.files.retain(|_, locks| !locks.is_empty());
+    }
+
+    /// Tell the server where we take callbacks, if we ask for delegations. This is done every time
+    /// the lease is renewed, so that a server that restarted knows it again.
+    fn set_callback(&self) {
+        let addr = self.callback_addr.lock().unwrap().clone();
+        if let Some(addr) = addr {
+            let args = ZipCallbackArgs::new(self.client_id, addr);
+            if let Err(err) = self.rpc(|znfs| znfs.setcallback(args.clone())) {
+                println!("Unable to tell the server where we take callbacks: {}", err);
+            }
+        }
+    }
+
+    /// Ask for a delegation of the file, unless we hold one that is good enough already.
+    fn delegate(&self, ino: u64, kind: ZipDelegationType) -> Result<(), c_int> {
+        let recalls = {
+            let meta = self.meta();
+            match meta.delegations.get(&ino) {
+                Some(&ZipDelegationType::WRITE) => return Ok(()),
+                Some(&held) if held == kind => return Ok(()),
+                _ => {}
+            }
+            meta.recalls
+        };
+
+        let args = ZipDelegateArgs::new(
+            ZipFileHandle::new(ino as i64),
+            self.client_id,
+            kind,
+            false,
+        );
+        let result = self.rpc(|znfs| znfs.delegate(args.clone()));
+
+        self.note_delegation(ino, recalls, result?);
+        Ok(())
+    }
+
+    /// Remember what delegation of the file the server says we hold, along with the file's
+    /// attributes as of then, given the number of recalls when we asked. If any recall came in
+    /// since, it may have been of this delegation, so we forget about it instead.
+    fn note_delegation(&self, ino: u64, recalls: u64, res: ZipDelegateRes) {
+        let mut meta = self.meta();
+
+        if meta.recalls != recalls || res.granted == ZipDelegationType::NONE {
+            meta.forget_delegation(ino);
+            return;
+        }
+
+        meta.delegations.insert(ino, res.granted);
+        if let Some(attributes) = res.attributes {
+            meta.prime_attr(to_file_attr(attributes));
+        }
+    }
+
+    /// Give back our delegation of the file, as the server asked. Whatever we wrote under a WRITE
+    /// delegation is committed, so that whoever it was recalled for sees it.
+    fn recall(&self, ino: u64, kind: ZipDelegationType) -> Result<(), c_int> {
+        println!("Recall of the {:?} delegation of {}", kind, ino);
+
+        {
+            let mut meta = self.meta();
+            meta.recalls += 1;
+            meta.forget_delegation(ino);
+        }
+
+        if kind == ZipDelegationType::WRITE {
+            self.commit(ino as Fid)?;
+        }
         Ok(())
     }
+
+    /// Forget all of our delegations, because the server has dropped them.
+    fn drop_delegations(&self) {
+        let mut meta = self.meta();
+
+        if !meta.delegations.is_empty() {
+            println!("Dropping our delegations, since our lease has run out");
+        }
+        let inos: Vec<u64> = meta.delegations.keys().cloned().collect();
+        for ino in inos {
+            meta.forget_delegation(ino);
+        }
+        meta.recalls += 1;
+    }
+
+    /// If we hold any delegations, check whether the server has restarted, and take them back if
+    /// it has. The files may have changed before we got them back, so their attributes are
+    /// checked again.
+    fn check_delegations(&self) {
+        let held: Vec<(u64, ZipDelegationType)> = self.meta()
+            .delegations
+            .iter()
+            .map(|(&ino, &kind)| (ino, kind))
+            .collect();
+        if held.is_empty() {
+            return;
+        }
+
+        let epoch = match self.rpc(|znfs| znfs.null()) {
+            Ok(epoch) => epoch as u64,
+            Err(err) => {
+                println!("Unable to check the server epoch: {}", err);
+                return;
+            }
+        };
+
+        let recalls = {
+            let mut meta = self.meta();
+            if epoch <= meta.delegation_epoch {
+                return;
+            }
+            meta.delegation_epoch = epoch;
+            meta.recalls
+        };
+
+        for (ino, kind) in held {
+            let args = ZipDelegateArgs::new(
+                ZipFileHandle::new(ino as i64),
+                self.client_id,
+                kind,
+                true,
+            );
+
+            let result = self.rpc(|znfs| znfs.delegate(args.clone()));
+            match result {
+                Ok(res) => self.note_delegation(ino, recalls, res),
+                Err(err) => {
+                    println!("Lost the delegation of {} after the server restarted: {}", ino, err);
+                    self.meta().forget_delegation(ino);
+                }
+            }
+        }
+    }
+
+    /// Wake the background flusher, because there is too much dirty data.
+    fn wake_flusher(&self) {
+        *self.flush_wanted.lock().unwrap() = true;
+        self.flush_wake.notify_one();
+    }
+
+    /// The main loop of the background flusher, which commits files that have been dirty for too
+    /// long, or when there is too much dirty data, much like the kernel's writeback.
+    fn flusher(&self) {
+        let interval = Duration::from_secs(FLUSH_INTERVAL_SECS);
+
+        loop {
+            {
+                let mut wanted = self.flush_wanted.lock().unwrap();
+                if !*wanted {
+                    wanted = self.flush_wake.wait_timeout(wanted, interval).unwrap().0;
+                }
+                *wanted = false;
+            }
+
+            self.flush_dirty();
+        }
+    }
+
+    /// Commit the files that have been dirty for longer than `dirty_expire`, and then more files,
+    /// oldest first, until there is at most half of `dirty_bytes` of dirty data.
+    fn flush_dirty(&self) {
+        let config = self.meta().config;
+
+        let files: Vec<_> = self.dirty
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&fid, file)| (fid, file.clone()))
+            .collect();
+
+        // Oldest first
+        let mut ages: Vec<_> = files
+            .into_iter()
+            .filter_map(|(fid, file)| {
+                let dirty = file.lock().unwrap();
+                if dirty.bufs.is_empty() {
+                    None
+                } else {
+                    Some((dirty.since, fid))
+                }
+            })
+            .collect();
+        ages.sort();
+
+        for (since, fid) in ages {
+            let expired = since.elapsed() >= config.dirty_expire;
+            let too_much = self.dirty_bytes.load(Ordering::SeqCst) > config.dirty_bytes / 2;
+
+            // Everything after this is newer
+            if !expired && !too_much {
+                break;
+            }
+
+            println!("Flushing fid={} in the background", fid);
+            if let Err(err) = self.commit(fid) {
+                println!("Background commit of fid={} failed: {}", fid, err);
+            }
+        }
+    }
+
+    /// Commit all of the dirty files, e.g. before unmounting.
+    fn flush_all(&self) {
+        let fids: Vec<_> = self.dirty.lock().unwrap().keys().cloned().collect();
+
+        for fid in fids {
+            if let Err(err) = self.commit(fid) {
+                println!("Commit of fid={} failed: {}", fid, err);
+            }
+        }
+    }
+
+    /// Describe the file's uncommitted writes, for debugging.
+    fn describe_dirty(&self, fid: Fid, dirty: &Dirty) -> String {
+        let bytes = extents_len(&dirty.bufs);
+        let age = if dirty.bufs.is_empty() {
+            0
+        } else {
+            dirty.since.elapsed().as_secs()
+        };
+
+        format!(
+            "fid={} epoch={} age={}s extents={} bytes={} gathered={} in_flight={}\n",
+            fid,
+            dirty.epoch,
+            age,
+            dirty.bufs.len(),
+            bytes,
+            dirty.gather.as_ref().map_or(0, |&(_, ref buf)| buf.len()),
+            dirty.in_flight.len()
+        )
+    }
+
+    /// The dirty state of the file, or a summary of all files for the root, for debugging.
+    fn dirty_state(&self, ino: u64) -> String {
+        if ino != 1 {
+            let mut state = match self.dirty_file(ino as Fid) {
+                Some(file) => {
+                    let dirty = file.lock().unwrap();
+                    let state = self.describe_dirty(ino as Fid, &dirty);
+                    state
+                }
+                None => "clean\n".to_owned(),
+            };
+            state.push_str(&self.handles().describe(ino));
+            return state;
+        }
+
+        let config = self.meta().config;
+        let files: Vec<_> = self.dirty
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&fid, file)| (fid, file.clone()))
+            .collect();
+
+        let mut state = format!(
+            "files={} bytes={} dirty_bytes={} dirty_expire={}s\n",
+            files.len(),
+            self.dirty_bytes.load(Ordering::SeqCst),
+            config.dirty_bytes,
+            config.dirty_expire.as_secs()
+        );
+        for (fid, file) in files {
+            let dirty = file.lock().unwrap();
+            state.push_str(&self.describe_dirty(fid, &dirty));
+        }
+        state
+    }
+
+    fn lookup(&self, parent: u64, name: String) -> Result<FileAttr, c_int> {
+        if let Some(attr) = self.meta().cached_entry(parent, &name) {
+            println!("lookup hit in cache: {:?}", attr);
+            return Ok(attr);
+        }
+
+        let args = ZipDirOpArgs::new(ZipFileHandle::new(parent as i64), name.clone(), None);
+
+        let result = self.rpc(|znfs| znfs.lookup(args.clone()));
+        let dopres = result?;
+
+        println!("lookup response: {:?}", dopres);
+        let attr = to_file_attr(dopres.attributes);
+        self.meta().prime_entry(parent, name, attr);
+        Ok(attr)
+    }
+
+    fn getattr(&self, ino: u64) -> Result<FileAttr, c_int> {
+        if let Some(attr) = self.meta().cached_attr(ino) {
+            println!("getattr hit in cache: {:?}", attr);
+            return Ok(attr);
+        }
+
+        self.getattr_uncached(ino)
+    }
+
+    /// Get the file's attributes from the server, and remember them.
+    fn getattr_uncached(&self, ino: u64) -> Result<FileAttr, c_int> {
+        // The size and times aren't settled until the writes in flight land
+        self.sync_file(ino as Fid)?;
+
+        let args = ZipFileHandle::new(ino as i64);
+
+        let result = self.rpc(|znfs| znfs.getattr(args.clone()));
+        let resattr = result?;
+
+        println!("response: {:?}", resattr);
+        let attr = to_file_attr(resattr.attributes);
+        self.meta().prime_attr(attr);
+        Ok(attr)
+    }
+
+    /// Open the file, and return a new handle for it.
+    ///
+    /// The server is told about the open, if it keeps track of them, and its reply has the file's
+    /// attributes. Otherwise, they are checked with a GETATTR. Either way, anything another client
+    /// wrote before closing the file is seen (close-to-open consistency). What the disk cache has
+    /// of the file may be from before a remount, so it is always checked. If we take
+    /// delegations, we then ask for one, so that the file can be cached until someone else
+    /// changes it.
+    fn open(&self, ino: u64, flags: u32) -> Result<u64, c_int> {
+        if self.caps() & CAP_OPEN_STATE != 0 {
+            // The attributes aren't settled until the writes in flight land
+            self.sync_file(ino as Fid)?;
+
+            let args = ZipOpenArgs::new(ZipFileHandle::new(ino as i64), self.client_id);
+            let result = self.rpc(|znfs| znfs.open(args.clone()));
+            if let Some(attributes) = result?.attributes {
+                let attr = to_file_attr(attributes);
+                self.meta().prime_attr(attr);
+                self.disk(|disk| disk.revalidate(ino, version_of(&attr)));
+            }
+
+            // Only a reader can make do with knowing that nobody else is changing the file
+            if self.callback_addr.lock().unwrap().is_some() {
+                let kind = if flags as c_int & O_ACCMODE == O_RDONLY {
+                    ZipDelegationType::READ
+                } else {
+                    ZipDelegationType::WRITE
+                };
+                if let Err(err) = self.delegate(ino, kind) {
+                    println!("Unable to get a delegation of {}: {}", ino, err);
+                }
+            }
+        } else if self.meta().config.cto || self.disk.lock().unwrap().is_some() {
+            let attr = self.getattr_uncached(ino)?;
+            self.disk(|disk| disk.revalidate(ino, version_of(&attr)));
+        }
+
+        Ok(self.handles().open(ino, flags, false))
+    }
+
+    /// Flush the handle, which commits the file if anything was written through it, so that
+    /// another client that opens the file next sees the writes (close-to-open consistency). Under
+    /// a WRITE delegation, that can wait until it is recalled, since nobody else can open the
+    /// file before then. The owner's locks on the file go too, since closing any descriptor for a
+    /// file releases them.
+    fn flush(&self, ino: u64, fh: u64, owner: u64) -> Result<(), c_int> {
+        let written = {
+            let mut handles = self.handles();
+            handles.flushed_by(ino, owner);
+            handles.flushed(fh)
+        };
+        if written && !self.meta().write_delegated(ino) {
+            self.commit(ino as Fid)?;
+        }
+
+        self.unlock_owner(ino, owner)
+    }
+
+    /// Close the handle. When it is the last one for the file, whatever is still dirty is
+    /// committed, so that nothing is left uncommitted for a file nobody has open, unless we hold
+    /// a WRITE delegation of it, and it isn't about to be removed.
+    fn release(&self, fh: u64) -> Result<(), c_int> {
+        let released = self.handles().release(fh);
+        let (handle, last) = match released {
+            Some(released) => released,
+            None => return Ok(()),
+        };
+
+        let silly = self.handles().silly.contains_key(&handle.ino);
+        let result = if last && (silly || !self.meta().write_delegated(handle.ino)) {
+            self.commit(handle.ino as Fid)
+        } else {
+            Ok(())
+        };
+
+        if !handle.is_dir && self.caps() & CAP_OPEN_STATE != 0 {
+            let args = ZipOpenArgs::new(ZipFileHandle::new(handle.ino as i64), self.client_id);
+            self.rpc(|znfs| znfs.close(args.clone()))?;
+        }
+
+        // An open file that was unlinked goes now
+        if last {
+            let silly = self.handles().silly.remove(&handle.ino);
+            if let Some((parent, name)) = silly {
+                self.forget_data(handle.ino);
+                self.remove(parent, name)?;
+            }
+        }
+
+        result
+    }
+
+    /// How long the kernel may cache the file's attributes, which is as long as we do.
+    fn ttl(&self, ino: u64) -> Timespec {
+        let ttl = self.meta().ttl(ino);
+        Timespec::new(ttl.as_secs() as i64, ttl.subsec_nanos() as i32)
+    }
+
+    fn read(&self, ino: u64, offset: u64, size: u32) -> Result<Vec<u8>, c_int> {
+        // Anything still in flight to the file has to land first
+        self.sync_file(ino as Fid)?;
+
+        // The cached data is only good if it is of the file as it is now
+        let version = version_of(&self.getattr(ino)?);
+
+        // Need to return the exact amount of data
+        let result = self.pipelined(|| {
+            self.read_cached(ino as Fid, version, offset, size as u64)
+        });
+        let buf = result?;
+
+        println!("Recv {} B", buf.len());
+        Ok(buf)
+    }
+
+    fn readdir(&self, ino: u64, offset: u64, mut reply: ReplyDirectory) {
+        // A new listing is a good time to clean up
+        if offset == 0 {
+            self.meta().expire_cache();
+        }
+
+        // The offset is the cookie (i.e. FID) of the last entry the kernel has seen. If it is
+        // where the last page we got left off, carry on from there. Either way, what we kept is
+        // only good for the very next call.
+        let rest = self.meta().readdir_rest.remove(&(ino as Fid));
+        let (entries, eof) = match rest {
+            Some((cookie, entries, eof)) if offset != 0 && cookie == offset => (entries, eof),
+            _ => {
+                match self.readdir_page(ino, offset) {
+                    Ok(page) => page,
+
+                    // FUSE won't pass kernel-internal errors on, and a listing can't be resumed
+                    // from a bad offset anyway
+                    Err(EBADCOOKIE) => {
+                        reply.error(EINVAL);
+                        return;
+                    }
+                    Err(err) => {
+                        reply.error(err);
+                        return;
+                    }
+                }
+            }
+        };
+
+        let mut meta = self.meta();
+        let mut last_cookie = offset;
+        let mut entries = entries.into_iter();
+
+        while let Some(entry) = entries.next() {
+            println!("entry: {:?}", entry);
+
+            // READDIRPLUS gave us everything the kernel is about to ask for
+            let attr = to_file_attr(entry.attributes.clone());
+            meta.prime_entry(ino, entry.fname.clone(), attr);
+
+            let full = reply.add(
+                entry.fid as u64,
+                entry.fid as u64, // cookie
+                attr.kind,
+                &entry.fname,
+            );
+
+            // Keep whatever doesn't fit for the next call
+            if full {
+                let rest = Some(entry).into_iter().chain(entries).collect();
+                meta.readdir_rest.insert(ino as Fid, (last_cookie, rest, eof));
+                reply.ok();
+                return;
+            }
+
+            last_cookie = entry.fid as u64;
+        }
+
+        // The kernel will come back for the page after `last_cookie`, which we know is empty.
+        if eof && last_cookie != offset {
+            meta.readdir_rest.insert(ino as Fid, (last_cookie, vec![], true));
+        }
+
+        reply.ok();
+    }
+
+    fn setattr(&self, ino: u64, newattrs: ZipSattr) -> Result<FileAttr, c_int> {
+        // Writes in flight mustn't land after a truncate
+        self.sync_file(ino as Fid)?;
+        self.forget_data(ino);
+
+        let args = ZipSattrArgs::new(ZipFileHandle::new(ino as i64), newattrs, self.client_id);
+
+        let result = self.rpc(|znfs| znfs.setattr(args.clone()));
+        let resattr = result?;
+
+        println!("response: {:?}", resattr);
+        let attr = to_file_attr(resattr.attributes);
+        self.meta().prime_attr(attr);
+        Ok(attr)
+    }
+
+    fn mkdir(&self, parent: u64, dir_name: String, attrs: ZipSattr) -> Result<FileAttr, c_int> {
+        let dir_args = ZipDirOpArgs::new(
+            ZipFileHandle::new(parent as i64),
+            dir_name.clone(),
+            new_xid(),
+        );
+        let args = ZipCreateArgs::new(dir_args, attrs);
+
+        let result = self.rpc(|znfs| znfs.mkdir(args.clone()));
+        let dopres = result?;
+
+        let attr = to_file_attr(dopres.attributes);
+        self.meta().prime_entry(parent, dir_name, attr);
+        Ok(attr)
+    }
+
+    fn create(&self, parent: u64, file_name: String, attrs: ZipSattr) -> Result<FileAttr, c_int> {
+        let dir_args = ZipDirOpArgs::new(
+            ZipFileHandle::new(parent as i64),
+            file_name.clone(),
+            new_xid(),
+        );
+        let args = ZipCreateArgs::new(dir_args, attrs);
+
+        let result = self.rpc(|znfs| znfs.create(args.clone()));
+        let dopres = result?;
+
+        let attr = to_file_attr(dopres.attributes);
+        self.meta().prime_entry(parent, file_name, attr);
+        Ok(attr)
+    }
+
+    fn write(&self, ino: u64, offset: u64, data: Vec<u8>) -> Result<u32, c_int> {
+        // The size, times and data are about to change
+        self.meta().attr_cache.remove(&ino);
+        self.forget_data(ino);
+
+        let mut data_vec = data;
+        let data_len = data_vec.len();
+
+        let mut sent_bytes = 0;
+
+        while data_vec.len() > 0 {
+            let to_send_len = min(data_vec.len(), self.wsize);
+
+            let mut to_send = data_vec;
+            data_vec = to_send.split_off(to_send_len);
+
+            // We know that this fully writes the data.
+            if ASYNC_WRITES && self.caps() & CAP_UNSTABLE_WRITES != 0 {
+                self.write_async_part(
+                    ino as Fid,
+                    offset + sent_bytes,
+                    to_send_len as u64,
+                    to_send,
+                )?;
+            } else {
+                self.write_part(ino, offset + sent_bytes, to_send, ZipWriteStable::FILE_SYNC)?;
+            }
+
+            sent_bytes += to_send_len as u64;
+        }
+
+        // If there is too much dirty data, get the flusher going, and if it can't keep up, hold
+        // up the writer by committing its file here
+        let dirty_bytes = self.dirty_bytes.load(Ordering::SeqCst);
+        let limit = self.meta().config.dirty_bytes;
+        if dirty_bytes > limit {
+            self.wake_flusher();
+        }
+        if dirty_bytes > limit * 2 {
+            self.commit(ino as Fid)?;
+        }
+
+        // We know that if we got here, we must have fully sent all bytes without errors
+        Ok(data_len as u32)
+    }
+
+    /// Unlink the file. If we have it open, it is renamed to a silly name instead, and only
+    /// removed when the last handle for it is closed, so that it can still be used until then.
+    fn unlink(&self, parent: u64, fname: String) -> Result<(), c_int> {
+        // A file that already has a silly name is just removed
+        if silly_owner(&fname).is_none() {
+            let ino = self.lookup(parent, fname.clone()).ok().map(|attr| attr.ino);
+            if let Some(ino) = ino {
+                if self.handles().is_open(ino) {
+                    return self.silly_rename(parent, fname, ino);
+                }
+            }
+        }
+
+        self.remove(parent, fname)
+    }
+
+    /// Remove the file from the server.
+    fn remove(&self, parent: u64, fname: String) -> Result<(), c_int> {
+        self.meta().forget_entry(parent, &fname);
+
+        let args = ZipDirOpArgs::new(ZipFileHandle::new(parent as i64), fname, new_xid());
+
+        self.rpc(|znfs| znfs.remove(args.clone()))
+    }
+
+    /// Rename the open file to its silly name, to be removed when the last handle is closed.
+    fn silly_rename(&self, parent: u64, fname: String, ino: u64) -> Result<(), c_int> {
+        let silly = silly_name(self.client_id, ino);
+        self.rename(parent, fname, parent, silly.clone())?;
+
+        // The last handle may have been closed in the meantime
+        let still_open = {
+            let mut handles = self.handles();
+            let open = handles.is_open(ino);
+            if open {
+                handles.silly.insert(ino, (parent, silly.clone()));
+            }
+            open
+        };
+
+        if still_open {
+            Ok(())
+        } else {
+            self.forget_data(ino);
+            self.remove(parent, silly)
+        }
+    }
+
+    fn rmdir(&self, parent: u64, dname: String) -> Result<(), c_int> {
+        self.meta().forget_entry(parent, &dname);
+
+        let args = ZipDirOpArgs::new(ZipFileHandle::new(parent as i64), dname, new_xid());
+
+        self.rpc(|znfs| znfs.rmdir(args.clone()))
+    }
+
+    fn rename(
+        &self,
+        parent: u64,
+        old_name: String,
+        newparent: u64,
+        new_name: String,
+    ) -> Result<(), c_int> {
+        {
+            let mut meta = self.meta();
+            meta.forget_entry(parent, &old_name);
+            meta.forget_entry(newparent, &new_name);
+        }
+
+        let old_args = ZipDirOpArgs::new(ZipFileHandle::new(parent as i64), old_name, new_xid());
+        let new_args = ZipDirOpArgs::new(ZipFileHandle::new(newparent as i64), new_name, None);
+
+        // The server only reaps our silly files if it knows they're ours, and it can only tell
+        // when they're orphaned if it knows which files we have open
+        let client = if self.caps() & CAP_OPEN_STATE != 0 {
+            Some(self.client_id)
+        } else {
+            None
+        };
+        let args = ZipRenameArgs::new(old_args, new_args, client);
+
+        self.rpc(|znfs| znfs.rename(args.clone()))
+    }
+
+    fn statfs(&self, ino: u64) -> Result<ZipStatFsRes, c_int> {
+        let args = ZipFileHandle::new(ino as i64);
+
+        self.rpc(|znfs| znfs.statfs(args.clone()))
+    }
+}
+
+/// A request from the kernel, waiting for a worker
+type Job = Box<FnMut(&ZippyFs) + Send>;
+
+/// The FUSE filesystem. `fuse` calls it from just one thread, so all it does is hand each
+/// request to a pool of workers, which handle them at the same time using the shared `ZippyFs`.
+/// Requests from different processes, or for different files, don't wait for each other.
+struct ZippyFileSystem {
+    jobs: Sender<Job>,
+
+    // For requests that may wait for a long time (for a lock), which get their own threads
+    // rather than tying up workers
+    waiters: Sender<Job>,
+}
+
+/// Start `threads` threads named after `name` handling the jobs sent to the returned queue with
+/// `fs`.
+fn start_workers(fs: &Arc<ZippyFs>, name: &str, threads: usize) -> Result<Sender<Job>, String> {
+    let (jobs, queue) = channel::<Job>();
+    let queue = Arc::new(Mutex::new(queue));
+
+    for i in 0..threads {
+        let fs = fs.clone();
+        let queue = queue.clone();
+
+        thread::Builder::new()
+            .name(format!("{}-{}", name, i))
+            .spawn(move || loop {
+                // Only hold the lock while waiting, not while working
+                let mut job = match queue.lock().unwrap().recv() {
+                    Ok(job) => job,
+                    Err(_) => return,
+                };
+
+                job(&*fs);
+            })
+            .map_err(|e| format!("Unable to start {}-{}: {}", name, i, e))?;
+    }
+
+    Ok(jobs)
+}
+
+/// Send a request to the workers taking jobs from the queue.
+fn submit<F: FnOnce(&ZippyFs) + Send + 'static>(jobs: &Sender<Job>, f: F) {
+    // A boxed closure has to be called through a `&mut`, so it can't be an `FnOnce`
+    let mut f = Some(f);
+    let _ = jobs.send(Box::new(move |fs: &ZippyFs| if let Some(f) = f.take() {
+        f(fs)
+    }));
+}
+
+impl ZippyFileSystem {
+    /// Start `threads` workers handling requests with `fs`, and `LOCK_WAITERS` lock waiters.
+    fn new(fs: Arc<ZippyFs>, threads: usize) -> Result<ZippyFileSystem, String> {
+        Ok(ZippyFileSystem {
+            jobs: start_workers(&fs, "fuse", threads)?,
+            waiters: start_workers(&fs, "lock-waiter", LOCK_WAITERS)?,
+        })
+    }
+
+    /// Handle a request on one of the workers.
+    fn spawn<F: FnOnce(&ZippyFs) + Send + 'static>(&self, f: F) {
+        submit(&self.jobs, f);
+    }
+
+    /// Handle a request that may wait for a long time on one of the lock waiters.
+    fn spawn_waiter<F: FnOnce(&ZippyFs) + Send + 'static>(&self, f: F) {
+        submit(&self.waiters, f);
+    }
 }
 
 impl Filesystem for ZippyFileSystem {
-    /// Get the server epoch
-    fn init(&mut self, _req: &Request) -> Result<(), c_int> {
-        println!("init()");
-
-        let result =
-            do_with_retry! {
-                self,
-                self.znfs.null().map_err(|e| e.into())
-            };
-
-        match result {
-            Err(err) => Err(err),
-            Ok(epoch) => {
-                self.server_epoch = epoch as u64;
-                Ok(())
-            }
-        }
-    }
-
     fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
         println!("lookup(parent={}, name={:?})", parent, name);
 
-        let args = ZipDirOpArgs::new(
-            ZipFileHandle::new(parent as i64),
-            name.to_os_string().into_string().unwrap(),
-        );
-
-        let result =
-            do_with_retry! {
-                self,
-                self.znfs.lookup(args.clone()).map_err(|e| e.into())
-            };
+        let name = name.to_os_string().into_string().unwrap();
 
-        match result {
+        self.spawn(move |fs| match fs.lookup(parent, name) {
+            Ok(attr) => reply.entry(&fs.ttl(attr.ino), &attr, 0),
             Err(err) => reply.error(err),
-            Ok(dopres) => {
-                println!("lookup response: {:?}", dopres);
-                let lres = dopres.attributes;
-                let my_time = to_sys_time(lres.ctime);
-                let attr: FileAttr = FileAttr {
-                    ino: lres.fid as u64,
-                    size: lres.size as u64,
-                    blocks: lres.blocks as u64,
-                    atime: to_sys_time(lres.atime),
-                    mtime: to_sys_time(lres.mtime),
-                    ctime: my_time,
-                    crtime: my_time,
-                    kind: match lres.type_ {
-                        ZipFtype::NFREG => FileType::RegularFile,
-                        ZipFtype::NFDIR => FileType::Directory,
-                        ZipFtype::NFNON => FileType::NamedPipe,
-                        ZipFtype::NFBLK => FileType::BlockDevice,
-                        ZipFtype::NFCHR => FileType::CharDevice,
-                        ZipFtype::NFLNK => FileType::Symlink,
-                    },
-                    perm: lres.mode as u16,
-                    nlink: lres.nlink as u32,
-                    uid: lres.uid as u32,
-                    gid: lres.gid as u32,
-                    rdev: lres.rdev as u32,
-                    flags: 0,
-                };
-                reply.entry(&TTL, &attr, 0);
-            }
-        }
+        });
     }
 
     fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
         println!("getattr(ino={})", ino);
-        let args = ZipFileHandle::new(ino as i64);
-        //println!("getattr response: {:?}", res);
-
-        let result =
-            do_with_retry! {
-                self,
-                self.znfs.getattr(args.clone()).map_err(|e| e.into())
-            };
 
-        match result {
+        self.spawn(move |fs| match fs.getattr(ino) {
+            Ok(attr) => reply.attr(&fs.ttl(attr.ino), &attr),
             Err(err) => reply.error(err),
-            Ok(resattr) => {
-                println!("response: {:?}", resattr);
-                let lres = resattr.attributes;
-                let my_time = to_sys_time(lres.ctime);
-                let attr: FileAttr = FileAttr {
-                    ino: lres.fid as u64,
-                    size: lres.size as u64,
-                    blocks: lres.blocks as u64,
-                    atime: to_sys_time(lres.atime),
-                    mtime: to_sys_time(lres.mtime),
-                    ctime: my_time,
-                    crtime: my_time,
-                    kind: match lres.type_ {
-                        ZipFtype::NFREG => FileType::RegularFile,
-                        ZipFtype::NFDIR => FileType::Directory,
-                        ZipFtype::NFNON => FileType::NamedPipe,
-                        ZipFtype::NFBLK => FileType::BlockDevice,
-                        ZipFtype::NFCHR => FileType::CharDevice,
-                        ZipFtype::NFLNK => FileType::Symlink,
-                    },
-                    perm: lres.mode as u16,
-                    nlink: lres.nlink as u32,
-                    uid: lres.uid as u32,
-                    gid: lres.gid as u32,
-                    rdev: lres.rdev as u32,
-                    flags: 0,
-                };
-                reply.attr(&TTL, &attr);
-            }
-        };
+        });
     }
 
     fn read(
@@ -507,39 +2475,10 @@ impl Filesystem for ZippyFileSystem {
             size
         );
 
-        // Need to return the exact amount of data
-        let mut buf = Vec::new();
-
-        let size = size as usize;
-        let offset = offset as usize;
-
-        while buf.len() < size {
-            let so_far = buf.len();
-            let to_go = size - buf.len();
-
-            let result = self.read_part(
-                _req,
-                ino,
-                _fh,
-                (offset + so_far) as u64,
-                to_go as u32,
-                &mut buf,
-            );
-
-            match result {
-                Err(err) => {
-                    reply.error(err);
-                    return;
-                }
-                Ok(0) => {
-                    // EOF
-                    break;
-                }
-                Ok(_) => {}
-            }
-        }
-
-        reply.data(&buf);
+        self.spawn(move |fs| match fs.read(ino, offset, size) {
+            Ok(buf) => reply.data(&buf),
+            Err(err) => reply.error(err),
+        });
     }
 
     fn readdir(
@@ -548,48 +2487,11 @@ impl Filesystem for ZippyFileSystem {
         ino: u64,
         _fh: u64,
         offset: u64,
-        mut reply: ReplyDirectory,
+        reply: ReplyDirectory,
     ) {
         println!("readdir(ino={}, _fh={}, off={}", ino, _fh, offset);
 
-        let args = ZipReadDirArgs::new(ZipFileHandle::new(ino as i64), offset as i64);
-
-        let result =
-            do_with_retry! {
-                self,
-                self.znfs.readdir(args.clone()).map_err(|e| e.into())
-            };
-
-        match result {
-            Err(err) => reply.error(err),
-            Ok(dir_list) => {
-                println!("resp: {:?}", dir_list);
-                let mut count = 1u64;
-                for entry in dir_list.entries.into_iter() {
-                    println!("entry {}: {:?}", count, entry);
-                    let full = reply.add(
-                        entry.fid as u64,
-                        offset + count,
-                        match entry.type_ {
-                            ZipFtype::NFREG => FileType::RegularFile,
-                            ZipFtype::NFDIR => FileType::Directory,
-                            ZipFtype::NFNON => FileType::NamedPipe,
-                            ZipFtype::NFBLK => FileType::BlockDevice,
-                            ZipFtype::NFCHR => FileType::CharDevice,
-                            ZipFtype::NFLNK => FileType::Symlink,
-                        },
-                        entry.fname,
-                    );
-
-                    if full {
-                        break;
-                    }
-
-                    count += 1;
-                }
-                reply.ok();
-            }
-        };
+        self.spawn(move |fs| fs.readdir(ino, offset, reply));
     }
 
     fn setattr(
@@ -609,8 +2511,8 @@ impl Filesystem for ZippyFileSystem {
         _flags: Option<u32>,
         reply: ReplyAttr,
     ) {
-
         println!("setattr(ino={})", _ino);
+
         let newattrs = ZipSattr::new(
             mode.map(|m| m as i16),
             uid.map(|m| m as i64),
@@ -619,45 +2521,11 @@ impl Filesystem for ZippyFileSystem {
             atime.map(|m| to_zip_time(m)),
             mtime.map(|m| to_zip_time(m)),
         );
-        let args = ZipSattrArgs::new(ZipFileHandle::new(_ino as i64), newattrs);
-
-        let result =
-            do_with_retry! {
-                self,
-                self.znfs.setattr(args.clone()).map_err(|e| e.into())
-            };
 
-        match result {
+        self.spawn(move |fs| match fs.setattr(_ino, newattrs) {
+            Ok(attr) => reply.attr(&fs.ttl(attr.ino), &attr),
             Err(err) => reply.error(err),
-            Ok(resattr) => {
-                let lres = resattr.attributes;
-                let my_time = to_sys_time(lres.ctime);
-                let attr: FileAttr = FileAttr {
-                    ino: lres.fid as u64,
-                    size: lres.size as u64,
-                    blocks: lres.blocks as u64,
-                    atime: to_sys_time(lres.atime),
-                    mtime: to_sys_time(lres.mtime),
-                    ctime: my_time,
-                    crtime: my_time,
-                    kind: match lres.type_ {
-                        ZipFtype::NFREG => FileType::RegularFile,
-                        ZipFtype::NFDIR => FileType::Directory,
-                        ZipFtype::NFNON => FileType::NamedPipe,
-                        ZipFtype::NFBLK => FileType::BlockDevice,
-                        ZipFtype::NFCHR => FileType::CharDevice,
-                        ZipFtype::NFLNK => FileType::Symlink,
-                    },
-                    perm: lres.mode as u16,
-                    nlink: lres.nlink as u32,
-                    uid: lres.uid as u32,
-                    gid: lres.gid as u32,
-                    rdev: lres.rdev as u32,
-                    flags: 0,
-                };
-                reply.attr(&TTL, &attr);
-            }
-        }
+        });
     }
 
     fn mkdir(&mut self, _req: &Request, parent: u64, name: &OsStr, mode: u32, reply: ReplyEntry) {
@@ -679,56 +2547,57 @@ impl Filesystem for ZippyFileSystem {
             Some(to_zip_time(time_now)),
         );
 
-        let dir_args = ZipDirOpArgs::new(
-            ZipFileHandle::new(parent as i64),
-            name.to_os_string().into_string().unwrap(),
-        );
-        let args = ZipCreateArgs::new(dir_args, attrs);
-
-        let result =
-            do_with_retry! {
-                self,
-                self.znfs.mkdir(args.clone()).map_err(|e| e.into())
-            };
+        let dir_name = name.to_os_string().into_string().unwrap();
 
-        match result {
+        self.spawn(move |fs| match fs.mkdir(parent, dir_name, attrs) {
+            Ok(attr) => reply.entry(&fs.ttl(attr.ino), &attr, 0),
             Err(err) => reply.error(err),
-            Ok(dopres) => {
-                let lres = dopres.attributes;
-                let my_time = to_sys_time(lres.ctime);
-                let attr: FileAttr = FileAttr {
-                    ino: lres.fid as u64,
-                    size: lres.size as u64,
-                    blocks: lres.blocks as u64,
-                    atime: to_sys_time(lres.atime),
-                    mtime: to_sys_time(lres.mtime),
-                    ctime: my_time,
-                    crtime: my_time,
-                    kind: match lres.type_ {
-                        ZipFtype::NFREG => FileType::RegularFile,
-                        ZipFtype::NFDIR => FileType::Directory,
-                        ZipFtype::NFNON => FileType::NamedPipe,
-                        ZipFtype::NFBLK => FileType::BlockDevice,
-                        ZipFtype::NFCHR => FileType::CharDevice,
-                        ZipFtype::NFLNK => FileType::Symlink,
-                    },
-                    perm: lres.mode as u16,
-                    nlink: lres.nlink as u32,
-                    uid: lres.uid as u32,
-                    gid: lres.gid as u32,
-                    rdev: lres.rdev as u32,
-                    flags: 0,
-                };
-                reply.entry(&TTL, &attr, 0);
-            }
-        }
+        });
     }
 
     fn open(&mut self, _req: &Request, ino: u64, flags: u32, reply: ReplyOpen) {
-        // since our file handles and inos are same we can safely return the
-        // ino as fh and flags as such
         println!("open(ino={}, flags={})", ino, flags);
-        reply.opened(ino, flags);
+
+        self.spawn(move |fs| match fs.open(ino, flags) {
+            Ok(fh) => reply.opened(fh, flags),
+            Err(err) => reply.error(err),
+        });
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        fh: u64,
+        _flags: u32,
+        _lock_owner: u64,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        println!("release(ino={}, fh={})", ino, fh);
+
+        self.spawn(move |fs| match fs.release(fh) {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err),
+        });
+    }
+
+    fn opendir(&mut self, _req: &Request, ino: u64, flags: u32, reply: ReplyOpen) {
+        println!("opendir(ino={}, flags={})", ino, flags);
+
+        self.spawn(move |fs| {
+            let fh = fs.handles().open(ino, flags, true);
+            reply.opened(fh, flags);
+        });
+    }
+
+    fn releasedir(&mut self, _req: &Request, ino: u64, fh: u64, _flags: u32, reply: ReplyEmpty) {
+        println!("releasedir(ino={}, fh={})", ino, fh);
+
+        self.spawn(move |fs| {
+            fs.handles().release(fh);
+            reply.ok();
+        });
     }
 
     fn create(
@@ -759,50 +2628,20 @@ impl Filesystem for ZippyFileSystem {
             Some(to_zip_time(time_now)),
         );
 
-        let dir_args = ZipDirOpArgs::new(
-            ZipFileHandle::new(parent as i64),
-            name.to_os_string().into_string().unwrap(),
-        );
-        let args = ZipCreateArgs::new(dir_args, attrs);
+        let file_name = name.to_os_string().into_string().unwrap();
 
-        let result =
-            do_with_retry! {
-                self,
-                self.znfs.create(args.clone()).map_err(|e| e.into())
-            };
-
-        match result {
-            Err(err) => reply.error(err),
-            Ok(dopres) => {
-                let lres = dopres.attributes;
-                let my_time = to_sys_time(lres.ctime);
-                let attr: FileAttr = FileAttr {
-                    ino: lres.fid as u64,
-                    size: lres.size as u64,
-                    blocks: lres.blocks as u64,
-                    atime: to_sys_time(lres.atime),
-                    mtime: to_sys_time(lres.mtime),
-                    ctime: my_time,
-                    crtime: my_time,
-                    kind: match lres.type_ {
-                        ZipFtype::NFREG => FileType::RegularFile,
-                        ZipFtype::NFDIR => FileType::Directory,
-                        ZipFtype::NFNON => FileType::NamedPipe,
-                        ZipFtype::NFBLK => FileType::BlockDevice,
-                        ZipFtype::NFCHR => FileType::CharDevice,
-                        ZipFtype::NFLNK => FileType::Symlink,
-                    },
-                    perm: lres.mode as u16,
-                    nlink: lres.nlink as u32,
-                    uid: lres.uid as u32,
-                    gid: lres.gid as u32,
-                    rdev: lres.rdev as u32,
-                    flags: flags,
+        self.spawn(move |fs| match fs.create(parent, file_name, attrs) {
+            Ok(mut attr) => {
+                // The new file is open too
+                let fh = match fs.open(attr.ino, flags) {
+                    Ok(fh) => fh,
+                    Err(err) => return reply.error(err),
                 };
-
-                reply.created(&TTL, &attr, 0u64, dopres.file.fid as u64, flags);
+                attr.flags = flags;
+                reply.created(&fs.ttl(attr.ino), &attr, 0u64, fh, flags);
             }
-        }
+            Err(err) => reply.error(err),
+        });
     }
 
     fn write(
@@ -823,34 +2662,15 @@ impl Filesystem for ZippyFileSystem {
             _flags
         );
 
-        let mut data_vec = Vec::from(data);
-        let data_len = data_vec.len();
-
-        let mut sent_bytes = 0;
-
-        while data_vec.len() > 0 {
-            let to_send_len = min(data_vec.len(), MAX_BUF_LEN);
-
-            let mut to_send = data_vec;
-            data_vec = to_send.split_off(to_send_len);
-
-            // We know that this fully writes the data.
-            let result = if ASYNC_WRITES {
-                self.write_async_part(ino as Fid, offset + sent_bytes, to_send_len as u64, to_send)
-            } else {
-                self.write_part(ino, offset + sent_bytes, to_send, ZipWriteStable::FILE_SYNC)
-            };
+        let data_vec = Vec::from(data);
 
-            if let Err(err) = result {
-                reply.error(err);
-                return;
-            } else {
-                sent_bytes += to_send_len as u64;
+        self.spawn(move |fs| {
+            fs.handles().wrote(fh);
+            match fs.write(ino, offset, data_vec) {
+                Ok(written) => reply.written(written),
+                Err(err) => reply.error(err),
             }
-        }
-
-        // We know that if we got here, we must have fully sent all bytes without errors
-        reply.written(data_len as u32);
+        });
     }
 
     fn unlink(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
@@ -860,22 +2680,12 @@ impl Filesystem for ZippyFileSystem {
             name,
         );
 
-        let args = ZipDirOpArgs::new(
-            ZipFileHandle::new(parent as i64),
-            name.to_os_string().into_string().unwrap(),
-        );
-        let result =
-            do_with_retry! {
-                self,
-                self.znfs.remove(args.clone()).map_err(|e| e.into())
-            };
+        let fname = name.to_os_string().into_string().unwrap();
 
-        match result {
+        self.spawn(move |fs| match fs.unlink(parent, fname) {
+            Ok(()) => reply.ok(),
             Err(err) => reply.error(err),
-            Ok(()) => {
-                reply.ok();
-            }
-        }
+        });
     }
 
     fn rmdir(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
@@ -885,23 +2695,12 @@ impl Filesystem for ZippyFileSystem {
             name,
         );
 
-        let args = ZipDirOpArgs::new(
-            ZipFileHandle::new(parent as i64),
-            name.to_os_string().into_string().unwrap(),
-        );
-
-        let result =
-            do_with_retry! {
-                self,
-                self.znfs.rmdir(args.clone()).map_err(|e| e.into())
-            };
+        let dname = name.to_os_string().into_string().unwrap();
 
-        match result {
+        self.spawn(move |fs| match fs.rmdir(parent, dname) {
+            Ok(()) => reply.ok(),
             Err(err) => reply.error(err),
-            Ok(()) => {
-                reply.ok();
-            }
-        }
+        });
     }
 
     fn rename(
@@ -921,43 +2720,19 @@ impl Filesystem for ZippyFileSystem {
             newname,
         );
 
-        let old_args = ZipDirOpArgs::new(
-            ZipFileHandle::new(parent as i64),
-            name.to_os_string().into_string().unwrap(),
-        );
-        let new_args = ZipDirOpArgs::new(
-            ZipFileHandle::new(newparent as i64),
-            newname.to_os_string().into_string().unwrap(),
-        );
-
-        let args = ZipRenameArgs::new(old_args, new_args);
-        let result =
-            do_with_retry! {
-                self,
-                self.znfs.rename(args.clone()).map_err(|e| e.into())
-            };
+        let old_name = name.to_os_string().into_string().unwrap();
+        let new_name = newname.to_os_string().into_string().unwrap();
 
-        match result {
+        self.spawn(move |fs| match fs.rename(parent, old_name, newparent, new_name) {
+            Ok(()) => reply.ok(),
             Err(err) => reply.error(err),
-            Ok(()) => {
-                reply.ok();
-            }
-        }
+        });
     }
 
     fn statfs(&mut self, _req: &Request, _ino: u64, reply: ReplyStatfs) {
         println!("StatFs(ino={})", _ino);
 
-        let args = ZipFileHandle::new(_ino as i64);
-
-        let result =
-            do_with_retry! {
-                self,
-                self.znfs.statfs(args.clone()).map_err(|e| e.into())
-            };
-
-        match result {
-            Err(err) => reply.error(err),
+        self.spawn(move |fs| match fs.statfs(_ino) {
             Ok(result) => {
                 reply.statfs(
                     result.blocks as u64,
@@ -970,26 +2745,192 @@ impl Filesystem for ZippyFileSystem {
                     result.tsize as u32,
                 );
             }
-        }
+            Err(err) => reply.error(err),
+        });
     }
 
-    fn flush(&mut self, _req: &Request, ino: u64, _fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
-        println!("flush(ino={})", ino);
-        match self.commit(ino as Fid) {
+    fn flush(&mut self, _req: &Request, ino: u64, fh: u64, lock_owner: u64, reply: ReplyEmpty) {
+        println!("flush(ino={}, fh={}, lock_owner={:#x})", ino, fh, lock_owner);
+
+        self.spawn(move |fs| match fs.flush(ino, fh, lock_owner) {
             Ok(()) => reply.ok(),
             Err(err) => reply.error(err),
-        }
+        });
     }
 
     fn fsync(&mut self, _req: &Request, ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
         println!("fsync(ino={})", ino);
-        match self.commit(ino as Fid) {
+
+        self.spawn(move |fs| match fs.commit(ino as Fid) {
             Ok(()) => reply.ok(),
             Err(err) => reply.error(err),
+        });
+    }
+
+    fn getlk(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: u32,
+        pid: u32,
+        reply: ReplyLock,
+    ) {
+        println!(
+            "getlk(ino={}, lock_owner={:#x}, start={}, end={}, typ={})",
+            ino,
+            lock_owner,
+            start,
+            end,
+            typ
+        );
+
+        self.spawn(move |fs| match fs.getlk(ino, lock_owner, start, end, typ, pid) {
+            Ok(Some(conflict)) => {
+                let (start, end, typ, pid) = from_zip_lock(&conflict);
+                reply.locked(start, end, typ, pid)
+            }
+            Ok(None) => reply.locked(start, end, F_UNLCK as u32, 0),
+            Err(err) => reply.error(err),
+        });
+    }
+
+    fn setlk(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: u32,
+        pid: u32,
+        sleep: bool,
+        reply: ReplyEmpty,
+    ) {
+        println!(
+            "setlk(ino={}, lock_owner={:#x}, start={}, end={}, typ={}, sleep={})",
+            ino,
+            lock_owner,
+            start,
+            end,
+            typ,
+            sleep
+        );
+
+        let wait = if sleep { Some(fh) } else { None };
+        let handle = move |fs: &ZippyFs| {
+            match fs.setlk(ino, lock_owner, start, end, typ, pid, wait) {
+                Ok(()) => reply.ok(),
+                Err(err) => reply.error(err),
+            }
+        };
+
+        if sleep {
+            self.spawn_waiter(handle);
+        } else {
+            self.spawn(handle);
+        }
+    }
+
+    fn getxattr(&mut self, _req: &Request, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        println!("getxattr(ino={}, name={:?})", ino, name);
+
+        if name != OsStr::new(DIRTY_XATTR) {
+            reply.error(ENODATA);
+            return;
+        }
+
+        self.spawn(move |fs| {
+            let state = fs.dirty_state(ino);
+            if size == 0 {
+                reply.size(state.len() as u32);
+            } else if state.len() > size as usize {
+                reply.error(ERANGE);
+            } else {
+                reply.data(state.as_bytes());
+            }
+        });
+    }
+}
+
+/// Takes the server's callbacks.
+struct CallbackHandler(Arc<ZippyFs>);
+
+impl ZippyCallbackSyncHandler for CallbackHandler {
+    fn handle_recall(&self, fsargs: ZipRecallArgs) -> thrift::Result<()> {
+        // The delegation is gone either way, so a failure is only reported
+        let ino = fsargs.file.fid as u64;
+        if let Err(err) = self.0.recall(ino, fsargs.kind) {
+            println!("Recall of the delegation of {} failed: {}", ino, err);
+        }
+        Ok(())
+    }
+}
+
+/// Take the server's callbacks on one connection, until it hangs up.
+fn serve_callbacks(fs: Arc<ZippyFs>, stream: TcpStream) -> thrift::Result<()> {
+    let (i_chan, o_chan) = TTcpChannel::with_stream(stream).split()?;
+    let mut i_prot = TCompactInputProtocol::new(read_transport(i_chan));
+    let mut o_prot = TCompactOutputProtocol::new(write_transport(o_chan));
+    let processor = ZippyCallbackSyncProcessor::new(CallbackHandler(fs));
+
+    loop {
+        processor.process(&mut i_prot, &mut o_prot)?;
+    }
+}
+
+/// The main loop of the callback listener. Each connection gets its own thread, since committing
+/// for a recall can take a while, and the server may recall several delegations at once.
+fn take_callbacks(fs: Arc<ZippyFs>, listener: TcpListener) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                println!("Unable to take a callback: {}", e);
+                continue;
+            }
+        };
+
+        let fs = fs.clone();
+        let result = thread::Builder::new().name("callback".to_owned()).spawn(
+            move || {
+                // The server hangs up after each recall, which ends in an error here
+                let _ = serve_callbacks(fs, stream);
+            },
+        );
+        if let Err(e) = result {
+            println!("Unable to start a callback thread: {}", e);
         }
     }
 }
 
+/// Start taking callbacks from the server, on a new port of the address we reach it from, and
+/// tell the server about it, so that we can ask for delegations.
+fn listen_for_callbacks(fs: &Arc<ZippyFs>) -> Result<(), String> {
+    let local = TcpStream::connect(&fs.server_addr as &str)
+        .and_then(|stream| stream.local_addr())
+        .map_err(|e| format!("Unable to find our address: {}", e))?;
+    let listener = TcpListener::bind((local.ip(), 0)).map_err(|e| {
+        format!("Unable to listen for callbacks: {}", e)
+    })?;
+    let addr = listener.local_addr().map_err(|e| format!("{}", e))?;
+    println!("Taking callbacks on {}", addr);
+
+    let callbacks = fs.clone();
+    thread::Builder::new()
+        .name("callbacks".to_owned())
+        .spawn(move || take_callbacks(callbacks, listener))
+        .map_err(|e| format!("Unable to start the callback listener: {}", e))?;
+
+    *fs.callback_addr.lock().unwrap() = Some(format!("{}", addr));
+    fs.set_callback();
+    Ok(())
+}
+
 /// Checks if the given String represents a valid network address
 fn is_addr(arg: String) -> Result<(), String> {
     use std::net::ToSocketAddrs;
@@ -999,30 +2940,131 @@ fn is_addr(arg: String) -> Result<(), String> {
         .map(|_| ())
 }
 
+/// Checks if the given String is a positive number
+fn is_positive(arg: String) -> Result<(), String> {
+    match arg.parse::<usize>() {
+        Ok(n) if n > 0 => Ok(()),
+        _ => Err("Not a positive number".to_owned()),
+    }
+}
+
+/// Split the `-o` mount options into the ones that set up the caches, which are mostly the same
+/// as for the Linux NFS client, and the rest, which are passed on to FUSE.
+fn parse_mount_options(opts: &str) -> Result<(CacheConfig, Vec<String>), String> {
+    let mut config = CacheConfig::default();
+    let mut rest = Vec::new();
+
+    for opt in opts.split(',').filter(|opt| !opt.is_empty()) {
+        let mut parts = opt.splitn(2, '=');
+        let name = parts.next().unwrap();
+        let value = parts.next();
+        let number = || -> Result<usize, String> {
+            value.unwrap_or("").parse().map_err(
+                |_| format!("Not a number: {}", opt),
+            )
+        };
+        let secs = value.map(|_| number().map(|n| Duration::from_secs(n as u64)));
+
+        match (name, secs) {
+            ("acregmin", Some(secs)) => config.acregmin = secs?,
+            ("acregmax", Some(secs)) => config.acregmax = secs?,
+            ("acdirmin", Some(secs)) => config.acdirmin = secs?,
+            ("acdirmax", Some(secs)) => config.acdirmax = secs?,
+            ("actimeo", Some(secs)) => {
+                let secs = secs?;
+                config.acregmin = secs;
+                config.acregmax = secs;
+                config.acdirmin = secs;
+                config.acdirmax = secs;
+            }
+            ("noac", None) => {
+                let zero = Duration::new(0, 0);
+                config.acregmin = zero;
+                config.acregmax = zero;
+                config.acdirmin = zero;
+                config.acdirmax = zero;
+            }
+            ("cto", None) => config.cto = true,
+            ("nocto", None) => config.cto = false,
+            ("deleg", None) => config.deleg = true,
+            ("nodeleg", None) => config.deleg = false,
+            ("cachesize", Some(_)) => config.cache_size = number()? << 20,
+            ("readahead", Some(_)) => config.readahead = number()? << 10,
+            ("disksize", Some(_)) => config.disk_size = (number()? as u64) << 20,
+            ("dirtyexpire", Some(secs)) => config.dirty_expire = secs?,
+            ("dirtybytes", Some(_)) => config.dirty_bytes = number()? << 20,
+            _ => rest.push(opt.to_owned()),
+        }
+    }
+
+    Ok((config, rest))
+}
+
 /// The main routinue of the FUSE client.
 ///
 /// It parses args and then attempts to FUSE mount.
-fn run(server_addr: &str, mnt_path: &str) -> Result<(), String> {
-    // build a rpc client
-    let znfs = new_client(server_addr).map_err(|e| format!("{}", e))?;
+fn run(
+    server_addr: &str,
+    mnt_path: &str,
+    threads: usize,
+    config: PoolConfig,
+    mount_opts: &str,
+    cache_dir: Option<&str>,
+) -> Result<(), String> {
+    let (cache_config, fuse_opts) = parse_mount_options(mount_opts)?;
+    let fs = Arc::new(ZippyFs::connect(
+        server_addr,
+        config,
+        cache_config,
+        cache_dir,
+    )?);
 
     // Mount the file system
     //
-    // using spawn_mount method, an additional thread will be started to handle the mount commands
-    // and current thread of execution will work for handling the rpc setup
+    // `fuse` reads requests from the kernel on this thread, and hands them to the workers
     let mount_path = Path::new(mnt_path);
 
+    // Whatever options we don't handle ourselves are for FUSE
+    let fuse_opts = fuse_opts.join(",");
+    let mut options = Vec::new();
+    if !fuse_opts.is_empty() {
+        options.push(OsStr::new("-o"));
+        options.push(OsStr::new(&fuse_opts));
+    }
+
+    // Commit writes in the background, so that they don't pile up
+    let flusher = fs.clone();
+    thread::Builder::new()
+        .name("flusher".to_owned())
+        .spawn(move || flusher.flusher())
+        .map_err(|e| format!("Unable to start the flusher: {}", e))?;
+
+    // Keep our lease, if the server gives them out
+    if fs.caps & CAP_LEASES != 0 {
+        let renewer = fs.clone();
+        thread::Builder::new()
+            .name("renewer".to_owned())
+            .spawn(move || renewer.renewer())
+            .map_err(|e| format!("Unable to start the renewer: {}", e))?;
+    }
+
+    // Delegations only work if the server can tell when we have gone away, and sees our opens
+    let deleg_caps = CAP_DELEGATIONS | CAP_LEASES | CAP_OPEN_STATE;
+    if cache_config.deleg && fs.caps & deleg_caps == deleg_caps {
+        listen_for_callbacks(&fs)?;
+    }
+
     fuse::mount(
-        ZippyFileSystem {
-            znfs: znfs,
-            server_addr: server_addr.to_owned(),
-            server_epoch: 0, // until we set it in `init`
-            async_bufs: HashMap::new(),
-        },
+        ZippyFileSystem::new(fs.clone(), threads)?,
         &mount_path,
-        &[], // mount options
+        &options,
     ).expect("Unable to mount!");
 
+    // Anything still dirty would be lost
+    fs.flush_all();
+
+    println!("Connection pool: {}", fs.pool.metrics());
+
     Ok(())
 }
 
@@ -1035,6 +3077,19 @@ fn main() {
             (about: "Client for ZippyNFS for Testing")
             (@arg server: -s --server {is_addr} +required +takes_value "\"IPAddr:Port\" for server")
             (@arg mount: -m --mount +required +takes_value "The mount path for the nfs client")
+            (@arg threads: -t --threads {is_positive} +takes_value
+                "The number of threads handling requests from the kernel (default 8)")
+            (@arg retries: -r --retries {is_positive} +takes_value
+                "The number of times to try an RPC before giving up on the server (default 5)")
+            (@arg backoff: -b --backoff {is_positive} +takes_value
+                "The longest wait between tries of an RPC, in seconds (default 16)")
+            (@arg options: -o {|s| parse_mount_options(&s).map(|_| ())} +takes_value
+                "Mount options, e.g. acregmin=3,acregmax=60,acdirmin=30,acdirmax=60,actimeo=<n>,\
+                 noac,nocto,nodeleg,\
+                 cachesize=<MB>,readahead=<KB>,disksize=<MB>,dirtyexpire=<s>,dirtybytes=<MB>, \
+                 and any FUSE options")
+            (@arg cache: -c --cache +takes_value
+                "A directory to cache file data in across mounts")
     }.get_matches();
 
     // Get the server address
@@ -1042,8 +3097,136 @@ fn main() {
 
     let mnt_path = matches.value_of("mount").unwrap();
 
-    if let Err(e) = run(server_addr, mnt_path) {
+    let threads = matches
+        .value_of("threads")
+        .map(|t| t.parse().unwrap())
+        .unwrap_or(DEFAULT_THREADS);
+
+    // Every thread can have an RPC going at once, so keep that many connections around
+    let mut config = PoolConfig::default();
+    config.max_idle = threads;
+    if let Some(retries) = matches.value_of("retries") {
+        config.max_tries = retries.parse().unwrap();
+    }
+    if let Some(max_backoff) = matches.value_of("backoff") {
+        config.max_backoff = Duration::from_secs(max_backoff.parse().unwrap());
+    }
+
+    let mount_opts = matches.value_of("options").unwrap_or("");
+    let cache_dir = matches.value_of("cache");
+
+    if let Err(e) = run(server_addr, mnt_path, threads, config, mount_opts, cache_dir) {
         println!("Error! {}", e);
         exit(-1);
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{extents_len, merge_extent, split_extents};
+
+    type Extents = Vec<(usize, usize, Vec<u8>)>;
+
+    /// Extents from (offset, data) pairs.
+    fn extents(extents: &[(usize, &str)]) -> Extents {
+        extents
+            .iter()
+            .map(|&(offset, data)| (offset, data.len(), data.as_bytes().to_vec()))
+            .collect()
+    }
+
+    /// Merge a write into the given extents, and return the result.
+    fn merge(before: &[(usize, &str)], offset: usize, data: &str) -> Extents {
+        let mut merged = extents(before);
+        merge_extent(&mut merged, offset, data.as_bytes());
+        merged
+    }
+
+    #[test]
+    fn test_merge_disjoint() {
+        assert_eq!(merge(&[], 10, "abc"), extents(&[(10, "abc")]));
+        assert_eq!(
+            merge(&[(10, "abc")], 0, "xy"),
+            extents(&[(0, "xy"), (10, "abc")])
+        );
+        assert_eq!(
+            merge(&[(0, "xy"), (10, "abc")], 5, "z"),
+            extents(&[(0, "xy"), (5, "z"), (10, "abc")])
+        );
+        assert_eq!(
+            merge(&[(0, "xy"), (10, "abc")], 20, "z"),
+            extents(&[(0, "xy"), (10, "abc"), (20, "z")])
+        );
+    }
+
+    #[test]
+    fn test_merge_adjacent() {
+        assert_eq!(merge(&[(0, "ab")], 2, "cd"), extents(&[(0, "abcd")]));
+        assert_eq!(merge(&[(4, "ef")], 2, "cd"), extents(&[(2, "cdef")]));
+
+        // Filling the gap between two joins all three
+        assert_eq!(
+            merge(&[(0, "aa"), (4, "bb")], 2, "cc"),
+            extents(&[(0, "aaccbb")])
+        );
+    }
+
+    #[test]
+    fn test_merge_overlapping() {
+        assert_eq!(merge(&[(0, "aaaa")], 2, "bbbb"), extents(&[(0, "aabbbb")]));
+        assert_eq!(merge(&[(2, "aaaa")], 0, "bbbb"), extents(&[(0, "bbbbaa")]));
+
+        // Several at once, leaving the ones past the end alone
+        assert_eq!(
+            merge(&[(0, "aa"), (4, "bb"), (8, "cc"), (20, "d")], 1, "xxxxxxxx"),
+            extents(&[(0, "axxxxxxxxc"), (20, "d")])
+        );
+    }
+
+    #[test]
+    fn test_merge_contained() {
+        assert_eq!(merge(&[(0, "aaaaaa")], 2, "bb"), extents(&[(0, "aabbaa")]));
+        assert_eq!(merge(&[(0, "aaaaaa")], 0, "bb"), extents(&[(0, "bbaaaa")]));
+        assert_eq!(merge(&[(0, "aaaaaa")], 4, "bb"), extents(&[(0, "aaaabb")]));
+        assert_eq!(merge(&[(2, "aa")], 0, "bbbbbb"), extents(&[(0, "bbbbbb")]));
+        assert_eq!(
+            merge(&[(1, "a"), (3, "a")], 0, "bbbbbb"),
+            extents(&[(0, "bbbbbb")])
+        );
+    }
+
+    #[test]
+    fn test_merge_sequential() {
+        let mut merged = Vec::new();
+        for i in 0..100 {
+            merge_extent(&mut merged, i * 3, b"abc");
+        }
+
+        assert_eq!(merged.len(), 1);
+        assert_eq!(merged[0].0, 0);
+        assert_eq!(extents_len(&merged), 300);
+        assert!(merged[0].2.chunks(3).all(|chunk| chunk == b"abc"));
+    }
+
+    #[test]
+    fn test_split_extents() {
+        assert!(split_extents(&[], 4).is_empty());
+
+        // Exactly `wsize`, and just past it
+        assert_eq!(split_extents(&extents(&[(0, "abcd")]), 4), extents(&[(0, "abcd")]));
+        assert_eq!(
+            split_extents(&extents(&[(0, "abcde")]), 4),
+            extents(&[(0, "abcd"), (4, "e")])
+        );
+        assert_eq!(
+            split_extents(&extents(&[(10, "abcdefgh")]), 4),
+            extents(&[(10, "abcd"), (14, "efgh")])
+        );
+
+        // Extents are never joined, even if they are close
+        assert_eq!(
+            split_extents(&extents(&[(0, "ab"), (3, "cdefg")]), 4),
+            extents(&[(0, "ab"), (3, "cdef"), (7, "g")])
+        );
+    }
+}
@@ -0,0 +1,1313 @@
+//! A minimal S3 gateway, so that tools that only speak S3 can read and write the FS.
+//!
+//! Buckets are the top-level directories, and keys are paths inside them, so `s3://b/x/y.txt` is
+//! `/b/x/y.txt`. Directories are created as needed when objects are put, and removed again when
+//! the last object in them is deleted. Only path-style requests are supported, and there is no
+//! authentication: any credentials are accepted, and signatures are not checked.
+//!
+//! We support GET, HEAD, PUT, and DELETE of objects (including `aws-chunked` uploads and byte
+//! ranges), creating, deleting, and listing buckets, ListObjects (V1 and V2, with prefix and
+//! delimiter), and multipart uploads. Parts are stored next to the bucket's objects until the
+//! upload is completed or aborted, in a directory that is hidden from listings.
+
+#[macro_use]
+extern crate clap;
+extern crate time;
+
+extern crate client;
+extern crate thrift;
+extern crate zippyrpc;
+
+use std::cmp::min;
+use std::io::{BufRead, BufReader, Read};
+use std::process::exit;
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+use zippyrpc::*;
+use client::new_xid;
+use client::gateway::{self, Backend, Gateway, WriteError, ROOT_FID};
+use client::gateway::http::{self, Body, HttpResult, Request, Response, Status};
+
+/// The namespace of all S3 XML
+const XMLNS: &'static str = "http://s3.amazonaws.com/doc/2006-03-01/";
+
+/// The directory in each bucket where multipart uploads are kept
+const UPLOADS_DIR: &'static str = ".zippynfs-uploads";
+
+/// The file in an upload's directory with the key being uploaded
+const UPLOAD_KEY: &'static str = "key";
+
+/// The file in an upload's directory that the parts are put together in
+const UPLOAD_OBJECT: &'static str = "object";
+
+/// The most parts in a multipart upload
+const MAX_PARTS: u32 = 10000;
+
+/// The most keys we return in one listing
+const MAX_KEYS: usize = 1000;
+
+/// The longest CompleteMultipartUpload request we accept
+const MAX_COMPLETE_LEN: u64 = 1 << 20;
+
+/// How many times we try to put the parts of an upload together if the server restarts
+const ASSEMBLE_ATTEMPTS: usize = 3;
+
+/// Makes upload IDs unique within this process
+static UPLOAD_COUNTER: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// An S3 error, which is sent as XML.
+#[derive(Debug)]
+struct S3Error {
+    status: u16,
+    code: &'static str,
+    message: String,
+}
+
+impl S3Error {
+    fn new(status: u16, code: &'static str, message: &str) -> S3Error {
+        S3Error {
+            status,
+            code,
+            message: message.to_owned(),
+        }
+    }
+
+    fn no_such_bucket() -> S3Error {
+        S3Error::new(404, "NoSuchBucket", "The specified bucket does not exist")
+    }
+
+    fn no_such_key() -> S3Error {
+        S3Error::new(404, "NoSuchKey", "The specified key does not exist")
+    }
+
+    fn no_such_upload() -> S3Error {
+        S3Error::new(404, "NoSuchUpload", "The specified upload does not exist")
+    }
+
+    fn not_implemented() -> S3Error {
+        S3Error::new(501, "NotImplemented", "This gateway doesn't do that")
+    }
+
+    fn key_conflict() -> S3Error {
+        S3Error::new(
+            409,
+            "InvalidRequest",
+            "A key can't be both an object and a prefix of other keys",
+        )
+    }
+
+    fn malformed_xml() -> S3Error {
+        S3Error::new(400, "MalformedXML", "The XML was not well-formed")
+    }
+
+    fn response(&self, req: &Request) -> Response {
+        let xml = format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<Error><Code>{}</Code>\
+             <Message>{}</Message><Resource>{}</Resource></Error>\n",
+            self.code,
+            http::escape(&self.message),
+            http::escape(&http::href(&req.path, req.trailing_slash))
+        );
+
+        Response::new(self.status).body("application/xml", xml.into_bytes())
+    }
+}
+
+impl From<ZipError> for S3Error {
+    fn from(e: ZipError) -> S3Error {
+        match e {
+            ZipError::Nfs(ZipErrorType::NFSERR_NOENT, _) |
+            ZipError::Nfs(ZipErrorType::NFSERR_STALE, _) => S3Error::no_such_key(),
+            ZipError::Nfs(ZipErrorType::NFSERR_EXIST, _) |
+            ZipError::Nfs(ZipErrorType::NFSERR_ISDIR, _) |
+            ZipError::Nfs(ZipErrorType::NFSERR_NOTDIR, _) => S3Error::key_conflict(),
+            ZipError::Nfs(ZipErrorType::NFSERR_NOTEMPTY, _) => {
+                S3Error::new(
+                    409,
+                    "BucketNotEmpty",
+                    "The bucket you tried to delete is not empty",
+                )
+            }
+            ZipError::Nfs(ZipErrorType::NFSERR_NAMETOOLONG, _) => {
+                S3Error::new(400, "KeyTooLongError", "Your key is too long")
+            }
+            ZipError::Nfs(ZipErrorType::NFSERR_INVAL, _) => {
+                S3Error::new(400, "InvalidArgument", "Invalid Argument")
+            }
+            e => {
+                println!("Error from the server: {:?}", e);
+                S3Error::new(503, "ServiceUnavailable", "Please try again")
+            }
+        }
+    }
+}
+
+impl From<thrift::Error> for S3Error {
+    fn from(e: thrift::Error) -> S3Error {
+        ZipError::from(e).into()
+    }
+}
+
+impl From<WriteError> for S3Error {
+    fn from(e: WriteError) -> S3Error {
+        match e {
+            WriteError::Read(e) => {
+                println!("Error reading the body: {}", e);
+                S3Error::new(400, "IncompleteBody", "Couldn't read the whole body")
+            }
+            WriteError::Rpc(e) => e.into(),
+
+            // The data may have been lost, so the client has to send it again
+            WriteError::Restarted => {
+                S3Error::new(503, "ServiceUnavailable", "The server restarted; please retry")
+            }
+        }
+    }
+}
+
+type S3Result<T> = Result<T, S3Error>;
+
+/// Is this the error the server gives for something that isn't there?
+fn is_noent(e: &ZipError) -> bool {
+    match *e {
+        ZipError::Nfs(ZipErrorType::NFSERR_NOENT, _) => true,
+        _ => false,
+    }
+}
+
+/// Is this the error the server gives for something that is already there?
+fn is_exist(e: &ZipError) -> bool {
+    match *e {
+        ZipError::Nfs(ZipErrorType::NFSERR_EXIST, _) => true,
+        _ => false,
+    }
+}
+
+/// Could this be an upload ID that `initiate_upload` made? They are all lowercase hex, so this
+/// keeps a client from naming anything else in the uploads directory, like "." or "..".
+fn is_upload_id(upload_id: &str) -> bool {
+    !upload_id.is_empty() &&
+        upload_id.bytes().all(|b| match b {
+            b'0'...b'9' | b'a'...b'f' => true,
+            _ => false,
+        })
+}
+
+/// The objects and common prefixes of a listing, as they are found.
+struct Listing<'a> {
+    prefix: &'a str,
+    delimiter: Option<&'a str>,
+
+    /// Only keys after this are listed
+    after: String,
+    max_keys: usize,
+
+    keys: Vec<(String, ZipFattr)>,
+    prefixes: Vec<String>,
+
+    /// The last key or prefix added
+    last: Option<String>,
+
+    /// There is more than `max_keys`
+    truncated: bool,
+}
+
+impl<'a> Listing<'a> {
+    fn len(&self) -> usize {
+        self.keys.len() + self.prefixes.len()
+    }
+
+    fn add_key(&mut self, key: String, attr: ZipFattr) {
+        if key <= self.after {
+            return;
+        }
+        if self.len() == self.max_keys {
+            self.truncated = true;
+            return;
+        }
+
+        self.last = Some(key.clone());
+        self.keys.push((key, attr));
+    }
+
+    fn add_prefix(&mut self, prefix: &str) {
+        // Everything with the same common prefix is found together
+        if prefix <= self.after.as_str() ||
+            self.prefixes.last().map(|p| p.as_str()) == Some(prefix)
+        {
+            return;
+        }
+        if self.len() == self.max_keys {
+            self.truncated = true;
+            return;
+        }
+
+        self.last = Some(prefix.to_owned());
+        self.prefixes.push(prefix.to_owned());
+    }
+}
+
+struct S3 {
+    backend: Backend,
+}
+
+impl Gateway for S3 {
+    fn backend(&mut self) -> &mut Backend {
+        &mut self.backend
+    }
+
+    fn handle<R: BufRead>(&mut self, req: &Request, body: &mut Body<R>) -> HttpResult<Response> {
+        let result = match req.path.split_first() {
+            None => {
+                match req.method.as_str() {
+                    "GET" | "HEAD" => self.list_buckets(),
+                    _ => Err(S3Error::not_implemented()),
+                }
+            }
+            Some((bucket, key)) if key.is_empty() => self.bucket_op(req, bucket),
+            Some((bucket, key)) => self.object_op(req, bucket, key, body),
+        };
+
+        Ok(result.unwrap_or_else(|e| e.response(req)))
+    }
+}
+
+impl S3 {
+    fn bucket_op(&mut self, req: &Request, bucket: &str) -> S3Result<Response> {
+        match req.method.as_str() {
+            "GET" if req.param("location").is_some() => {
+                self.bucket(bucket)?;
+                let xml = format!(
+                    "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+                     <LocationConstraint xmlns=\"{}\"/>\n",
+                    XMLNS
+                );
+                Ok(Response::new(200).body("application/xml", xml.into_bytes()))
+            }
+            "GET" if req.param("uploads").is_some() => Err(S3Error::not_implemented()),
+            "GET" => self.list_objects(req, bucket),
+            "HEAD" => {
+                self.bucket(bucket)?;
+                Ok(Response::new(200))
+            }
+            "PUT" => {
+                match self.backend.mkdir(ROOT_FID, bucket).map_err(ZipError::from) {
+                    Ok(_) => Ok(Response::new(200).header("Location", format!("/{}", bucket))),
+                    Err(ref e) if is_exist(e) => {
+                        Err(S3Error::new(
+                            409,
+                            "BucketAlreadyOwnedByYou",
+                            "The bucket already exists",
+                        ))
+                    }
+                    Err(e) => Err(e.into()),
+                }
+            }
+            "DELETE" => self.delete_bucket(bucket),
+            _ => Err(S3Error::not_implemented()),
+        }
+    }
+
+    fn object_op<R: BufRead>(
+        &mut self,
+        req: &Request,
+        bucket: &str,
+        key: &[String],
+        body: &mut Body<R>,
+    ) -> S3Result<Response> {
+        if key[0] == UPLOADS_DIR {
+            return Err(S3Error::new(
+                400,
+                "InvalidArgument",
+                "Keys in the uploads directory are reserved",
+            ));
+        }
+
+        let upload_id = req.param("uploadId");
+
+        match (req.method.as_str(), upload_id) {
+            ("GET", None) | ("HEAD", None) => self.get_object(req, bucket, key),
+            ("PUT", None) if req.header("x-amz-copy-source").is_some() => {
+                Err(S3Error::not_implemented())
+            }
+            ("PUT", None) => self.put_object(req, bucket, key, body),
+            ("PUT", Some(upload_id)) => self.upload_part(req, bucket, upload_id, body),
+            ("DELETE", None) => self.delete_object(req, bucket, key),
+            ("DELETE", Some(upload_id)) => self.abort_upload(bucket, upload_id),
+            ("POST", None) if req.param("uploads").is_some() => {
+                self.initiate_upload(bucket, key)
+            }
+            ("POST", Some(upload_id)) => self.complete_upload(bucket, key, upload_id, body),
+            _ => Err(S3Error::not_implemented()),
+        }
+    }
+
+    /// Find the directory of the given bucket.
+    fn bucket(&mut self, bucket: &str) -> S3Result<i64> {
+        match self.backend.lookup(ROOT_FID, bucket).map_err(ZipError::from) {
+            Ok(ref res) if res.attributes.type_ == ZipFtype::NFDIR => Ok(res.file.fid),
+            Ok(_) => Err(S3Error::no_such_bucket()),
+            Err(ref e) if is_noent(e) => Err(S3Error::no_such_bucket()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Find everything along the path of the given key. Returns the directories leading to it
+    /// and then the object itself.
+    fn walk(&mut self, bucket: &str, key: &[String]) -> S3Result<Vec<ZipDirOpRes>> {
+        let mut dir = self.bucket(bucket)?;
+        let mut found = Vec::with_capacity(key.len());
+
+        for name in key {
+            let res = match self.backend.lookup(dir, name).map_err(ZipError::from) {
+                Ok(res) => res,
+
+                // Part of the way is a file rather than a directory
+                Err(ZipError::Nfs(ZipErrorType::NFSERR_NOTDIR, _)) => {
+                    return Err(S3Error::no_such_key())
+                }
+                Err(e) => return Err(e.into()),
+            };
+            dir = res.file.fid;
+            found.push(res);
+        }
+
+        Ok(found)
+    }
+
+    /// Make all of the given directories in `dir` (like `mkdir -p`), and return the last one.
+    fn mkdir_all(&mut self, mut dir: i64, names: &[String]) -> S3Result<i64> {
+        for name in names {
+            let res = match self.backend.lookup(dir, name).map_err(ZipError::from) {
+                Ok(res) => res,
+                Err(ref e) if is_noent(e) => {
+                    match self.backend.mkdir(dir, name).map_err(ZipError::from) {
+                        Ok(res) => res,
+
+                        // Somebody else made it first
+                        Err(ref e) if is_exist(e) => self.backend.lookup(dir, name)?,
+                        Err(e) => return Err(e.into()),
+                    }
+                }
+                Err(e) => return Err(e.into()),
+            };
+
+            if res.attributes.type_ != ZipFtype::NFDIR {
+                return Err(S3Error::key_conflict());
+            }
+            dir = res.file.fid;
+        }
+
+        Ok(dir)
+    }
+
+    fn list_buckets(&mut self) -> S3Result<Response> {
+        let mut buckets: Vec<_> = self.backend
+            .list_dir(ROOT_FID)?
+            .into_iter()
+            .filter(|entry| entry.attributes.type_ == ZipFtype::NFDIR)
+            .collect();
+        buckets.sort_by(|a, b| a.fname.cmp(&b.fname));
+
+        let mut xml = format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<ListAllMyBucketsResult xmlns=\"{}\">\
+             <Owner><ID>zippynfs</ID><DisplayName>zippynfs</DisplayName></Owner><Buckets>",
+            XMLNS
+        );
+        for bucket in buckets {
+            xml.push_str(&format!(
+                "<Bucket><Name>{}</Name><CreationDate>{}</CreationDate></Bucket>",
+                http::escape(&bucket.fname),
+                http::iso_date(&bucket.attributes.ctime)
+            ));
+        }
+        xml.push_str("</Buckets></ListAllMyBucketsResult>\n");
+
+        Ok(Response::new(200).body("application/xml", xml.into_bytes()))
+    }
+
+    fn delete_bucket(&mut self, bucket: &str) -> S3Result<Response> {
+        let dir = self.bucket(bucket)?;
+
+        // Unfinished uploads don't keep a bucket alive
+        for entry in self.backend.list_dir(dir)? {
+            if entry.fname != UPLOADS_DIR {
+                return Err(ZipError::Nfs(ZipErrorType::NFSERR_NOTEMPTY, String::new()).into());
+            }
+
+            let uploads = ZipDirOpRes::new(entry.file, entry.attributes);
+            self.backend.remove_tree(dir, UPLOADS_DIR, &uploads)?;
+        }
+
+        let args = ZipDirOpArgs::new(ZipFileHandle::new(ROOT_FID), bucket.to_owned(), new_xid());
+        self.backend.change(|znfs| znfs.rmdir(args.clone()))?;
+
+        Ok(Response::new(204))
+    }
+
+    /// ListObjects, both V1 and V2, which differ only in how they page through the results.
+    fn list_objects(&mut self, req: &Request, bucket: &str) -> S3Result<Response> {
+        let dir = self.bucket(bucket)?;
+
+        let v2 = req.param("list-type") == Some("2");
+        let prefix = req.param("prefix").unwrap_or("");
+        let delimiter = match req.param("delimiter") {
+            Some("") | None => None,
+            delimiter => delimiter,
+        };
+        let url_encode = req.param("encoding-type") == Some("url");
+
+        let max_keys = match req.param("max-keys") {
+            Some(max) => {
+                max.parse().map_err(|_| {
+                    S3Error::new(400, "InvalidArgument", "max-keys is not a number")
+                })?
+            }
+            None => MAX_KEYS,
+        };
+
+        // Continuation tokens are just the last key or prefix returned
+        let token = req.param("continuation-token");
+        let start_after = if v2 {
+            req.param("start-after")
+        } else {
+            req.param("marker")
+        };
+        let after = match (token, start_after) {
+            (Some(a), Some(b)) => if a > b { a } else { b },
+            (Some(a), None) | (None, Some(a)) => a,
+            (None, None) => "",
+        };
+
+        let mut listing = Listing {
+            prefix,
+            delimiter,
+            after: after.to_owned(),
+            max_keys: min(max_keys, MAX_KEYS),
+            keys: vec![],
+            prefixes: vec![],
+            last: None,
+            truncated: false,
+        };
+        {
+            let backend = &mut self.backend;
+            let mut list_dir = |dir| backend.list_dir(dir).map_err(S3Error::from);
+            list_tree(&mut list_dir, dir, "", &mut listing)?;
+        }
+
+        let encode = |s: &str| if url_encode {
+            http::percent_encode(s, true)
+        } else {
+            http::escape(s)
+        };
+
+        let mut xml = format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<ListBucketResult xmlns=\"{}\">\
+             <Name>{}</Name><Prefix>{}</Prefix><MaxKeys>{}</MaxKeys><IsTruncated>{}</IsTruncated>",
+            XMLNS,
+            http::escape(bucket),
+            encode(prefix),
+            max_keys,
+            listing.truncated
+        );
+        if let Some(delimiter) = delimiter {
+            xml.push_str(&format!("<Delimiter>{}</Delimiter>", encode(delimiter)));
+        }
+        if url_encode {
+            xml.push_str("<EncodingType>url</EncodingType>");
+        }
+
+        let next = if listing.truncated {
+            listing.last.clone()
+        } else {
+            None
+        };
+        if v2 {
+            xml.push_str(&format!("<KeyCount>{}</KeyCount>", listing.len()));
+            if let Some(token) = token {
+                xml.push_str(&format!(
+                    "<ContinuationToken>{}</ContinuationToken>",
+                    http::escape(token)
+                ));
+            }
+            if let Some(next) = next {
+                xml.push_str(&format!(
+                    "<NextContinuationToken>{}</NextContinuationToken>",
+                    http::escape(&next)
+                ));
+            }
+            if let Some(start_after) = start_after {
+                xml.push_str(&format!("<StartAfter>{}</StartAfter>", encode(start_after)));
+            }
+        } else {
+            xml.push_str(&format!("<Marker>{}</Marker>", encode(after)));
+            if let Some(next) = next {
+                xml.push_str(&format!("<NextMarker>{}</NextMarker>", encode(&next)));
+            }
+        }
+
+        for &(ref key, ref attr) in &listing.keys {
+            xml.push_str(&format!(
+                "<Contents><Key>{}</Key><LastModified>{}</LastModified><ETag>{}</ETag>\
+                 <Size>{}</Size><StorageClass>STANDARD</StorageClass></Contents>",
+                encode(key),
+                http::iso_date(&attr.mtime),
+                http::escape(&http::etag(attr)),
+                attr.size
+            ));
+        }
+        for prefix in &listing.prefixes {
+            xml.push_str(&format!(
+                "<CommonPrefixes><Prefix>{}</Prefix></CommonPrefixes>",
+                encode(prefix)
+            ));
+        }
+        xml.push_str("</ListBucketResult>\n");
+
+        Ok(Response::new(200).body("application/xml", xml.into_bytes()))
+    }
+
+    fn get_object(&mut self, req: &Request, bucket: &str, key: &[String]) -> S3Result<Response> {
+        let object = self.walk(bucket, key)?.pop().unwrap();
+
+        if object.attributes.type_ == ZipFtype::NFDIR {
+            return Err(S3Error::no_such_key());
+        }
+
+        let response = http::get_file(
+            req,
+            object.file.fid,
+            &object.attributes,
+            "application/octet-stream",
+        );
+        if response.status == Status(416) {
+            return Err(S3Error::new(
+                416,
+                "InvalidRange",
+                "The requested range is not satisfiable",
+            ));
+        }
+
+        Ok(response)
+    }
+
+    fn put_object<R: BufRead>(
+        &mut self,
+        req: &Request,
+        bucket: &str,
+        key: &[String],
+        body: &mut Body<R>,
+    ) -> S3Result<Response> {
+        let bucket = self.bucket(bucket)?;
+
+        // An empty object whose key ends with a slash is a "folder"
+        if req.trailing_slash {
+            self.mkdir_all(bucket, key)?;
+            return Ok(Response::new(200));
+        }
+
+        let (name, dirs) = key.split_last().unwrap();
+        let dir = self.mkdir_all(bucket, dirs)?;
+        let (file, _) = self.backend.create_or_truncate(dir, name)?;
+
+        // Signed uploads are chunked again inside the body, with a signature on each chunk
+        let aws_chunked = req.header("content-encoding")
+            .map(|e| e.contains("aws-chunked"))
+            .unwrap_or(false) ||
+            req.header("x-amz-content-sha256")
+                .map(|sha| sha.starts_with("STREAMING-"))
+                .unwrap_or(false);
+
+        if aws_chunked {
+            let mut inner = BufReader::new(body);
+            self.backend.write_from(
+                file.fid,
+                0,
+                &mut Body::chunked(&mut inner),
+            )?;
+        } else {
+            self.backend.write_from(file.fid, 0, body)?;
+        }
+
+        let attr = self.backend.znfs.getattr(file)?.attributes;
+        Ok(Response::new(200).header("ETag", http::etag(&attr)))
+    }
+
+    /// Delete an object, or a "folder" if the key ends with a slash. A folder with objects in it
+    /// stays, as they still need its prefix, which is what S3 would show too.
+    fn delete_object(
+        &mut self,
+        req: &Request,
+        bucket: &str,
+        key: &[String],
+    ) -> S3Result<Response> {
+        // Deleting something that isn't there succeeds
+        let found = match self.walk(bucket, key) {
+            Ok(found) => found,
+            Err(ref e) if e.code == "NoSuchKey" => return Ok(Response::new(204)),
+            Err(e) => return Err(e),
+        };
+
+        let bucket = self.bucket(bucket)?;
+        let dirs: Vec<i64> = Some(bucket)
+            .into_iter()
+            .chain(found.iter().map(|res| res.file.fid))
+            .collect();
+
+        // A folder is only named with a slash, and an object only without one
+        let is_dir = found.last().unwrap().attributes.type_ == ZipFtype::NFDIR;
+        if is_dir != req.trailing_slash {
+            return Ok(Response::new(204));
+        }
+
+        let args = ZipDirOpArgs::new(
+            ZipFileHandle::new(dirs[key.len() - 1]),
+            key[key.len() - 1].clone(),
+            new_xid(),
+        );
+        let remove = if is_dir {
+            self.backend.change(|znfs| znfs.rmdir(args.clone()))
+        } else {
+            self.backend.change(|znfs| znfs.remove(args.clone()))
+        };
+        match remove.map_err(ZipError::from) {
+            Ok(()) => {}
+            Err(ref e) if is_noent(e) => {}
+            Err(ZipError::Nfs(ZipErrorType::NFSERR_NOTEMPTY, _)) => return Ok(Response::new(204)),
+            Err(e) => return Err(e.into()),
+        }
+
+        // Remove the directories that are now empty, so that their prefixes go away too. Stop at
+        // the first one that isn't empty.
+        for i in (0..key.len() - 1).rev() {
+            let args = ZipDirOpArgs::new(ZipFileHandle::new(dirs[i]), key[i].clone(), new_xid());
+            let rmdir = self.backend.change(|znfs| znfs.rmdir(args.clone()));
+            if rmdir.is_err() {
+                break;
+            }
+        }
+
+        Ok(Response::new(204))
+    }
+
+    /// Find the directory of the given upload, and the directory it is in.
+    fn upload(&mut self, bucket: &str, upload_id: &str) -> S3Result<(i64, i64)> {
+        if !is_upload_id(upload_id) {
+            return Err(S3Error::no_such_upload());
+        }
+
+        let bucket = self.bucket(bucket)?;
+
+        let uploads = match self.backend.lookup(bucket, UPLOADS_DIR).map_err(ZipError::from) {
+            Ok(uploads) => uploads.file.fid,
+            Err(ref e) if is_noent(e) => return Err(S3Error::no_such_upload()),
+            Err(e) => return Err(e.into()),
+        };
+
+        match self.backend.lookup(uploads, upload_id).map_err(ZipError::from) {
+            Ok(ref upload) if upload.attributes.type_ == ZipFtype::NFDIR => {
+                Ok((uploads, upload.file.fid))
+            }
+            Ok(_) => Err(S3Error::no_such_upload()),
+            Err(ref e) if is_noent(e) => Err(S3Error::no_such_upload()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn initiate_upload(&mut self, bucket_name: &str, key: &[String]) -> S3Result<Response> {
+        let bucket = self.bucket(bucket_name)?;
+        let uploads = self.mkdir_all(bucket, &[UPLOADS_DIR.to_owned()])?;
+
+        // Upload IDs only have to be unique within the bucket, and the directory tells us if
+        // they aren't.
+        let now = time::get_time();
+        let upload = loop {
+            let upload_id = format!(
+                "{:x}{:08x}{:x}",
+                now.sec,
+                now.nsec,
+                UPLOAD_COUNTER.fetch_add(1, Ordering::Relaxed)
+            );
+
+            match self.backend.mkdir(uploads, &upload_id).map_err(ZipError::from) {
+                Ok(res) => break (upload_id, res.file.fid),
+                Err(ref e) if is_exist(e) => continue,
+                Err(e) => return Err(e.into()),
+            }
+        };
+
+        // Remember the key, so that the upload can't be completed as something else
+        let key = key.join("/");
+        let (file, _) = self.backend.create_or_truncate(upload.1, UPLOAD_KEY)?;
+        self.backend.write_from(file.fid, 0, &mut key.as_bytes())?;
+
+        let xml = format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+             <InitiateMultipartUploadResult xmlns=\"{}\"><Bucket>{}</Bucket><Key>{}</Key>\
+             <UploadId>{}</UploadId></InitiateMultipartUploadResult>\n",
+            XMLNS,
+            http::escape(bucket_name),
+            http::escape(&key),
+            upload.0
+        );
+        Ok(Response::new(200).body("application/xml", xml.into_bytes()))
+    }
+
+    fn upload_part<R: BufRead>(
+        &mut self,
+        req: &Request,
+        bucket: &str,
+        upload_id: &str,
+        body: &mut Body<R>,
+    ) -> S3Result<Response> {
+        let part = match req.param("partNumber").map(|n| n.parse::<u32>()) {
+            Some(Ok(part)) if part >= 1 && part <= MAX_PARTS => part,
+            _ => {
+                return Err(S3Error::new(
+                    400,
+                    "InvalidArgument",
+                    "Part number must be an integer between 1 and 10000",
+                ))
+            }
+        };
+
+        let (_, upload) = self.upload(bucket, upload_id)?;
+
+        // Each part is committed before we reply, so it survives the server restarting
+        let (file, _) = self.backend.create_or_truncate(upload, &part.to_string())?;
+        self.backend.write_from(file.fid, 0, body)?;
+
+        let attr = self.backend.znfs.getattr(file)?.attributes;
+        Ok(Response::new(200).header("ETag", http::etag(&attr)))
+    }
+
+    fn abort_upload(&mut self, bucket: &str, upload_id: &str) -> S3Result<Response> {
+        let (uploads, upload) = self.upload(bucket, upload_id)?;
+
+        let attr = self.backend.znfs.getattr(ZipFileHandle::new(upload))?;
+        self.backend.remove_tree(
+            uploads,
+            upload_id,
+            &ZipDirOpRes::new(ZipFileHandle::new(upload), attr.attributes),
+        )?;
+
+        Ok(Response::new(204))
+    }
+
+    fn complete_upload<R: BufRead>(
+        &mut self,
+        bucket_name: &str,
+        key: &[String],
+        upload_id: &str,
+        body: &mut Body<R>,
+    ) -> S3Result<Response> {
+        let (_, upload) = self.upload(bucket_name, upload_id)?;
+
+        // Make sure it is an upload of this key
+        let key_file = self.backend.lookup(upload, UPLOAD_KEY)?;
+        let upload_key = self.backend.znfs.read(ZipReadArgs::new(
+            key_file.file,
+            0,
+            key_file.attributes.size,
+            None,
+        ))?;
+        if upload_key.data != key.join("/").into_bytes() {
+            return Err(S3Error::no_such_upload());
+        }
+
+        // Find the parts, and make sure they are the ones the client thinks they are
+        let mut xml = String::new();
+        (&mut *body)
+            .take(MAX_COMPLETE_LEN)
+            .read_to_string(&mut xml)
+            .map_err(|_| S3Error::malformed_xml())?;
+
+        let mut parts = Vec::new();
+        for (number, etag) in parse_complete(&xml)? {
+            if parts.last().map(|&(last, _)| number <= last).unwrap_or(false) {
+                return Err(S3Error::new(
+                    400,
+                    "InvalidPartOrder",
+                    "The parts must be in ascending order",
+                ));
+            }
+
+            let part = match self.backend.lookup(upload, &number.to_string()) {
+                Ok(part) => part,
+                Err(_) => return Err(invalid_part(number)),
+            };
+            if !etag.is_empty() && etag != http::etag(&part.attributes).trim_matches('"') {
+                return Err(invalid_part(number));
+            }
+
+            parts.push((number, part));
+        }
+        if parts.is_empty() {
+            return Err(S3Error::malformed_xml());
+        }
+
+        // Put the parts together next to them, and then move the result into place, so that
+        // nobody sees a partial object. The parts are already committed, so if the server
+        // restarts in the middle, we can just start over.
+        //
+        // The server can't join files, so every byte of every part is read back and written
+        // again through us, and this takes time in proportion to the size of the whole object.
+        // Nothing more than one READ's worth is held in memory at a time, though.
+        let mut attempt = 0;
+        let object = loop {
+            let (object, _) = self.backend.create_or_truncate(upload, UPLOAD_OBJECT)?;
+
+            match self.assemble(object.fid, &parts) {
+                Ok(()) => break object,
+                Err(WriteError::Restarted) if attempt + 1 < ASSEMBLE_ATTEMPTS => attempt += 1,
+                Err(e) => return Err(e.into()),
+            }
+        };
+
+        let bucket = self.bucket(bucket_name)?;
+        let (name, dirs) = key.split_last().unwrap();
+        let dir = self.mkdir_all(bucket, dirs)?;
+
+        // The server won't rename over an existing file
+        match self.backend.lookup(dir, name).map_err(ZipError::from) {
+            Ok(ref res) if res.attributes.type_ == ZipFtype::NFDIR => {
+                return Err(S3Error::key_conflict())
+            }
+            Ok(_) => {
+                let args = ZipDirOpArgs::new(ZipFileHandle::new(dir), name.clone(), new_xid());
+                self.backend.change(|znfs| znfs.remove(args.clone()))?
+            }
+            Err(ref e) if is_noent(e) => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        let args = ZipRenameArgs::new(
+            ZipDirOpArgs::new(
+                ZipFileHandle::new(upload),
+                UPLOAD_OBJECT.to_owned(),
+                new_xid(),
+            ),
+            ZipDirOpArgs::new(ZipFileHandle::new(dir), name.clone(), None),
+            None,
+        );
+        self.backend.change(|znfs| znfs.rename(args.clone()))?;
+
+        // And clean up the parts
+        self.abort_upload(bucket_name, upload_id)?;
+
+        let attr = self.backend.znfs.getattr(object)?.attributes;
+        let xml = format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+             <CompleteMultipartUploadResult xmlns=\"{}\"><Location>{}</Location>\
+             <Bucket>{}</Bucket><Key>{}</Key><ETag>{}</ETag></CompleteMultipartUploadResult>\n",
+            XMLNS,
+            http::escape(&format!(
+                "/{}/{}",
+                bucket_name,
+                http::percent_encode(&key.join("/"), true)
+            )),
+            http::escape(bucket_name),
+            http::escape(&key.join("/")),
+            http::escape(&http::etag(&attr))
+        );
+        Ok(Response::new(200).body("application/xml", xml.into_bytes()))
+    }
+
+    /// Copy the given parts, in order, into the object, and commit it. This reads and writes the
+    /// whole object, so it is O(total size) in time and traffic to the server.
+    fn assemble(&mut self, object: i64, parts: &[(u32, ZipDirOpRes)]) -> Result<(), WriteError> {
+        let mut verf = None;
+        let mut offset = 0;
+
+        for &(_, ref part) in parts {
+            let size = part.attributes.size as u64;
+            let mut copied = 0;
+
+            while copied < size {
+                let count = min(size - copied, self.backend.rsize as u64);
+                let res = self.backend.znfs.read(ZipReadArgs::new(
+                    ZipFileHandle::new(part.file.fid),
+                    copied as i64,
+                    count as i64,
+                    None,
+                ))?;
+                if res.data.is_empty() {
+                    break;
+                }
+
+                self.backend.write(object, offset, &res.data, &mut verf)?;
+                copied += res.data.len() as u64;
+                offset += res.data.len() as u64;
+            }
+        }
+
+        self.backend.commit(object, verf)
+    }
+}
+
+/// Walk the tree under `dir` (whose keys all start with `dir_key`) in key order, adding what
+/// matches to the listing until it is full. Subtrees that can't match the prefix, that come before
+/// where the listing starts, or that fall under one common prefix are skipped. Directories are
+/// listed with `list_dir`.
+fn list_tree<F>(
+    list_dir: &mut F,
+    dir: i64,
+    dir_key: &str,
+    listing: &mut Listing,
+) -> S3Result<()>
+where
+    F: FnMut(i64) -> S3Result<Vec<ZipDirEntryPlus>>,
+{
+    // Sort by key, rather than by name: `a/` has to come after `a-b`.
+    let mut entries: Vec<_> = list_dir(dir)?
+        .into_iter()
+        .filter(|entry| !(dir_key.is_empty() && entry.fname == UPLOADS_DIR))
+        .map(|entry| {
+            let is_dir = entry.attributes.type_ == ZipFtype::NFDIR;
+            let key = format!("{}{}{}", dir_key, entry.fname, if is_dir { "/" } else { "" });
+            (key, is_dir, entry)
+        })
+        .collect();
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+    for (key, is_dir, entry) in entries {
+        if listing.truncated {
+            break;
+        }
+
+        let matches = key.starts_with(listing.prefix);
+        if !matches && !(is_dir && listing.prefix.starts_with(key.as_str())) {
+            continue;
+        }
+        if is_dir && key < listing.after && !listing.after.starts_with(key.as_str()) {
+            continue;
+        }
+
+        // Everything with the delimiter after the prefix is rolled up into a common prefix.
+        // For a directory, that covers everything in it.
+        if let (true, Some(delimiter)) = (matches, listing.delimiter) {
+            if let Some(i) = key[listing.prefix.len()..].find(delimiter) {
+                let common = &key[..listing.prefix.len() + i + delimiter.len()];
+                listing.add_prefix(common);
+                continue;
+            }
+        }
+
+        if is_dir {
+            list_tree(list_dir, entry.file.fid, &key, listing)?;
+        } else {
+            listing.add_key(key, entry.attributes);
+        }
+    }
+
+    Ok(())
+}
+
+fn invalid_part(number: u32) -> S3Error {
+    S3Error::new(
+        400,
+        "InvalidPart",
+        &format!("Part {} is missing or has changed", number),
+    )
+}
+
+/// The text inside the first `<name>` element of the given XML, if there is one.
+fn xml_tag<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let open = format!("<{}>", name);
+    let close = format!("</{}>", name);
+
+    let start = xml.find(&open)? + open.len();
+    let end = xml[start..].find(&close)? + start;
+    Some(&xml[start..end])
+}
+
+/// Parse the part numbers and ETags out of a CompleteMultipartUpload request. ETags may be
+/// missing, in which case they are empty.
+fn parse_complete(xml: &str) -> S3Result<Vec<(u32, String)>> {
+    let mut parts = Vec::new();
+    let mut rest = xml;
+
+    while let Some(start) = rest.find("<Part>") {
+        let end = rest[start..].find("</Part>").ok_or_else(
+            S3Error::malformed_xml,
+        )? + start;
+        let part = &rest[start..end];
+
+        let number = xml_tag(part, "PartNumber")
+            .and_then(|n| n.trim().parse().ok())
+            .ok_or_else(S3Error::malformed_xml)?;
+        let etag = xml_tag(part, "ETag")
+            .unwrap_or("")
+            .replace("&quot;", "")
+            .replace("&#34;", "")
+            .trim()
+            .trim_matches('"')
+            .to_owned();
+
+        parts.push((number, etag));
+        rest = &rest[end..];
+    }
+
+    Ok(parts)
+}
+
+/// Checks if the given string is a valid IP:port pair.
+///
+/// This is used for parsing command line args.
+fn is_addr(arg: String) -> Result<(), String> {
+    use std::net::ToSocketAddrs;
+
+    arg.to_socket_addrs()
+        .map_err(|_| "Not a valid IP:Port".to_owned())
+        .map(|_| ())
+}
+
+fn main() {
+    let matches = clap_app!{
+        zippynfs_s3 =>
+            (version: "1.0")
+            (author: "Team Chimney")
+            (about: "S3 gateway for ZippyNFS")
+            (@arg server: -s --server {is_addr} +required +takes_value "\"IPAddr:Port\" for server")
+            (@arg listen: -l --listen {is_addr} +required +takes_value
+                "The \"IPAddr:Port\" to serve S3 at")
+    }.get_matches();
+
+    // Get the server address
+    let server_addr = matches.value_of("server").unwrap().to_owned();
+
+    // Get the address to listen at
+    let listen_addr = matches.value_of("listen").unwrap();
+
+    println!("Serving S3 at http://{}/", listen_addr);
+
+    let result = gateway::serve(listen_addr, move || {
+        Backend::connect(&server_addr).map(|backend| S3 { backend })
+    });
+
+    if let Err(e) = result {
+        println!("Error! {}", e);
+        exit(-1);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use zippyrpc::*;
+
+    use super::{is_upload_id, list_tree, parse_complete, Listing, UPLOADS_DIR};
+
+    fn attr(fid: i64, type_: ZipFtype) -> ZipFattr {
+        let t = ZipTimeVal::new(0, 0);
+        ZipFattr::new(type_, 0o644, 1, 0, 0, 0, 4096, 0, 0, 0, fid, t.clone(), t.clone(), t)
+    }
+
+    /// A bucket (FID 1) holding `a-b`, `a/x`, `a/y/z`, `b`, and an upload, as directory FID ->
+    /// (name, FID, is a directory).
+    fn tree() -> HashMap<i64, Vec<(&'static str, i64, bool)>> {
+        let mut tree = HashMap::new();
+        tree.insert(
+            1,
+            vec![("b", 2, false), ("a", 3, true), (UPLOADS_DIR, 4, true), ("a-b", 5, false)],
+        );
+        tree.insert(3, vec![("y", 6, true), ("x", 7, false)]);
+        tree.insert(4, vec![("1234", 8, true)]);
+        tree.insert(6, vec![("z", 9, false)]);
+        tree.insert(8, vec![]);
+        tree
+    }
+
+    fn listing<'a>(
+        prefix: &'a str,
+        delimiter: Option<&'a str>,
+        after: &str,
+        max_keys: usize,
+    ) -> Listing<'a> {
+        Listing {
+            prefix,
+            delimiter,
+            after: after.to_owned(),
+            max_keys,
+            keys: vec![],
+            prefixes: vec![],
+            last: None,
+            truncated: false,
+        }
+    }
+
+    /// List the tree. Returns the keys, the common prefixes, and the directories that had to be
+    /// listed.
+    fn list(listing: &mut Listing) -> (Vec<String>, Vec<String>, Vec<i64>) {
+        let tree = tree();
+        let mut listed = Vec::new();
+        {
+            let mut list_dir = |dir: i64| {
+                listed.push(dir);
+                Ok(
+                    tree[&dir]
+                        .iter()
+                        .map(|&(name, fid, is_dir)| {
+                            let type_ = if is_dir { ZipFtype::NFDIR } else { ZipFtype::NFREG };
+                            ZipDirEntryPlus::new(
+                                fid,
+                                name.to_owned(),
+                                ZipFileHandle::new(fid),
+                                attr(fid, type_),
+                            )
+                        })
+                        .collect(),
+                )
+            };
+            list_tree(&mut list_dir, 1, "", listing).unwrap();
+        }
+
+        let keys = listing.keys.iter().map(|&(ref key, _)| key.clone()).collect();
+        (keys, listing.prefixes.clone(), listed)
+    }
+
+    /// List the tree a page of `max_keys` at a time, and return every page.
+    fn list_pages(prefix: &str, delimiter: Option<&str>, max_keys: usize) -> Vec<Vec<String>> {
+        let mut pages = Vec::new();
+        let mut after = String::new();
+
+        loop {
+            let mut page = listing(prefix, delimiter, &after, max_keys);
+            let (keys, prefixes, _) = list(&mut page);
+            assert!(page.len() <= max_keys);
+
+            let mut found: Vec<String> = keys.into_iter().chain(prefixes).collect();
+            found.sort();
+            pages.push(found);
+
+            if !page.truncated {
+                return pages;
+            }
+            after = page.last.unwrap();
+        }
+    }
+
+    fn strings(strs: &[&str]) -> Vec<String> {
+        strs.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_list_tree() {
+        // Keys are in key order, and uploads are hidden
+        let (keys, prefixes, _) = list(&mut listing("", None, "", 1000));
+        assert_eq!(keys, strings(&["a-b", "a/x", "a/y/z", "b"]));
+        assert!(prefixes.is_empty());
+
+        let (keys, prefixes, listed) = list(&mut listing("", Some("/"), "", 1000));
+        assert_eq!(keys, strings(&["a-b", "b"]));
+        assert_eq!(prefixes, strings(&["a/"]));
+        assert_eq!(listed, vec![1]);
+
+        let (keys, prefixes, _) = list(&mut listing("a/", Some("/"), "", 1000));
+        assert_eq!(keys, strings(&["a/x"]));
+        assert_eq!(prefixes, strings(&["a/y/"]));
+
+        // Other delimiters work within names
+        let (keys, prefixes, _) = list(&mut listing("", Some("-"), "", 1000));
+        assert_eq!(keys, strings(&["a/x", "a/y/z", "b"]));
+        assert_eq!(prefixes, strings(&["a-"]));
+
+        // Only directories that can match the prefix are listed
+        let (keys, _, listed) = list(&mut listing("a/y", None, "", 1000));
+        assert_eq!(keys, strings(&["a/y/z"]));
+        assert_eq!(listed, vec![1, 3, 6]);
+
+        let (keys, _, listed) = list(&mut listing("b", None, "", 1000));
+        assert_eq!(keys, strings(&["b"]));
+        assert_eq!(listed, vec![1]);
+
+        let (keys, prefixes, _) = list(&mut listing("c", Some("/"), "", 1000));
+        assert!(keys.is_empty() && prefixes.is_empty());
+
+        // Starting after a key skips the directories before it
+        let (keys, _, listed) = list(&mut listing("", None, "a/x", 1000));
+        assert_eq!(keys, strings(&["a/y/z", "b"]));
+        assert_eq!(listed, vec![1, 3, 6]);
+
+        let (keys, _, listed) = list(&mut listing("", None, "a/y/z", 1000));
+        assert_eq!(keys, strings(&["b"]));
+        assert_eq!(listed, vec![1, 3, 6]);
+
+        let (keys, _, listed) = list(&mut listing("", None, "a0", 1000));
+        assert_eq!(keys, strings(&["b"]));
+        assert_eq!(listed, vec![1]);
+    }
+
+    #[test]
+    fn test_list_pages() {
+        assert_eq!(
+            list_pages("", None, 1),
+            vec![
+                strings(&["a-b"]),
+                strings(&["a/x"]),
+                strings(&["a/y/z"]),
+                strings(&["b"]),
+            ]
+        );
+        assert_eq!(
+            list_pages("", None, 3),
+            vec![strings(&["a-b", "a/x", "a/y/z"]), strings(&["b"])]
+        );
+
+        // A common prefix is only returned once, even though it covers many keys
+        assert_eq!(
+            list_pages("", Some("/"), 1),
+            vec![strings(&["a-b"]), strings(&["a/"]), strings(&["b"])]
+        );
+        assert_eq!(
+            list_pages("a/", Some("/"), 1),
+            vec![strings(&["a/x"]), strings(&["a/y/"])]
+        );
+
+        // No room for anything
+        let mut page = listing("", None, "", 0);
+        let (keys, _, _) = list(&mut page);
+        assert!(keys.is_empty());
+        assert!(page.truncated);
+    }
+
+    #[test]
+    fn test_parse_complete() {
+        let xml = "<CompleteMultipartUpload>\
+                   <Part><PartNumber>1</PartNumber><ETag>\"abc\"</ETag></Part>\
+                   <Part><ETag>&quot;def&quot;</ETag><PartNumber> 2 </PartNumber></Part>\
+                   <Part><PartNumber>5</PartNumber></Part>\
+                   </CompleteMultipartUpload>";
+        assert_eq!(
+            parse_complete(xml).unwrap(),
+            vec![(1, "abc".to_owned()), (2, "def".to_owned()), (5, "".to_owned())]
+        );
+
+        assert!(parse_complete("").unwrap().is_empty());
+        assert!(parse_complete("<CompleteMultipartUpload/>").unwrap().is_empty());
+
+        // Missing, out of range, or not a number at all
+        for bad in &[
+            "<Part><ETag>x</ETag></Part>",
+            "<Part><PartNumber>-1</PartNumber></Part>",
+            "<Part><PartNumber>4294967296</PartNumber></Part>",
+            "<Part><PartNumber>one</PartNumber></Part>",
+            "<Part><PartNumber>1</PartNumber>",
+            "<Part><PartNumber>1</Part>",
+        ]
+        {
+            assert_eq!(parse_complete(bad).unwrap_err().code, "MalformedXML", "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_is_upload_id() {
+        assert!(is_upload_id("0123456789abcdef"));
+        assert!(is_upload_id("0"));
+
+        for bad in &["", ".", "..", "ABC", "12g", "a/b", "key", " 12", "-1"] {
+            assert!(!is_upload_id(bad), "{}", bad);
+        }
+    }
+}
@@ -0,0 +1,341 @@
+//! A WebDAV gateway, so that the FS can be browsed and edited over HTTP without mounting it.
+//!
+//! Each WebDAV request is translated into RPCs on the connection to the ZippyNFS server. We
+//! support enough of WebDAV (RFC 4918, class 1) for common clients: OPTIONS, GET and HEAD (with
+//! single byte ranges), PUT (including chunked uploads), MKCOL, DELETE, MOVE, and PROPFIND. There
+//! are no locks, and no COPY or PROPPATCH.
+//!
+//! Directories can also be browsed with a plain web browser, which gets an HTML listing.
+
+#[macro_use]
+extern crate clap;
+
+extern crate client;
+extern crate zippyrpc;
+
+use std::io::{BufRead, Read};
+use std::process::exit;
+
+use zippyrpc::*;
+use client::new_xid;
+use client::gateway::{self, Backend, Gateway, WriteError};
+use client::gateway::http::{self, Body, HttpResult, Request, Response, Status};
+
+/// The methods we support, as reported by OPTIONS
+const ALLOW: &'static str = "OPTIONS, GET, HEAD, PUT, DELETE, MKCOL, MOVE, PROPFIND";
+
+struct WebDav {
+    backend: Backend,
+}
+
+impl Gateway for WebDav {
+    fn backend(&mut self) -> &mut Backend {
+        &mut self.backend
+    }
+
+    fn handle<R: BufRead>(&mut self, req: &Request, body: &mut Body<R>) -> HttpResult<Response> {
+        match req.method.as_str() {
+            "OPTIONS" => {
+                Ok(
+                    Response::new(200)
+                        .header("DAV", "1".to_owned())
+                        .header("Allow", ALLOW.to_owned())
+                        .header("MS-Author-Via", "DAV".to_owned()),
+                )
+            }
+            "GET" | "HEAD" => self.get(req),
+            "PUT" => self.put(req, body),
+            "MKCOL" => self.mkcol(req, body),
+            "DELETE" => self.delete(req),
+            "MOVE" => self.move_(req),
+            "PROPFIND" => self.propfind(req),
+            "COPY" | "PROPPATCH" | "LOCK" | "UNLOCK" => Err(Status(405)),
+            _ => Err(Status(501)),
+        }
+    }
+}
+
+impl WebDav {
+    /// Find the directory containing the given path, and return its FID with the last component
+    /// of the path. A missing directory is a conflict, as WebDAV won't create it for us.
+    fn lookup_parent(&mut self, path: &[String]) -> HttpResult<(i64, String)> {
+        let (name, dir_path) = match path.split_last() {
+            Some(split) => split,
+            None => return Err(Status(403)),
+        };
+
+        match self.backend.lookup_path(dir_path).map_err(Status::from) {
+            Ok(ref dir) if dir.attributes.type_ != ZipFtype::NFDIR => Err(Status(409)),
+            Ok(dir) => Ok((dir.file.fid, name.clone())),
+            Err(Status(404)) => Err(Status(409)),
+            Err(status) => Err(status),
+        }
+    }
+
+    /// Like `lookup_parent`, for things that must already exist.
+    fn lookup_existing_parent(&mut self, path: &[String]) -> HttpResult<(i64, String)> {
+        self.lookup_parent(path).map_err(|status| if status == Status(409) {
+            Status(404)
+        } else {
+            status
+        })
+    }
+
+    fn get(&mut self, req: &Request) -> HttpResult<Response> {
+        let res = self.backend.lookup_path(&req.path)?;
+
+        if res.attributes.type_ != ZipFtype::NFDIR {
+            return Ok(http::get_file(
+                req,
+                res.file.fid,
+                &res.attributes,
+                "application/octet-stream",
+            ));
+        }
+
+        // Directories get a listing for browsers
+        let mut entries = self.backend.list_dir(res.file.fid)?;
+        entries.sort_by(|a, b| a.fname.cmp(&b.fname));
+
+        let title = http::escape(&http::href(&req.path, true));
+        let mut html = format!(
+            "<!DOCTYPE html>\n<html><head><title>{0}</title></head><body>\n<h1>{0}</h1>\n<ul>\n",
+            title
+        );
+        if !req.path.is_empty() {
+            html.push_str("<li><a href=\"../\">../</a></li>\n");
+        }
+        for entry in &entries {
+            let is_dir = entry.attributes.type_ == ZipFtype::NFDIR;
+            let mut path = req.path.clone();
+            path.push(entry.fname.clone());
+            html.push_str(&format!(
+                "<li><a href=\"{}\">{}{}</a></li>\n",
+                http::escape(&http::href(&path, is_dir)),
+                http::escape(&entry.fname),
+                if is_dir { "/" } else { "" }
+            ));
+        }
+        html.push_str("</ul>\n</body></html>\n");
+
+        Ok(Response::new(200).body(
+            "text/html; charset=utf-8",
+            html.into_bytes(),
+        ))
+    }
+
+    fn put<R: BufRead>(&mut self, req: &Request, body: &mut Body<R>) -> HttpResult<Response> {
+        let (dir, name) = self.lookup_parent(&req.path)?;
+
+        // If the server restarts before the writes are committed, they may have been lost, and
+        // the client has to retry the PUT.
+        let existed = match self.backend.replace(dir, &name, body) {
+            Ok((_, existed)) => existed,
+            Err(WriteError::Read(e)) => {
+                println!("Error reading PUT body: {}", e);
+                return Err(Status(400));
+            }
+            // Somebody else created it between our remove and rename
+            Err(WriteError::Rpc(ZipError::Nfs(ZipErrorType::NFSERR_EXIST, _))) => {
+                return Err(Status(409))
+            }
+            Err(WriteError::Rpc(e)) => return Err(e.into()),
+            Err(WriteError::Restarted) => return Err(Status(503)),
+        };
+
+        Ok(Response::new(if existed { 204 } else { 201 }))
+    }
+
+    fn mkcol<R: BufRead>(&mut self, req: &Request, body: &mut Body<R>) -> HttpResult<Response> {
+        // We don't understand any MKCOL bodies
+        if body.read(&mut [0]).map_err(|_| Status(400))? != 0 {
+            return Err(Status(415));
+        }
+
+        let (dir, name) = self.lookup_parent(&req.path)?;
+        self.backend.mkdir(dir, &name)?;
+
+        Ok(Response::new(201))
+    }
+
+    fn delete(&mut self, req: &Request) -> HttpResult<Response> {
+        let (dir, name) = self.lookup_existing_parent(&req.path)?;
+        let target = self.backend.lookup(dir, &name)?;
+
+        self.backend.remove_tree(dir, &name, &target)?;
+
+        Ok(Response::new(204))
+    }
+
+    fn move_(&mut self, req: &Request) -> HttpResult<Response> {
+        let dest = http::parse_path(req.header("destination").ok_or(Status(400))?)?;
+        let overwrite = req.header("overwrite").map(|o| o.trim()) != Some("F");
+
+        // Moving something into itself can't work
+        if dest.starts_with(&req.path) {
+            return Err(Status(403));
+        }
+
+        // Make sure the source is there before touching the destination
+        let (from_dir, from_name) = self.lookup_existing_parent(&req.path)?;
+        self.backend.lookup(from_dir, &from_name)?;
+
+        let (to_dir, to_name) = self.lookup_parent(&dest)?;
+
+        // The server won't rename over an existing file, so remove it first. This means that
+        // the MOVE is not atomic.
+        let replaced = match self.backend.lookup(to_dir, &to_name).map_err(Status::from) {
+            Ok(_) if !overwrite => return Err(Status(412)),
+            Ok(target) => {
+                self.backend.remove_tree(to_dir, &to_name, &target)?;
+                true
+            }
+            Err(Status(404)) => false,
+            Err(status) => return Err(status),
+        };
+
+        let args = ZipRenameArgs::new(
+            ZipDirOpArgs::new(ZipFileHandle::new(from_dir), from_name, new_xid()),
+            ZipDirOpArgs::new(ZipFileHandle::new(to_dir), to_name, None),
+            None,
+        );
+        self.backend.change(|znfs| znfs.rename(args.clone()))?;
+
+        Ok(Response::new(if replaced { 204 } else { 201 }))
+    }
+
+    fn propfind(&mut self, req: &Request) -> HttpResult<Response> {
+        let depth = parse_depth(req.header("depth"))?;
+
+        let res = self.backend.lookup_path(&req.path)?;
+
+        let mut xml = String::from(
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:multistatus xmlns:D=\"DAV:\">\n",
+        );
+        propfind_response(&mut xml, &req.path, &res.attributes);
+
+        if depth == 1 && res.attributes.type_ == ZipFtype::NFDIR {
+            for entry in self.backend.list_dir(res.file.fid)? {
+                let mut path = req.path.clone();
+                path.push(entry.fname);
+                propfind_response(&mut xml, &path, &entry.attributes);
+            }
+        }
+
+        xml.push_str("</D:multistatus>\n");
+
+        Ok(Response::new(207).body(
+            "application/xml; charset=utf-8",
+            xml.into_bytes(),
+        ))
+    }
+}
+
+/// Parse the `Depth` header of a PROPFIND. We only do depth 0 and 1, which is all that most
+/// clients ask for; a missing header means infinity, which we refuse as RFC 4918 allows.
+fn parse_depth(depth: Option<&str>) -> HttpResult<u32> {
+    match depth.map(|d| d.trim()) {
+        Some("0") => Ok(0),
+        Some("1") => Ok(1),
+        _ => Err(Status(403)),
+    }
+}
+
+/// Add the properties of one file to a PROPFIND response.
+fn propfind_response(xml: &mut String, path: &[String], attr: &ZipFattr) {
+    let is_dir = attr.type_ == ZipFtype::NFDIR;
+
+    xml.push_str("<D:response>\n");
+    xml.push_str(&format!(
+        "<D:href>{}</D:href>\n",
+        http::escape(&http::href(path, is_dir))
+    ));
+    xml.push_str("<D:propstat>\n<D:prop>\n");
+    xml.push_str(&format!(
+        "<D:displayname>{}</D:displayname>\n",
+        http::escape(path.last().map(|s| s.as_str()).unwrap_or(""))
+    ));
+
+    if is_dir {
+        xml.push_str("<D:resourcetype><D:collection/></D:resourcetype>\n");
+    } else {
+        xml.push_str("<D:resourcetype/>\n");
+        xml.push_str(&format!(
+            "<D:getcontentlength>{}</D:getcontentlength>\n",
+            attr.size
+        ));
+        xml.push_str("<D:getcontenttype>application/octet-stream</D:getcontenttype>\n");
+        xml.push_str(&format!(
+            "<D:getetag>{}</D:getetag>\n",
+            http::escape(&http::etag(attr))
+        ));
+    }
+
+    xml.push_str(&format!(
+        "<D:getlastmodified>{}</D:getlastmodified>\n",
+        http::http_date(&attr.mtime)
+    ));
+    xml.push_str(&format!(
+        "<D:creationdate>{}</D:creationdate>\n",
+        http::iso_date(&attr.ctime)
+    ));
+    xml.push_str("</D:prop>\n<D:status>HTTP/1.1 200 OK</D:status>\n</D:propstat>\n");
+    xml.push_str("</D:response>\n");
+}
+
+/// Checks if the given string is a valid IP:port pair.
+///
+/// This is used for parsing command line args.
+fn is_addr(arg: String) -> Result<(), String> {
+    use std::net::ToSocketAddrs;
+
+    arg.to_socket_addrs()
+        .map_err(|_| "Not a valid IP:Port".to_owned())
+        .map(|_| ())
+}
+
+fn main() {
+    let matches = clap_app!{
+        zippynfs_webdav =>
+            (version: "1.0")
+            (author: "Team Chimney")
+            (about: "WebDAV gateway for ZippyNFS")
+            (@arg server: -s --server {is_addr} +required +takes_value "\"IPAddr:Port\" for server")
+            (@arg listen: -l --listen {is_addr} +required +takes_value
+                "The \"IPAddr:Port\" to serve HTTP at")
+    }.get_matches();
+
+    // Get the server address
+    let server_addr = matches.value_of("server").unwrap().to_owned();
+
+    // Get the address to listen at
+    let listen_addr = matches.value_of("listen").unwrap();
+
+    println!("Serving WebDAV at http://{}/", listen_addr);
+
+    let result = gateway::serve(listen_addr, move || {
+        Backend::connect(&server_addr).map(|backend| WebDav { backend })
+    });
+
+    if let Err(e) = result {
+        println!("Error! {}", e);
+        exit(-1);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use client::gateway::http::Status;
+
+    use super::parse_depth;
+
+    #[test]
+    fn test_parse_depth() {
+        assert_eq!(parse_depth(Some("0")), Ok(0));
+        assert_eq!(parse_depth(Some(" 1 ")), Ok(1));
+
+        for depth in &[None, Some("infinity"), Some("2"), Some("-1"), Some(""), Some("01")] {
+            assert_eq!(parse_depth(*depth), Err(Status(403)), "{:?}", depth);
+        }
+    }
+}
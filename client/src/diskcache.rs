@@ -0,0 +1,497 @@
+//! A cache of the data of files on local disk, which outlives the client.
+//!
+//! This sits under the page cache: blocks that aren't in memory are looked for here before asking
+//! the server, and blocks that come from the server are kept here as well. Like the page cache,
+//! everything is keyed by FID, which the server never reuses, and belongs to a `Version` of the
+//! file. After a remount, the blocks of a file are only used once its attributes have been
+//! fetched again and its version has turned out not to have changed.
+//!
+//! The cache directory holds
+//!
+//! - `block_size`: the size of the blocks. If it isn't ours, everything is thrown away.
+//! - `<FID>/version`: the version of the file that the blocks are of
+//! - `<FID>/<block number>`: the data of one block
+//!
+//! Everything is written to a temporary file that is then renamed into place, so a crash never
+//! leaves a torn block behind. When the blocks take up more than the cap, the least recently used
+//! ones go. Uses are only tracked in memory, so right after a remount, the blocks written longest
+//! ago go first.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use pagecache::Version;
+
+/// What is cached for one file.
+struct CachedFile {
+    version: Version,
+
+    // block number -> (size, when it was last used)
+    blocks: HashMap<u64, (u64, u64)>,
+}
+
+/// The cache of the data of all files, in one directory.
+pub struct DiskCache {
+    dir: PathBuf,
+    block_size: usize,
+
+    /// The most bytes of blocks to keep
+    capacity: u64,
+
+    /// The bytes of blocks kept now
+    used: u64,
+
+    files: HashMap<u64, CachedFile>,
+
+    /// Every cached block, least recently used first: when it was last used -> (FID, block)
+    lru: BTreeMap<u64, (u64, u64)>,
+
+    /// Counts uses of blocks, for `lru`
+    clock: u64,
+}
+
+/// Read a whole file.
+fn read_file(path: &Path) -> io::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    File::open(path)?.read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Atomically replace the file at `path` with one holding `data`.
+fn write_file(path: &Path, data: &[u8]) -> io::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+
+    {
+        let mut tmp = File::create(&tmp_path)?;
+        tmp.write_all(data)?;
+    } // File closed
+
+    fs::rename(&tmp_path, path)
+}
+
+fn encode_version(version: &Version) -> String {
+    let (secs, usecs) = version.mtime();
+    format!("{} {} {}\n", secs, usecs, version.size())
+}
+
+fn decode_version(bytes: &[u8]) -> Option<Version> {
+    let text = String::from_utf8_lossy(bytes);
+    let mut fields = text.split_whitespace().map(|n| n.parse::<i64>().ok());
+
+    match (fields.next(), fields.next(), fields.next()) {
+        (Some(Some(secs)), Some(Some(usecs)), Some(Some(size))) => {
+            Some(Version::new((secs, usecs), size as u64))
+        }
+        _ => None,
+    }
+}
+
+impl DiskCache {
+    /// Open the cache in the given directory, creating it if needed, for blocks of `block_size`
+    /// bytes, keeping up to `capacity` bytes of them.
+    pub fn open<P: AsRef<Path>>(
+        dir: P,
+        block_size: usize,
+        capacity: u64,
+    ) -> io::Result<DiskCache> {
+        let dir = dir.as_ref().to_owned();
+        fs::create_dir_all(&dir)?;
+
+        let size_path = dir.join("block_size");
+        let size = read_file(&size_path).ok().map(|bytes| {
+            String::from_utf8_lossy(&bytes).trim().to_owned()
+        });
+        match size {
+            Some(ref size) if *size == block_size.to_string() => {}
+            Some(_) => {
+                // Blocks of another size are no use to us
+                fs::remove_dir_all(&dir)?;
+                fs::create_dir_all(&dir)?;
+                write_file(&size_path, format!("{}\n", block_size).as_bytes())?;
+            }
+            None => {
+                // Never throw away anything that isn't ours
+                if fs::read_dir(&dir)?.next().is_some() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AlreadyExists,
+                        format!("{} is not empty, and not a cache", dir.display()),
+                    ));
+                }
+                write_file(&size_path, format!("{}\n", block_size).as_bytes())?;
+            }
+        }
+
+        let mut cache = DiskCache {
+            dir,
+            block_size,
+            capacity,
+            used: 0,
+            files: HashMap::new(),
+            lru: BTreeMap::new(),
+            clock: 0,
+        };
+
+        // Find out what we already have
+        let mut found = Vec::new();
+        for entry in fs::read_dir(&cache.dir)? {
+            let entry = entry?;
+            let fid = match entry.file_name().to_str().and_then(|name| name.parse().ok()) {
+                Some(fid) => fid,
+                None => continue,
+            };
+
+            let path = entry.path();
+            let version = read_file(&path.join("version")).ok().and_then(
+                |bytes| decode_version(&bytes),
+            );
+            let version = match version {
+                Some(version) => version,
+                None => {
+                    // There's no telling what the blocks are of
+                    fs::remove_dir_all(&path)?;
+                    continue;
+                }
+            };
+
+            for entry in fs::read_dir(&path)? {
+                let entry = entry?;
+                let name = entry.file_name();
+                match name.to_str().and_then(|name| name.parse::<u64>().ok()) {
+                    Some(block) => {
+                        let metadata = entry.metadata()?;
+                        found.push((metadata.modified()?, fid, block, metadata.len()));
+                    }
+                    None if name.to_str() != Some("version") => fs::remove_file(entry.path())?,
+                    None => {}
+                }
+            }
+
+            cache.files.insert(
+                fid,
+                CachedFile {
+                    version,
+                    blocks: HashMap::new(),
+                },
+            );
+        }
+
+        // The blocks written longest ago are the first to go
+        found.sort();
+        for (_, fid, block, size) in found {
+            cache.note(fid, block, size);
+        }
+        cache.make_room();
+
+        println!(
+            "Disk cache: {} B in {} blocks of {} files",
+            cache.used,
+            cache.lru.len(),
+            cache.files.len()
+        );
+
+        Ok(cache)
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn file_dir(&self, fid: u64) -> PathBuf {
+        self.dir.join(fid.to_string())
+    }
+
+    fn block_path(&self, fid: u64, block: u64) -> PathBuf {
+        self.file_dir(fid).join(block.to_string())
+    }
+
+    /// Note that we have the given block of a file we know of, and that it was just used.
+    fn note(&mut self, fid: u64, block: u64, size: u64) {
+        self.clock += 1;
+        let now = self.clock;
+
+        if let Some(file) = self.files.get_mut(&fid) {
+            if let Some((old_size, last_used)) = file.blocks.insert(block, (size, now)) {
+                self.lru.remove(&last_used);
+                self.used -= old_size;
+            }
+            self.lru.insert(now, (fid, block));
+            self.used += size;
+        }
+    }
+
+    /// Throw away the least recently used blocks until we are under the cap.
+    fn make_room(&mut self) {
+        while self.used > self.capacity {
+            let (&last_used, &(fid, block)) = self.lru.iter().next().unwrap();
+            self.lru.remove(&last_used);
+
+            if let Some(file) = self.files.get_mut(&fid) {
+                if let Some((size, _)) = file.blocks.remove(&block) {
+                    self.used -= size;
+                }
+            }
+
+            // If this fails, the block is only forgotten until the next remount
+            let _ = fs::remove_file(self.block_path(fid, block));
+        }
+    }
+
+    /// Check what we have of a file against its current version, throwing it all away if it has
+    /// changed.
+    pub fn revalidate(&mut self, fid: u64, version: Version) -> io::Result<()> {
+        let stale = match self.files.get(&fid) {
+            Some(file) => file.version != version,
+            None => false,
+        };
+
+        if stale {
+            self.forget(fid)?;
+        }
+        Ok(())
+    }
+
+    /// Take the given block of the given version of the file, if we have it.
+    pub fn get(
+        &mut self,
+        fid: u64,
+        version: Version,
+        block: u64,
+    ) -> io::Result<Option<Vec<u8>>> {
+        let size = match self.files.get(&fid) {
+            Some(file) if file.version == version => {
+                match file.blocks.get(&block) {
+                    Some(&(size, _)) => size,
+                    None => return Ok(None),
+                }
+            }
+            _ => return Ok(None),
+        };
+
+        let data = read_file(&self.block_path(fid, block))?;
+        if data.len() as u64 != size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Block {} of file {} changed size", block, fid),
+            ));
+        }
+
+        self.note(fid, block, size);
+        Ok(Some(data))
+    }
+
+    /// Keep a block of the given version of the file.
+    pub fn insert(
+        &mut self,
+        fid: u64,
+        version: Version,
+        block: u64,
+        data: &[u8],
+    ) -> io::Result<()> {
+        if data.len() as u64 > self.capacity {
+            return Ok(());
+        }
+
+        // The blocks of another version are no good any more
+        self.revalidate(fid, version)?;
+        if !self.files.contains_key(&fid) {
+            let file_dir = self.file_dir(fid);
+            fs::create_dir_all(&file_dir)?;
+            write_file(&file_dir.join("version"), encode_version(&version).as_bytes())?;
+
+            self.files.insert(
+                fid,
+                CachedFile {
+                    version,
+                    blocks: HashMap::new(),
+                },
+            );
+        }
+
+        write_file(&self.block_path(fid, block), data)?;
+        self.note(fid, block, data.len() as u64);
+        self.make_room();
+
+        Ok(())
+    }
+
+    /// Forget everything about the file, e.g. because we changed it.
+    pub fn forget(&mut self, fid: u64) -> io::Result<()> {
+        if let Some(file) = self.files.remove(&fid) {
+            for (_, &(size, last_used)) in file.blocks.iter() {
+                self.lru.remove(&last_used);
+                self.used -= size;
+            }
+            fs::remove_dir_all(self.file_dir(fid))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io;
+    use std::path::{Path, PathBuf};
+
+    use rand;
+
+    use pagecache::Version;
+
+    use super::DiskCache;
+
+    /// A directory to keep a cache in that doesn't exist yet. The caller should remove it.
+    fn temp_dir(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("zippy-diskcache-{}-{:x}", name, rand::random::<u64>()))
+    }
+
+    /// Whether the given block is on disk.
+    fn on_disk(dir: &Path, fid: u64, block: u64) -> bool {
+        dir.join(fid.to_string()).join(block.to_string()).exists()
+    }
+
+    #[test]
+    fn test_make_room() {
+        let dir = temp_dir("make-room");
+        let v = Version::new((1, 0), 100);
+
+        {
+            // Room for 8B
+            let mut cache = DiskCache::open(&dir, 4, 8).unwrap();
+
+            cache.insert(1, v, 0, &[0; 4]).unwrap();
+            cache.insert(1, v, 1, &[1; 4]).unwrap();
+            assert_eq!(cache.used, 8);
+
+            // Using block 0 makes block 1 the least recently used, so it goes first
+            assert_eq!(cache.get(1, v, 0).unwrap(), Some(vec![0; 4]));
+            cache.insert(2, v, 0, &[2; 4]).unwrap();
+            assert_eq!(cache.used, 8);
+            assert_eq!(cache.lru.len(), 2);
+            assert_eq!(cache.get(1, v, 1).unwrap(), None);
+            assert!(!on_disk(&dir, 1, 1));
+            assert!(on_disk(&dir, 1, 0));
+            assert!(on_disk(&dir, 2, 0));
+
+            // Replacing a block only counts its new size
+            cache.insert(2, v, 0, &[3; 2]).unwrap();
+            assert_eq!(cache.used, 6);
+            assert_eq!(cache.get(2, v, 0).unwrap(), Some(vec![3; 2]));
+
+            // A block bigger than the whole cache isn't kept at all
+            cache.insert(3, v, 0, &[4; 9]).unwrap();
+            assert_eq!(cache.used, 6);
+            assert!(!dir.join("3").exists());
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_revalidate() {
+        let dir = temp_dir("revalidate");
+        let v1 = Version::new((1, 0), 100);
+        let v2 = Version::new((2, 0), 100);
+
+        {
+            let mut cache = DiskCache::open(&dir, 4, 64).unwrap();
+
+            cache.insert(1, v1, 0, &[0; 4]).unwrap();
+            cache.insert(2, v1, 0, &[1; 4]).unwrap();
+
+            // Asking for another version finds nothing, but doesn't throw anything away
+            assert_eq!(cache.get(1, v2, 0).unwrap(), None);
+            assert_eq!(cache.get(1, v1, 0).unwrap(), Some(vec![0; 4]));
+
+            // Neither does checking against the same version
+            cache.revalidate(1, v1).unwrap();
+            assert_eq!(cache.used, 8);
+
+            // But once the file has changed, everything cached for it goes, and nothing else
+            cache.revalidate(1, v2).unwrap();
+            assert_eq!(cache.used, 4);
+            assert_eq!(cache.lru.len(), 1);
+            assert!(!dir.join("1").exists());
+            assert_eq!(cache.get(2, v1, 0).unwrap(), Some(vec![1; 4]));
+
+            // Keeping a block of a new version throws away the blocks of the old one
+            cache.insert(2, v1, 1, &[2; 4]).unwrap();
+            cache.insert(2, v2, 1, &[3; 4]).unwrap();
+            assert_eq!(cache.used, 4);
+            assert_eq!(cache.get(2, v2, 0).unwrap(), None);
+            assert_eq!(cache.get(2, v2, 1).unwrap(), Some(vec![3; 4]));
+            assert!(!on_disk(&dir, 2, 0));
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rescan_on_open() {
+        let dir = temp_dir("rescan");
+        let v = Version::new((1, 0), 100);
+
+        {
+            let mut cache = DiskCache::open(&dir, 4, 64).unwrap();
+            cache.insert(1, v, 0, &[0; 4]).unwrap();
+            cache.insert(1, v, 1, &[1; 3]).unwrap();
+            cache.insert(2, v, 0, &[2; 4]).unwrap();
+        }
+
+        // Leave behind a stray file, and blocks with no version
+        File::create(dir.join("1").join("junk")).unwrap();
+        fs::create_dir(dir.join("3")).unwrap();
+        File::create(dir.join("3").join("0")).unwrap();
+
+        // After a remount, the blocks are all found again, and the rest thrown away
+        {
+            let mut cache = DiskCache::open(&dir, 4, 64).unwrap();
+            assert_eq!(cache.used, 11);
+            assert_eq!(cache.lru.len(), 3);
+            assert!(!dir.join("1").join("junk").exists());
+            assert!(!dir.join("3").exists());
+
+            assert_eq!(cache.get(1, v, 1).unwrap(), Some(vec![1; 3]));
+            assert_eq!(cache.get(2, Version::new((2, 0), 100), 0).unwrap(), None);
+        }
+
+        // With less room, blocks are thrown away until what is left fits
+        {
+            let cache = DiskCache::open(&dir, 4, 8).unwrap();
+            assert!(cache.used <= 8);
+            assert_eq!(cache.lru.len(), 2);
+
+            let left = [(1, 0), (1, 1), (2, 0)]
+                .iter()
+                .filter(|&&(fid, block)| on_disk(&dir, fid, block))
+                .count();
+            assert_eq!(left, 2);
+        }
+
+        // Blocks of another size are no use, so everything goes
+        {
+            let cache = DiskCache::open(&dir, 8, 64).unwrap();
+            assert_eq!(cache.used, 0);
+            assert!(cache.files.is_empty());
+            assert!(!dir.join("1").exists());
+            assert!(!dir.join("2").exists());
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+
+        // A directory with something else in it is never taken for a cache
+        let other = temp_dir("other");
+        fs::create_dir(&other).unwrap();
+        File::create(other.join("precious")).unwrap();
+        match DiskCache::open(&other, 4, 64) {
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+            _ => assert!(false),
+        }
+        assert!(other.join("precious").exists());
+
+        fs::remove_dir_all(&other).unwrap();
+    }
+}
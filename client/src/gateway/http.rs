@@ -0,0 +1,684 @@
+//! Just enough HTTP/1.1 for the gateways: parsing requests (including chunked bodies) and
+//! building responses.
+
+use std::cmp::min;
+use std::io::{self, BufRead, Read};
+
+use time::{self, Timespec};
+
+use zippyrpc::*;
+
+/// The longest request line or header line we accept
+const MAX_LINE_LEN: usize = 8 << 10;
+
+/// The most headers we accept in one request
+const MAX_HEADERS: usize = 100;
+
+/// An HTTP status, used as the error of anything that fails.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Status(pub u16);
+
+impl Status {
+    pub fn reason(&self) -> &'static str {
+        match self.0 {
+            200 => "OK",
+            201 => "Created",
+            204 => "No Content",
+            206 => "Partial Content",
+            207 => "Multi-Status",
+            400 => "Bad Request",
+            403 => "Forbidden",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            409 => "Conflict",
+            411 => "Length Required",
+            412 => "Precondition Failed",
+            414 => "URI Too Long",
+            415 => "Unsupported Media Type",
+            416 => "Range Not Satisfiable",
+            500 => "Internal Server Error",
+            501 => "Not Implemented",
+            502 => "Bad Gateway",
+            503 => "Service Unavailable",
+            _ => "Unknown",
+        }
+    }
+}
+
+/// The obvious mapping of errors from the server onto statuses. Errors that aren't the client's
+/// fault are "Bad Gateway".
+impl From<ZipError> for Status {
+    fn from(e: ZipError) -> Status {
+        match e {
+            ZipError::Nfs(ZipErrorType::NFSERR_NOENT, _) |
+            ZipError::Nfs(ZipErrorType::NFSERR_STALE, _) => Status(404),
+            ZipError::Nfs(ZipErrorType::NFSERR_EXIST, _) |
+            ZipError::Nfs(ZipErrorType::NFSERR_ISDIR, _) => Status(405),
+            ZipError::Nfs(ZipErrorType::NFSERR_NOTDIR, _) |
+            ZipError::Nfs(ZipErrorType::NFSERR_NOTEMPTY, _) => Status(409),
+            ZipError::Nfs(ZipErrorType::NFSERR_NAMETOOLONG, _) => Status(414),
+            ZipError::Nfs(ZipErrorType::NFSERR_INVAL, _) => Status(400),
+            e => {
+                println!("Error from the server: {:?}", e);
+                Status(502)
+            }
+        }
+    }
+}
+
+impl From<::thrift::Error> for Status {
+    fn from(e: ::thrift::Error) -> Status {
+        ZipError::from(e).into()
+    }
+}
+
+pub type HttpResult<T> = Result<T, Status>;
+
+/// A parsed HTTP request, without its body.
+pub struct Request {
+    pub method: String,
+
+    /// The decoded components of the path
+    pub path: Vec<String>,
+
+    /// The path ended with a slash
+    pub trailing_slash: bool,
+
+    /// The decoded query parameters, in order
+    pub query: Vec<(String, String)>,
+
+    /// Header names are lowercase
+    pub headers: Vec<(String, String)>,
+
+    /// HTTP/1.1 or later
+    pub http11: bool,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|&&(ref n, _)| n == name)
+            .map(|&(_, ref v)| v.as_str())
+    }
+
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .find(|&&(ref n, _)| n == name)
+            .map(|&(_, ref v)| v.as_str())
+    }
+}
+
+/// What to send as the body of a response.
+pub enum Payload {
+    Bytes(Vec<u8>),
+
+    /// `len` bytes of the file with the given FID, starting at `offset`
+    File { fid: i64, offset: u64, len: u64 },
+}
+
+/// A response, waiting to be sent.
+pub struct Response {
+    pub status: Status,
+    pub headers: Vec<(&'static str, String)>,
+    pub payload: Payload,
+}
+
+impl Response {
+    pub fn new(status: u16) -> Response {
+        Response {
+            status: Status(status),
+            headers: vec![],
+            payload: Payload::Bytes(vec![]),
+        }
+    }
+
+    pub fn header(mut self, name: &'static str, value: String) -> Response {
+        self.headers.push((name, value));
+        self
+    }
+
+    pub fn body(mut self, content_type: &str, body: Vec<u8>) -> Response {
+        self.headers.push(("Content-Type", content_type.to_owned()));
+        self.payload = Payload::Bytes(body);
+        self
+    }
+}
+
+/// Reads the body of a request, undoing any chunked transfer encoding.
+pub struct Body<'a, R: 'a> {
+    stream: &'a mut R,
+    chunked: bool,
+
+    /// Bytes left in the body (or in the current chunk)
+    remaining: u64,
+
+    /// We have seen the last chunk
+    done: bool,
+}
+
+impl<'a, R: BufRead> Body<'a, R> {
+    pub fn new(stream: &'a mut R, req: &Request) -> HttpResult<Body<'a, R>> {
+        let chunked = req.header("transfer-encoding")
+            .map(|te| te.to_lowercase().contains("chunked"))
+            .unwrap_or(false);
+
+        if chunked {
+            return Ok(Body::chunked(stream));
+        }
+
+        let remaining = match req.header("content-length") {
+            Some(len) => len.trim().parse().map_err(|_| Status(400))?,
+            None => 0,
+        };
+
+        Ok(Body {
+            stream,
+            chunked,
+            remaining,
+            done: remaining == 0,
+        })
+    }
+
+    /// Decode chunks from the given stream, which may itself be a body (e.g. for S3's
+    /// `aws-chunked` encoding, which is chunks inside the body).
+    pub fn chunked(stream: &'a mut R) -> Body<'a, R> {
+        Body {
+            stream,
+            chunked: true,
+            remaining: 0,
+            done: false,
+        }
+    }
+
+    /// Read and throw away the rest of the body.
+    pub fn drain(&mut self) -> io::Result<()> {
+        io::copy(self, &mut io::sink()).map(|_| ())
+    }
+}
+
+impl<'a, R: BufRead> Read for Body<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+
+        // Start the next chunk: `<hex size>[;extensions]\r\n`. The last chunk is empty, and is
+        // followed by trailers we don't care about.
+        if self.chunked && self.remaining == 0 {
+            let line = read_line(self.stream)?;
+            let size = line.split(';').next().unwrap().trim();
+            self.remaining = u64::from_str_radix(size, 16).map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidData, "bad chunk size")
+            })?;
+
+            if self.remaining == 0 {
+                while !read_line(self.stream)?.is_empty() {}
+                self.done = true;
+                return Ok(0);
+            }
+        }
+
+        let len = min(buf.len() as u64, self.remaining) as usize;
+        let n = self.stream.read(&mut buf[..len])?;
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "client hung up in the middle of a body",
+            ));
+        }
+
+        self.remaining -= n as u64;
+        if self.remaining == 0 {
+            if self.chunked {
+                // Each chunk ends with a CRLF
+                read_line(self.stream)?;
+            } else {
+                self.done = true;
+            }
+        }
+
+        Ok(n)
+    }
+}
+
+/// Read a line ending in CRLF (or just LF), and return it without the line ending.
+fn read_line<R: BufRead>(stream: &mut R) -> io::Result<String> {
+    let mut line = Vec::new();
+    (&mut *stream).take(MAX_LINE_LEN as u64).read_until(
+        b'\n',
+        &mut line,
+    )?;
+
+    if line.last() != Some(&b'\n') {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "line too long, or client hung up",
+        ));
+    }
+
+    while line.last() == Some(&b'\n') || line.last() == Some(&b'\r') {
+        line.pop();
+    }
+
+    String::from_utf8(line).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Read the request line and headers of the next request. Returns `None` if the client hung up
+/// between requests.
+pub fn read_request<R: BufRead>(stream: &mut R) -> io::Result<Option<HttpResult<Request>>> {
+    // Clients may send empty lines between requests
+    let mut line = String::new();
+    while line.is_empty() {
+        if stream.fill_buf()?.is_empty() {
+            return Ok(None);
+        }
+        line = read_line(stream)?;
+    }
+
+    let mut headers = Vec::new();
+    loop {
+        let header = read_line(stream)?;
+        if header.is_empty() {
+            break;
+        }
+
+        if headers.len() == MAX_HEADERS {
+            return Ok(Some(Err(Status(400))));
+        }
+
+        if let Some(colon) = header.find(':') {
+            headers.push((
+                header[..colon].trim().to_lowercase(),
+                header[colon + 1..].trim().to_owned(),
+            ));
+        }
+    }
+
+    let parts: Vec<&str> = line.split_whitespace().collect();
+    if parts.len() != 3 {
+        return Ok(Some(Err(Status(400))));
+    }
+
+    let (path, query) = split_target(parts[1]);
+    let parsed = parse_path(path).and_then(|components| {
+        parse_query(query.unwrap_or("")).map(|query| (components, query))
+    });
+    let (components, query) = match parsed {
+        Ok(parsed) => parsed,
+        Err(status) => return Ok(Some(Err(status))),
+    };
+
+    Ok(Some(Ok(Request {
+        method: parts[0].to_owned(),
+        path: components,
+        trailing_slash: path.len() > 1 && path.ends_with('/'),
+        query,
+        headers,
+        http11: parts[2] != "HTTP/1.0",
+    })))
+}
+
+/// Split a request target (a URL, or just its path) into its path and query.
+fn split_target(url: &str) -> (&str, Option<&str>) {
+    // Drop the scheme and host, if there are any
+    let path = match url.find("://") {
+        Some(scheme_end) => {
+            let rest = &url[scheme_end + 3..];
+            &rest[rest.find('/').unwrap_or(rest.len())..]
+        }
+        None => url,
+    };
+
+    match path.find('?') {
+        Some(q) => (&path[..q], Some(&path[q + 1..])),
+        None => (path, None),
+    }
+}
+
+/// Parse a URL (or just its path) into decoded path components.
+pub fn parse_path(url: &str) -> HttpResult<Vec<String>> {
+    let (path, _) = split_target(url);
+
+    let mut components = Vec::new();
+    for component in path.split('/').filter(|c| !c.is_empty()) {
+        let component = percent_decode(component)?;
+        if component == "." || component == ".." {
+            return Err(Status(400));
+        }
+        components.push(component);
+    }
+
+    Ok(components)
+}
+
+/// Parse a query string into decoded names and values.
+fn parse_query(query: &str) -> HttpResult<Vec<(String, String)>> {
+    query
+        .split('&')
+        .filter(|param| !param.is_empty())
+        .map(|param| {
+            let mut parts = param.splitn(2, '=');
+            let name = parts.next().unwrap().replace('+', " ");
+            let value = parts.next().unwrap_or("").replace('+', " ");
+            Ok((percent_decode(&name)?, percent_decode(&value)?))
+        })
+        .collect()
+}
+
+pub fn percent_decode(s: &str) -> HttpResult<String> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = s.get(i + 1..i + 3).ok_or(Status(400))?;
+
+            // `from_str_radix` would take a sign, e.g. "%+1"
+            if !hex.chars().all(|c| c.is_digit(16)) {
+                return Err(Status(400));
+            }
+            decoded.push(u8::from_str_radix(hex, 16).map_err(|_| Status(400))?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    String::from_utf8(decoded).map_err(|_| Status(400))
+}
+
+/// Encode a string for use in a URL. Slashes are left alone if `keep_slashes` is set.
+pub fn percent_encode(s: &str, keep_slashes: bool) -> String {
+    let mut encoded = String::new();
+    for &b in s.as_bytes() {
+        match b {
+            b'A'...b'Z' | b'a'...b'z' | b'0'...b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(b as char)
+            }
+            b'/' if keep_slashes => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+/// Encode a path for use in a URL.
+pub fn href(path: &[String], is_dir: bool) -> String {
+    let mut href = String::new();
+    for component in path {
+        href.push('/');
+        href.push_str(&percent_encode(component, false));
+    }
+
+    if is_dir || path.is_empty() {
+        href.push('/');
+    }
+    href
+}
+
+/// Escape text for use in XML or HTML.
+pub fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// Format a time for an HTTP header (RFC 1123).
+pub fn http_date(t: &ZipTimeVal) -> String {
+    format!("{}", time::at_utc(Timespec::new(t.seconds, 0)).rfc822())
+}
+
+/// Format a time as ISO 8601, as used in XML.
+pub fn iso_date(t: &ZipTimeVal) -> String {
+    format!("{}", time::at_utc(Timespec::new(t.seconds, 0)).rfc3339())
+}
+
+/// An entity tag that changes whenever the file does.
+pub fn etag(attr: &ZipFattr) -> String {
+    format!(
+        "\"{:x}-{:x}-{:x}\"",
+        attr.fid,
+        attr.mtime.seconds * 1_000_000 + attr.mtime.useconds,
+        attr.size
+    )
+}
+
+/// Parse a `Range` header into the `(offset, len)` to send of a file of `size` bytes. Returns
+/// `Ok(None)` if the whole file should be sent, which is also what we do for anything we don't
+/// understand (e.g. multiple ranges).
+pub fn parse_range(range: &str, size: u64) -> HttpResult<Option<(u64, u64)>> {
+    let mut parts = range.trim().splitn(2, '=');
+    let spec = match (parts.next(), parts.next()) {
+        (Some(unit), Some(spec)) if unit.trim() == "bytes" && !spec.contains(',') => spec.trim(),
+        _ => return Ok(None),
+    };
+
+    let dash = match spec.find('-') {
+        Some(dash) => dash,
+        None => return Ok(None),
+    };
+    let (first, last) = (&spec[..dash], &spec[dash + 1..]);
+
+    let (start, end) = if first.is_empty() {
+        // The last N bytes
+        let suffix: u64 = match last.parse() {
+            Ok(suffix) => suffix,
+            Err(_) => return Ok(None),
+        };
+        if suffix == 0 {
+            return Err(Status(416));
+        }
+        (size.saturating_sub(suffix), size)
+    } else {
+        let start: u64 = match first.parse() {
+            Ok(start) => start,
+            Err(_) => return Ok(None),
+        };
+        let end = if last.is_empty() {
+            size
+        } else {
+            match last.parse::<u64>() {
+                Ok(last) if last >= start => min(last + 1, size),
+                _ => return Ok(None),
+            }
+        };
+        (start, end)
+    };
+
+    if start >= size {
+        return Err(Status(416));
+    }
+
+    Ok(Some((start, end - start)))
+}
+
+/// Respond to a GET of a file, honouring any `Range` header.
+pub fn get_file(req: &Request, fid: i64, attr: &ZipFattr, content_type: &str) -> Response {
+    let size = attr.size as u64;
+    let response = Response::new(200)
+        .header("Accept-Ranges", "bytes".to_owned())
+        .header("ETag", etag(attr))
+        .header("Last-Modified", http_date(&attr.mtime))
+        .header("Content-Type", content_type.to_owned());
+
+    let range = match req.header("range").map(|range| parse_range(range, size)) {
+        Some(Ok(range)) => range,
+        Some(Err(status)) => {
+            return Response::new(status.0).header("Content-Range", format!("bytes */{}", size))
+        }
+        None => None,
+    };
+
+    let (mut response, offset, len) = match range {
+        Some((offset, len)) => {
+            let mut response = response.header(
+                "Content-Range",
+                format!("bytes {}-{}/{}", offset, offset + len - 1, size),
+            );
+            response.status = Status(206);
+            (response, offset, len)
+        }
+        None => (response, 0, size),
+    };
+
+    response.payload = Payload::File { fid, offset, len };
+    response
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, Cursor, Read};
+
+    use super::{parse_range, percent_decode, Body, Request, Status};
+
+    /// A request with the given headers.
+    fn request(headers: &[(&str, &str)]) -> Request {
+        Request {
+            method: "PUT".to_owned(),
+            path: vec![],
+            trailing_slash: false,
+            query: vec![],
+            headers: headers
+                .iter()
+                .map(|&(n, v)| (n.to_owned(), v.to_owned()))
+                .collect(),
+            http11: true,
+        }
+    }
+
+    /// Read the whole body, in small pieces to cross chunk boundaries.
+    fn read_body<R: Read>(body: &mut R) -> io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        let mut buf = [0; 3];
+        loop {
+            match body.read(&mut buf)? {
+                0 => return Ok(data),
+                n => data.extend_from_slice(&buf[..n]),
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Ok(Some((0, 100))));
+        assert_eq!(parse_range(" bytes = 10-19 ", 1000), Ok(Some((10, 10))));
+        assert_eq!(parse_range("bytes=500-", 1000), Ok(Some((500, 500))));
+        assert_eq!(parse_range("bytes=-100", 1000), Ok(Some((900, 100))));
+
+        // Ranges past the end are cut short
+        assert_eq!(parse_range("bytes=990-2000", 1000), Ok(Some((990, 10))));
+        assert_eq!(parse_range("bytes=-2000", 1000), Ok(Some((0, 1000))));
+
+        // Nothing to send
+        assert_eq!(parse_range("bytes=1000-", 1000), Err(Status(416)));
+        assert_eq!(parse_range("bytes=1000-1001", 1000), Err(Status(416)));
+        assert_eq!(parse_range("bytes=0-", 0), Err(Status(416)));
+        assert_eq!(parse_range("bytes=-0", 1000), Err(Status(416)));
+
+        // Anything we don't understand gets the whole file
+        assert_eq!(parse_range("bytes=0-1,5-6", 1000), Ok(None));
+        assert_eq!(parse_range("items=0-1", 1000), Ok(None));
+        assert_eq!(parse_range("bytes", 1000), Ok(None));
+        assert_eq!(parse_range("bytes=12", 1000), Ok(None));
+        assert_eq!(parse_range("bytes=x-5", 1000), Ok(None));
+        assert_eq!(parse_range("bytes=0-y", 1000), Ok(None));
+        assert_eq!(parse_range("bytes=-z", 1000), Ok(None));
+        assert_eq!(parse_range("bytes=5-2", 1000), Ok(None));
+        assert_eq!(parse_range("bytes=-1-2", 1000), Ok(None));
+    }
+
+    #[test]
+    fn test_chunked_body() {
+        let mut stream = Cursor::new(
+            &b"5\r\nhello\r\n6;name=value\r\n world\r\n0\r\nTrailer: x\r\n\r\nNEXT"[..],
+        );
+
+        {
+            let req = request(&[("transfer-encoding", "gzip, Chunked")]);
+            let mut body = Body::new(&mut stream, &req).unwrap();
+            assert_eq!(read_body(&mut body).unwrap(), b"hello world");
+
+            // It stays finished
+            assert_eq!(body.read(&mut [0; 4]).unwrap(), 0);
+        }
+
+        // The trailers are read, and nothing after them
+        let mut rest = String::new();
+        stream.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "NEXT");
+
+        // Bare LFs will do too
+        let mut stream = Cursor::new(&b"3\nabc\n0\n\n"[..]);
+        assert_eq!(read_body(&mut Body::chunked(&mut stream)).unwrap(), b"abc");
+    }
+
+    #[test]
+    fn test_bad_chunked_body() {
+        let mut stream = Cursor::new(&b"zz\r\nhello\r\n0\r\n\r\n"[..]);
+        let e = read_body(&mut Body::chunked(&mut stream)).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+
+        // Chunk sizes are unsigned
+        let mut stream = Cursor::new(&b"-1\r\nhello\r\n0\r\n\r\n"[..]);
+        let e = read_body(&mut Body::chunked(&mut stream)).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+
+        // The client hung up in a chunk, or before the last one
+        let mut stream = Cursor::new(&b"5\r\nhel"[..]);
+        let e = read_body(&mut Body::chunked(&mut stream)).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+
+        let mut stream = Cursor::new(&b"5\r\nhello\r\n"[..]);
+        let e = read_body(&mut Body::chunked(&mut stream)).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_sized_body() {
+        let mut stream = Cursor::new(&b"abcdef"[..]);
+        {
+            let req = request(&[("content-length", " 3 ")]);
+            let mut body = Body::new(&mut stream, &req).unwrap();
+            assert_eq!(read_body(&mut body).unwrap(), b"abc");
+        }
+        assert_eq!(stream.position(), 3);
+
+        // No length means no body
+        let mut body = Body::new(&mut stream, &request(&[])).unwrap();
+        assert_eq!(read_body(&mut body).unwrap(), b"");
+
+        // Longer than what the client sends
+        let mut stream = Cursor::new(&b"abc"[..]);
+        let req = request(&[("content-length", "4")]);
+        let e = read_body(&mut Body::new(&mut stream, &req).unwrap()).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+
+        for len in &["x", "-1", "18446744073709551616"] {
+            let req = request(&[("content-length", *len)]);
+            match Body::new(&mut stream, &req) {
+                Err(status) => assert_eq!(status, Status(400)),
+                Ok(_) => panic!("Content-Length {} was accepted", len),
+            }
+        }
+    }
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!(percent_decode("plain"), Ok("plain".to_owned()));
+        assert_eq!(percent_decode("a%20b+c"), Ok("a b+c".to_owned()));
+        assert_eq!(percent_decode("%2f%2F"), Ok("//".to_owned()));
+        assert_eq!(percent_decode("%E2%9C%93"), Ok("\u{2713}".to_owned()));
+        assert_eq!(percent_decode(""), Ok("".to_owned()));
+
+        // Cut short, not hex, or not UTF-8
+        for bad in &["%", "a%2", "%zz", "%+1", "%-1", "%\u{e9}0", "%FF", "%E2%9C"] {
+            assert_eq!(percent_decode(bad), Err(Status(400)), "{}", bad);
+        }
+    }
+}
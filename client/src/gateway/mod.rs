@@ -0,0 +1,540 @@
+//! Common code for the HTTP gateways (WebDAV and S3), which serve the FS to clients that can't
+//! mount it by translating their requests into RPCs.
+//!
+//! Each HTTP connection gets its own connection to the server, and the requests on it are
+//! handled one at a time.
+
+pub mod http;
+
+use std::cmp::min;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+
+use rand;
+use thrift;
+
+use zippyrpc::*;
+
+use {connect, new_xid, ZnfsClient};
+
+use self::http::{Body, HttpResult, Payload, Request, Response};
+
+/// The FID of the root of the FS
+pub const ROOT_FID: i64 = 1;
+
+/// How many times to send a request that changes the FS before giving up on a broken connection
+const CHANGE_TRIES: usize = 3;
+
+/// Something that serves HTTP requests from the FS.
+pub trait Gateway {
+    /// The connection to the server, which is also used to send the contents of files.
+    fn backend(&mut self) -> &mut Backend;
+
+    /// Handle one request. The body may be left unread.
+    fn handle<R: BufRead>(&mut self, req: &Request, body: &mut Body<R>) -> HttpResult<Response>;
+}
+
+/// Why writing to a file failed.
+#[derive(Debug)]
+pub enum WriteError {
+    /// Reading the data to write failed
+    Read(io::Error),
+
+    /// An RPC failed
+    Rpc(ZipError),
+
+    /// The server restarted before the writes were committed, so they may have been lost.
+    Restarted,
+}
+
+impl From<thrift::Error> for WriteError {
+    fn from(e: thrift::Error) -> WriteError {
+        WriteError::Rpc(e.into())
+    }
+}
+
+/// A connection to the server, with helpers for the operations the gateways have in common.
+pub struct Backend {
+    pub znfs: ZnfsClient,
+
+    /// The server, for reconnecting
+    server_addr: String,
+
+    /// The most data to ask for in one READ
+    pub rsize: usize,
+
+    /// The most data to send in one WRITE
+    pub wsize: usize,
+
+    /// Whether the server buffers UNSTABLE writes
+    pub unstable: bool,
+}
+
+impl Backend {
+    pub fn connect(server_addr: &str) -> thrift::Result<Backend> {
+        let (mut znfs, hello) = connect(server_addr)?;
+        let fsinfo = znfs.fsinfo(ZipFileHandle::new(ROOT_FID))?;
+
+        Ok(Backend {
+            znfs,
+            server_addr: server_addr.to_owned(),
+            rsize: min(fsinfo.rtmax as usize, MAX_IO_LEN),
+            wsize: min(fsinfo.wtmax as usize, MAX_IO_LEN),
+            unstable: hello.capabilities & CAP_UNSTABLE_WRITES != 0,
+        })
+    }
+
+    /// Send a request that changes the FS (i.e. CREATE, MKDIR, REMOVE, RMDIR or RENAME). If the
+    /// connection breaks, we reconnect and send it again, so its arguments must carry an XID from
+    /// `new_xid`, made once for all of the tries, so that the server only does it once.
+    pub fn change<T, F>(&mut self, mut rpc: F) -> thrift::Result<T>
+    where
+        F: FnMut(&mut ZnfsClient) -> thrift::Result<T>,
+    {
+        let mut tries = 1;
+        loop {
+            match rpc(&mut self.znfs) {
+                Err(thrift::Error::Transport(ref e)) if tries < CHANGE_TRIES => {
+                    println!("Resending after error: {:?}", e);
+                }
+                Err(thrift::Error::Protocol(ref e)) if tries < CHANGE_TRIES => {
+                    println!("Resending after error: {:?}", e);
+                }
+                result => return result,
+            }
+
+            // The server may have been swapped for a different build in the meantime
+            let (znfs, hello) = connect(&self.server_addr)?;
+            self.znfs = znfs;
+            self.unstable = hello.capabilities & CAP_UNSTABLE_WRITES != 0;
+            tries += 1;
+        }
+    }
+
+    pub fn lookup(&mut self, dir: i64, name: &str) -> thrift::Result<ZipDirOpRes> {
+        self.znfs.lookup(ZipDirOpArgs::new(
+            ZipFileHandle::new(dir),
+            name.to_owned(),
+            None,
+        ))
+    }
+
+    /// Find the file or directory at the given path.
+    pub fn lookup_path(&mut self, path: &[String]) -> thrift::Result<ZipDirOpRes> {
+        let root = self.znfs.getattr(ZipFileHandle::new(ROOT_FID))?;
+        let mut res = ZipDirOpRes::new(ZipFileHandle::new(ROOT_FID), root.attributes);
+
+        for component in path {
+            res = self.lookup(res.file.fid, component)?;
+        }
+
+        Ok(res)
+    }
+
+    /// List the whole directory.
+    pub fn list_dir(&mut self, dir: i64) -> thrift::Result<Vec<ZipDirEntryPlus>> {
+        let mut entries = Vec::new();
+        let mut cookie = 0;
+        let mut cookieverf = 0;
+
+        loop {
+            let page = self.znfs.readdirplus(ZipReadDirArgs::new(
+                ZipFileHandle::new(dir),
+                cookie,
+                cookieverf,
+                0,
+            ))?;
+
+            cookieverf = page.cookieverf;
+            if let Some(last) = page.entries.last() {
+                cookie = last.fid;
+            }
+
+            entries.extend(page.entries);
+
+            if page.eof {
+                return Ok(entries);
+            }
+        }
+    }
+
+    pub fn mkdir(&mut self, dir: i64, name: &str) -> thrift::Result<ZipDirOpRes> {
+        let sattr = ZipSattr::new(None, None, None, None, None, None);
+        let args = ZipCreateArgs::new(
+            ZipDirOpArgs::new(ZipFileHandle::new(dir), name.to_owned(), new_xid()),
+            sattr,
+        );
+        self.change(|znfs| znfs.mkdir(args.clone()))
+    }
+
+    /// Remove a file, or a directory and everything in it.
+    pub fn remove_tree(
+        &mut self,
+        dir: i64,
+        name: &str,
+        target: &ZipDirOpRes,
+    ) -> thrift::Result<()> {
+        let dirop = ZipDirOpArgs::new(ZipFileHandle::new(dir), name.to_owned(), new_xid());
+
+        if target.attributes.type_ != ZipFtype::NFDIR {
+            return self.change(|znfs| znfs.remove(dirop.clone()));
+        }
+
+        for entry in self.list_dir(target.file.fid)? {
+            let child = ZipDirOpRes::new(entry.file, entry.attributes);
+            self.remove_tree(target.file.fid, &entry.fname, &child)?;
+        }
+
+        self.change(|znfs| znfs.rmdir(dirop.clone()))
+    }
+
+    /// Truncate the file with the given name, or create it if it isn't there. Returns its
+    /// handle, and whether it was already there.
+    pub fn create_or_truncate(
+        &mut self,
+        dir: i64,
+        name: &str,
+    ) -> Result<(ZipFileHandle, bool), ZipError> {
+        match self.lookup(dir, name).map_err(ZipError::from) {
+            Ok(ref res) if res.attributes.type_ == ZipFtype::NFDIR => {
+                Err(nfs_error(ZipErrorType::NFSERR_ISDIR).into())
+            }
+            Ok(res) => {
+                let sattr = ZipSattr::new(None, None, None, Some(0), None, None);
+                self.znfs.setattr(ZipSattrArgs::new(
+                    ZipFileHandle::new(res.file.fid),
+                    sattr,
+                    None,
+                ))?;
+                Ok((res.file, true))
+            }
+            Err(ZipError::Nfs(ZipErrorType::NFSERR_NOENT, _)) => {
+                let sattr = ZipSattr::new(None, None, None, None, None, None);
+                let args = ZipCreateArgs::new(
+                    ZipDirOpArgs::new(ZipFileHandle::new(dir), name.to_owned(), new_xid()),
+                    sattr,
+                );
+                let res = self.change(|znfs| znfs.create(args.clone()))?;
+                Ok((res.file, false))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Replace the file with the given name with one holding everything from `data`, or create
+    /// it if it isn't there. Returns the handle of the new file, and whether there was one before.
+    ///
+    /// The data goes to a temporary file, which is only renamed into place once all of it has been
+    /// written and committed, so a request that fails half way leaves the old file alone. The
+    /// server won't rename over a file, so the old one is removed just before the rename, and for
+    /// a moment there is no file with the name at all.
+    pub fn replace<R: Read>(
+        &mut self,
+        dir: i64,
+        name: &str,
+        data: &mut R,
+    ) -> Result<(ZipFileHandle, bool), WriteError> {
+        // Don't bother reading the data if it can't go anywhere
+        match self.lookup(dir, name).map_err(ZipError::from) {
+            Ok(ref res) if res.attributes.type_ == ZipFtype::NFDIR => {
+                return Err(WriteError::Rpc(nfs_error(ZipErrorType::NFSERR_ISDIR).into()))
+            }
+            Ok(_) |
+            Err(ZipError::Nfs(ZipErrorType::NFSERR_NOENT, _)) => {}
+            Err(e) => return Err(WriteError::Rpc(e)),
+        }
+
+        let tmp_name = format!(".put-{:016x}", rand::random::<u64>());
+        let (tmp, _) = self.create_or_truncate(dir, &tmp_name).map_err(WriteError::Rpc)?;
+
+        let result = match self.write_from(tmp.fid, 0, data) {
+            Ok(_) => self.rename_over(dir, &tmp_name, name).map_err(WriteError::Rpc),
+            Err(e) => Err(e),
+        };
+
+        match result {
+            Ok(existed) => Ok((tmp, existed)),
+            Err(e) => {
+                let dirop = ZipDirOpArgs::new(ZipFileHandle::new(dir), tmp_name, new_xid());
+                if let Err(e) = self.change(|znfs| znfs.remove(dirop.clone())) {
+                    println!("Unable to remove {:?}: {}", dirop.filename, e);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Rename a file in the directory to the given name, removing any file already there first.
+    /// Returns whether there was one.
+    fn rename_over(&mut self, dir: i64, from: &str, to: &str) -> Result<bool, ZipError> {
+        let target = ZipDirOpArgs::new(ZipFileHandle::new(dir), to.to_owned(), new_xid());
+        let existed = match self.change(|znfs| znfs.remove(target.clone())) {
+            Ok(()) => true,
+            Err(e) => {
+                match ZipError::from(e) {
+                    ZipError::Nfs(ZipErrorType::NFSERR_NOENT, _) => false,
+                    e => return Err(e),
+                }
+            }
+        };
+
+        let args = ZipRenameArgs::new(
+            ZipDirOpArgs::new(ZipFileHandle::new(dir), from.to_owned(), new_xid()),
+            ZipDirOpArgs::new(ZipFileHandle::new(dir), to.to_owned(), None),
+            None,
+        );
+        self.change(|znfs| znfs.rename(args.clone()))?;
+
+        Ok(existed)
+    }
+
+    /// Write `data` to the file at `offset`. Writes are UNSTABLE if the server supports it, so
+    /// they have to be committed; `verf` keeps track of the verifier they were written under,
+    /// and this fails if it changes.
+    pub fn write(
+        &mut self,
+        fid: i64,
+        offset: u64,
+        data: &[u8],
+        verf: &mut Option<i64>,
+    ) -> Result<(), WriteError> {
+        let stable = if self.unstable {
+            ZipWriteStable::UNSTABLE
+        } else {
+            ZipWriteStable::FILE_SYNC
+        };
+
+        for (i, piece) in data.chunks(self.wsize).enumerate() {
+            let res = self.znfs.write(ZipWriteArgs::new(
+                ZipFileHandle::new(fid),
+                (offset + (i * self.wsize) as u64) as i64,
+                piece.len() as i64,
+                piece.to_vec(),
+                stable,
+                None,
+            ))?;
+
+            if *verf.get_or_insert(res.verf) != res.verf {
+                return Err(WriteError::Restarted);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Commit the writes made to the file under the given verifier.
+    pub fn commit(&mut self, fid: i64, verf: Option<i64>) -> Result<(), WriteError> {
+        if !self.unstable || verf.is_none() {
+            return Ok(());
+        }
+
+        let res = self.znfs.commit(
+            ZipCommitArgs::new(ZipFileHandle::new(fid), 0, 0, None),
+        )?;
+        if Some(res.verf) != verf {
+            return Err(WriteError::Restarted);
+        }
+
+        Ok(())
+    }
+
+    /// Write everything from `data` to the file, starting at `offset`, and commit it. Returns the
+    /// number of bytes written.
+    ///
+    /// We don't keep what we wrote around to resend, so if the server restarts before the
+    /// commit, this fails with `Restarted` and the whole thing has to be retried.
+    pub fn write_from<R: Read>(
+        &mut self,
+        fid: i64,
+        offset: u64,
+        data: &mut R,
+    ) -> Result<u64, WriteError> {
+        let mut buf = vec![0; self.wsize];
+        let mut written = 0;
+        let mut verf = None;
+
+        loop {
+            let len = read_full(data, &mut buf).map_err(WriteError::Read)?;
+            if len == 0 {
+                break;
+            }
+
+            self.write(fid, offset + written, &buf[..len], &mut verf)?;
+            written += len as u64;
+        }
+
+        self.commit(fid, verf)?;
+        Ok(written)
+    }
+}
+
+/// Read from `data` until `buf` is full. Returns the number of bytes read, which is less than
+/// the size of `buf` only at the end of the data.
+pub fn read_full<R: Read>(data: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match data.read(&mut buf[len..])? {
+            0 => break,
+            n => len += n,
+        }
+    }
+    Ok(len)
+}
+
+/// Send a response. The contents of files are read from the server as they are sent.
+fn send<W: Write>(
+    out: &mut W,
+    backend: &mut Backend,
+    response: Response,
+    head: bool,
+) -> io::Result<()> {
+    let len = match response.payload {
+        Payload::Bytes(ref bytes) => bytes.len() as u64,
+        Payload::File { len, .. } => len,
+    };
+
+    let mut header = format!(
+        "HTTP/1.1 {} {}\r\nContent-Length: {}\r\n",
+        response.status.0,
+        response.status.reason(),
+        len
+    );
+    for (name, value) in response.headers {
+        header.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    header.push_str("\r\n");
+    out.write_all(header.as_bytes())?;
+
+    if head {
+        return out.flush();
+    }
+
+    match response.payload {
+        Payload::Bytes(bytes) => out.write_all(&bytes)?,
+        Payload::File { fid, offset, len } => {
+            let mut sent = 0;
+            while sent < len {
+                let count = min(len - sent, backend.rsize as u64);
+                let res = backend
+                    .znfs
+                    .read(ZipReadArgs::new(
+                        ZipFileHandle::new(fid),
+                        (offset + sent) as i64,
+                        count as i64,
+                        None,
+                    ))
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{}", e)))?;
+
+                // The file shrank after we sent the headers, so we can't send what we said we
+                // would.
+                if res.data.is_empty() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "file shrank while sending it",
+                    ));
+                }
+
+                out.write_all(&res.data)?;
+                sent += res.data.len() as u64;
+            }
+        }
+    }
+
+    out.flush()
+}
+
+/// Serve requests on the given connection until the client hangs up.
+fn handle_conn<G, F>(new_gateway: &F, stream: TcpStream) -> io::Result<()>
+where
+    G: Gateway,
+    F: Fn() -> thrift::Result<G>,
+{
+    let mut out = stream.try_clone()?;
+    let mut stream = BufReader::new(stream);
+
+    let mut gateway = new_gateway().map_err(|e| {
+        io::Error::new(io::ErrorKind::Other, format!("{}", e))
+    })?;
+
+    while let Some(req) = http::read_request(&mut stream)? {
+        let req = match req {
+            Ok(req) => req,
+            Err(status) => {
+                let response = Response::new(status.0).header("Connection", "close".to_owned());
+                return send(&mut out, gateway.backend(), response, false);
+            }
+        };
+
+        println!("{} {}", req.method, http::href(&req.path, req.trailing_slash));
+
+        // Clients that wait for the go-ahead before sending a body (e.g. S3 SDKs) get it
+        // right away.
+        if req.header("expect").map(|e| e.to_lowercase()) == Some("100-continue".to_owned()) {
+            out.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+        }
+
+        let (response, body_done) = {
+            let mut body = match Body::new(&mut stream, &req) {
+                Ok(body) => body,
+                Err(status) => {
+                    let response =
+                        Response::new(status.0).header("Connection", "close".to_owned());
+                    return send(&mut out, gateway.backend(), response, false);
+                }
+            };
+
+            let response = gateway.handle(&req, &mut body);
+
+            // Whatever the handler didn't read has to go before the next request
+            (response, body.drain().is_ok())
+        };
+
+        let mut response = response.unwrap_or_else(|status| Response::new(status.0));
+
+        // After a server error, the connection to the server may be broken, so start over with
+        // a new one.
+        let keep_alive = req.http11 && body_done && response.status.0 < 500 &&
+            req.header("connection").map(|c| c.to_lowercase()) != Some("close".to_owned());
+        if !keep_alive {
+            response = response.header("Connection", "close".to_owned());
+        }
+
+        send(&mut out, gateway.backend(), response, req.method == "HEAD")?;
+
+        if !keep_alive {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// Accept HTTP connections at the given address forever, with a gateway from `new_gateway` for
+/// each one.
+pub fn serve<G, F>(listen_addr: &str, new_gateway: F) -> Result<(), String>
+where
+    G: Gateway,
+    F: Fn() -> thrift::Result<G> + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind(listen_addr).map_err(|e| format!("{}", e))?;
+    let new_gateway = Arc::new(new_gateway);
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                println!("Failed to accept a connection: {}", e);
+                continue;
+            }
+        };
+
+        let new_gateway = new_gateway.clone();
+        thread::spawn(move || if let Err(e) = handle_conn(&*new_gateway, stream) {
+            println!("Connection failed: {}", e);
+        });
+    }
+
+    Ok(())
+}
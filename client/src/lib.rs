@@ -1,22 +1,36 @@
-//! This library contains all the common code for CLI and FUSE clients.
+//! This library contains all the common code for the CLI and FUSE clients and the gateways.
 
+extern crate rand;
 extern crate thrift;
+extern crate time;
 extern crate zippyrpc;
 
+pub mod diskcache;
+pub mod gateway;
+pub mod pagecache;
+pub mod pipeline;
+pub mod pool;
+
+use thrift::ApplicationErrorKind;
 use thrift::protocol::{TCompactInputProtocol, TCompactOutputProtocol};
-use thrift::transport::{ReadHalf, TBufferedReadTransport, TBufferedWriteTransport, TIoChannel,
+use thrift::transport::{ReadHalf, TFramedReadTransport, TFramedWriteTransport, TIoChannel,
                         TTcpChannel, WriteHalf};
 
-use zippyrpc::ZippynfsSyncClient;
+use zippyrpc::{nfs_error_details, TZippynfsSyncClient, ZipErrorType, ZipFileHandle, ZipHelloArgs,
+               ZipHelloRes, ZippynfsSyncClient, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+use zippyrpc::transport::{read_transport, write_transport};
 
-type ClientInputProtocol = TCompactInputProtocol<TBufferedReadTransport<ReadHalf<TTcpChannel>>>;
-type ClientOutputProtocol = TCompactOutputProtocol<TBufferedWriteTransport<WriteHalf<TTcpChannel>>>;
+type ClientInputProtocol = TCompactInputProtocol<TFramedReadTransport<ReadHalf<TTcpChannel>>>;
+type ClientOutputProtocol = TCompactOutputProtocol<TFramedWriteTransport<WriteHalf<TTcpChannel>>>;
 
 pub type ZnfsClient = ZippynfsSyncClient<ClientInputProtocol, ClientOutputProtocol>;
-/// Create a new thrift client communicating with the given server address.
-pub fn new_client(
-    server_addr: &str,
-) -> thrift::Result<ZippynfsSyncClient<ClientInputProtocol, ClientOutputProtocol>> {
+
+/// Create a new thrift client communicating with the given server address, and return it along
+/// with the server's HELLO, which says which protocol version we are using and what the server
+/// supports.
+///
+/// This fails if the client and server have no protocol version in common.
+pub fn connect(server_addr: &str) -> thrift::Result<(ZnfsClient, ZipHelloRes)> {
     let mut c = TTcpChannel::new();
 
     // open the underlying TCP stream
@@ -27,14 +41,64 @@ pub fn new_client(
     // we'll use for reading, the other for writing
     let (i_chan, o_chan) = c.split()?;
 
-    // wrap the raw sockets (slow) with a buffered transport of some kind
-    let i_tran = TBufferedReadTransport::new(i_chan);
-    let o_tran = TBufferedWriteTransport::new(o_chan);
+    // wrap the raw sockets with framed transports big enough for any message
+    let i_tran = read_transport(i_chan);
+    let o_tran = write_transport(o_chan);
 
     // now create the protocol implementations
     let i_prot = TCompactInputProtocol::new(i_tran);
     let o_prot = TCompactOutputProtocol::new(o_tran);
 
+    let mut client = ZippynfsSyncClient::new(i_prot, o_prot);
+
+    // make sure we can understand each other
+    let hello = match client.hello(ZipHelloArgs::new(PROTOCOL_VERSION)) {
+        Err(thrift::Error::Application(ref e)) if e.kind == ApplicationErrorKind::UnknownMethod => {
+            println!("server doesn't know HELLO, so it speaks version 0");
+            let fsinfo = client.fsinfo(ZipFileHandle::new(1))?;
+            ZipHelloRes::new(0, 0, fsinfo)
+        }
+        hello => hello?,
+    };
+    check_hello(&hello)?;
+
     // we're done!
-    Ok(ZippynfsSyncClient::new(i_prot, o_prot))
+    Ok((client, hello))
+}
+
+/// Returns a new request ID (XID) for a request that changes the FS (i.e. CREATE, MKDIR, REMOVE,
+/// RMDIR or RENAME). The server does a request at most once for each XID, so every retry of a
+/// request must use the same one, and different requests must not.
+pub fn new_xid() -> i64 {
+    // Zero means no XID
+    loop {
+        let xid = rand::random();
+        if xid != 0 {
+            return xid;
+        }
+    }
+}
+
+/// Check the server's HELLO. The server rejects us if we are too old for it, but it's up to us to
+/// check whether it is too old for us.
+fn check_hello(hello: &ZipHelloRes) -> thrift::Result<()> {
+    if hello.version < MIN_PROTOCOL_VERSION {
+        return Err(nfs_error_details(
+            ZipErrorType::NFSERR_VERSION,
+            &format!(
+                "server speaks version {}, but client needs {} to {}",
+                hello.version,
+                MIN_PROTOCOL_VERSION,
+                PROTOCOL_VERSION
+            ),
+        ));
+    }
+
+    println!(
+        "speaking protocol version {} with server capabilities {:#x}",
+        hello.version,
+        hello.capabilities
+    );
+
+    Ok(())
 }
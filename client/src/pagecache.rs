@@ -0,0 +1,346 @@
+//! A cache of the data of files, in blocks.
+//!
+//! Blocks are keyed by FID and block number. FIDs are never reused, so cached data can only go
+//! stale if the file changes, which we notice from its mtime and size (its `Version`). Every READ
+//! reply carries the file's attributes, and as soon as we see a new version of a file, everything
+//! cached for it is thrown away. When the cache is full, the least recently used block goes.
+//!
+//! The cache also reads ahead for sequential readers. Each read that starts where the last one
+//! ended doubles the read-ahead window, up to a limit, and any other read closes it. The blocks
+//! in the window are requested on the pipeline without waiting for the replies, and whoever needs
+//! one of them first waits for its reply instead of asking again.
+
+use std::cmp::{max, min};
+use std::collections::{BTreeMap, HashMap};
+
+use zippyrpc::{ZipFattr, ZipReadRes};
+
+use pipeline::Pending;
+
+/// The size of a block, unless the server can't send that much in one READ
+pub const BLOCK_SIZE: usize = 64 << 10; // 64KB
+
+/// The version of a file, which changes whenever its data does.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Version {
+    mtime: (i64, i64),
+    size: u64,
+}
+
+impl Version {
+    /// The version of a file with the given mtime (seconds, microseconds) and size.
+    pub fn new(mtime: (i64, i64), size: u64) -> Version {
+        Version { mtime, size }
+    }
+
+    /// The version of a file with the given attributes.
+    pub fn of(attr: &ZipFattr) -> Version {
+        Version::new((attr.mtime.seconds, attr.mtime.useconds), attr.size as u64)
+    }
+
+    /// The mtime of the file, in seconds and microseconds
+    pub fn mtime(&self) -> (i64, i64) {
+        self.mtime
+    }
+
+    /// The size of the file
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+/// What the cache has of a block.
+pub enum Block {
+    /// The data of the block, which is short only at EOF
+    Cached(Vec<u8>),
+
+    /// The reply to a read-ahead of the block
+    Coming(Pending<ZipReadRes>),
+
+    /// Nothing
+    Missing,
+}
+
+/// What is cached for one file.
+struct CachedFile {
+    version: Version,
+
+    // block number -> (data, when it was last used)
+    blocks: HashMap<u64, (Vec<u8>, u64)>,
+
+    // block number -> reply to a read-ahead
+    coming: HashMap<u64, Pending<ZipReadRes>>,
+
+    // Where the last read ended, to tell whether the next one is sequential
+    next_offset: u64,
+
+    // The number of blocks to read ahead
+    window: usize,
+}
+
+impl CachedFile {
+    fn new(version: Version) -> CachedFile {
+        CachedFile {
+            version,
+            blocks: HashMap::new(),
+            coming: HashMap::new(),
+            next_offset: 0,
+            window: 0,
+        }
+    }
+}
+
+/// The cache of the data of all files.
+pub struct PageCache {
+    block_size: usize,
+
+    /// The most blocks to keep
+    capacity: usize,
+
+    /// The most blocks to read ahead
+    readahead: usize,
+
+    files: HashMap<u64, CachedFile>,
+
+    /// Every cached block, least recently used first: when it was last used -> (FID, block)
+    lru: BTreeMap<u64, (u64, u64)>,
+
+    /// Counts uses of blocks, for `lru`
+    clock: u64,
+}
+
+impl PageCache {
+    /// A cache of blocks of `block_size` bytes, holding up to `capacity` bytes and reading up to
+    /// `readahead` bytes ahead.
+    pub fn new(block_size: usize, capacity: usize, readahead: usize) -> PageCache {
+        PageCache {
+            block_size,
+            capacity: capacity / block_size,
+            readahead: readahead / block_size,
+            files: HashMap::new(),
+            lru: BTreeMap::new(),
+            clock: 0,
+        }
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    /// Make sure that what we have of the file is of the given version, throwing it all away if
+    /// it isn't.
+    fn validate(&mut self, fid: u64, version: Version) {
+        let current = self.files.get(&fid).map(|file| file.version);
+
+        match current {
+            Some(current) if current == version => {}
+            Some(_) => {
+                self.forget(fid);
+                self.files.insert(fid, CachedFile::new(version));
+            }
+            None => {
+                self.files.insert(fid, CachedFile::new(version));
+            }
+        }
+    }
+
+    /// Take what we have of the given block of the given version of the file.
+    pub fn get(&mut self, fid: u64, version: Version, block: u64) -> Block {
+        self.validate(fid, version);
+        self.clock += 1;
+        let now = self.clock;
+
+        let file = self.files.get_mut(&fid).unwrap();
+        if let Some(reply) = file.coming.remove(&block) {
+            return Block::Coming(reply);
+        }
+
+        let (data, last_used) = match file.blocks.get_mut(&block) {
+            Some(&mut (ref data, ref mut last_used)) => {
+                let previous = *last_used;
+                *last_used = now;
+                (data.clone(), previous)
+            }
+            None => return Block::Missing,
+        };
+
+        self.lru.remove(&last_used);
+        self.lru.insert(now, (fid, block));
+
+        Block::Cached(data)
+    }
+
+    /// Remember the data of a block of the given version of the file.
+    pub fn insert(&mut self, fid: u64, version: Version, block: u64, data: Vec<u8>) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        self.validate(fid, version);
+        self.clock += 1;
+        let now = self.clock;
+
+        {
+            let file = self.files.get_mut(&fid).unwrap();
+            if let Some((_, last_used)) = file.blocks.insert(block, (data, now)) {
+                self.lru.remove(&last_used);
+            }
+        }
+        self.lru.insert(now, (fid, block));
+
+        // Make room
+        while self.lru.len() > self.capacity {
+            let (&last_used, &(fid, block)) = self.lru.iter().next().unwrap();
+            self.lru.remove(&last_used);
+
+            let unused = match self.files.get_mut(&fid) {
+                Some(file) => {
+                    file.blocks.remove(&block);
+                    file.blocks.is_empty() && file.coming.is_empty()
+                }
+                None => false,
+            };
+            if unused {
+                self.files.remove(&fid);
+            }
+        }
+    }
+
+    /// Note a read of `offset..end` of the given version of the file, and return the blocks after
+    /// it that should be read ahead. The caller should start reading them, and hand over the
+    /// replies with `reading_ahead`.
+    pub fn readahead(&mut self, fid: u64, version: Version, offset: u64, end: u64) -> Vec<u64> {
+        self.validate(fid, version);
+
+        let block_size = self.block_size as u64;
+        let limit = self.readahead;
+        let file = self.files.get_mut(&fid).unwrap();
+
+        let sequential = offset == file.next_offset;
+        file.next_offset = end;
+        file.window = if sequential {
+            min(max(file.window * 2, 1), limit)
+        } else {
+            0
+        };
+
+        // The first block after the read, up to EOF
+        let next = (end + block_size - 1) / block_size;
+        let eof = (version.size + block_size - 1) / block_size;
+
+        (next..min(next + file.window as u64, eof))
+            .filter(|block| {
+                !file.blocks.contains_key(block) && !file.coming.contains_key(block)
+            })
+            .collect()
+    }
+
+    /// Remember the reply to a read-ahead of a block of the given version of the file.
+    pub fn reading_ahead(
+        &mut self,
+        fid: u64,
+        version: Version,
+        block: u64,
+        reply: Pending<ZipReadRes>,
+    ) {
+        self.validate(fid, version);
+        self.files.get_mut(&fid).unwrap().coming.insert(block, reply);
+    }
+
+    /// Forget everything about the file, e.g. because we changed it.
+    pub fn forget(&mut self, fid: u64) {
+        if let Some(file) = self.files.remove(&fid) {
+            for (_, &(_, last_used)) in file.blocks.iter() {
+                self.lru.remove(&last_used);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Block, PageCache, Version};
+
+    /// Whether the cache has the given block, with the given data.
+    fn has(cache: &mut PageCache, fid: u64, version: Version, block: u64, data: &[u8]) -> bool {
+        match cache.get(fid, version, block) {
+            Block::Cached(cached) => cached == data,
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn test_lru_eviction() {
+        // Room for 3 blocks of 4B
+        let mut cache = PageCache::new(4, 12, 0);
+        let v = Version::new((1, 0), 100);
+
+        cache.insert(1, v, 0, vec![0; 4]);
+        cache.insert(1, v, 1, vec![1; 4]);
+        cache.insert(2, v, 0, vec![2; 4]);
+
+        // Using block 0 makes block 1 the least recently used, so it goes first
+        assert!(has(&mut cache, 1, v, 0, &[0; 4]));
+        cache.insert(2, v, 1, vec![3; 4]);
+        assert!(!has(&mut cache, 1, v, 1, &[1; 4]));
+        assert!(has(&mut cache, 1, v, 0, &[0; 4]));
+        assert!(has(&mut cache, 2, v, 0, &[2; 4]));
+        assert!(has(&mut cache, 2, v, 1, &[3; 4]));
+        assert_eq!(cache.lru.len(), 3);
+
+        // Replacing a block doesn't take any more room
+        cache.insert(2, v, 1, vec![4; 4]);
+        assert_eq!(cache.lru.len(), 3);
+        assert!(has(&mut cache, 2, v, 1, &[4; 4]));
+
+        // Once all of a file's blocks are gone, so is the file
+        cache.insert(3, v, 0, vec![5; 4]);
+        cache.insert(3, v, 1, vec![6; 4]);
+        assert_eq!(cache.lru.len(), 3);
+        assert!(!cache.files.contains_key(&1));
+        assert_eq!(
+            cache.files.values().map(|file| file.blocks.len()).sum::<usize>(),
+            3
+        );
+    }
+
+    #[test]
+    fn test_no_capacity() {
+        let mut cache = PageCache::new(4, 0, 0);
+        let v = Version::new((1, 0), 100);
+
+        cache.insert(1, v, 0, vec![0; 4]);
+        assert!(!has(&mut cache, 1, v, 0, &[0; 4]));
+        assert!(cache.lru.is_empty());
+    }
+
+    #[test]
+    fn test_version_revalidation() {
+        let mut cache = PageCache::new(4, 16, 0);
+        let v1 = Version::new((1, 0), 100);
+        let v2 = Version::new((1, 500), 100);
+        let v3 = Version::new((1, 500), 104);
+
+        cache.insert(1, v1, 0, vec![0; 4]);
+        cache.insert(1, v1, 1, vec![1; 4]);
+        cache.insert(2, v1, 0, vec![2; 4]);
+        assert!(has(&mut cache, 1, v1, 0, &[0; 4]));
+
+        // A new mtime throws away everything cached for the file, and nothing else
+        assert!(!has(&mut cache, 1, v2, 0, &[0; 4]));
+        assert!(!has(&mut cache, 1, v1, 1, &[1; 4]));
+        assert!(has(&mut cache, 2, v1, 0, &[2; 4]));
+        assert_eq!(cache.lru.len(), 1);
+
+        // So does a new size
+        cache.insert(1, v2, 0, vec![3; 4]);
+        assert!(has(&mut cache, 1, v2, 0, &[3; 4]));
+        assert!(!has(&mut cache, 1, v3, 0, &[3; 4]));
+        assert_eq!(cache.lru.len(), 1);
+
+        // Forgetting a file throws away its blocks too
+        cache.forget(2);
+        assert!(!has(&mut cache, 2, v1, 0, &[2; 4]));
+        assert!(cache.lru.is_empty());
+    }
+}
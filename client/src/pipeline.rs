@@ -0,0 +1,416 @@
+//! A client that keeps many RPCs in flight on one connection.
+//!
+//! `ZnfsClient` sends a request and then waits for its reply before it can send anything else,
+//! so every RPC costs a full round trip to the server, which adds up quickly over a long
+//! distance. A `Pipeline` sends each request as soon as it is asked to, and hands back a
+//! `Pending` reply to wait for whenever the caller is ready. A thread reads the replies as they
+//! arrive and passes each one to whoever is waiting for it, matching them up by their Thrift
+//! sequence numbers.
+//!
+//! A server with `CAP_PIPELINING` works on the requests at the same time and replies in whatever
+//! order they finish. That also means that requests in flight at the same time can take effect
+//! in any order, so callers must wait for a request before sending another that depends on it
+//! (e.g. two WRITEs to the same bytes).
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::marker::PhantomData;
+use std::net::{Shutdown, TcpStream};
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+
+use thrift::{self, ApplicationErrorKind, TransportErrorKind};
+use thrift::protocol::{TCompactInputProtocol, TCompactOutputProtocol, TFieldIdentifier,
+                       TInputProtocol, TMessageIdentifier, TMessageType, TOutputProtocol,
+                       TStructIdentifier, TType};
+
+use zippyrpc::*;
+
+use super::check_hello;
+
+/// The most requests a `Pipeline` keeps in flight at once. This is as many as the server will
+/// work on at once for one connection; any more would just wait at the server.
+pub const MAX_IN_FLIGHT: usize = 16;
+
+/// The argument of an RPC.
+pub trait RpcArgs {
+    /// Write the argument as a field of the `<method>_args` struct.
+    fn write(&self, o_prot: &mut TOutputProtocol) -> thrift::Result<()>;
+}
+
+/// The result of an RPC.
+pub trait RpcResult: Sized {
+    /// Read the `success` field of the `<method>_result` struct.
+    fn read(i_prot: &mut TInputProtocol) -> thrift::Result<Self>;
+
+    /// The result of a successful RPC with no `success` field, if that is allowed (i.e. for
+    /// `void` methods).
+    fn void() -> Option<Self> {
+        None
+    }
+}
+
+macro_rules! rpc_args {
+    ($($t:ident),*) => { $(
+        impl RpcArgs for $t {
+            fn write(&self, o_prot: &mut TOutputProtocol) -> thrift::Result<()> {
+                // Every one of our methods takes a single argument, numbered 1
+                o_prot.write_field_begin(&TFieldIdentifier::new("fsargs", TType::Struct, 1))?;
+                self.write_to_out_protocol(o_prot)?;
+                o_prot.write_field_end()
+            }
+        }
+    )* }
+}
+
+macro_rules! rpc_results {
+    ($($t:ident),*) => { $(
+        impl RpcResult for $t {
+            fn read(i_prot: &mut TInputProtocol) -> thrift::Result<$t> {
+                $t::read_from_in_protocol(i_prot)
+            }
+        }
+    )* }
+}
+
+rpc_args!(ZipHelloArgs, ZipFileHandle, ZipSattrArgs, ZipDirOpArgs, ZipReadArgs, ZipWriteArgs,
+          ZipCreateArgs, ZipRenameArgs, ZipReadDirArgs, ZipCommitArgs);
+
+rpc_results!(ZipHelloRes, ZipAttrStat, ZipDirOpRes, ZipReadRes, ZipWriteRes, ZipReadDirRes,
+             ZipReadDirPlusRes, ZipStatFsRes, ZipFsInfoRes, ZipCommitRes);
+
+/// NULL takes no arguments.
+impl RpcArgs for () {
+    fn write(&self, _o_prot: &mut TOutputProtocol) -> thrift::Result<()> {
+        Ok(())
+    }
+}
+
+/// NULL returns the server's epoch.
+impl RpcResult for i64 {
+    fn read(i_prot: &mut TInputProtocol) -> thrift::Result<i64> {
+        i_prot.read_i64()
+    }
+}
+
+/// REMOVE, RENAME and RMDIR return nothing.
+impl RpcResult for () {
+    fn read(_i_prot: &mut TInputProtocol) -> thrift::Result<()> {
+        Ok(())
+    }
+
+    fn void() -> Option<()> {
+        Some(())
+    }
+}
+
+/// The replies that haven't arrived yet, and where to send them.
+struct Waiting {
+    /// Sequence number -> whoever is waiting for the reply
+    replies: HashMap<i32, Sender<thrift::Result<Vec<u8>>>>,
+
+    /// The sequence number of the last request
+    last_seqid: i32,
+
+    /// Why the connection is closed, once it is
+    closed: Option<String>,
+}
+
+/// What the `Pipeline` shares with the thread that reads the replies.
+struct Shared {
+    waiting: Mutex<Waiting>,
+
+    /// Signalled whenever a reply arrives, so there is room for another request
+    room: Condvar,
+}
+
+/// A connection to the server with many RPCs in flight at once. It can be shared between
+/// threads.
+pub struct Pipeline {
+    stream: Mutex<TcpStream>,
+    shared: Arc<Shared>,
+}
+
+/// The reply to an RPC sent through a `Pipeline`, which may not have arrived yet.
+pub struct Pending<R> {
+    reply: Receiver<thrift::Result<Vec<u8>>>,
+    result: PhantomData<R>,
+}
+
+impl<R: RpcResult> Pending<R> {
+    /// Wait for the reply.
+    pub fn wait(self) -> thrift::Result<R> {
+        match self.reply.recv() {
+            Ok(reply) => decode_reply(&reply?),
+            Err(_) => Err(closed_error("connection closed")),
+        }
+    }
+}
+
+/// Generate the methods that send each RPC.
+macro_rules! rpcs {
+    ($($method:ident($args:ty) -> $res:ty;)*) => {
+        impl Pipeline {
+            $(
+                pub fn $method(&self, args: $args) -> thrift::Result<Pending<$res>> {
+                    self.send(stringify!($method), &args)
+                }
+            )*
+        }
+    }
+}
+
+rpcs! {
+    hello(ZipHelloArgs) -> ZipHelloRes;
+    getattr(ZipFileHandle) -> ZipAttrStat;
+    setattr(ZipSattrArgs) -> ZipAttrStat;
+    lookup(ZipDirOpArgs) -> ZipDirOpRes;
+    read(ZipReadArgs) -> ZipReadRes;
+    write(ZipWriteArgs) -> ZipWriteRes;
+    create(ZipCreateArgs) -> ZipDirOpRes;
+    remove(ZipDirOpArgs) -> ();
+    rename(ZipRenameArgs) -> ();
+    mkdir(ZipCreateArgs) -> ZipDirOpRes;
+    rmdir(ZipDirOpArgs) -> ();
+    readdir(ZipReadDirArgs) -> ZipReadDirRes;
+    readdirplus(ZipReadDirArgs) -> ZipReadDirPlusRes;
+    statfs(ZipFileHandle) -> ZipStatFsRes;
+    fsinfo(ZipFileHandle) -> ZipFsInfoRes;
+    commit(ZipCommitArgs) -> ZipCommitRes;
+    open(ZipOpenArgs) -> ZipOpenRes;
+    close(ZipOpenArgs) -> ZipOpenRes;
+}
+
+impl Pipeline {
+    /// Connect to the server at the given address, and return the connection along with the
+    /// server's HELLO.
+    ///
+    /// This fails if the client and server have no protocol version in common.
+    pub fn connect(server_addr: &str) -> thrift::Result<(Pipeline, ZipHelloRes)> {
+        println!("connecting pipeline to ZippyNFS server on {}", server_addr);
+
+        let stream = TcpStream::connect(server_addr)?;
+        stream.set_nodelay(true)?;
+
+        let shared = Arc::new(Shared {
+            waiting: Mutex::new(Waiting {
+                replies: HashMap::new(),
+                last_seqid: 0,
+                closed: None,
+            }),
+            room: Condvar::new(),
+        });
+
+        {
+            let stream = stream.try_clone()?;
+            let shared = shared.clone();
+            thread::Builder::new()
+                .name("pipeline-replies".to_owned())
+                .spawn(move || read_replies(stream, &shared))?;
+        }
+
+        let pipeline = Pipeline {
+            stream: Mutex::new(stream),
+            shared,
+        };
+
+        let hello = pipeline.hello(ZipHelloArgs::new(PROTOCOL_VERSION))?.wait()?;
+        check_hello(&hello)?;
+
+        Ok((pipeline, hello))
+    }
+
+    /// Whether the connection has failed, so that nothing more can be sent on it.
+    pub fn is_closed(&self) -> bool {
+        self.shared.waiting.lock().unwrap().closed.is_some()
+    }
+
+    /// Send a request, without waiting for the reply. If there are already `MAX_IN_FLIGHT`
+    /// requests in flight, this waits for one of them to finish first.
+    pub fn send<A: RpcArgs, R: RpcResult>(
+        &self,
+        method: &str,
+        args: &A,
+    ) -> thrift::Result<Pending<R>> {
+        let (tx, rx) = channel();
+
+        let seqid = {
+            let mut waiting = self.shared.waiting.lock().unwrap();
+            loop {
+                if let Some(ref why) = waiting.closed {
+                    return Err(closed_error(why));
+                }
+                if waiting.replies.len() < MAX_IN_FLIGHT {
+                    break;
+                }
+                waiting = self.shared.room.wait(waiting).unwrap();
+            }
+
+            waiting.last_seqid = waiting.last_seqid.wrapping_add(1);
+            let seqid = waiting.last_seqid;
+            waiting.replies.insert(seqid, tx);
+            seqid
+        };
+
+        let result = encode_call(method, seqid, args).and_then(|request| {
+            let mut stream = self.stream.lock().unwrap();
+            stream.write_all(&frame_len(request.len()))?;
+            stream.write_all(&request)?;
+            Ok(())
+        });
+
+        if let Err(e) = result {
+            self.shared.waiting.lock().unwrap().replies.remove(&seqid);
+
+            // We may have sent part of the request, so the connection is no good any more. This
+            // also fails everything else in flight.
+            let _ = self.stream.lock().unwrap().shutdown(Shutdown::Both);
+            return Err(e);
+        }
+
+        Ok(Pending {
+            reply: rx,
+            result: PhantomData,
+        })
+    }
+}
+
+impl Drop for Pipeline {
+    /// Hang up, which also stops the thread reading the replies.
+    fn drop(&mut self) {
+        let _ = self.stream.lock().unwrap().shutdown(Shutdown::Both);
+    }
+}
+
+/// The error for requests on a connection that has been closed. It is a transport error, so
+/// that callers know to reconnect.
+fn closed_error(why: &str) -> thrift::Error {
+    thrift::new_transport_error(
+        TransportErrorKind::NotOpen,
+        format!("pipeline closed: {}", why),
+    )
+}
+
+/// The 4-byte big-endian length that precedes each frame.
+fn frame_len(len: usize) -> [u8; 4] {
+    [(len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8]
+}
+
+/// Read one whole frame from the server.
+fn read_frame(stream: &mut TcpStream) -> io::Result<Vec<u8>> {
+    let mut len = [0; 4];
+    stream.read_exact(&mut len)?;
+
+    let len = len.iter().fold(0usize, |n, &b| n << 8 | b as usize);
+    if len > MAX_MSG_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("frame of {} bytes is too big", len),
+        ));
+    }
+
+    let mut frame = vec![0; len];
+    stream.read_exact(&mut frame)?;
+    Ok(frame)
+}
+
+/// The main loop of the thread that reads the replies.
+fn read_replies(mut stream: TcpStream, shared: &Shared) {
+    let why = loop {
+        let reply = match read_frame(&mut stream) {
+            Ok(reply) => reply,
+            Err(e) => break format!("{}", e),
+        };
+
+        let seqid = match TCompactInputProtocol::new(&reply[..]).read_message_begin() {
+            Ok(ident) => ident.sequence_number,
+            Err(e) => break format!("bad reply: {}", e),
+        };
+
+        let mut waiting = shared.waiting.lock().unwrap();
+        match waiting.replies.remove(&seqid) {
+            Some(tx) => {
+                // Whoever sent it may not care any more
+                let _ = tx.send(Ok(reply));
+                shared.room.notify_one();
+            }
+            None => break format!("reply to unknown request {}", seqid),
+        }
+    };
+
+    // Fail everything still in flight, and everything sent from now on
+    let _ = stream.shutdown(Shutdown::Both);
+    let mut waiting = shared.waiting.lock().unwrap();
+    for (_, tx) in waiting.replies.drain() {
+        let _ = tx.send(Err(closed_error(&why)));
+    }
+    waiting.closed = Some(why);
+    shared.room.notify_all();
+}
+
+/// Encode a call to the given method, just as the generated client would.
+fn encode_call<A: RpcArgs>(method: &str, seqid: i32, args: &A) -> thrift::Result<Vec<u8>> {
+    let mut request = Vec::new();
+
+    {
+        let mut o_prot = TCompactOutputProtocol::new(&mut request);
+        o_prot.write_message_begin(&TMessageIdentifier::new(
+            method,
+            TMessageType::Call,
+            seqid,
+        ))?;
+        o_prot.write_struct_begin(&TStructIdentifier::new(format!("{}_args", method)))?;
+        args.write(&mut o_prot)?;
+        o_prot.write_field_stop()?;
+        o_prot.write_struct_end()?;
+        o_prot.write_message_end()?;
+        o_prot.flush()?;
+    }
+
+    Ok(request)
+}
+
+/// Decode a reply, just as the generated client would.
+fn decode_reply<R: RpcResult>(reply: &[u8]) -> thrift::Result<R> {
+    let mut i_prot = TCompactInputProtocol::new(reply);
+
+    let ident = i_prot.read_message_begin()?;
+    if ident.message_type == TMessageType::Exception {
+        let e = thrift::Error::read_application_error_from_in_protocol(&mut i_prot)?;
+        i_prot.read_message_end()?;
+        return Err(thrift::Error::Application(e));
+    }
+
+    let mut success = None;
+    let mut ex = None;
+
+    i_prot.read_struct_begin()?;
+    loop {
+        let field = i_prot.read_field_begin()?;
+        if field.field_type == TType::Stop {
+            break;
+        }
+
+        match field.id {
+            Some(0) => success = Some(R::read(&mut i_prot)?),
+            Some(1) => ex = Some(ZipException::read_from_in_protocol(&mut i_prot)?),
+            _ => i_prot.skip(field.field_type)?,
+        }
+
+        i_prot.read_field_end()?;
+    }
+    i_prot.read_struct_end()?;
+    i_prot.read_message_end()?;
+
+    if let Some(ex) = ex {
+        return Err(thrift::Error::User(Box::new(ex)));
+    }
+
+    success.or_else(R::void).ok_or_else(|| {
+        thrift::new_application_error(
+            ApplicationErrorKind::MissingResult,
+            format!("no result in reply to {}", ident.name),
+        )
+    })
+}
@@ -0,0 +1,440 @@
+//! A pool of connections to the server, which keeps them healthy.
+//!
+//! Callers run each RPC through the pool, which lends it a connection for just that RPC. If a
+//! connection fails with a transport or protocol error, we can't tell what state it is in, so it
+//! is thrown away and the RPC is tried again on a fresh one, after a backoff. The backoff grows
+//! exponentially up to a limit, and half of it is random, so that clients which all lost the
+//! server at once don't all come back at once too.
+//!
+//! Connections that sit idle are checked with a NULL every so often, so that a dead one is
+//! usually found by the pool rather than by an RPC.
+
+use std::cmp::min;
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread::{self, sleep};
+use std::time::{Duration, Instant};
+
+use rand::{self, Rng};
+
+use zippyrpc::{TZippynfsSyncClient, ZipError, ZipHelloRes};
+
+use super::{connect, ZnfsClient};
+
+/// How the pool behaves.
+#[derive(Clone, Debug)]
+pub struct PoolConfig {
+    /// The most idle connections to keep. Any more are closed when they are returned.
+    pub max_idle: usize,
+
+    /// How long a connection can be idle before it is checked with a NULL
+    pub probe_interval: Duration,
+
+    /// How many times to try an RPC before giving up on a transport or protocol error
+    pub max_tries: usize,
+
+    /// The backoff before the first retry, which doubles for each one after that
+    pub min_backoff: Duration,
+
+    /// The longest backoff between retries
+    pub max_backoff: Duration,
+}
+
+impl Default for PoolConfig {
+    fn default() -> PoolConfig {
+        PoolConfig {
+            max_idle: 16,
+            probe_interval: Duration::from_secs(30),
+            max_tries: 5,
+            min_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(16),
+        }
+    }
+}
+
+/// Counts of what the pool has done, for monitoring.
+#[derive(Clone, Debug, Default)]
+pub struct PoolMetrics {
+    /// Connections made
+    pub connects: u64,
+
+    /// Attempts to connect that failed
+    pub connect_failures: u64,
+
+    /// Connections thrown away after a transport or protocol error
+    pub broken: u64,
+
+    /// RPCs retried after a transport or protocol error
+    pub retries: u64,
+
+    /// Connections lent out
+    pub checkouts: u64,
+
+    /// Health checks of idle connections
+    pub probes: u64,
+
+    /// Health checks that failed, closing the connection
+    pub probe_failures: u64,
+
+    /// Connections in the pool now
+    pub idle: usize,
+
+    /// Connections lent out now
+    pub busy: usize,
+}
+
+impl fmt::Display for PoolMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} idle, {} busy; {} connects ({} failed), {} broken; {} checkouts, {} retries; \
+             {} health checks ({} failed)",
+            self.idle,
+            self.busy,
+            self.connects,
+            self.connect_failures,
+            self.broken,
+            self.checkouts,
+            self.retries,
+            self.probes,
+            self.probe_failures
+        )
+    }
+}
+
+/// What the pool shares with its health checker.
+struct Inner {
+    server_addr: String,
+    config: PoolConfig,
+
+    /// Idle connections, with when each was last used. The most recently used is last.
+    idle: Mutex<Vec<(ZnfsClient, Instant)>>,
+
+    metrics: Mutex<PoolMetrics>,
+
+    /// The server's HELLO, from the newest connection. The server may have been upgraded or
+    /// downgraded since the first one.
+    hello: Mutex<ZipHelloRes>,
+}
+
+impl Inner {
+    /// Update the metrics.
+    fn count<F: FnOnce(&mut PoolMetrics)>(&self, f: F) {
+        f(&mut *self.metrics.lock().unwrap())
+    }
+
+    /// Put a connection back in the pool, unless it is full.
+    fn put(&self, client: ZnfsClient) {
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < self.config.max_idle {
+            idle.push((client, Instant::now()));
+        }
+    }
+}
+
+/// A pool of connections to one server.
+pub struct ConnPool {
+    inner: Arc<Inner>,
+}
+
+/// A connection lent out by the pool. It goes back when dropped.
+pub struct PooledConn<'a> {
+    inner: &'a Inner,
+    client: Option<ZnfsClient>,
+}
+
+impl ConnPool {
+    /// Make a pool of connections to the given server, with one connection to start with.
+    ///
+    /// This fails if we can't connect, or the client and server have no protocol version in
+    /// common.
+    pub fn new(server_addr: &str, config: PoolConfig) -> Result<ConnPool, ZipError> {
+        let (client, hello) = connect(server_addr)?;
+
+        let inner = Arc::new(Inner {
+            server_addr: server_addr.to_owned(),
+            config,
+            idle: Mutex::new(Vec::new()),
+            metrics: Mutex::new(PoolMetrics::default()),
+            hello: Mutex::new(hello),
+        });
+        inner.count(|m| m.connects += 1);
+        inner.put(client);
+
+        let weak = Arc::downgrade(&inner);
+        thread::Builder::new()
+            .name("pool-probe".to_owned())
+            .spawn(move || probe(&weak))
+            .map_err(::thrift::Error::from)?;
+
+        Ok(ConnPool { inner })
+    }
+
+    /// The server's HELLO, from the newest connection.
+    pub fn hello(&self) -> ZipHelloRes {
+        self.inner.hello.lock().unwrap().clone()
+    }
+
+    /// The server's capabilities, from the newest connection.
+    pub fn capabilities(&self) -> i64 {
+        self.inner.hello.lock().unwrap().capabilities
+    }
+
+    /// A snapshot of the metrics.
+    pub fn metrics(&self) -> PoolMetrics {
+        let mut metrics = self.inner.metrics.lock().unwrap().clone();
+        metrics.idle = self.inner.idle.lock().unwrap().len();
+        metrics
+    }
+
+    /// Borrow a connection, making a new one if there are none in the pool.
+    pub fn get(&self) -> Result<PooledConn, ZipError> {
+        let idle = self.inner.idle.lock().unwrap().pop();
+
+        let client = match idle {
+            Some((client, _)) => client,
+            None => {
+                match connect(&self.inner.server_addr) {
+                    Ok((client, hello)) => {
+                        self.inner.count(|m| m.connects += 1);
+
+                        let mut last = self.inner.hello.lock().unwrap();
+                        if hello.version != last.version ||
+                            hello.capabilities != last.capabilities
+                        {
+                            println!(
+                                "Server changed from version {} with capabilities {:#x} to \
+                                 version {} with capabilities {:#x}",
+                                last.version,
+                                last.capabilities,
+                                hello.version,
+                                hello.capabilities
+                            );
+                        }
+                        *last = hello;
+
+                        client
+                    }
+                    Err(e) => {
+                        self.inner.count(|m| m.connect_failures += 1);
+                        return Err(e.into());
+                    }
+                }
+            }
+        };
+
+        self.inner.count(|m| {
+            m.checkouts += 1;
+            m.busy += 1;
+        });
+
+        Ok(PooledConn {
+            inner: &self.inner,
+            client: Some(client),
+        })
+    }
+
+    /// Run an RPC on one of the connections. If the connection fails, it is thrown away and
+    /// the RPC is retried on another, as by `retry`.
+    pub fn call<T, F>(&self, mut rpc: F) -> Result<T, ZipError>
+    where
+        F: FnMut(&mut ZnfsClient) -> ::thrift::Result<T>,
+    {
+        self.retry(|| {
+            let mut conn = self.get()?;
+            match rpc(&mut *conn) {
+                Ok(res) => Ok(res),
+                Err(e) => {
+                    let e = ZipError::from(e);
+                    if is_broken(&e) {
+                        conn.discard();
+                    }
+                    Err(e)
+                }
+            }
+        })
+    }
+
+    /// Run `f` until it doesn't fail with a transport or protocol error, backing off between
+    /// tries, or we run out of tries. Any other error is returned right away.
+    ///
+    /// This is for RPCs that don't go through the pool's connections, but should be retried the
+    /// same way.
+    pub fn retry<T, F>(&self, mut f: F) -> Result<T, ZipError>
+    where
+        F: FnMut() -> Result<T, ZipError>,
+    {
+        let mut tries = 1;
+        loop {
+            match f() {
+                Err(ref e) if is_broken(e) && tries < self.inner.config.max_tries => {
+                    println!("Retrying after error: {:?}", e);
+                    self.inner.count(|m| m.retries += 1);
+                    sleep(backoff(&self.inner.config, tries));
+                    tries += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+impl<'a> PooledConn<'a> {
+    /// Close the connection rather than returning it to the pool, because it failed.
+    pub fn discard(&mut self) {
+        if self.client.take().is_some() {
+            self.inner.count(|m| {
+                m.broken += 1;
+                m.busy -= 1;
+            });
+        }
+    }
+}
+
+impl<'a> Deref for PooledConn<'a> {
+    type Target = ZnfsClient;
+
+    fn deref(&self) -> &ZnfsClient {
+        self.client.as_ref().unwrap()
+    }
+}
+
+impl<'a> DerefMut for PooledConn<'a> {
+    fn deref_mut(&mut self) -> &mut ZnfsClient {
+        self.client.as_mut().unwrap()
+    }
+}
+
+impl<'a> Drop for PooledConn<'a> {
+    fn drop(&mut self) {
+        if let Some(client) = self.client.take() {
+            self.inner.count(|m| m.busy -= 1);
+            self.inner.put(client);
+        }
+    }
+}
+
+/// Whether an error means that the connection it happened on is no good any more.
+pub fn is_broken(e: &ZipError) -> bool {
+    match *e {
+        ZipError::Transport(_) |
+        ZipError::Protocol(_) => true,
+        _ => false,
+    }
+}
+
+/// How long to wait after the given number of tries.
+fn backoff(config: &PoolConfig, tries: usize) -> Duration {
+    let mut backoff = min(config.min_backoff, config.max_backoff);
+    for _ in 1..tries {
+        backoff = min(backoff * 2, config.max_backoff);
+    }
+
+    let ms = as_millis(backoff);
+    Duration::from_millis(ms / 2 + rand::thread_rng().gen_range(0, ms / 2 + 1))
+}
+
+fn as_millis(d: Duration) -> u64 {
+    d.as_secs() * 1000 + (d.subsec_nanos() / 1_000_000) as u64
+}
+
+/// The main loop of the health checker, which runs until the pool is dropped.
+fn probe(inner: &Weak<Inner>) {
+    loop {
+        let interval = match inner.upgrade() {
+            Some(inner) => inner.config.probe_interval,
+            None => return,
+        };
+
+        sleep(interval);
+
+        let inner = match inner.upgrade() {
+            Some(inner) => inner,
+            None => return,
+        };
+
+        // Take out the connections that have been idle for a while, and check them without
+        // holding up everyone else
+        let stale: Vec<_> = {
+            let mut idle = inner.idle.lock().unwrap();
+            let (stale, fresh) = idle.drain(..).partition(
+                |&(_, since)| since.elapsed() >= interval,
+            );
+            *idle = fresh;
+            stale
+        };
+
+        for (mut client, _) in stale {
+            inner.count(|m| m.probes += 1);
+
+            match client.null() {
+                Ok(_) => inner.put(client),
+                Err(e) => {
+                    println!("Closing a connection that failed a health check: {}", e);
+                    inner.count(|m| m.probe_failures += 1);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cmp::{max, min};
+    use std::time::Duration;
+
+    use super::{as_millis, backoff, PoolConfig};
+
+    /// The shortest and longest backoffs after the given number of tries, in ms.
+    fn bounds(config: &PoolConfig, tries: usize) -> (u64, u64) {
+        let (mut shortest, mut longest) = (u64::max_value(), 0);
+        for _ in 0..1000 {
+            let ms = as_millis(backoff(config, tries));
+            shortest = min(shortest, ms);
+            longest = max(longest, ms);
+        }
+        (shortest, longest)
+    }
+
+    #[test]
+    fn test_backoff_bounds() {
+        let config = PoolConfig {
+            min_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(1000),
+            ..PoolConfig::default()
+        };
+
+        // The backoff doubles with each try, up to the limit, and is always between half of that
+        // and all of it
+        let expected = [(1, 100), (2, 200), (3, 400), (4, 800), (5, 1000), (20, 1000)];
+        for &(tries, full) in expected.iter() {
+            let (shortest, longest) = bounds(&config, tries);
+            assert!(shortest >= full / 2, "{} tries: {} < {}", tries, shortest, full / 2);
+            assert!(longest <= full, "{} tries: {} > {}", tries, longest, full);
+
+            // Half of it is random
+            assert!(shortest < longest, "{} tries: always {}", tries, shortest);
+        }
+    }
+
+    #[test]
+    fn test_backoff_limits() {
+        // A limit below the first backoff wins
+        let config = PoolConfig {
+            min_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_millis(100),
+            ..PoolConfig::default()
+        };
+        let (shortest, longest) = bounds(&config, 1);
+        assert!(shortest >= 50 && longest <= 100);
+
+        // No backoff at all is allowed
+        let config = PoolConfig {
+            min_backoff: Duration::from_millis(0),
+            max_backoff: Duration::from_millis(0),
+            ..PoolConfig::default()
+        };
+        assert_eq!(bounds(&config, 3), (0, 0));
+    }
+}
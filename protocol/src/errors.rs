@@ -5,6 +5,17 @@ use zippynfs::{ZipException, ZipErrorType};
 
 /// Returns a Thrift Error with corresponding NFS error inside.
 pub fn nfs_error(error: ZipErrorType) -> Error {
+    nfs_exception(error).into()
+}
+
+/// Like `nfs_error`, but with some details about what went wrong added to the message.
+pub fn nfs_error_details(error: ZipErrorType, details: &str) -> Error {
+    let mut exception = nfs_exception(error);
+    exception.message = format!("{} ({})", exception.message, details);
+    exception.into()
+}
+
+fn nfs_exception(error: ZipErrorType) -> ZipException {
     ZipException {
         error: Box::new(error),
         message: match error {
@@ -15,8 +26,13 @@ pub fn nfs_error(error: ZipErrorType) -> Error {
             ZipErrorType::NFSERR_NOTEMPTY => "NFSERR_NOTEMPTY: Directory not empty".to_owned(),
             ZipErrorType::NFSERR_NOENT => "NFSERR_NOENT: No such file or directory".to_owned(),
             ZipErrorType::NFSERR_NAMETOOLONG => "NFSERR_NAMETOOLONG: File name too long".to_owned(),
+            ZipErrorType::NFSERR_BAD_COOKIE => "NFSERR_BAD_COOKIE: Readdir cookie is stale".to_owned(),
+            ZipErrorType::NFSERR_TOOSMALL => "NFSERR_TOOSMALL: Reply buffer too small".to_owned(),
+            ZipErrorType::NFSERR_VERSION => "NFSERR_VERSION: Incompatible protocol version".to_owned(),
+            ZipErrorType::NFSERR_GRACE => "NFSERR_GRACE: Server is in its grace period".to_owned(),
+            ZipErrorType::NFSERR_INVAL => "NFSERR_INVAL: Invalid argument".to_owned(),
         },
-    }.into()
+    }
 }
 
 #[derive(Debug)]
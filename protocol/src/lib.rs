@@ -7,9 +7,22 @@ extern crate try_from;
 mod zippynfs;
 
 mod errors;
+mod locks;
+mod silly;
+mod version;
+
+pub mod transport;
+pub mod wire;
 
 pub use zippynfs::*;
 pub use errors::*;
+pub use locks::*;
+pub use silly::*;
+pub use version::*;
+
+/// The most file data a single READ or WRITE can move (1MiB).
+pub const MAX_IO_LEN: usize = 1 << 20;
 
-/// The maximum size of a message via Thrift... this appears to be a bug in the thrift rust impl.
-pub const MAX_BUF_LEN: usize = 4000;
+/// The largest message either end will send or accept: a full READ or WRITE, plus plenty of room
+/// for everything else in the message.
+pub const MAX_MSG_LEN: usize = MAX_IO_LEN + (64 << 10);
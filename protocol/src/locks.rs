@@ -0,0 +1,110 @@
+//! Advisory byte-range locks, as the server keeps them for everyone and a client keeps them for
+//! itself.
+//!
+//! Like POSIX `fcntl` locks, these only keep out other lockers, not readers or writers. Each lock
+//! belongs to an owner (e.g. a process) on a client. Read locks only conflict with write locks,
+//! and never with locks of the same owner. A lock or unlock by an owner replaces whatever it held
+//! in the range, so locks are split, merged and converted between kinds as needed.
+
+use std::cmp::min;
+use std::i64;
+
+use zippynfs::{ZipLock, ZipLockType};
+
+/// The first byte after the lock's range, where "up to EOF and beyond" is the largest.
+fn end(lock: &ZipLock) -> i64 {
+    if lock.end < 0 { i64::MAX } else { lock.end }
+}
+
+/// Whether the ranges of the two locks overlap.
+fn overlap(a: &ZipLock, b: &ZipLock) -> bool {
+    a.start < end(b) && b.start < end(a)
+}
+
+/// The locks on one file, along with the clients holding them.
+#[derive(Clone, Debug, Default)]
+pub struct LockSet {
+    // (client, lock). The locks of each owner are disjoint, and those of the same kind don't even
+    // touch.
+    locks: Vec<(i64, ZipLock)>,
+}
+
+impl LockSet {
+    pub fn new() -> LockSet {
+        LockSet::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.locks.is_empty()
+    }
+
+    /// All of the locks, along with the clients holding them.
+    pub fn locks(&self) -> &[(i64, ZipLock)] {
+        &self.locks
+    }
+
+    /// Return whether the owner on the client holds any locks.
+    pub fn holds(&self, client: i64, owner: i64) -> bool {
+        self.locks.iter().any(|&(holder, ref held)| {
+            holder == client && held.owner == owner
+        })
+    }
+
+    /// Find a lock held by someone else that keeps the client from taking the given lock, along
+    /// with its client. Nothing keeps anyone from unlocking.
+    pub fn conflict(&self, client: i64, lock: &ZipLock) -> Option<&(i64, ZipLock)> {
+        if lock.kind == ZipLockType::UNLOCK {
+            return None;
+        }
+
+        self.locks.iter().find(|&&(holder, ref held)| {
+            (holder != client || held.owner != lock.owner) && overlap(held, lock) &&
+                (held.kind == ZipLockType::WRITE || lock.kind == ZipLockType::WRITE)
+        })
+    }
+
+    /// Take the lock for the client, or unlock its range for an UNLOCK, replacing whatever the
+    /// same owner held in the range. This doesn't check for conflicts.
+    pub fn set(&mut self, client: i64, lock: &ZipLock) {
+        let mut merged = lock.clone();
+        let mut kept = Vec::with_capacity(self.locks.len() + 2);
+
+        for (holder, held) in self.locks.drain(..) {
+            if holder != client || held.owner != lock.owner {
+                kept.push((holder, held));
+            } else if held.kind == lock.kind && held.start <= end(lock) &&
+                       lock.start <= end(&held)
+            {
+                // Locks of the same kind that overlap or touch become one
+                merged.start = min(merged.start, held.start);
+                if end(&held) > end(&merged) {
+                    merged.end = held.end;
+                }
+            } else if !overlap(&held, lock) {
+                kept.push((holder, held));
+            } else {
+                // Only the parts outside the range are left
+                if held.start < lock.start {
+                    let mut before = held.clone();
+                    before.end = lock.start;
+                    kept.push((holder, before));
+                }
+                if end(&held) > end(lock) {
+                    let mut after = held;
+                    after.start = end(lock);
+                    kept.push((holder, after));
+                }
+            }
+        }
+
+        if lock.kind != ZipLockType::UNLOCK {
+            kept.push((client, merged));
+        }
+        self.locks = kept;
+    }
+
+    /// Drop all of the client's locks, e.g. because it has gone away.
+    pub fn remove_client(&mut self, client: i64) {
+        self.locks.retain(|&(holder, _)| holder != client);
+    }
+}
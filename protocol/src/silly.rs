@@ -0,0 +1,35 @@
+//! The names files are "silly renamed" to.
+//!
+//! On POSIX, a file that is unlinked while it is open can still be used until it is closed. The
+//! server has no idea which files are open through the kernel, so when a file that is open is
+//! unlinked, the client renames it to a hidden name instead, and removes it when the last handle
+//! is closed. The name says which client it belongs to, so that if that client dies first, the
+//! server can clean up after it. The server only does so for files it saw the client itself give
+//! that name, since anyone can make a file with a name like this.
+
+/// The start of every silly name
+pub const SILLY_PREFIX: &'static str = ".zippy";
+
+/// The name the given client gives the file with the given FID when unlinking it while it is
+/// open.
+pub fn silly_name(client: i64, fid: u64) -> String {
+    format!("{}{:016x}.{:x}", SILLY_PREFIX, client as u64, fid)
+}
+
+/// The client that gave a file the given name, if it is a silly name.
+pub fn silly_owner(name: &str) -> Option<i64> {
+    if !name.starts_with(SILLY_PREFIX) {
+        return None;
+    }
+
+    let mut parts = name[SILLY_PREFIX.len()..].splitn(2, '.');
+    match (parts.next(), parts.next()) {
+        (Some(client), Some(fid)) if client.len() == 16 => {
+            match (u64::from_str_radix(client, 16), u64::from_str_radix(fid, 16)) {
+                (Ok(client), Ok(_)) => Some(client as i64),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
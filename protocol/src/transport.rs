@@ -0,0 +1,55 @@
+//! Thrift transports that can carry messages of up to `MAX_MSG_LEN` bytes.
+//!
+//! The stock Thrift transports have fixed 4KB buffers and simply fail on any message that doesn't
+//! fit, which is where our old limit of 4000 bytes per READ or WRITE came from. Instead, both the
+//! client and the server use framed transports with buffers big enough for our largest message.
+//! Framing also means the reader knows the size of a whole message before it starts to parse it.
+
+use std::io::{Read, Write};
+
+use thrift::transport::{TFramedReadTransport, TFramedWriteTransport, TReadTransport,
+                        TReadTransportFactory, TWriteTransport, TWriteTransportFactory};
+
+use super::MAX_MSG_LEN;
+
+/// Wrap the given channel in a transport which can read any of our messages.
+pub fn read_transport<C: Read>(channel: C) -> TFramedReadTransport<C> {
+    TFramedReadTransport::with_capacity(MAX_MSG_LEN, channel)
+}
+
+/// Wrap the given channel in a transport which can write any of our messages.
+pub fn write_transport<C: Write>(channel: C) -> TFramedWriteTransport<C> {
+    TFramedWriteTransport::with_capacity(MAX_MSG_LEN, channel)
+}
+
+/// Creates a `read_transport` for each connection to the server.
+#[derive(Debug, Default)]
+pub struct ReadTransportFactory;
+
+impl ReadTransportFactory {
+    pub fn new() -> ReadTransportFactory {
+        ReadTransportFactory
+    }
+}
+
+impl TReadTransportFactory for ReadTransportFactory {
+    fn create(&self, channel: Box<Read + Send>) -> Box<TReadTransport + Send> {
+        Box::new(read_transport(channel))
+    }
+}
+
+/// Creates a `write_transport` for each connection to the server.
+#[derive(Debug, Default)]
+pub struct WriteTransportFactory;
+
+impl WriteTransportFactory {
+    pub fn new() -> WriteTransportFactory {
+        WriteTransportFactory
+    }
+}
+
+impl TWriteTransportFactory for WriteTransportFactory {
+    fn create(&self, channel: Box<Write + Send>) -> Box<TWriteTransport + Send> {
+        Box::new(write_transport(channel))
+    }
+}
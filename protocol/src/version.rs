@@ -0,0 +1,45 @@
+//! Protocol versions and capabilities, which clients and servers exchange in a HELLO.
+//!
+//! Clients and servers from different builds routinely talk to each other (e.g. during a
+//! rollout), so before doing anything else, a client says HELLO to find out whether the two can
+//! understand each other at all, and what the server supports.
+
+/// The version of the protocol spoken by this build. Bump this for any change to the protocol
+/// that older builds would not understand.
+///
+/// - 0: Servers from before HELLO. Clients treat them as having no capabilities.
+/// - 1: HELLO and capabilities.
+/// - 2: XIDs on requests that change the FS, and `NFSERR_INVAL`.
+pub const PROTOCOL_VERSION: i32 = 2;
+
+/// The oldest version of the protocol this build can still speak. Everything since 0 only adds
+/// optional fields, capabilities and errors, so we can still speak all of them.
+pub const MIN_PROTOCOL_VERSION: i32 = 0;
+
+/// The server buffers UNSTABLE writes until a COMMIT.
+pub const CAP_UNSTABLE_WRITES: i64 = 1 << 0;
+
+/// The server supports READDIRPLUS.
+pub const CAP_READDIRPLUS: i64 = 1 << 1;
+
+/// The server supports symbolic links.
+pub const CAP_SYMLINKS: i64 = 1 << 2;
+
+/// The server works on several requests from the same connection at once, and replies to each
+/// as soon as it is done, so replies may come back in a different order than the requests. A
+/// client can keep many requests in flight, and match the replies to them by their Thrift
+/// sequence numbers, which act as request IDs.
+pub const CAP_PIPELINING: i64 = 1 << 3;
+
+/// The server keeps track of which files clients have open, with OPEN and CLOSE.
+pub const CAP_OPEN_STATE: i64 = 1 << 4;
+
+/// The server gives each client a lease, which it keeps by sending RENEW, and cleans up after
+/// clients whose leases run out, e.g. the files they silly renamed.
+pub const CAP_LEASES: i64 = 1 << 5;
+
+/// The server keeps advisory byte-range locks, with LOCK and TESTLOCK.
+pub const CAP_LOCKS: i64 = 1 << 6;
+
+/// The server gives out delegations of files with DELEGATE, and recalls them with callbacks.
+pub const CAP_DELEGATIONS: i64 = 1 << 7;
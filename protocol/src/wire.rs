@@ -0,0 +1,102 @@
+//! Sizes of our messages in Thrift's compact protocol encoding.
+//!
+//! Thrift can't tell us how big a message is until it has been written, but sometimes we need to
+//! know ahead of time (e.g. to fill a READDIR reply right up to the size the client asked for).
+//! These functions compute exactly how many bytes the compact protocol uses for our types.
+
+use zippynfs::{ZipDirEntry, ZipDirEntryPlus, ZipFattr, ZipFileHandle, ZipTimeVal};
+
+/// The size of a field header. Our structs number their fields consecutively from 1, so the
+/// compact protocol always uses the 1-byte short form (a 4-bit delta and a 4-bit type).
+const FIELD_HEADER_LEN: usize = 1;
+
+/// The size of the marker at the end of every struct.
+const STOP_LEN: usize = 1;
+
+/// The size of an unsigned varint.
+pub fn varint_len(n: u64) -> usize {
+    let mut n = n;
+    let mut len = 1;
+    while n >= 0x80 {
+        n >>= 7;
+        len += 1;
+    }
+    len
+}
+
+/// The size of a signed integer. All of i16, i32, i64, and enums are zigzag-encoded varints.
+pub fn int_len(n: i64) -> usize {
+    varint_len(((n << 1) ^ (n >> 63)) as u64)
+}
+
+/// The size of a string or binary: a varint length followed by the bytes.
+pub fn string_len(s: &str) -> usize {
+    varint_len(s.len() as u64) + s.len()
+}
+
+/// The size of a list header. Lists of fewer than 15 elements fit the size in the type byte.
+pub fn list_header_len(num_elems: usize) -> usize {
+    if num_elems < 15 {
+        1
+    } else {
+        1 + varint_len(num_elems as u64)
+    }
+}
+
+pub fn file_handle_len(fh: &ZipFileHandle) -> usize {
+    FIELD_HEADER_LEN + int_len(fh.fid) + STOP_LEN
+}
+
+pub fn time_val_len(t: &ZipTimeVal) -> usize {
+    FIELD_HEADER_LEN + int_len(t.seconds) + FIELD_HEADER_LEN + int_len(t.useconds) + STOP_LEN
+}
+
+pub fn fattr_len(a: &ZipFattr) -> usize {
+    let ints = [
+        a.type_ as i64,
+        a.mode as i64,
+        a.nlink,
+        a.uid,
+        a.gid,
+        a.size,
+        a.blocksize,
+        a.rdev,
+        a.blocks,
+        a.fsid,
+        a.fid,
+    ];
+    let times = [&a.atime, &a.mtime, &a.ctime];
+
+    ints.iter().map(|&n| FIELD_HEADER_LEN + int_len(n)).sum::<usize>() +
+        times.iter().map(|t| FIELD_HEADER_LEN + time_val_len(t)).sum::<usize>() + STOP_LEN
+}
+
+pub fn dir_entry_len(e: &ZipDirEntry) -> usize {
+    FIELD_HEADER_LEN + int_len(e.fid) + FIELD_HEADER_LEN + string_len(&e.fname) +
+        FIELD_HEADER_LEN + int_len(e.type_ as i64) + STOP_LEN
+}
+
+pub fn dir_entry_plus_len(e: &ZipDirEntryPlus) -> usize {
+    FIELD_HEADER_LEN + int_len(e.fid) + FIELD_HEADER_LEN + string_len(&e.fname) +
+        FIELD_HEADER_LEN + file_handle_len(&e.file) + FIELD_HEADER_LEN +
+        fattr_len(&e.attributes) + STOP_LEN
+}
+
+/// The size of a READDIR or READDIRPLUS result apart from the entries themselves: the list
+/// header, the cookie verifier, and the eof flag (which lives entirely in its field header).
+pub fn read_dir_res_len(num_entries: usize, cookieverf: i64) -> usize {
+    FIELD_HEADER_LEN + list_header_len(num_entries) + FIELD_HEADER_LEN + int_len(cookieverf) +
+        FIELD_HEADER_LEN + STOP_LEN
+}
+
+/// The most that a reply to `method` can take on top of the result itself: the message header
+/// and the struct the result is wrapped in.
+pub fn reply_overhead(method: &str) -> usize {
+    // Protocol id, version and message type, sequence number (a varint i32), and method name
+    let header = 2 + varint_len(u32::max_value() as u64) + string_len(method);
+
+    // The result is field 0 of the wrapper, which needs a 2-byte long-form field header
+    let wrapper = 2 + STOP_LEN;
+
+    header + wrapper
+}
@@ -13,6 +13,11 @@ enum ZipErrorType {
    NFSERR_NOTEMPTY,
    NFSERR_STALE,
    NFSERR_NAMETOOLONG,
+   NFSERR_BAD_COOKIE,
+   NFSERR_TOOSMALL,
+   NFSERR_VERSION,
+   NFSERR_GRACE, // The server restarted recently, and only locks held before that can be taken
+   NFSERR_INVAL, // The arguments don't make sense, e.g. a WRITE whose count isn't its data's length
 }
 
 struct ZipTimeVal {
@@ -66,11 +71,13 @@ struct ZipAttrStat {
 struct ZipSattrArgs{
     1: required ZipFileHandle file;
     2: required ZipSattr attributes;
+    3: optional i64 client; // The client, as in OPEN, so that its own delegations aren't recalled
 }
 
 struct ZipDirOpArgs{
     1: required ZipFileHandle dir;
     2: required string filename;
+    3: optional i64 xid; // Request ID of a CREATE, MKDIR, REMOVE, RMDIR or RENAME, kept across retries
 }
 
 struct ZipDirOpRes{
@@ -82,6 +89,7 @@ struct ZipReadArgs{
     1: required ZipFileHandle file;
     2: required i64 offset;
     3: required i64 count;
+    4: optional i64 client; // The client, as in OPEN, so that its own delegations aren't recalled
 }
 
 struct ZipReadRes{
@@ -91,7 +99,9 @@ struct ZipReadRes{
 
 struct ZipReadDirArgs{
     1: required ZipFileHandle dir;
-    2: required i64 offset;
+    2: required i64 cookie; // FID of the last entry seen, or 0 to start from the beginning
+    3: required i64 cookieverf; // Verifier returned with the last page, or 0 to start
+    4: required i64 maxcount; // Max size of the reply in bytes, or 0 for as much as possible
 }
 
 struct ZipDirEntry {
@@ -102,6 +112,21 @@ struct ZipDirEntry {
 
 struct ZipReadDirRes{
     1: required list<ZipDirEntry> entries;
+    2: required i64 cookieverf;
+    3: required bool eof; // True if there are no entries after these
+}
+
+struct ZipDirEntryPlus {
+    1: required i64 fid;
+    2: required string fname;
+    3: required ZipFileHandle file;
+    4: required ZipFattr attributes;
+}
+
+struct ZipReadDirPlusRes{
+    1: required list<ZipDirEntryPlus> entries;
+    2: required i64 cookieverf;
+    3: required bool eof; // True if there are no entries after these
 }
 
 enum ZipWriteStable {
@@ -116,6 +141,7 @@ struct ZipWriteArgs{
     3: required i64 count;
     4: required binary data;
     5: required ZipWriteStable stable;
+    6: optional i64 client; // The client, as in OPEN, so that its own delegations aren't recalled
 }
 
 struct ZipWriteRes {
@@ -137,22 +163,110 @@ struct ZipStatFsRes{
     5: required i64 bavail;
 }
 
+struct ZipFsInfoRes{
+    1: required i64 rtmax; // The most data the server will return from one READ
+    2: required i64 wtmax; // The most data the server will accept in one WRITE
+    3: required i64 dtpref; // The preferred size of a READDIR reply
+    4: required i64 maxmsg; // The largest message the server will send or accept
+}
+
+struct ZipHelloArgs{
+    1: required i32 version; // The newest protocol version the client speaks
+}
+
+struct ZipHelloRes{
+    1: required i32 version; // The protocol version to use from now on
+    2: required i64 capabilities; // Bitmap of the CAP_* the server supports
+    3: required ZipFsInfoRes fsinfo;
+}
+
 struct ZipRenameArgs{
     1: required ZipDirOpArgs old_loc;
     2: required ZipDirOpArgs new_loc;
+    3: optional i64 client; // The client, as in OPEN, if it is tracking its open files for us
 }
 
 struct ZipCommitArgs{
     1: required ZipFileHandle file;
     2: required i64 count;
     3: required i64 offset;
+    4: optional i64 client; // The client, as in OPEN, so that its own delegations aren't recalled
 }
 
 struct ZipCommitRes{
     1: required i64 verf;
 }
 
+struct ZipOpenArgs{
+    1: required ZipFileHandle file;
+    2: required i64 client; // Identifies the client, e.g. a random number picked when mounting
+}
+
+struct ZipOpenRes{
+    1: required i64 opens; // How many times all clients have the file open, after this request
+    2: optional ZipFattr attributes; // The attributes of the file, for an OPEN
+}
+
+enum ZipLockType {
+    READ = 1,
+    WRITE = 2,
+    UNLOCK = 3,
+}
+
+struct ZipLock{
+    1: required ZipLockType kind;
+    2: required i64 start;
+    3: required i64 end; // The first byte after the range, or -1 for up to EOF and beyond
+    4: required i64 owner; // Identifies the holder within the client, e.g. a process
+    5: required i64 pid; // The process holding the lock, if it is on the same client, or 0
+}
+
+struct ZipLockArgs{
+    1: required ZipFileHandle file;
+    2: required i64 client; // As for OPEN
+    3: required ZipLock lock;
+    4: required bool reclaim; // Taking back a lock held before the server restarted
+}
+
+struct ZipLockRes{
+    1: required bool granted; // For TESTLOCK, whether the lock would be granted
+    2: optional ZipLock conflict; // A lock held by someone else that is in the way
+    3: required i64 epoch; // As for NULL, to tell when locks have to be reclaimed
+}
+
+enum ZipDelegationType {
+    NONE = 0,
+    READ = 1, // Nobody else is changing the file
+    WRITE = 2, // Nobody else is even using the file
+}
+
+struct ZipCallbackArgs{
+    1: required i64 client;
+    2: required string addr; // The "IP:Port" address the client takes callbacks at
+}
+
+struct ZipDelegateArgs{
+    1: required ZipFileHandle file;
+    2: required i64 client;
+    3: required ZipDelegationType kind; // NONE gives back whatever the client has
+    4: required bool reclaim; // Taking back a delegation held before the server restarted
+}
+
+struct ZipDelegateRes{
+    1: required ZipDelegationType granted;
+    2: optional ZipFattr attributes; // The attributes of the file, as of the grant
+    3: required i64 epoch; // As for NULL, to tell when delegations have to be reclaimed
+}
+
+struct ZipRecallArgs{
+    1: required ZipFileHandle file;
+    2: required ZipDelegationType kind;
+}
+
+// Each request is one framed Thrift message. Its sequence number identifies the request, and the
+// reply carries the same one. A server with CAP_PIPELINING may reply out of order.
 service Zippynfs {
+   ZipHelloRes hello(1:ZipHelloArgs fsargs) throws (1: ZipException ex);
    i64 null(); // Returns the epoch at the server
    ZipAttrStat getattr(1:ZipFileHandle fhandle) throws (1: ZipException ex);
    ZipAttrStat setattr(1:ZipSattrArgs fsargs) throws (1: ZipException ex);
@@ -165,6 +279,24 @@ service Zippynfs {
    ZipDirOpRes mkdir(1:ZipCreateArgs fsargs) throws (1: ZipException ex);
    void rmdir(1:ZipDirOpArgs fsargs) throws (1: ZipException ex);
    ZipReadDirRes readdir(1:ZipReadDirArgs fsargs) throws (1: ZipException ex);
+   ZipReadDirPlusRes readdirplus(1:ZipReadDirArgs fsargs) throws (1: ZipException ex);
    ZipStatFsRes statfs(1:ZipFileHandle fhandle) throws (1: ZipException ex);
+   ZipFsInfoRes fsinfo(1:ZipFileHandle fhandle) throws (1: ZipException ex);
    ZipCommitRes commit(1:ZipCommitArgs fsargs) throws (1: ZipException ex);
+   ZipOpenRes open(1:ZipOpenArgs fsargs) throws (1: ZipException ex);
+   ZipOpenRes close(1:ZipOpenArgs fsargs) throws (1: ZipException ex);
+   // Keeps the client's lease, and returns how long it lasts in seconds
+   i64 renew(1:i64 client) throws (1: ZipException ex);
+   ZipLockRes lock(1:ZipLockArgs fsargs) throws (1: ZipException ex);
+   ZipLockRes testlock(1:ZipLockArgs fsargs) throws (1: ZipException ex);
+   void setcallback(1:ZipCallbackArgs fsargs) throws (1: ZipException ex);
+   ZipDelegateRes delegate(1:ZipDelegateArgs fsargs) throws (1: ZipException ex);
+}
+
+// Calls from the server to a client, on a connection the server makes to the address the client
+// gave in SETCALLBACK.
+service ZippyCallback {
+   // Takes back the client's delegation of the file. The client commits whatever it wrote under
+   // a WRITE delegation before replying.
+   void recall(1:ZipRecallArgs fsargs) throws (1: ZipException ex);
 }
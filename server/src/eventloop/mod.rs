@@ -0,0 +1,371 @@
+//! An event-driven core for the Thrift server.
+//!
+//! `TServer` gives each connection a thread from a fixed pool for as long as it is open, so a
+//! handful of idle clients starve everyone else, and one slow fsync holds up its client's
+//! connection. Instead, one thread multiplexes all of the connections with `mio`: it accepts
+//! them, reads framed requests as they arrive, and writes replies back, without ever blocking.
+//! The requests themselves, and so all of the blocking FS work, run on a fixed pool of workers.
+//! Idle connections only cost a socket.
+//!
+//! A connection may have several requests running at once, and their replies are sent in the
+//! order they finish; Thrift clients match them up by sequence number.
+
+mod pool;
+
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::net::{self, SocketAddr};
+use std::sync::Arc;
+use std::sync::mpsc::channel;
+
+use mio::{Events, Poll, PollOpt, Ready, Registration, Token};
+use mio::net::{TcpListener, TcpStream};
+
+use thrift::server::TProcessor;
+
+use zippyrpc::MAX_MSG_LEN;
+
+use self::pool::{Done, Job, Pool};
+
+pub use self::pool::park;
+
+/// The token of the listening socket
+const LISTENER: Token = Token(0);
+
+/// The token the workers use to wake up the event loop when they finish something
+const WAKER: Token = Token(1);
+
+/// The token of the first connection. Tokens are never reused, so that a reply can't go to a
+/// new connection that happens to get the token of a closed one.
+const FIRST_CONN: usize = 2;
+
+/// The most requests from one connection that can be waiting for or running on a worker. Once
+/// a connection has this many, we stop reading from it until some of them finish.
+const MAX_IN_FLIGHT: usize = 16;
+
+/// We also stop reading from a connection while it has this many bytes of replies that it
+/// hasn't read yet.
+const MAX_UNSENT: usize = 4 * MAX_MSG_LEN;
+
+/// How many requests can wait for a worker, per worker. Beyond that, requests wait in the event
+/// loop's backlog.
+const QUEUE_PER_WORKER: usize = 4;
+
+/// How much to try to read from a connection at once
+const READ_LEN: usize = 64 << 10;
+
+/// One client's connection.
+struct Conn {
+    stream: TcpStream,
+
+    /// What we have read that isn't a whole request yet
+    incoming: Vec<u8>,
+
+    /// Replies waiting to be written
+    outgoing: Vec<u8>,
+
+    /// Requests waiting for or running on a worker
+    in_flight: usize,
+
+    /// We stopped reading because of `MAX_IN_FLIGHT` or `MAX_UNSENT`, so we have to start again
+    /// ourselves rather than waiting for the socket to be readable.
+    paused: bool,
+}
+
+impl Conn {
+    fn new(stream: TcpStream) -> Conn {
+        Conn {
+            stream,
+            incoming: Vec::new(),
+            outgoing: Vec::new(),
+            in_flight: 0,
+            paused: false,
+        }
+    }
+
+    /// Whether we can take more requests from this connection.
+    fn can_read(&self) -> bool {
+        self.in_flight < MAX_IN_FLIGHT && self.outgoing.len() < MAX_UNSENT
+    }
+
+    /// Read everything available, and put the whole requests in `backlog`. Fails if the
+    /// connection should be closed.
+    fn read(&mut self, token: Token, backlog: &mut VecDeque<Job>) -> io::Result<()> {
+        loop {
+            while self.can_read() {
+                match take_frame(&mut self.incoming)? {
+                    Some(request) => {
+                        backlog.push_back(Job { token, request });
+                        self.in_flight += 1;
+                    }
+                    None => break,
+                }
+            }
+
+            self.paused = !self.can_read();
+            if self.paused {
+                return Ok(());
+            }
+
+            let len = self.incoming.len();
+            self.incoming.resize(len + READ_LEN, 0);
+            let result = self.stream.read(&mut self.incoming[len..]);
+            self.incoming.truncate(len + *result.as_ref().unwrap_or(&0));
+
+            match result {
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "client hung up",
+                    ))
+                }
+                Ok(_) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Queue a reply to be written.
+    fn reply(&mut self, reply: &[u8]) {
+        self.outgoing.extend_from_slice(&frame_len(reply.len()));
+        self.outgoing.extend_from_slice(reply);
+    }
+
+    /// Write as much as we can of the replies. Fails if the connection should be closed.
+    fn flush(&mut self) -> io::Result<()> {
+        while !self.outgoing.is_empty() {
+            match self.stream.write(&self.outgoing) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    self.outgoing.drain(..n);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The 4-byte big-endian length that precedes each frame.
+fn frame_len(len: usize) -> [u8; 4] {
+    [(len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8]
+}
+
+/// Take the first whole frame out of `buf`, if there is one. Fails if the frame is too big to
+/// be one of our messages.
+fn take_frame(buf: &mut Vec<u8>) -> io::Result<Option<Vec<u8>>> {
+    if buf.len() < 4 {
+        return Ok(None);
+    }
+
+    let len = buf[..4].iter().fold(0usize, |n, &b| n << 8 | b as usize);
+    if len > MAX_MSG_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("frame of {} bytes is too big", len),
+        ));
+    }
+
+    if buf.len() < 4 + len {
+        return Ok(None);
+    }
+
+    let frame = buf[4..4 + len].to_vec();
+    buf.drain(..4 + len);
+    Ok(Some(frame))
+}
+
+/// A Thrift server with an event loop and a pool of workers.
+pub struct Server<P> {
+    listener: TcpListener,
+    processor: Arc<P>,
+    workers: usize,
+}
+
+impl<P: TProcessor + Send + Sync + 'static> Server<P> {
+    /// Listen at the given address, running requests through `processor` on `workers` workers.
+    pub fn bind(addr: &str, processor: P, workers: usize) -> Result<Server<P>, String> {
+        let listener = net::TcpListener::bind(addr)
+            .and_then(TcpListener::from_std)
+            .map_err(|e| format!("Unable to listen at {}: {}", addr, e))?;
+
+        Ok(Server {
+            listener,
+            processor: Arc::new(processor),
+            workers,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Serve forever.
+    pub fn run(self) -> Result<(), String> {
+        let poll = Poll::new().map_err(|e| format!("{}", e))?;
+        poll.register(
+            &self.listener,
+            LISTENER,
+            Ready::readable(),
+            PollOpt::edge(),
+        ).map_err(|e| format!("{}", e))?;
+
+        let (registration, waker) = Registration::new2();
+        poll.register(&registration, WAKER, Ready::readable(), PollOpt::edge())
+            .map_err(|e| format!("{}", e))?;
+
+        let (done_tx, done_rx) = channel();
+        let pool = Pool::new(
+            self.workers,
+            self.workers * QUEUE_PER_WORKER,
+            self.processor.clone(),
+            done_tx,
+            waker.clone(),
+        )?;
+
+        let mut conns: HashMap<Token, Conn> = HashMap::new();
+        let mut next_token = FIRST_CONN;
+
+        // Requests that didn't fit in the pool's queue yet, oldest first
+        let mut backlog = VecDeque::new();
+
+        // Connections to close at the end of this iteration
+        let mut closed = Vec::new();
+
+        let mut events = Events::with_capacity(1024);
+
+        loop {
+            if let Err(e) = poll.poll(&mut events, None) {
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(format!("{}", e));
+            }
+
+            for event in events.iter() {
+                match event.token() {
+                    LISTENER => {
+                        self.accept(&poll, &mut conns, &mut next_token);
+                    }
+
+                    // Replies are handled below, whatever woke us up
+                    WAKER => {}
+
+                    token => {
+                        let conn = match conns.get_mut(&token) {
+                            Some(conn) => conn,
+                            None => continue,
+                        };
+
+                        let readiness = event.readiness();
+                        let mut result = Ok(());
+                        if readiness.is_writable() {
+                            result = conn.flush();
+                        }
+                        if result.is_ok() && (readiness.is_readable() || conn.paused) &&
+                            conn.can_read()
+                        {
+                            result = conn.read(token, &mut backlog);
+                        }
+
+                        if let Err(e) = result {
+                            debug!("Closing connection {:?}: {}", token, e);
+                            closed.push(token);
+                        }
+                    }
+                }
+            }
+
+            // Send back what the workers have finished. Clear the waker first, so that anything
+            // finished after we look wakes us up again.
+            let _ = waker.set_readiness(Ready::empty());
+            while let Ok(Done { token, reply }) = done_rx.try_recv() {
+                let conn = match conns.get_mut(&token) {
+                    Some(conn) => conn,
+
+                    // The client hung up in the meantime
+                    None => continue,
+                };
+
+                conn.in_flight -= 1;
+
+                let mut result = match reply {
+                    Some(ref reply) if reply.is_empty() => Ok(()),
+                    Some(reply) => {
+                        conn.reply(&reply);
+                        conn.flush()
+                    }
+                    None => Err(io::Error::new(io::ErrorKind::InvalidData, "bad request")),
+                };
+
+                // It may have been waiting for this to finish
+                if result.is_ok() && conn.paused && conn.can_read() {
+                    result = conn.read(token, &mut backlog);
+                }
+
+                if let Err(e) = result {
+                    debug!("Closing connection {:?}: {}", token, e);
+                    closed.push(token);
+                }
+            }
+
+            for token in closed.drain(..) {
+                if let Some(conn) = conns.remove(&token) {
+                    let _ = poll.deregister(&conn.stream);
+                }
+            }
+
+            // Hand as much of the backlog to the workers as they have room for
+            while let Some(job) = backlog.pop_front() {
+                if !conns.contains_key(&job.token) {
+                    continue;
+                }
+
+                if let Err(job) = pool.try_submit(job) {
+                    backlog.push_front(job);
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Accept all of the pending connections.
+    fn accept(&self, poll: &Poll, conns: &mut HashMap<Token, Conn>, next_token: &mut usize) {
+        loop {
+            let (stream, peer) = match self.listener.accept() {
+                Ok(accepted) => accepted,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    warn!("Failed to accept a connection: {}", e);
+                    return;
+                }
+            };
+
+            let token = Token(*next_token);
+            *next_token += 1;
+
+            let _ = stream.set_nodelay(true);
+            if let Err(e) = poll.register(
+                &stream,
+                token,
+                Ready::readable() | Ready::writable(),
+                PollOpt::edge(),
+            )
+            {
+                warn!("Failed to register the connection from {}: {}", peer, e);
+                continue;
+            }
+
+            debug!("New connection {:?} from {}", token, peer);
+            conns.insert(token, Conn::new(stream));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test;
@@ -0,0 +1,204 @@
+//! A fixed pool of workers, which run requests through the Thrift processor. This is where all of
+//! the blocking FS work happens, so that it never holds up the event loop.
+//!
+//! A request may also have to wait for a client to do something else first, like recalling a
+//! delegation, which waits for the holder to COMMIT what it wrote. That needs a worker too, so if
+//! every worker were waiting like this, they would all wait until they time out. So a worker that
+//! is about to wait `park`s, and another worker stands in for it until it is back.
+
+use std::cell::RefCell;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, Sender, SyncSender, TrySendError};
+use std::thread;
+
+use mio::{Ready, SetReadiness, Token};
+
+use thrift::protocol::{TCompactInputProtocol, TCompactOutputProtocol};
+use thrift::server::TProcessor;
+
+/// A request from the connection with the given token.
+pub struct Job {
+    pub token: Token,
+    pub request: Vec<u8>,
+}
+
+/// The reply to a `Job`. There is no reply if the request couldn't be processed, in which case
+/// the connection should be closed, as `TServer` does.
+pub struct Done {
+    pub token: Token,
+    pub reply: Option<Vec<u8>>,
+}
+
+/// The most workers that can be parked at once with a stand-in. Any more just wait.
+const MAX_PARKED: usize = 64;
+
+/// What the workers of a pool share, for standing in for each other.
+struct Shared {
+    /// Starts a worker with the given name
+    start_worker: Box<Fn(&Arc<Shared>, String) -> io::Result<()> + Send + Sync>,
+
+    /// The number of parked workers with a stand-in
+    parked: AtomicUsize,
+
+    /// The number of workers that should exit once they are done with their job, because the
+    /// ones they stood in for are back
+    surplus: AtomicUsize,
+}
+
+thread_local! {
+    /// The pool the current thread works for, if it is a worker
+    static POOL: RefCell<Option<Arc<Shared>>> = RefCell::new(None);
+}
+
+/// The pool. Workers exit when it is dropped.
+pub struct Pool {
+    jobs: SyncSender<Job>,
+}
+
+impl Pool {
+    /// Start `workers` workers, with room for `queue_len` jobs waiting for them. Replies are
+    /// sent to `done`, and `waker` is set readable after each one.
+    pub fn new<P>(
+        workers: usize,
+        queue_len: usize,
+        processor: Arc<P>,
+        done: Sender<Done>,
+        waker: SetReadiness,
+    ) -> Result<Pool, String>
+    where
+        P: TProcessor + Send + Sync + 'static,
+    {
+        let (jobs, queue) = sync_channel(queue_len);
+        let queue = Arc::new(Mutex::new(queue));
+
+        // A `Sender` can only be used by one thread at a time
+        let done = Mutex::new(done);
+
+        let shared = Arc::new(Shared {
+            start_worker: Box::new(move |shared: &Arc<Shared>, name: String| {
+                let queue = queue.clone();
+                let processor = processor.clone();
+                let done = done.lock().unwrap().clone();
+                let waker = waker.clone();
+                let shared = shared.clone();
+
+                thread::Builder::new()
+                    .name(name)
+                    .spawn(move || work(&queue, &*processor, &done, &waker, shared))
+                    .map(|_| ())
+            }),
+            parked: AtomicUsize::new(0),
+            surplus: AtomicUsize::new(0),
+        });
+
+        for i in 0..workers {
+            (shared.start_worker)(&shared, format!("worker-{}", i))
+                .map_err(|e| format!("Unable to start worker {}: {}", i, e))?;
+        }
+
+        Ok(Pool { jobs })
+    }
+
+    /// Queue a job, unless the queue is full, in which case it is handed back.
+    pub fn try_submit(&self, job: Job) -> Result<(), Job> {
+        match self.jobs.try_send(job) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(job)) |
+            Err(TrySendError::Disconnected(job)) => Err(job),
+        }
+    }
+}
+
+/// Run `f`, which may wait a long time for something that needs a worker to happen (e.g. for a
+/// client to answer a recall). If this is a worker, another one stands in for it meanwhile.
+pub fn park<T, F: FnOnce() -> T>(f: F) -> T {
+    let shared = match POOL.with(|pool| pool.borrow().clone()) {
+        Some(shared) => shared,
+        None => return f(),
+    };
+
+    if shared.parked.fetch_add(1, Ordering::SeqCst) >= MAX_PARKED {
+        shared.parked.fetch_sub(1, Ordering::SeqCst);
+        return f();
+    }
+    if let Err(e) = (shared.start_worker)(&shared, "stand-in".to_owned()) {
+        warn!("Unable to start a worker to stand in for a parked one: {}", e);
+        shared.parked.fetch_sub(1, Ordering::SeqCst);
+        return f();
+    }
+
+    let result = f();
+
+    shared.parked.fetch_sub(1, Ordering::SeqCst);
+    shared.surplus.fetch_add(1, Ordering::SeqCst);
+    result
+}
+
+/// Take one from the count, unless it is already 0. Returns whether it did.
+fn take_one(count: &AtomicUsize) -> bool {
+    let mut n = count.load(Ordering::SeqCst);
+    while n > 0 {
+        match count.compare_exchange(n, n - 1, Ordering::SeqCst, Ordering::SeqCst) {
+            Ok(_) => return true,
+            Err(actual) => n = actual,
+        }
+    }
+    false
+}
+
+/// The main loop of a worker.
+fn work<P: TProcessor>(
+    queue: &Mutex<Receiver<Job>>,
+    processor: &P,
+    done: &Sender<Done>,
+    waker: &SetReadiness,
+    shared: Arc<Shared>,
+) {
+    POOL.with(|pool| *pool.borrow_mut() = Some(shared.clone()));
+
+    loop {
+        // Only hold the lock while waiting, not while working
+        let job = match queue.lock().unwrap().recv() {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+
+        let reply = process(processor, &job.request);
+
+        if done.send(Done {
+            token: job.token,
+            reply,
+        }).is_err()
+        {
+            return;
+        }
+        let _ = waker.set_readiness(Ready::readable());
+
+        // Workers are all alike, so whichever finishes first makes way for a parked one that is
+        // back
+        if take_one(&shared.surplus) {
+            return;
+        }
+    }
+}
+
+/// Run one request (a whole frame) through the processor, and return the reply.
+pub fn process<P: TProcessor>(processor: &P, request: &[u8]) -> Option<Vec<u8>> {
+    let mut reply = Vec::new();
+
+    let result = {
+        let mut i_prot = TCompactInputProtocol::new(request);
+        let mut o_prot = TCompactOutputProtocol::new(&mut reply);
+        processor.process(&mut i_prot, &mut o_prot)
+    };
+
+    match result {
+        Ok(()) => Some(reply),
+        Err(e) => {
+            warn!("Failed to process a request: {}", e);
+            None
+        }
+    }
+}
@@ -0,0 +1,232 @@
+//! Unit tests for the event loop
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use mio::{Registration, Token};
+
+use thrift::protocol::{TCompactInputProtocol, TCompactOutputProtocol, TFieldIdentifier,
+                       TInputProtocol, TMessageIdentifier, TMessageType, TOutputProtocol,
+                       TStructIdentifier, TType};
+use thrift;
+use thrift::server::TProcessor;
+use thrift::transport::{TIoChannel, TTcpChannel};
+
+use zippyrpc::{TZippynfsSyncClient, ZipFileHandle, ZipFtype, ZippynfsSyncClient,
+               ZippynfsSyncProcessor, MAX_MSG_LEN};
+use zippyrpc::transport::{read_transport, write_transport};
+
+use handler::ZippynfsServer;
+use shared::SharedHandler;
+use testutil::run_with_clone_fs;
+
+use super::{frame_len, take_frame, Server};
+use super::pool::{park, Job, Pool};
+
+/// Encode a GETATTR of the given FID, with the given sequence number.
+fn getattr_request(fid: i64, seqid: i32) -> Vec<u8> {
+    let mut request = Vec::new();
+    {
+        let mut o_prot = TCompactOutputProtocol::new(&mut request);
+        o_prot
+            .write_message_begin(&TMessageIdentifier::new("getattr", TMessageType::Call, seqid))
+            .unwrap();
+        o_prot
+            .write_struct_begin(&TStructIdentifier::new("getattr_args"))
+            .unwrap();
+        o_prot
+            .write_field_begin(&TFieldIdentifier::new("fhandle", TType::Struct, 1))
+            .unwrap();
+        ZipFileHandle::new(fid)
+            .write_to_out_protocol(&mut o_prot)
+            .unwrap();
+        o_prot.write_field_end().unwrap();
+        o_prot.write_field_stop().unwrap();
+        o_prot.write_struct_end().unwrap();
+        o_prot.write_message_end().unwrap();
+    }
+    request
+}
+
+#[test]
+fn test_take_frame() {
+    let mut buf = frame_len(3).to_vec();
+    buf.extend_from_slice(b"abc");
+    buf.extend_from_slice(&frame_len(2));
+    buf.push(b'd');
+
+    // One whole frame, and part of another
+    assert_eq!(take_frame(&mut buf).unwrap().unwrap(), b"abc");
+    assert!(take_frame(&mut buf).unwrap().is_none());
+
+    buf.push(b'e');
+    assert_eq!(take_frame(&mut buf).unwrap().unwrap(), b"de");
+    assert!(buf.is_empty());
+    assert!(take_frame(&mut buf).unwrap().is_none());
+
+    // Too big
+    let mut buf = frame_len(MAX_MSG_LEN + 1).to_vec();
+    assert!(take_frame(&mut buf).is_err());
+}
+
+#[test]
+fn test_idle_clients_dont_starve() {
+    run_with_clone_fs("test_files/test1", |fspath| {
+        let handler = Arc::new(ZippynfsServer::new(fspath.to_owned()));
+        let processor = ZippynfsSyncProcessor::new(SharedHandler(handler));
+
+        // Just one worker
+        let server = Server::bind("127.0.0.1:0", processor, 1).unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || server.run());
+
+        // Lots of clients that connect and then do nothing
+        let idle: Vec<_> = (0..20).map(|_| TcpStream::connect(addr).unwrap()).collect();
+
+        // Don't get in the way of one that does something
+        let mut channel = TTcpChannel::new();
+        channel.open(&addr.to_string()).unwrap();
+        let (i_chan, o_chan) = channel.split().unwrap();
+        let mut client = ZippynfsSyncClient::new(
+            TCompactInputProtocol::new(read_transport(i_chan)),
+            TCompactOutputProtocol::new(write_transport(o_chan)),
+        );
+
+        for _ in 0..10 {
+            let root = client.getattr(ZipFileHandle::new(1)).unwrap();
+            assert_eq!(root.attributes.type_, ZipFtype::NFDIR);
+        }
+
+        drop(idle);
+    })
+}
+
+#[test]
+fn test_bad_frame_closes_connection() {
+    run_with_clone_fs("test_files/test1", |fspath| {
+        let handler = Arc::new(ZippynfsServer::new(fspath.to_owned()));
+        let processor = ZippynfsSyncProcessor::new(SharedHandler(handler));
+
+        let server = Server::bind("127.0.0.1:0", processor, 2).unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || server.run());
+
+        // A frame that is too big
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(&frame_len(MAX_MSG_LEN + 1)).unwrap();
+        assert_eq!(stream.read(&mut [0; 16]).unwrap(), 0);
+
+        // A frame that isn't a Thrift message
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(&frame_len(3)).unwrap();
+        stream.write_all(&[1, 2, 3]).unwrap();
+        assert_eq!(stream.read(&mut [0; 16]).unwrap(), 0);
+    })
+}
+
+#[test]
+fn test_pipelined_requests() {
+    run_with_clone_fs("test_files/test1", |fspath| {
+        let handler = Arc::new(ZippynfsServer::new(fspath.to_owned()));
+        let processor = ZippynfsSyncProcessor::new(SharedHandler(handler));
+
+        let server = Server::bind("127.0.0.1:0", processor, 4).unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || server.run());
+
+        // Send lots of requests before reading any of the replies
+        let mut stream = TcpStream::connect(addr).unwrap();
+        for seqid in 1..33 {
+            let request = getattr_request(1, seqid);
+            stream.write_all(&frame_len(request.len())).unwrap();
+            stream.write_all(&request).unwrap();
+        }
+
+        // Every one gets a reply, in whatever order
+        let mut buf = Vec::new();
+        let mut seqids = Vec::new();
+        while seqids.len() < 32 {
+            match take_frame(&mut buf).unwrap() {
+                Some(reply) => {
+                    let mut i_prot = TCompactInputProtocol::new(&reply[..]);
+                    let ident = i_prot.read_message_begin().unwrap();
+                    assert_eq!(ident.message_type, TMessageType::Reply);
+                    seqids.push(ident.sequence_number);
+                }
+                None => {
+                    let mut chunk = [0; 4096];
+                    let n = stream.read(&mut chunk).unwrap();
+                    assert!(n > 0);
+                    buf.extend_from_slice(&chunk[..n]);
+                }
+            }
+        }
+
+        seqids.sort();
+        assert_eq!(seqids, (1..33).collect::<Vec<_>>());
+    })
+}
+
+/// A processor whose requests are a single byte: a 1 opens the gate, and a 0 waits for it to open
+/// (for at most a few seconds), parked. The reply is whether the gate is open.
+#[derive(Default)]
+struct Gate {
+    open: Mutex<bool>,
+    opened: Condvar,
+}
+
+impl TProcessor for Gate {
+    fn process(
+        &self,
+        i_prot: &mut TInputProtocol,
+        o_prot: &mut TOutputProtocol,
+    ) -> thrift::Result<()> {
+        let open = if i_prot.read_byte()? == 1 {
+            *self.open.lock().unwrap() = true;
+            self.opened.notify_all();
+            true
+        } else {
+            park(|| {
+                let deadline = Instant::now() + Duration::from_secs(5);
+                let mut open = self.open.lock().unwrap();
+                while !*open && Instant::now() < deadline {
+                    open = self.opened
+                        .wait_timeout(open, Duration::from_millis(100))
+                        .unwrap()
+                        .0;
+                }
+                *open
+            })
+        };
+
+        o_prot.write_bool(open)?;
+        o_prot.flush()
+    }
+}
+
+#[test]
+fn test_park() {
+    let (done_tx, done_rx) = channel();
+    let (_registration, waker) = Registration::new2();
+
+    // Just one worker, which waits for the gate, so the request that opens it needs another
+    let pool = Pool::new(1, 4, Arc::new(Gate::default()), done_tx, waker).unwrap();
+    for &(token, request) in &[(0, 0), (1, 1), (2, 0)] {
+        let job = Job {
+            token: Token(token),
+            request: vec![request],
+        };
+        assert!(pool.try_submit(job).is_ok());
+    }
+
+    for _ in 0..3 {
+        let done = done_rx.recv_timeout(Duration::from_secs(4)).unwrap();
+        let reply = done.reply.unwrap();
+        let open = TCompactInputProtocol::new(&reply[..]).read_bool().unwrap();
+        assert!(open, "request {:?} didn't see the gate open", done.token);
+    }
+}
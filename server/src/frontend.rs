@@ -0,0 +1,64 @@
+//! What the NFSv3 and 9P frontends have in common.
+//!
+//! Both turn requests in their own protocol into calls on the handler, and the handler's errors
+//! back into their protocol's failures: an `nfsstat3` for NFSv3, and an errno for 9P. Each
+//! frontend says how with an implementation of `Statuses`.
+
+use thrift;
+
+use zippyrpc::*;
+
+/// Why a request did not succeed.
+pub enum Fail {
+    /// We could not decode the request
+    Garbage(String),
+
+    /// The request failed with the given status of the frontend's protocol
+    Status(u32),
+
+    /// The handler failed with the given error
+    Nfs(ZipErrorType),
+
+    /// The handler failed in some other way, which has been logged
+    Fault,
+}
+
+impl From<String> for Fail {
+    fn from(e: String) -> Fail {
+        Fail::Garbage(e)
+    }
+}
+
+impl From<thrift::Error> for Fail {
+    fn from(e: thrift::Error) -> Fail {
+        match ZipError::from(e) {
+            ZipError::Nfs(error, _) => Fail::Nfs(error),
+            e => {
+                error!("Handler failed: {:?}", e);
+                Fail::Fault
+            }
+        }
+    }
+}
+
+impl Fail {
+    /// Return the status of the frontend's protocol to fail with, or why the request could not be
+    /// decoded.
+    pub fn status<S: Statuses>(self) -> Result<u32, String> {
+        match self {
+            Fail::Garbage(e) => Err(e),
+            Fail::Status(status) => Ok(status),
+            Fail::Nfs(error) => Ok(S::nfs(error)),
+            Fail::Fault => Ok(S::fault()),
+        }
+    }
+}
+
+/// The statuses a frontend's protocol fails with.
+pub trait Statuses {
+    /// The status for an error from the handler
+    fn nfs(error: ZipErrorType) -> u32;
+
+    /// The status for any other failure of the handler
+    fn fault() -> u32;
+}
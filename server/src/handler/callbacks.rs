@@ -0,0 +1,174 @@
+//! The callback channels to clients, over which the server recalls their delegations.
+//!
+//! A client that wants delegations first tells the server where it takes callbacks with
+//! SETCALLBACK, which it sends again every time it renews its lease, so that a restarted server
+//! hears it again too. Recalls are rare, so the server makes a new connection for each one rather
+//! than keeping one open to every client. A request can conflict with the delegations of several
+//! clients, so they are all recalled at once, each from its own thread, and the request waits for
+//! all of them together for at most `RECALL_TIMEOUT_SECS`. A client that can't be reached in time
+//! loses its delegation anyway, since the request that conflicts with it can't wait forever, and
+//! is forgotten until it sends SETCALLBACK again, so that it doesn't hold up the next request too.
+
+use std::cmp::min;
+use std::collections::HashMap;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::Mutex;
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use thrift::protocol::{TCompactInputProtocol, TCompactOutputProtocol};
+use thrift::transport::{TIoChannel, TTcpChannel};
+
+use zippyrpc::{TZippyCallbackSyncClient, ZipDelegationType, ZipFileHandle, ZipRecallArgs,
+               ZippyCallbackSyncClient};
+use zippyrpc::transport::{read_transport, write_transport};
+
+use super::Fid;
+
+/// How long a request waits for the answers to all of its recalls, in seconds. Recalling a WRITE
+/// delegation waits for the client to commit, so this is generous.
+const RECALL_TIMEOUT_SECS: u64 = 30;
+
+/// How long to wait to connect to a client, in seconds. A client that is around answers quickly.
+const CONNECT_TIMEOUT_SECS: u64 = 5;
+
+/// Where each client takes callbacks.
+#[derive(Debug, Default)]
+pub struct Callbacks {
+    /// client -> "IP:Port" address
+    addrs: Mutex<HashMap<i64, String>>,
+}
+
+impl Callbacks {
+    pub fn new() -> Callbacks {
+        Callbacks::default()
+    }
+
+    /// Note where the client takes callbacks.
+    pub fn set(&self, client: i64, addr: String) {
+        self.addrs.lock().unwrap().insert(client, addr);
+    }
+
+    /// Return whether we know where the client takes callbacks.
+    pub fn has(&self, client: i64) -> bool {
+        self.addrs.lock().unwrap().contains_key(&client)
+    }
+
+    /// Forget where the client takes callbacks, e.g. because it has gone away.
+    pub fn forget_client(&self, client: i64) {
+        self.addrs.lock().unwrap().remove(&client);
+    }
+
+    /// Tell the clients that their delegations of the file have been taken away, and wait for
+    /// them to finish giving them back, or until the time is up. Return the clients that failed to
+    /// along with why, which are forgotten.
+    pub fn recall(
+        &self,
+        fid: Fid,
+        holders: Vec<(i64, ZipDelegationType)>,
+    ) -> Vec<(i64, String)> {
+        let deadline = Instant::now() + Duration::from_secs(RECALL_TIMEOUT_SECS);
+        let mut failed = vec![];
+
+        // Recall them all at once
+        let (answers, answered) = channel();
+        let mut waiting = vec![];
+        for (client, kind) in holders {
+            let addr = match self.addrs.lock().unwrap().get(&client) {
+                Some(addr) => addr.clone(),
+                None => {
+                    failed.push((client, "No callback address".to_owned()));
+                    continue;
+                }
+            };
+
+            let answers = answers.clone();
+            let spawned = thread::Builder::new()
+                .name("recall".to_owned())
+                .spawn(move || {
+                    let result = recall_one(&addr, fid, kind, deadline);
+                    let _ = answers.send((client, result));
+                });
+            match spawned {
+                Ok(_) => waiting.push(client),
+                Err(e) => failed.push((client, format!("Unable to start a recall: {}", e))),
+            }
+        }
+
+        while !waiting.is_empty() {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+
+            match answered.recv_timeout(deadline - now) {
+                Ok((client, result)) => {
+                    waiting.retain(|&other| other != client);
+                    if let Err(e) = result {
+                        failed.push((client, e));
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+        for client in waiting {
+            failed.push((client, format!("No answer in {} s", RECALL_TIMEOUT_SECS)));
+        }
+
+        for &(client, _) in failed.iter() {
+            self.forget_client(client);
+        }
+        failed
+    }
+}
+
+/// Tell the client at the given address that its delegation of the file has been taken away, and
+/// wait for it to finish giving it back, until the deadline.
+fn recall_one(
+    addr: &str,
+    fid: Fid,
+    kind: ZipDelegationType,
+    deadline: Instant,
+) -> Result<(), String> {
+    let sock_addr = addr.to_socket_addrs()
+        .map_err(|e| format!("{}: {}", addr, e))?
+        .next();
+    let sock_addr = match sock_addr {
+        Some(sock_addr) => sock_addr,
+        None => return Err(format!("{}: no address", addr)),
+    };
+
+    // Never wait on a client past the deadline
+    let left = || {
+        let now = Instant::now();
+        if now < deadline {
+            Ok(deadline - now)
+        } else {
+            Err(format!("No answer from {} in time", addr))
+        }
+    };
+    let connect_timeout = min(left()?, Duration::from_secs(CONNECT_TIMEOUT_SECS));
+    let stream = TcpStream::connect_timeout(&sock_addr, connect_timeout).map_err(
+        |e| format!("Unable to connect to {}: {}", addr, e),
+    )?;
+    let timeout = left()?;
+    stream.set_read_timeout(Some(timeout)).map_err(
+        |e| format!("{}", e),
+    )?;
+    stream.set_write_timeout(Some(timeout)).map_err(
+        |e| format!("{}", e),
+    )?;
+
+    let (i_chan, o_chan) = TTcpChannel::with_stream(stream).split().map_err(
+        |e| format!("{:?}", e),
+    )?;
+    let mut client = ZippyCallbackSyncClient::new(
+        TCompactInputProtocol::new(read_transport(i_chan)),
+        TCompactOutputProtocol::new(write_transport(o_chan)),
+    );
+
+    client
+        .recall(ZipRecallArgs::new(ZipFileHandle::new(fid as i64), kind))
+        .map_err(|e| format!("{:?}", e))
+}
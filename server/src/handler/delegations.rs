@@ -0,0 +1,228 @@
+//! The delegations clients hold on files.
+//!
+//! A delegation is a promise that nobody else will change the file (READ) or even use it (WRITE)
+//! without the holder hearing about it first. So for as long as it holds one, a client can cache
+//! the file's attributes and data without checking them with the server, and under a WRITE one,
+//! keep its writes uncommitted even after closing the file. Clients with `CAP_DELEGATIONS` ask for
+//! one with DELEGATE, and the server recalls it over the client's callback channel (see
+//! `callbacks`) before it carries out a conflicting request from anyone else: a write, commit,
+//! change of attributes or REMOVE recalls every delegation of the file, and a READ or OPEN recalls
+//! a WRITE one. Requests from NFSv3 and 9P clients and the gateway don't say who they are from, so
+//! they conflict with everyone's delegations. Until such a request is done, nobody is given a new
+//! delegation of the file that it would conflict with.
+//!
+//! Any number of clients can hold READ delegations of a file, but a WRITE delegation is only given
+//! to a client that nobody else shares the file with, not even by having it open.
+//!
+//! Like locks, delegations are only kept in memory, and are reclaimed during the grace period
+//! after a restart. The delegations of clients whose leases run out are dropped.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+use zippyrpc::ZipDelegationType;
+
+use super::Fid;
+
+/// Who holds the delegations of one file.
+#[derive(Debug)]
+enum Delegation {
+    Read(HashSet<i64>),
+    Write(i64),
+}
+
+/// The delegations of every file.
+#[derive(Debug, Default)]
+pub struct Delegations {
+    /// FID -> holders. Files nobody holds a delegation of are removed.
+    files: Mutex<HashMap<Fid, Delegation>>,
+
+    /// FID -> (conflicting requests in progress, how many of them are changes). Only ever locked
+    /// after `files`, if at all.
+    busy: Mutex<HashMap<Fid, (usize, usize)>>,
+}
+
+/// A request in progress that conflicts with delegations of a file, which keeps conflicting ones
+/// from being given until it is dropped.
+#[derive(Debug)]
+pub struct Conflict<'a> {
+    delegations: &'a Delegations,
+    fid: Fid,
+    change: bool,
+}
+
+impl<'a> Drop for Conflict<'a> {
+    fn drop(&mut self) {
+        let mut busy = self.delegations.busy.lock().unwrap();
+
+        let done = {
+            let count = busy.get_mut(&self.fid).unwrap();
+            count.0 -= 1;
+            if self.change {
+                count.1 -= 1;
+            }
+            count.0 == 0
+        };
+        if done {
+            busy.remove(&self.fid);
+        }
+    }
+}
+
+/// Take away the client's delegation of the file, if it holds one.
+fn remove_holder(files: &mut HashMap<Fid, Delegation>, fid: Fid, client: i64) {
+    let empty = match files.get_mut(&fid) {
+        Some(&mut Delegation::Read(ref mut holders)) => {
+            holders.remove(&client);
+            holders.is_empty()
+        }
+        Some(&mut Delegation::Write(holder)) => holder == client,
+        None => false,
+    };
+
+    if empty {
+        files.remove(&fid);
+    }
+}
+
+impl Delegations {
+    pub fn new() -> Delegations {
+        Delegations::default()
+    }
+
+    /// Give the client a delegation of the given kind of the file in place of whatever it held,
+    /// or give back what it held for NONE, and return what it holds now. If other clients hold
+    /// one that conflicts, nothing changes. `others_open` says whether other clients have the
+    /// file open, which keeps the client from getting a WRITE one. It is only called with the
+    /// delegations locked, so an OPEN that comes in after it either sees the delegation or was
+    /// seen by it. Nothing is given while a conflicting request is in progress, either.
+    pub fn grant<F>(
+        &self,
+        fid: Fid,
+        client: i64,
+        kind: ZipDelegationType,
+        others_open: F,
+    ) -> ZipDelegationType
+    where
+        F: FnOnce() -> bool,
+    {
+        let mut files = self.files.lock().unwrap();
+
+        // (what the client holds, what everyone else holds)
+        let (mine, others) = match files.get(&fid) {
+            Some(&Delegation::Read(ref holders)) => {
+                let mine = if holders.contains(&client) {
+                    ZipDelegationType::READ
+                } else {
+                    ZipDelegationType::NONE
+                };
+                let others = if holders.iter().any(|&holder| holder != client) {
+                    ZipDelegationType::READ
+                } else {
+                    ZipDelegationType::NONE
+                };
+                (mine, others)
+            }
+            Some(&Delegation::Write(holder)) if holder == client => {
+                (ZipDelegationType::WRITE, ZipDelegationType::NONE)
+            }
+            Some(&Delegation::Write(_)) => (ZipDelegationType::NONE, ZipDelegationType::WRITE),
+            None => (ZipDelegationType::NONE, ZipDelegationType::NONE),
+        };
+
+        // (conflicting requests in progress, how many of them are changes)
+        let busy = self.busy.lock().unwrap().get(&fid).cloned().unwrap_or((0, 0));
+
+        let allowed = match kind {
+            ZipDelegationType::NONE => true,
+            ZipDelegationType::READ => others != ZipDelegationType::WRITE && busy.1 == 0,
+            ZipDelegationType::WRITE => {
+                others == ZipDelegationType::NONE && busy.0 == 0 && !others_open()
+            }
+        };
+        if !allowed {
+            return mine;
+        }
+
+        remove_holder(&mut files, fid, client);
+        match kind {
+            ZipDelegationType::READ => {
+                let delegation = files.entry(fid).or_insert_with(
+                    || Delegation::Read(HashSet::new()),
+                );
+                if let Delegation::Read(ref mut holders) = *delegation {
+                    holders.insert(client);
+                }
+            }
+            ZipDelegationType::WRITE => {
+                files.insert(fid, Delegation::Write(client));
+            }
+            ZipDelegationType::NONE => {}
+        }
+
+        kind
+    }
+
+    /// Start a request that conflicts with delegations of the file, a change if `change` is true.
+    /// Take away the delegations for which `conflicts` returns true, given the holder and the
+    /// kind, and return them, so that their holders can be told before the request is carried
+    /// out. Until the returned `Conflict` is dropped, no conflicting delegation is given.
+    pub fn conflict<F>(
+        &self,
+        fid: Fid,
+        change: bool,
+        conflicts: F,
+    ) -> (Conflict, Vec<(i64, ZipDelegationType)>)
+    where
+        F: Fn(i64, ZipDelegationType) -> bool,
+    {
+        let mut files = self.files.lock().unwrap();
+
+        {
+            let mut busy = self.busy.lock().unwrap();
+            let count = busy.entry(fid).or_insert((0, 0));
+            count.0 += 1;
+            if change {
+                count.1 += 1;
+            }
+        }
+        let conflict = Conflict {
+            delegations: self,
+            fid,
+            change,
+        };
+
+        let taken: Vec<(i64, ZipDelegationType)> = match files.get(&fid) {
+            Some(&Delegation::Read(ref holders)) => {
+                holders
+                    .iter()
+                    .map(|&holder| (holder, ZipDelegationType::READ))
+                    .filter(|&(holder, kind)| conflicts(holder, kind))
+                    .collect()
+            }
+            Some(&Delegation::Write(holder)) => {
+                if conflicts(holder, ZipDelegationType::WRITE) {
+                    vec![(holder, ZipDelegationType::WRITE)]
+                } else {
+                    vec![]
+                }
+            }
+            None => vec![],
+        };
+
+        for &(holder, _) in taken.iter() {
+            remove_holder(&mut files, fid, holder);
+        }
+        (conflict, taken)
+    }
+
+    /// Drop all of the client's delegations, e.g. because it has gone away.
+    pub fn forget_client(&self, client: i64) {
+        let mut files = self.files.lock().unwrap();
+
+        let fids: Vec<Fid> = files.keys().cloned().collect();
+        for fid in fids {
+            remove_holder(&mut files, fid, client);
+        }
+    }
+}
@@ -0,0 +1,405 @@
+//! A persistent index from names to FIDs for each directory.
+//!
+//! Without the index, finding a name in a directory means listing the whole server directory and
+//! pairing up numbered and named files, which makes LOOKUP linear in the size of the directory.
+//! Instead, we keep an in-memory map for each directory, backed by an append-only log in
+//! `data_dir/index/<dir fid>`.
+//!
+//! The index is NOT the source of truth. The pairing invariant (an NFS file exists iff both its
+//! numbered and named server files exist) still decides whether a file exists. The index only
+//! needs to be a _superset_ of the existing files, so that a lookup can find a candidate FID and
+//! then check the two server files directly. To keep it a superset across crashes, the handler
+//! must:
+//!
+//! - durably `insert` an entry _before_ creating the named file that makes an object exist, and
+//! - only `remove` an entry _after_ the object has stopped existing.
+//!
+//! An entry whose object does not exist (e.g. because we crashed half way through a create) is
+//! harmless: it fails the pairing check, and it is dropped the next time the index is loaded.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::collections::Bound::{Excluded, Unbounded};
+use std::fs::{read_dir, remove_file, rename, File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use super::Fid;
+
+/// Log record tag for an inserted entry
+const RECORD_INSERT: u8 = b'+';
+
+/// Log record tag for a removed entry
+const RECORD_REMOVE: u8 = b'-';
+
+/// The size of a record header: tag (1B), FID (8B), name length (4B)
+const RECORD_HEADER_LEN: usize = 1 + 8 + 4;
+
+/// Compact the log when it has this many more records than live entries
+const COMPACT_SLACK: usize = 1024;
+
+/// The index of a single directory.
+#[derive(Debug)]
+struct DirIndex {
+    /// name -> FID
+    names: HashMap<String, Fid>,
+
+    /// FID -> names, kept in FID order. A FID only has more than one name if a rename failed half
+    /// way, leaving the file under both.
+    fids: BTreeMap<Fid, BTreeSet<String>>,
+
+    /// The log, opened for appending
+    log: File,
+
+    /// The number of records in the log
+    records: usize,
+}
+
+/// The indices of all directories, loaded lazily.
+#[derive(Debug)]
+pub struct DirIndexes {
+    /// The directory where the logs live
+    index_dir: PathBuf,
+
+    /// Directory FID -> index of that directory
+    dirs: Mutex<HashMap<Fid, Arc<Mutex<DirIndex>>>>,
+}
+
+/// Encode a single log record.
+fn encode_record(tag: u8, fid: Fid, name: &str) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(RECORD_HEADER_LEN + name.len());
+    buf.push(tag);
+    buf.extend_from_slice(&u64_to_bytes(fid as u64));
+    buf.extend_from_slice(&u32_to_bytes(name.len() as u32));
+    buf.extend_from_slice(name.as_bytes());
+    buf
+}
+
+pub fn u64_to_bytes(val: u64) -> [u8; 8] {
+    let mut bytes = [0; 8];
+    for (i, b) in bytes.iter_mut().enumerate() {
+        *b = (val >> (8 * i)) as u8;
+    }
+    bytes
+}
+
+pub fn u32_to_bytes(val: u32) -> [u8; 4] {
+    let mut bytes = [0; 4];
+    for (i, b) in bytes.iter_mut().enumerate() {
+        *b = (val >> (8 * i)) as u8;
+    }
+    bytes
+}
+
+pub fn bytes_to_u64(bytes: &[u8]) -> u64 {
+    bytes.iter().rev().fold(0, |acc, &b| (acc << 8) | b as u64)
+}
+
+/// Scan a server directory for NFS files (i.e. paired numbered and named files).
+///
+/// This is only needed the first time we see a directory without a log.
+fn scan_dir(dpath: &Path) -> Result<Vec<(Fid, String)>, String> {
+    let mut numbered = Vec::new();
+    let mut named = Vec::new();
+
+    for dirent in read_dir(dpath).map_err(|e| format!("{}", e))? {
+        let dirent = dirent.map_err(|e| format!("{}", e))?;
+        let fname = dirent.file_name();
+        let fname = match fname.to_str() {
+            Some(fname) => fname.to_owned(),
+            None => continue,
+        };
+
+        let mut parts = fname.splitn(2, '.');
+        let number: Fid = match parts.next().unwrap().parse() {
+            Ok(number) => number,
+            Err(_) => continue,
+        };
+
+        match parts.next() {
+            Some(name) => named.push((number, name.to_owned())),
+            None => numbered.push(number),
+        }
+    }
+
+    numbered.sort();
+
+    Ok(
+        named
+            .into_iter()
+            .filter(|&(fid, _)| numbered.binary_search(&fid).is_ok())
+            .collect(),
+    )
+}
+
+impl DirIndex {
+    /// Apply a record to the in-memory maps.
+    fn apply(&mut self, tag: u8, fid: Fid, name: String) {
+        match tag {
+            RECORD_INSERT => {
+                // If this FID was already here under another name, the old name goes away with
+                // the matching remove record. Until then, the FID has both.
+                self.fids
+                    .entry(fid)
+                    .or_insert_with(BTreeSet::new)
+                    .insert(name.clone());
+                self.names.insert(name, fid);
+            }
+            RECORD_REMOVE => {
+                if self.names.get(&name) == Some(&fid) {
+                    self.names.remove(&name);
+                }
+
+                let unnamed = match self.fids.get_mut(&fid) {
+                    Some(names) => {
+                        names.remove(&name);
+                        names.is_empty()
+                    }
+                    None => false,
+                };
+                if unnamed {
+                    self.fids.remove(&fid);
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    /// Append a record to the log and apply it.
+    fn append(&mut self, tag: u8, fid: Fid, name: &str, sync: bool) -> Result<(), String> {
+        self.log.write_all(&encode_record(tag, fid, name)).map_err(
+            |e| {
+                format!("{}", e)
+            },
+        )?;
+        if sync {
+            self.log.sync_data().map_err(|e| format!("{}", e))?;
+        }
+        self.records += 1;
+        self.apply(tag, fid, name.to_owned());
+        Ok(())
+    }
+}
+
+impl DirIndexes {
+    /// Returns the indices stored in the given directory, creating it if needed.
+    pub fn new<P: AsRef<Path>>(index_dir: P) -> Result<DirIndexes, String> {
+        let index_dir = index_dir.as_ref().to_owned();
+        ::std::fs::create_dir_all(&index_dir).map_err(|e| format!("{}", e))?;
+
+        Ok(DirIndexes {
+            index_dir,
+            dirs: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// The path of the log for the given directory.
+    fn log_path(&self, did: Fid) -> PathBuf {
+        self.index_dir.join(format!("{}", did))
+    }
+
+    /// Get the index for the directory `did` at server path `dpath`, loading it if needed.
+    fn get(&self, did: Fid, dpath: &Path) -> Result<Arc<Mutex<DirIndex>>, String> {
+        // NOTE: we hold the lock on the whole table while loading. This is what guarantees that
+        // nobody is modifying the directory while we look at it, since every modification goes
+        // through the index.
+        let mut dirs = self.dirs.lock().unwrap();
+
+        if let Some(index) = dirs.get(&did) {
+            return Ok(index.clone());
+        }
+
+        let index = Arc::new(Mutex::new(self.load(did, dpath)?));
+        dirs.insert(did, index.clone());
+        Ok(index)
+    }
+
+    /// Load the index of the given directory from its log, or build it from the directory
+    /// contents if there is no log. Either way, the result is written out as a fresh log
+    /// containing only entries that actually exist.
+    fn load(&self, did: Fid, dpath: &Path) -> Result<DirIndex, String> {
+        let log_path = self.log_path(did);
+
+        // Replay the log, if there is one
+        let entries = if log_path.exists() {
+            let mut bytes = Vec::new();
+            File::open(&log_path)
+                .and_then(|mut f| f.read_to_end(&mut bytes))
+                .map_err(|e| format!("{}", e))?;
+
+            let mut names: HashMap<String, Fid> = HashMap::new();
+            let mut pos = 0;
+
+            // A torn record at the end (from a crash during an append) is ignored. Since we
+            // sync after inserts, it can only be an insert whose named file was never created.
+            while pos + RECORD_HEADER_LEN <= bytes.len() {
+                let tag = bytes[pos];
+                let fid = bytes_to_u64(&bytes[pos + 1..pos + 9]) as Fid;
+                let len = bytes_to_u64(&bytes[pos + 9..pos + 13]) as usize;
+                let start = pos + RECORD_HEADER_LEN;
+
+                if start + len > bytes.len() {
+                    break;
+                }
+
+                let name = String::from_utf8_lossy(&bytes[start..start + len]).into_owned();
+                match tag {
+                    RECORD_INSERT => {
+                        names.insert(name, fid);
+                    }
+                    RECORD_REMOVE => {
+                        if names.get(&name) == Some(&fid) {
+                            names.remove(&name);
+                        }
+                    }
+                    _ => return Err(format!("Corrupt index log {:?}", log_path)),
+                }
+
+                pos = start + len;
+            }
+
+            // Drop anything that does not satisfy the pairing invariant (e.g. after a crash)
+            names
+                .into_iter()
+                .filter(|&(ref name, fid)| {
+                    dpath.join(format!("{}", fid)).exists() &&
+                        dpath.join(format!("{}.{}", fid, name)).exists()
+                })
+                .map(|(name, fid)| (fid, name))
+                .collect()
+        } else {
+            debug!("Building index for directory {} from {:?}", did, dpath);
+            scan_dir(dpath)?
+        };
+
+        self.rewrite(did, entries)
+    }
+
+    /// Atomically replace the log of the given directory with one containing exactly the given
+    /// entries, and return the resulting index.
+    fn rewrite(&self, did: Fid, entries: Vec<(Fid, String)>) -> Result<DirIndex, String> {
+        let log_path = self.log_path(did);
+        let tmp_path = self.index_dir.join(format!("{}.tmp", did));
+
+        {
+            let mut tmp = File::create(&tmp_path).map_err(|e| format!("{}", e))?;
+            for &(fid, ref name) in entries.iter() {
+                tmp.write_all(&encode_record(RECORD_INSERT, fid, name))
+                    .map_err(|e| format!("{}", e))?;
+            }
+            tmp.sync_all().map_err(|e| format!("{}", e))?;
+        } // File closed
+
+        rename(&tmp_path, &log_path).map_err(|e| format!("{}", e))?;
+
+        let log = OpenOptions::new()
+            .append(true)
+            .open(&log_path)
+            .map_err(|e| format!("{}", e))?;
+
+        let mut index = DirIndex {
+            names: HashMap::new(),
+            fids: BTreeMap::new(),
+            log,
+            records: entries.len(),
+        };
+
+        for (fid, name) in entries {
+            index.apply(RECORD_INSERT, fid, name);
+        }
+
+        Ok(index)
+    }
+
+    /// Returns the candidate FID for `name` in the directory, if any.
+    ///
+    /// The caller must still check that the candidate actually exists.
+    pub fn lookup(&self, did: Fid, dpath: &Path, name: &str) -> Result<Option<Fid>, String> {
+        let index = self.get(did, dpath)?;
+        let index = index.lock().unwrap();
+        Ok(index.names.get(name).cloned())
+    }
+
+    /// Durably record that `name` in the directory refers to `fid`.
+    ///
+    /// This must be done before the named file is created.
+    pub fn insert(&self, did: Fid, dpath: &Path, fid: Fid, name: &str) -> Result<(), String> {
+        let index = self.get(did, dpath)?;
+        let mut index = index.lock().unwrap();
+        index.append(RECORD_INSERT, fid, name, true)
+    }
+
+    /// Record that `name` in the directory no longer refers to `fid`.
+    ///
+    /// This must be done after the object has stopped existing. It need not be durable, since a
+    /// stale entry is dropped the next time the index is loaded.
+    pub fn remove(&self, did: Fid, dpath: &Path, fid: Fid, name: &str) -> Result<(), String> {
+        let index = self.get(did, dpath)?;
+        let mut index = index.lock().unwrap();
+        index.append(RECORD_REMOVE, fid, name, false)?;
+
+        // Keep the log from growing forever with dead records. Every name is kept, not just the
+        // newest one for each FID, since the old name of a file whose rename failed half way is
+        // still the one that exists.
+        if index.records > index.names.len() * 2 + COMPACT_SLACK {
+            let mut entries: Vec<(Fid, String)> = index
+                .names
+                .iter()
+                .map(|(name, &fid)| (fid, name.clone()))
+                .collect();
+            entries.sort();
+            *index = self.rewrite(did, entries)?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns all candidate `(fid, name)` entries in the directory, in FID order.
+    ///
+    /// This is only for tests. The handler always pages through a directory with
+    /// `entries_after`, so that a big directory is never read into memory at once.
+    #[cfg(test)]
+    pub fn entries(&self, did: Fid, dpath: &Path) -> Result<Vec<(Fid, String)>, String> {
+        let entries = self.entries_after(did, dpath, 0, usize::max_value())?;
+        Ok(
+            entries
+                .into_iter()
+                .flat_map(|(fid, names)| names.into_iter().map(move |name| (fid, name)))
+                .collect(),
+        )
+    }
+
+    /// Returns the candidate names of up to `count` FIDs in the directory greater than `after`,
+    /// in FID order. All the names of a FID come together, so that a listing resumed from a FID
+    /// never misses any of them.
+    pub fn entries_after(
+        &self,
+        did: Fid,
+        dpath: &Path,
+        after: Fid,
+        count: usize,
+    ) -> Result<Vec<(Fid, Vec<String>)>, String> {
+        let index = self.get(did, dpath)?;
+        let index = index.lock().unwrap();
+        Ok(
+            index
+                .fids
+                .range((Excluded(after), Unbounded))
+                .take(count)
+                .map(|(&fid, names)| (fid, names.iter().cloned().collect()))
+                .collect(),
+        )
+    }
+
+    /// Forget the index of a directory that has been removed.
+    pub fn remove_dir(&self, did: Fid) -> Result<(), String> {
+        self.dirs.lock().unwrap().remove(&did);
+
+        let log_path = self.log_path(did);
+        if log_path.exists() {
+            remove_file(log_path).map_err(|e| format!("{}", e))?;
+        }
+
+        Ok(())
+    }
+}
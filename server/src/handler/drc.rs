@@ -0,0 +1,303 @@
+//! A duplicate request cache (DRC), so that a retried request that changes the FS is only done
+//! once.
+//!
+//! CREATE, MKDIR, REMOVE, RMDIR and RENAME are not idempotent. If the reply to one of them is lost
+//! (e.g. the connection breaks), the client can't tell whether it was done, so it tries again, and
+//! the second try fails with `NFSERR_EXIST` or `NFSERR_NOENT` even though the first one worked.
+//! To avoid this, the client gives each such request a random request ID (XID), which it keeps
+//! for all of the tries. We remember the outcome of the most recent requests by XID, and a request
+//! with an XID we have already seen gets the same outcome again instead of being done twice.
+//!
+//! Only the newest `capacity` outcomes are kept, which is plenty for retries. They are backed by
+//! an append-only log in `data_dir/drc`, so that a retry is still recognized after the server
+//! restarts. The outcome is logged after the request is done, so a crash in between can still
+//! lead to the request being done twice.
+
+use std::collections::{HashMap, VecDeque};
+use std::fs::{rename, File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Condvar, Mutex};
+
+use zippyrpc::ZipErrorType;
+
+use super::Fid;
+use super::dirindex::{bytes_to_u64, u32_to_bytes, u64_to_bytes};
+
+/// Log record tag for a request that succeeded
+const RECORD_DONE: u8 = b'+';
+
+/// Log record tag for a request that failed
+const RECORD_FAILED: u8 = b'!';
+
+/// The size of a record header: XID (8B), tag (1B), value (8B), message length (4B)
+const RECORD_HEADER_LEN: usize = 8 + 1 + 8 + 4;
+
+/// Compact the log when it has this many more records than live entries
+const COMPACT_SLACK: usize = 1024;
+
+/// Every NFS error, for decoding the log.
+const ERROR_TYPES: [ZipErrorType; 12] = [
+    ZipErrorType::NFSERR_NOENT,
+    ZipErrorType::NFSERR_EXIST,
+    ZipErrorType::NFSERR_NOTDIR,
+    ZipErrorType::NFSERR_ISDIR,
+    ZipErrorType::NFSERR_NOTEMPTY,
+    ZipErrorType::NFSERR_STALE,
+    ZipErrorType::NFSERR_NAMETOOLONG,
+    ZipErrorType::NFSERR_BAD_COOKIE,
+    ZipErrorType::NFSERR_TOOSMALL,
+    ZipErrorType::NFSERR_VERSION,
+    ZipErrorType::NFSERR_GRACE,
+    ZipErrorType::NFSERR_INVAL,
+];
+
+/// What happened to a request.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Outcome {
+    /// It succeeded. For CREATE and MKDIR, this is the FID of the new object, and otherwise 0.
+    Done(Fid),
+
+    /// It failed with the given NFS error and message.
+    Failed(ZipErrorType, String),
+}
+
+/// The cached outcomes, and the log backing them.
+#[derive(Debug)]
+struct Entries {
+    /// XID -> outcome, or `None` while the request is still being done
+    outcomes: HashMap<i64, Option<Outcome>>,
+
+    /// The XIDs of the outcomes, oldest first
+    order: VecDeque<i64>,
+
+    /// The log, opened for appending
+    log: File,
+
+    /// The number of records in the log
+    records: usize,
+}
+
+/// The duplicate request cache.
+#[derive(Debug)]
+pub struct DupCache {
+    /// Where the log lives
+    log_path: PathBuf,
+
+    /// The most outcomes to keep
+    capacity: usize,
+
+    entries: Mutex<Entries>,
+
+    /// Signalled whenever a request finishes, for retries waiting on the first try
+    finished: Condvar,
+}
+
+/// Encode a single log record.
+fn encode_record(xid: i64, outcome: &Outcome) -> Vec<u8> {
+    let (tag, value, message) = match *outcome {
+        Outcome::Done(fid) => (RECORD_DONE, fid as u64, ""),
+        Outcome::Failed(error, ref message) => (RECORD_FAILED, error as u64, &message[..]),
+    };
+
+    let mut buf = Vec::with_capacity(RECORD_HEADER_LEN + message.len());
+    buf.extend_from_slice(&u64_to_bytes(xid as u64));
+    buf.push(tag);
+    buf.extend_from_slice(&u64_to_bytes(value));
+    buf.extend_from_slice(&u32_to_bytes(message.len() as u32));
+    buf.extend_from_slice(message.as_bytes());
+    buf
+}
+
+/// Decode the records in a log. A torn record at the end (from a crash during an append) is
+/// ignored.
+fn decode_records(bytes: &[u8]) -> Result<Vec<(i64, Outcome)>, String> {
+    let mut records = Vec::new();
+    let mut pos = 0;
+
+    while pos + RECORD_HEADER_LEN <= bytes.len() {
+        let xid = bytes_to_u64(&bytes[pos..pos + 8]) as i64;
+        let tag = bytes[pos + 8];
+        let value = bytes_to_u64(&bytes[pos + 9..pos + 17]);
+        let len = bytes_to_u64(&bytes[pos + 17..pos + 21]) as usize;
+        let start = pos + RECORD_HEADER_LEN;
+
+        if start + len > bytes.len() {
+            break;
+        }
+
+        let outcome = match tag {
+            RECORD_DONE => Outcome::Done(value as Fid),
+            RECORD_FAILED => {
+                let error = match ERROR_TYPES.iter().find(|&&e| e as u64 == value) {
+                    Some(&error) => error,
+                    None => return Err(format!("Unknown error {} in the DRC log", value)),
+                };
+                let message = String::from_utf8_lossy(&bytes[start..start + len]).into_owned();
+                Outcome::Failed(error, message)
+            }
+            _ => return Err("Corrupt DRC log".to_owned()),
+        };
+
+        records.push((xid, outcome));
+        pos = start + len;
+    }
+
+    Ok(records)
+}
+
+impl Entries {
+    /// Remember an outcome, forgetting the oldest one if there are too many.
+    fn insert(&mut self, xid: i64, outcome: Outcome, capacity: usize) {
+        // It may already be here as a request in progress
+        match self.outcomes.insert(xid, Some(outcome)) {
+            Some(Some(_)) => {}
+            _ => self.order.push_back(xid),
+        }
+
+        while self.order.len() > capacity {
+            let oldest = self.order.pop_front().unwrap();
+            self.outcomes.remove(&oldest);
+        }
+    }
+}
+
+impl DupCache {
+    /// Returns the cache backed by the log at the given path, creating it if needed.
+    pub fn new<P: AsRef<Path>>(log_path: P, capacity: usize) -> Result<DupCache, String> {
+        let log_path = log_path.as_ref().to_owned();
+
+        // Replay the log, if there is one
+        let records = if log_path.exists() {
+            let mut bytes = Vec::new();
+            File::open(&log_path)
+                .and_then(|mut f| f.read_to_end(&mut bytes))
+                .map_err(|e| format!("{}", e))?;
+            decode_records(&bytes)?
+        } else {
+            Vec::new()
+        };
+
+        // Only the newest ones are kept
+        let skip = records.len().saturating_sub(capacity);
+        let live: Vec<_> = records.into_iter().skip(skip).collect();
+
+        let entries = Self::rewrite_log(&log_path, &live, capacity)?;
+
+        Ok(DupCache {
+            log_path,
+            capacity,
+            entries: Mutex::new(entries),
+            finished: Condvar::new(),
+        })
+    }
+
+    /// Atomically replace the log with one containing exactly the given outcomes, and return
+    /// the resulting entries.
+    fn rewrite_log(
+        log_path: &Path,
+        live: &[(i64, Outcome)],
+        capacity: usize,
+    ) -> Result<Entries, String> {
+        let tmp_path = log_path.with_extension("tmp");
+
+        {
+            let mut tmp = File::create(&tmp_path).map_err(|e| format!("{}", e))?;
+            for &(xid, ref outcome) in live.iter() {
+                tmp.write_all(&encode_record(xid, outcome)).map_err(
+                    |e| format!("{}", e),
+                )?;
+            }
+            tmp.sync_all().map_err(|e| format!("{}", e))?;
+        } // File closed
+
+        rename(&tmp_path, log_path).map_err(|e| format!("{}", e))?;
+
+        let log = OpenOptions::new()
+            .append(true)
+            .open(log_path)
+            .map_err(|e| format!("{}", e))?;
+
+        let mut entries = Entries {
+            outcomes: HashMap::new(),
+            order: VecDeque::new(),
+            log,
+            records: live.len(),
+        };
+
+        for &(xid, ref outcome) in live.iter() {
+            entries.insert(xid, outcome.clone(), capacity);
+        }
+
+        Ok(entries)
+    }
+
+    /// Start a request with the given XID.
+    ///
+    /// If a request with the same XID has already been done, its outcome is returned, and the
+    /// request must not be done again. If one is being done right now, we wait for it to finish
+    /// first. Otherwise, this returns `None`, and the caller must do the request and then call
+    /// `finish`.
+    pub fn begin(&self, xid: i64) -> Option<Outcome> {
+        let mut entries = self.entries.lock().unwrap();
+
+        loop {
+            let state = entries.outcomes.get(&xid).cloned();
+            match state {
+                Some(Some(outcome)) => return Some(outcome),
+                Some(None) => entries = self.finished.wait(entries).unwrap(),
+                None => {
+                    entries.outcomes.insert(xid, None);
+                    return None;
+                }
+            }
+        }
+    }
+
+    /// Finish a request started with `begin`, remembering its outcome. If the outcome is `None`
+    /// (e.g. the request hit an I/O error), it is forgotten, so that a retry is done again.
+    pub fn finish(&self, xid: i64, outcome: Option<Outcome>) {
+        let mut entries = self.entries.lock().unwrap();
+
+        match outcome {
+            Some(outcome) => {
+                let record = encode_record(xid, &outcome);
+                entries.insert(xid, outcome, self.capacity);
+
+                // If this fails, the outcome is only remembered until the server restarts
+                let logged = entries.log.write_all(&record);
+                match logged.and_then(|_| entries.log.sync_data()) {
+                    Ok(()) => entries.records += 1,
+                    Err(e) => warn!("Unable to log the outcome of XID {:x}: {}", xid, e),
+                }
+
+                // Keep the log from growing forever with forgotten records
+                if entries.records > self.capacity * 2 + COMPACT_SLACK {
+                    let live: Vec<_> = entries
+                        .order
+                        .iter()
+                        .map(|xid| (*xid, entries.outcomes[xid].clone().unwrap()))
+                        .collect();
+                    match Self::rewrite_log(&self.log_path, &live, self.capacity) {
+                        Ok(mut compacted) => {
+                            // Requests still being done aren't logged yet, but their retries must
+                            // keep waiting for them
+                            for (&xid, outcome) in entries.outcomes.iter() {
+                                if outcome.is_none() {
+                                    compacted.outcomes.insert(xid, None);
+                                }
+                            }
+                            *entries = compacted;
+                        }
+                        Err(e) => warn!("Unable to compact the DRC log: {}", e),
+                    }
+                }
+            }
+            None => {
+                entries.outcomes.remove(&xid);
+            }
+        }
+
+        self.finished.notify_all();
+    }
+}
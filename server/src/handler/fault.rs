@@ -0,0 +1,64 @@
+//! Fault injection for testing the failure paths of the handler.
+//!
+//! The handler calls `FaultInjector::check` at interesting points. In tests, a fault point can be
+//! armed so that the check fails there as if the underlying filesystem had returned an error. In
+//! non-test builds nothing can ever be armed, so the checks always succeed.
+
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+/// The places in the handler where a fault can be injected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FaultPoint {
+    /// Looking up a name in a directory
+    FindByName,
+
+    /// Creating the numbered file of a new object
+    CreateNumbered,
+
+    /// Creating the named file of a new object
+    CreateNamed,
+
+    /// Setting the attributes of a file
+    SetAttr,
+
+    /// Creating the new named file during a rename
+    RenameNamed,
+
+    /// Moving the numbered file during a rename
+    RenameNumbered,
+}
+
+/// The set of currently armed fault points.
+#[derive(Debug)]
+pub struct FaultInjector {
+    armed: Mutex<HashSet<FaultPoint>>,
+}
+
+impl FaultInjector {
+    /// Returns a new injector with nothing armed.
+    pub fn new() -> FaultInjector {
+        FaultInjector { armed: Mutex::new(HashSet::new()) }
+    }
+
+    /// Make every future check of the given fault point fail until it is disarmed.
+    #[cfg(test)]
+    pub fn arm(&self, point: FaultPoint) {
+        self.armed.lock().unwrap().insert(point);
+    }
+
+    /// Make future checks of the given fault point succeed again.
+    #[cfg(test)]
+    pub fn disarm(&self, point: FaultPoint) {
+        self.armed.lock().unwrap().remove(&point);
+    }
+
+    /// Returns an error if the given fault point is armed.
+    pub fn check(&self, point: FaultPoint) -> Result<(), String> {
+        if cfg!(test) && self.armed.lock().unwrap().contains(&point) {
+            Err(format!("Injected fault at {:?}", point))
+        } else {
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,70 @@
+//! Which clients are still around.
+//!
+//! Clients with `CAP_LEASES` send a RENEW every so often (and an OPEN counts as one too). A client
+//! that hasn't been heard from for a whole lease is taken to be dead, and the server cleans up
+//! after it: it forgets what the client had open, and removes the files it silly renamed.
+//!
+//! Renewals are only kept in memory, so after a restart, every client gets one lease from the time
+//! the server started to renew before it is taken to be dead.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// When each client last renewed its lease.
+#[derive(Debug)]
+pub struct Leases {
+    /// How long a lease lasts
+    length: Duration,
+
+    /// When the server started
+    started: Instant,
+
+    /// client -> when it last renewed
+    renewed: Mutex<HashMap<i64, Instant>>,
+}
+
+impl Leases {
+    pub fn new(length: Duration) -> Leases {
+        Leases {
+            length,
+            started: Instant::now(),
+            renewed: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Note that the client renewed its lease.
+    pub fn renew(&self, client: i64) {
+        self.renewed.lock().unwrap().insert(client, Instant::now());
+    }
+
+    /// Return whether the client still has its lease. A client we haven't heard from since we
+    /// started has one until a lease after we started.
+    pub fn is_live(&self, client: i64) -> bool {
+        let renewed = self.renewed.lock().unwrap();
+        let since = renewed.get(&client).unwrap_or(&self.started);
+        since.elapsed() < self.length
+    }
+
+    /// Return whether we are in the grace period after starting, during which clients can take
+    /// back the locks they held before we restarted. It lasts one lease.
+    pub fn in_grace(&self) -> bool {
+        self.started.elapsed() < self.length
+    }
+
+    /// Forget the clients whose leases have run out, and return them.
+    pub fn expire(&self) -> Vec<i64> {
+        let mut renewed = self.renewed.lock().unwrap();
+
+        let expired: Vec<i64> = renewed
+            .iter()
+            .filter(|&(_, since)| since.elapsed() >= self.length)
+            .map(|(&client, _)| client)
+            .collect();
+        for client in expired.iter() {
+            renewed.remove(client);
+        }
+
+        expired
+    }
+}
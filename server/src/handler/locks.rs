@@ -0,0 +1,72 @@
+//! The advisory byte-range locks clients hold on files.
+//!
+//! Clients with `CAP_LOCKS` take and release locks with LOCK, and look for conflicting ones with
+//! TESTLOCK. The server never makes anyone wait: a lock that conflicts with another is just not
+//! granted, and a client that wants to wait for it polls.
+//!
+//! Locks are only kept in memory. After a restart, the server has a new epoch, and for one lease
+//! (the grace period) it only grants locks that clients reclaim, so that a lock held before the
+//! restart can't be taken by someone else before its holder notices and takes it back. The locks
+//! of clients whose leases run out are dropped.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use zippyrpc::{LockSet, ZipLock};
+
+use super::Fid;
+
+/// The locks on every file.
+#[derive(Debug, Default)]
+pub struct LockManager {
+    /// FID -> locks. Files with no locks are removed.
+    files: Mutex<HashMap<Fid, LockSet>>,
+}
+
+impl LockManager {
+    pub fn new() -> LockManager {
+        LockManager::default()
+    }
+
+    /// Find a lock held by someone else that keeps the client from taking the given one, along
+    /// with its client.
+    pub fn test(&self, fid: Fid, client: i64, lock: &ZipLock) -> Option<(i64, ZipLock)> {
+        let files = self.files.lock().unwrap();
+
+        let conflict = files.get(&fid).and_then(
+            |locks| locks.conflict(client, lock).cloned(),
+        );
+        conflict
+    }
+
+    /// Take the lock (or unlock), unless someone else holds a conflicting lock, which is returned
+    /// along with its client.
+    pub fn set(&self, fid: Fid, client: i64, lock: &ZipLock) -> Result<(), (i64, ZipLock)> {
+        let mut files = self.files.lock().unwrap();
+
+        let empty = {
+            let locks = files.entry(fid).or_insert_with(LockSet::new);
+            if let Some(conflict) = locks.conflict(client, lock) {
+                return Err(conflict.clone());
+            }
+
+            locks.set(client, lock);
+            locks.is_empty()
+        };
+
+        if empty {
+            files.remove(&fid);
+        }
+        Ok(())
+    }
+
+    /// Drop all of the client's locks, e.g. because it has gone away.
+    pub fn forget_client(&self, client: i64) {
+        let mut files = self.files.lock().unwrap();
+
+        for locks in files.values_mut() {
+            locks.remove_client(client);
+        }
+        files.retain(|_, locks| !locks.is_empty());
+    }
+}
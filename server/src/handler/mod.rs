@@ -2,7 +2,17 @@
 extern crate libc;
 extern crate thrift;
 
+mod callbacks;
 mod counter;
+mod delegations;
+mod dirindex;
+mod drc;
+mod fault;
+mod leases;
+mod locks;
+mod namelock;
+mod openfiles;
+mod silly;
 
 #[cfg(test)]
 mod test;
@@ -10,18 +20,30 @@ mod test;
 use std::cmp::min;
 use std::fs::{create_dir, read_dir, remove_dir, remove_file, rename, copy, File, OpenOptions};
 use std::io::{Write, Seek, SeekFrom};
-use std::mem;
 use std::path::{Path, PathBuf};
 use std::sync::{Mutex, RwLock, Arc};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::thread::current;
 use std::collections::{HashSet, HashMap, VecDeque};
 
 use regex::Regex;
 
 use zippyrpc::*;
+use zippyrpc::wire;
 
+use eventloop::park;
+
+use self::callbacks::Callbacks;
 use self::counter::AtomicPersistentUsize;
+use self::delegations::{Conflict, Delegations};
+use self::dirindex::DirIndexes;
+use self::drc::{DupCache, Outcome};
+use self::fault::{FaultInjector, FaultPoint};
+use self::leases::Leases;
+use self::locks::LockManager;
+use self::namelock::NameLockManager;
+use self::openfiles::OpenFiles;
+use self::silly::{SillyFile, SillyFiles};
 
 /// A type representing a File ID (FID)
 type Fid = usize;
@@ -35,6 +57,26 @@ const NUMBERED_FILE_RE: &'static str = r"^\d+$";
 /// The number of ns in a us
 const NANOS_PER_MICRO: u32 = 1000;
 
+/// The number of candidate entries to take from a directory index at a time during READDIR
+const READDIR_BATCH: usize = 64;
+
+/// The size of a READDIR reply if the client doesn't ask for a particular size (32KB)
+const DIR_PAGE_LEN: usize = 32 << 10;
+
+/// The number of outcomes of requests the duplicate request cache remembers
+const DRC_CAPACITY: usize = 4096;
+
+/// The optional features this server supports, as reported in a HELLO
+const SERVER_CAPS: i64 = CAP_UNSTABLE_WRITES | CAP_READDIRPLUS | CAP_PIPELINING | CAP_OPEN_STATE |
+    CAP_LEASES | CAP_LOCKS | CAP_DELEGATIONS;
+
+/// How long a client's lease lasts without a RENEW, in seconds
+pub const LEASE_SECS: u64 = 90;
+
+/// The longest name we accept. Names are stored as `<fid>.<name>`, and a FID takes up to 20
+/// digits, so this is what is left of the usual 255 bytes.
+pub const NAME_MAX: usize = 234;
+
 /// Converts from `SystemTime` to `ZipTimeVal` used with Trift.
 fn sys_time_to_zip_time(sys_time: SystemTime) -> ZipTimeVal {
     let since = sys_time.duration_since(UNIX_EPOCH).unwrap();
@@ -45,6 +87,16 @@ fn sys_time_to_zip_time(sys_time: SystemTime) -> ZipTimeVal {
     ZipTimeVal::new(secs as i64, nanos as i64)
 }
 
+/// Returns the FID of the directory at the given numbered server path.
+fn dir_fid(dpath: &Path) -> Fid {
+    dpath.file_name().unwrap().to_str().unwrap().parse().unwrap()
+}
+
+/// Returns true for "." and "..", which exist in every directory but can't be created or renamed.
+fn is_dot_name(name: &str) -> bool {
+    name == "." || name == ".."
+}
+
 /// A server to handle RPC calls
 pub struct ZippynfsServer<'a, P: AsRef<Path>> {
     /// The directory on the host system where the server stores stuff.
@@ -54,17 +106,19 @@ pub struct ZippynfsServer<'a, P: AsRef<Path>> {
     counter: AtomicPersistentUsize<'a>,
 
     /// We need to be sure that no two files in the system have exactly the same path, so for the
-    /// time until a file is created (or renamed) that name must be inserted into this set. The
-    /// procedure is as follows (to insert a file called "foo" into directory with fid=3):
+    /// time until a file is created (or renamed) that name must be locked. The procedure is as
+    /// follows (to insert a file called "foo" into directory with fid=3):
+    ///
+    /// 1. Lock "foo" in directory 3, getting a `NameGuard`
+    /// 2. Check that "foo" does not already exist
+    /// 3. Do FS stuff to create the file
+    /// 4. Drop the guard, which unlocks the name
     ///
-    /// 1. Grab the locked set
-    /// 2. Insert /path/to/fs/1/3/foo to set
-    /// 3. Release lock on set
-    /// 4. Do FS stuff to create the file
-    /// 5. Grab the locked set
-    /// 6. Remove our entry from the set
-    /// 7. Release the lock
-    name_lock: Mutex<HashSet<(PathBuf, String)>>,
+    /// Since the guard unlocks on drop, every error path releases the name.
+    name_locks: NameLockManager,
+
+    /// Points at which tests can make the handler fail.
+    faults: FaultInjector,
 
     /// The epoch number of this server. When it crashes, it should come up with a new number. This
     /// alerts writers that they probably should not count on cached data being there.
@@ -75,10 +129,43 @@ pub struct ZippynfsServer<'a, P: AsRef<Path>> {
     /// A cache to map the FID of a file to the FID of its parent.
     fid_cache: RwLock<HashMap<Fid, Fid>>,
 
+    /// A persistent index of the names in each directory, so that lookups don't need to list the
+    /// whole directory.
+    dir_index: DirIndexes,
+
     /// Buffers for data written by the client asynchronously (with the UNSTABLE flag).
     ///
     /// Fid -> [(offset, size, data)]
     async_bufs: RwLock<HashMap<Fid, Arc<Mutex<Vec<(usize, usize, Vec<u8>)>>>>>,
+
+    /// Locks for rewriting files. Stable writes and commits copy the file, write to the copy, and
+    /// rename it over the file, so two of them at once would lose one's changes.
+    ///
+    /// Fid -> lock, only while somebody wants it
+    rewrite_locks: Mutex<HashMap<Fid, Arc<Mutex<()>>>>,
+
+    /// The outcomes of recent requests that change the FS, by XID, so that retries are only done
+    /// once.
+    drc: DupCache,
+
+    /// The files that clients have open.
+    open_files: OpenFiles,
+
+    /// When clients last renewed their leases.
+    leases: Leases,
+
+    /// The files that clients silly renamed, so that they can be removed if the client goes away
+    /// before it gets to it.
+    silly_files: SillyFiles,
+
+    /// The advisory locks clients hold on files.
+    locks: LockManager,
+
+    /// The delegations clients hold on files.
+    delegations: Delegations,
+
+    /// Where clients take callbacks, for recalling their delegations.
+    callbacks: Callbacks,
 }
 
 impl<'a, P: AsRef<Path>> ZippynfsServer<'a, P> {
@@ -91,14 +178,33 @@ impl<'a, P: AsRef<Path>> ZippynfsServer<'a, P> {
         // Get the next FID to use as an epoch number for the server
         let epoch = counter.fetch_inc();
 
+        // Open the directory indices
+        let dir_index = DirIndexes::new((data_dir).as_ref().join("index")).unwrap();
+
+        // Remember the requests done before we restarted
+        let drc = DupCache::new((data_dir).as_ref().join("drc"), DRC_CAPACITY).unwrap();
+
+        // Remember the files silly renamed before we restarted
+        let silly_files = SillyFiles::new((data_dir).as_ref().join("silly")).unwrap();
+
         // Create the struct
         ZippynfsServer {
             data_dir,
             counter,
-            name_lock: Mutex::new(HashSet::new()),
+            name_locks: NameLockManager::new(),
+            faults: FaultInjector::new(),
             epoch,
             fid_cache: RwLock::new(HashMap::new()),
+            dir_index,
             async_bufs: RwLock::new(HashMap::new()),
+            rewrite_locks: Mutex::new(HashMap::new()),
+            drc,
+            open_files: OpenFiles::new(),
+            leases: Leases::new(Duration::from_secs(LEASE_SECS)),
+            silly_files,
+            locks: LockManager::new(),
+            delegations: Delegations::new(),
+            callbacks: Callbacks::new(),
         }
     }
 
@@ -249,35 +355,32 @@ impl<'a, P: AsRef<Path>> ZippynfsServer<'a, P> {
         }
     }
 
+    /// Returns true if the NFS file `fid` called `fname` exists in the directory `dpath`, i.e. if
+    /// both its numbered and named server files exist there.
+    fn fs_exists(&self, dpath: &Path, fid: Fid, fname: &str) -> bool {
+        dpath.join(format!("{}", fid)).exists() && dpath.join(format!("{}.{}", fid, fname)).exists()
+    }
+
     /// Get the id associated with a file named `fname` in the directory `path` on the NFS server.
+    ///
+    /// The directory index gives us a candidate in constant time, which we then check against the
+    /// server files themselves.
     fn fs_find_by_name(&self, path: PathBuf, fname: &str) -> Result<Option<usize>, String> {
         // Sanity
         assert!(fname.len() > 0);
         assert!(path.is_dir());
 
-        // Compile regex to check if a filename is a numbered file
-        let re_is_name = Regex::new(NUMBERED_FILE_RE).unwrap();
-
-        // Compile regex to check if this is the file we want
-        let re_is_file = Regex::new(&format!("^(\\d+)\\.{}$", fname)).unwrap();
-
-        // Get the named and numbered files in the directory
-        let (numbered_files, named_files) = self.get_numbered_and_named_files(&re_is_name, &path)?;
+        self.faults.check(FaultPoint::FindByName)?;
 
-        for fname in named_files.iter() {
-            // Found a match
-            if let Some(id) = re_is_file
-                .captures(fname.file_name().unwrap().to_str().unwrap())
-                .map(|m| m.get(1).unwrap().as_str().parse().unwrap())
-            {
-                // Check that there is a matching numbered file
-                if numbered_files.contains(&path.as_path().join(format!("{}", id))) {
-                    return Ok(Some(id));
-                }
-            }
-        }
+        // Look for a candidate in the index
+        let candidate = self.dir_index.lookup(dir_fid(&path), &path, fname)?;
 
-        Ok(None)
+        // Make sure it really exists
+        Ok(candidate.and_then(|fid| if self.fs_exists(&path, fid, fname) {
+            Some(fid)
+        } else {
+            None
+        }))
     }
 
     /// Get the attributes of the given existing file.
@@ -285,6 +388,12 @@ impl<'a, P: AsRef<Path>> ZippynfsServer<'a, P> {
     /// NOTE: This method ASSUMES the file actually exists! So you need to check before
     /// calling this method!
     fn fs_get_attr(&self, fpath_numbered: PathBuf, fid: u64) -> ZipFattr {
+        self.fs_try_get_attr(fpath_numbered, fid).unwrap()
+    }
+
+    /// Like `fs_get_attr`, but returns `None` rather than panicking if the file has disappeared.
+    /// Use this when the file may be concurrently removed, since we hold no locks on it.
+    fn fs_try_get_attr(&self, fpath_numbered: PathBuf, fid: u64) -> Option<ZipFattr> {
         // Sanity
         assert_eq!(
             fpath_numbered.file_name().unwrap().to_str().unwrap(),
@@ -292,7 +401,10 @@ impl<'a, P: AsRef<Path>> ZippynfsServer<'a, P> {
         );
 
         // Get attributes of the file
-        let fmeta = fpath_numbered.metadata().unwrap();
+        let fmeta = match fpath_numbered.metadata() {
+            Ok(fmeta) => fmeta,
+            Err(_) => return None,
+        };
 
         let size = fmeta.len() as u32;
         let blocks = (size + (BLOCK_SIZE - 1)) / BLOCK_SIZE;
@@ -313,8 +425,8 @@ impl<'a, P: AsRef<Path>> ZippynfsServer<'a, P> {
             ZipTimeVal::new(0, 0)
         };
 
-        ZipFattr::new(
-            if fpath_numbered.is_dir() {
+        Some(ZipFattr::new(
+            if fmeta.is_dir() {
                 ZipFtype::NFDIR
             } else {
                 ZipFtype::NFREG
@@ -332,7 +444,7 @@ impl<'a, P: AsRef<Path>> ZippynfsServer<'a, P> {
             accessed,
             modified,
             created,
-        )
+        ))
     }
 
     /// Set the attributes on the given file.
@@ -355,6 +467,8 @@ impl<'a, P: AsRef<Path>> ZippynfsServer<'a, P> {
         use std::ffi::CStr;
         use std::os::unix::io::AsRawFd;
 
+        self.faults.check(FaultPoint::SetAttr)?;
+
         // Create f, so we can change its metadata
         let mut open_options = OpenOptions::new();
         if fpath_numbered.is_dir() {
@@ -362,7 +476,7 @@ impl<'a, P: AsRef<Path>> ZippynfsServer<'a, P> {
         } else {
             open_options.read(true).write(true)
         };
-        let f = open_options.open(&fpath_numbered).unwrap();
+        let f = open_options.open(&fpath_numbered)?;
 
         // Update size
         if let Some(size) = size {
@@ -417,24 +531,6 @@ impl<'a, P: AsRef<Path>> ZippynfsServer<'a, P> {
         Ok(())
     }
 
-    /// Add the given name to the `name_lock`.
-    ///
-    /// Returns true if the name was locked and false it was already locked.
-    fn lock_name(&self, name: (PathBuf, String)) -> bool {
-        self.name_lock.lock().unwrap().insert(name)
-    }
-
-    /// Remove the given name from the `name_lock`
-    ///
-    /// This should always succeed.
-    ///
-    /// NOTE: The burden is on the caller to ensure the name is already in the `name_lock`.
-    /// We will `panic!` otherwise!
-    fn unlock_name(&self, name: &(PathBuf, String)) {
-        let present = self.name_lock.lock().unwrap().remove(name);
-        assert!(present);
-    }
-
     /// Create the filesystem object in the given directory and increment counter
     ///
     /// NOTE: This method ASSUMES the object does not exist! So you need to check before
@@ -449,6 +545,8 @@ impl<'a, P: AsRef<Path>> ZippynfsServer<'a, P> {
         let fpath_numbered = dpath.join(fid.to_string());
         let fpath_named = dpath.join(format!("{}.{}", fid, fname));
 
+        self.faults.check(FaultPoint::CreateNumbered)?;
+
         // Create numbered file or directory
         if is_file {
             // NOTE: Because we don't implement permissions, set them to 600, so that the server can do
@@ -463,20 +561,56 @@ impl<'a, P: AsRef<Path>> ZippynfsServer<'a, P> {
             create_dir(&fpath_numbered).map_err(|e| format!("{}", e))?;
         }
 
-        // Sync the directory
-        let dir = File::open(dpath).unwrap();
-        dir.sync_all().map_err(|e| format!("{}", e))?;
+        let named = (|| -> Result<(), String> {
+            // Sync the directory
+            let dir = File::open(&dpath).map_err(|e| format!("{}", e))?;
+            dir.sync_all().map_err(|e| format!("{}", e))?;
 
-        // Create named file
-        File::create(&fpath_named).map_err(|e| format!("{}", e))?;
+            // Add the name to the index before the object comes into existence
+            self.dir_index.insert(dir_fid(&dpath), &dpath, fid, fname)?;
 
-        // Sync the directory
-        dir.sync_all().map_err(|e| format!("{}", e))?;
+            self.faults.check(FaultPoint::CreateNamed)?;
+
+            // Create named file
+            File::create(&fpath_named).map_err(|e| format!("{}", e))?;
+
+            // Sync the directory
+            dir.sync_all().map_err(|e| format!("{}", e))
+        })();
+
+        // Don't leave an orphan numbered object behind
+        if let Err(e) = named {
+            self.fs_undo_create_obj(&dpath, fid, fname, is_file);
+            return Err(e);
+        }
 
         // Done
         Ok((fid, fpath_numbered))
     }
 
+    /// Remove whatever there is of an object that `fs_create_obj` created, because creating it
+    /// failed part of the way through. This is best effort: anything left behind is cleaned up
+    /// like after a crash, when the server restarts.
+    fn fs_undo_create_obj(&self, dpath: &Path, fid: Fid, fname: &str, is_file: bool) {
+        let fpath_numbered = dpath.join(fid.to_string());
+        let fpath_named = dpath.join(format!("{}.{}", fid, fname));
+
+        warn!("Removing half-created object {:?}", fpath_numbered);
+
+        let _ = if is_file {
+            remove_file(&fpath_numbered)
+        } else {
+            remove_dir(&fpath_numbered)
+        };
+        let _ = remove_file(&fpath_named);
+        if let Ok(dir) = File::open(dpath) {
+            let _ = dir.sync_all();
+        }
+
+        // Now that the object is gone, remove it from the index
+        let _ = self.dir_index.remove(dir_fid(dpath), dpath, fid, fname);
+    }
+
     /// A helper for `handle_mkdir` and `handle_create`, which creates either a file
     /// or a directory depending on is_file.
     fn create_object(&self, fsargs: ZipCreateArgs, is_file: bool) -> thrift::Result<ZipDirOpRes> {
@@ -497,27 +631,25 @@ impl<'a, P: AsRef<Path>> ZippynfsServer<'a, P> {
             return Err(nfs_error(ZipErrorType::NFSERR_NOTDIR));
         }
 
-        // Lock the name so that after we check we know we have the name
-        if !self.lock_name((dpath.clone(), filename.clone())) {
-            // Could not lock == name already exists (so one else got there first)
+        if is_dot_name(filename) {
             return Err(nfs_error(ZipErrorType::NFSERR_EXIST));
         }
+        if filename.len() > NAME_MAX {
+            return Err(nfs_error(ZipErrorType::NFSERR_NAMETOOLONG));
+        }
 
-        // NOTE: We cannot use `?` until we unlock so as not to cause deadlock!
+        // Lock the name so that after we check we know we have the name. The name is unlocked
+        // when `_guard` is dropped, including on any early return below.
+        let _guard = match self.name_locks.try_lock(fsargs.where_.dir.fid as Fid, filename) {
+            Some(guard) => guard,
+            None => {
+                // Could not lock == name already exists (so one else got there first)
+                return Err(nfs_error(ZipErrorType::NFSERR_EXIST));
+            }
+        };
 
         // Make sure the given filename does not exist already
-        let already = self.fs_find_by_name(dpath.clone(), &filename);
-
-        // If we have some random error, then unlock
-        if already.is_err() {
-            self.unlock_name(&(dpath.clone(), filename.clone()));
-            debug!("File \"{}\" exists", filename);
-            return Err(already.err().unwrap().into());
-        }
-
-        // If the name already exists, then unlock
-        if already.ok().unwrap().is_some() {
-            self.unlock_name(&(dpath.clone(), filename.clone()));
+        if self.fs_find_by_name(dpath.clone(), &filename)?.is_some() {
             debug!("File \"{}\" exists", filename);
             return Err(nfs_error(ZipErrorType::NFSERR_EXIST));
         }
@@ -527,17 +659,19 @@ impl<'a, P: AsRef<Path>> ZippynfsServer<'a, P> {
         // Create a new object
         let (new_fid, fpath_numbered) = self.fs_create_obj(dpath.clone(), filename, is_file)?;
 
-        // Set attributes on the new file
-        self.fs_set_attr(
+        // Set attributes on the new file. If we can't, the client would be told that the CREATE
+        // failed, so the object mustn't exist either.
+        if let Err(e) = self.fs_set_attr(
             fpath_numbered.clone(),
             new_fid,
             fsargs.attributes.atime,
             fsargs.attributes.mtime,
             fsargs.attributes.size.map(|s| s as usize),
-        )?;
-
-        // Unlock filename
-        self.unlock_name(&(dpath, filename.to_owned()));
+        )
+        {
+            self.fs_undo_create_obj(&dpath, new_fid, filename, is_file);
+            return Err(e);
+        }
 
         // Insert into cache
         self.fid_cache.write().unwrap().insert(
@@ -552,6 +686,270 @@ impl<'a, P: AsRef<Path>> ZippynfsServer<'a, P> {
         ))
     }
 
+    /// The reply to a CREATE or MKDIR of the given object that has already been done.
+    fn created_object(&self, fid: Fid) -> thrift::Result<ZipDirOpRes> {
+        // It may have been removed since, in which case we can't tell the client much
+        let fpath = match self.fs_find_by_fid(fid)? {
+            Some(fpath) => fpath,
+            None => return Err(nfs_error(ZipErrorType::NFSERR_STALE)),
+        };
+
+        Ok(ZipDirOpRes::new(
+            ZipFileHandle::new(fid as i64),
+            self.fs_get_attr(fpath, fid as u64),
+        ))
+    }
+
+    /// A helper for `handle_remove` and `handle_rmdir`, which removes either a file or a
+    /// directory depending on is_file.
+    fn remove_object(&self, fsargs: ZipDirOpArgs, is_file: bool) -> thrift::Result<()> {
+        // Find the directory
+        let dpath = self.fs_find_by_fid(fsargs.dir.fid as usize)?;
+        debug!("Found parent at path {:?}", dpath);
+
+        // Make sure that directory exists
+        if dpath.is_none() {
+            return Err(nfs_error(ZipErrorType::NFSERR_STALE));
+        }
+
+        let dpath = dpath.unwrap();
+
+        // Make sure dpath is a directory
+        if !dpath.is_dir() {
+            return Err(nfs_error(ZipErrorType::NFSERR_NOTDIR));
+        }
+
+        // Lookup the file in the directory
+        let fid = self.fs_find_by_name(dpath.clone(), &fsargs.filename)?;
+
+        match fid {
+            Some(fid) => {
+                debug!("File \"{}\" with fid = {}", fsargs.filename, fid);
+
+                // Make sure that it is the right kind of object
+                let is_dir = dpath.join(format!("{}", fid)).is_dir();
+                if is_file && is_dir {
+                    Err(nfs_error(ZipErrorType::NFSERR_ISDIR))
+                } else if !is_file && !is_dir {
+                    Err(nfs_error(ZipErrorType::NFSERR_NOTDIR))
+                } else {
+                    // Nobody can go on caching a file that is gone. REMOVE doesn't say who it is
+                    // from, so this recalls everyone's.
+                    let _conflict = if is_file {
+                        Some(self.recall(fid, true, |_, _| true))
+                    } else {
+                        None
+                    };
+
+                    // Remove the object
+                    self.fs_delete_obj(
+                        dpath,
+                        fid as u64,
+                        &fsargs.filename,
+                        is_file,
+                    )?;
+                    self.note_silly(fid, None, None);
+                    Ok(())
+                }
+            }
+            None => {
+                debug!("File \"{}\" does not exist", fsargs.filename);
+                Err(nfs_error(ZipErrorType::NFSERR_NOENT))
+            }
+        }
+    }
+
+    /// A helper for `handle_rename`, which moves a file or directory.
+    fn rename_object(&self, fsargs: ZipRenameArgs) -> thrift::Result<()> {
+        // Find the old directory
+        let old_loc_dpath = self.fs_find_by_fid(fsargs.old_loc.dir.fid as usize)?;
+
+        // Make sure it exists
+        let old_loc_dpath = if let Some(path) = old_loc_dpath {
+            path
+        } else {
+            return Err(nfs_error(ZipErrorType::NFSERR_STALE));
+        };
+
+        // Find the new directory
+        let new_loc_dpath = self.fs_find_by_fid(fsargs.new_loc.dir.fid as usize)?;
+
+        // Make sure it exists
+        let new_loc_dpath = if let Some(path) = new_loc_dpath {
+            path
+        } else {
+            return Err(nfs_error(ZipErrorType::NFSERR_STALE));
+        };
+
+        // Find the file to be moved
+        let fid = self.fs_find_by_name(
+            old_loc_dpath.clone(),
+            &fsargs.old_loc.filename,
+        )?;
+
+        // make sure it exists
+        if fid.is_none() {
+            return Err(nfs_error(ZipErrorType::NFSERR_NOENT));
+        }
+
+        if is_dot_name(&fsargs.new_loc.filename) {
+            return Err(nfs_error(ZipErrorType::NFSERR_EXIST));
+        }
+        if fsargs.new_loc.filename.len() > NAME_MAX {
+            return Err(nfs_error(ZipErrorType::NFSERR_NAMETOOLONG));
+        }
+
+        // Lock the name so that after we check we know we have the name. The name is unlocked
+        // when `guard` is dropped, including on any early return below.
+        let guard = match self.name_locks.try_lock(
+            fsargs.new_loc.dir.fid as Fid,
+            &fsargs.new_loc.filename,
+        ) {
+            Some(guard) => guard,
+            None => {
+                // Could not lock == name already exists (so one else got there first)
+                return Err(nfs_error(ZipErrorType::NFSERR_EXIST));
+            }
+        };
+
+        // Make sure the given filename does not exist already
+        if self.fs_find_by_name(
+            new_loc_dpath.clone(),
+            &fsargs.new_loc.filename,
+        )?
+            .is_some()
+        {
+            return Err(nfs_error(ZipErrorType::NFSERR_EXIST));
+        }
+
+        // If we get to this point, we know that we own the name!
+
+        let new_loc_dir = File::open(new_loc_dpath.clone())?;
+
+        let fid = fid.unwrap();
+        let old_loc_fpath_named =
+            old_loc_dpath.join(format!("{}.{}", fid, fsargs.old_loc.filename));
+        let new_loc_fpath_named = new_loc_dpath.clone().join(format!(
+            "{}.{}",
+            fid,
+            fsargs.new_loc.filename.clone()
+        ));
+
+        self.faults.check(FaultPoint::RenameNamed)?;
+
+        // Add the new name to the index before the file can be seen under it
+        self.dir_index.insert(
+            fsargs.new_loc.dir.fid as Fid,
+            &new_loc_dpath,
+            fid,
+            &fsargs.new_loc.filename,
+        )?;
+
+        // Create the new named file
+        File::create(&new_loc_fpath_named)?;
+
+        // Sync the directory
+        new_loc_dir.sync_all()?;
+
+        // Atomic rename numbered file to new location
+        //
+        // While we are doing the rename itself, we need to keep the `fid_cache` locked
+        let old_loc_fpath_numbered = old_loc_dpath.join(fid.to_string());
+        let new_loc_fpath_numbered = new_loc_dpath.clone().join(fid.to_string());
+        {
+            let mut fid_cache_locked = self.fid_cache.write().unwrap();
+
+            self.faults.check(FaultPoint::RenameNumbered)?;
+
+            rename(old_loc_fpath_numbered, new_loc_fpath_numbered)?;
+
+            // Sync the directory
+            new_loc_dir.sync_all()?;
+
+            // Update the cache if the value is in it. Otherwise insert it.
+            let old = fid_cache_locked.insert(fid, fsargs.new_loc.dir.fid as usize);
+
+            // Sanity
+            if let Some(old) = old {
+                assert_eq!(old, fsargs.old_loc.dir.fid as usize);
+            }
+        } // unlock `fid_cache`
+
+        // At this point the file has been renamed... we just need to clean up
+
+        // Unlock the name
+        drop(guard);
+
+        // Remove the old named file... we don't even need to sync!
+        remove_file(old_loc_fpath_named)?;
+
+        // The file no longer exists under the old name, so remove it from the index
+        self.dir_index.remove(
+            fsargs.old_loc.dir.fid as Fid,
+            &old_loc_dpath,
+            fid,
+            &fsargs.old_loc.filename,
+        )?;
+
+        self.note_silly(
+            fid,
+            Some((fsargs.new_loc.dir.fid as Fid, fsargs.new_loc.filename.as_str())),
+            fsargs.client,
+        );
+
+        // DONE!
+        Ok(())
+    }
+
+    /// Do a request that changes the FS at most once for the given XID, so that a retry gets the
+    /// outcome of the first try, rather than e.g. NFSERR_EXIST. Requests without an XID are just
+    /// done.
+    ///
+    /// `op` does the request, and returns the reply along with the FID to remember for it (see
+    /// `Outcome::Done`). `replay` builds the reply again from that FID.
+    fn at_most_once<T, F, G>(&self, xid: Option<i64>, op: F, replay: G) -> thrift::Result<T>
+    where
+        F: FnOnce() -> thrift::Result<(Fid, T)>,
+        G: FnOnce(Fid) -> thrift::Result<T>,
+    {
+        let xid = match xid {
+            Some(xid) if xid != 0 => xid,
+            _ => return op().map(|(_, res)| res),
+        };
+
+        match self.drc.begin(xid) {
+            Some(Outcome::Done(fid)) => {
+                info!("Replaying the reply to XID {:x}", xid);
+                return replay(fid);
+            }
+            Some(Outcome::Failed(error, message)) => {
+                info!("Replaying the error for XID {:x}", xid);
+                let exception = ZipException {
+                    error: Box::new(error),
+                    message,
+                };
+                return Err(exception.into());
+            }
+            None => {}
+        }
+
+        let result = op();
+
+        // Only remember NFS errors. Anything else (e.g. an I/O error) might not happen again.
+        let outcome = match result {
+            Ok((fid, _)) => Some(Outcome::Done(fid)),
+            Err(thrift::Error::User(ref e)) => {
+                e.downcast_ref::<ZipException>().map(|e| {
+                    Outcome::Failed(*e.error, e.message.clone())
+                })
+            }
+            Err(_) => None,
+        };
+        self.drc.finish(xid, outcome);
+
+        result.map(|(_, res)| res)
+    }
+
     /// Delete the filesystem object in the given directory with the given fid
     ///
     /// NOTE: This method ASSUMES the file actually exists! So you need to check before
@@ -587,7 +985,7 @@ impl<'a, P: AsRef<Path>> ZippynfsServer<'a, P> {
         let _ = fid_cache_locked.remove(&(fid as usize));
 
         // Sync the directory
-        let dir = File::open(dpath).unwrap();
+        let dir = File::open(&dpath).unwrap();
         dir.sync_all().map_err(|e| format!("{}", e))?;
 
         // Remove numbered file
@@ -596,55 +994,125 @@ impl<'a, P: AsRef<Path>> ZippynfsServer<'a, P> {
         // Sync the directory
         dir.sync_all().map_err(|e| format!("{}", e))?;
 
-        // `fid_cache_locked` dropped
+        // Now that the object is gone, remove it from the index
+        self.dir_index.remove(
+            dir_fid(&dpath),
+            &dpath,
+            fid as Fid,
+            fname,
+        )?;
+        if !is_file {
+            self.dir_index.remove_dir(fid as Fid)?;
+        }
+
+        // `fid_cache_locked` dropped
 
         // Done
         Ok(())
     }
 
-    /// Get a set of `(fid, name)` for all entries in the given directory.
-    fn fs_read_dir(&self, dpath: PathBuf) -> Result<HashSet<(u64, String, ZipFtype)>, String> {
-        let re = Regex::new(NUMBERED_FILE_RE).unwrap();
-        let (numbered_files, named_files) = self.get_numbered_and_named_files(&re, &dpath)?;
+    /// Offer each entry `(fid, names, type)` of the given directory with a FID greater than
+    /// `cookie` to `add`, in FID order, until `add` returns false to say that it has had enough.
+    /// Returns true if we reached the end of the directory.
+    ///
+    /// Since FIDs are never reused and new files always get larger FIDs, the FID of the last
+    /// entry taken is a stable place to resume listing from, no matter what gets created or
+    /// removed in the meantime. A file only has more than one name if a rename failed half way,
+    /// and `add` must take all of them or none, so that a listing resumed from its FID doesn't
+    /// miss any.
+    fn fs_read_dir<F>(&self, dpath: &Path, cookie: Fid, mut add: F) -> Result<bool, String>
+    where
+        F: FnMut(Fid, Vec<String>, ZipFtype) -> bool,
+    {
+        let did = dir_fid(dpath);
+        let mut cookie = cookie;
+
+        loop {
+            let candidates = self.dir_index.entries_after(
+                did,
+                dpath,
+                cookie,
+                READDIR_BATCH,
+            )?;
+
+            if let Some(&(fid, _)) = candidates.last() {
+                cookie = fid;
+            } else {
+                return Ok(true);
+            }
 
-        Ok(
-            named_files
-                .into_iter()
-                .map(|fname| {
-                    let mut named_file =
-                        fname.file_name().unwrap().to_str().unwrap().splitn(2, ".");
-                    let number = named_file.next().unwrap();
-                    let name = named_file.next().unwrap();
+            for (fid, names) in candidates {
+                // Some candidates in the index may not really exist
+                let names: Vec<String> = names
+                    .into_iter()
+                    .filter(|name| self.fs_exists(dpath, fid, name))
+                    .collect();
+                if names.is_empty() {
+                    continue;
+                }
 
-                    let numbered_file = fname.parent().unwrap().join(number);
+                let ftype = if dpath.join(format!("{}", fid)).is_dir() {
+                    ZipFtype::NFDIR
+                } else {
+                    ZipFtype::NFREG
+                };
 
-                    let ftype = if numbered_file.is_dir() {
-                        ZipFtype::NFDIR
-                    } else {
-                        ZipFtype::NFREG
-                    };
+                if !add(fid, names, ftype) {
+                    return Ok(false);
+                }
+            }
+        }
+    }
 
-                    (numbered_file, (
-                        number.parse().unwrap(),
-                        name.to_owned(),
-                        ftype,
-                    ))
-                })
-                .filter(|&(ref numbered_file, _)| {
-                    numbered_files.contains(numbered_file)
-                })
-                .map(|(_, pair)| pair)
-                .collect(),
-        )
+    /// Returns the number of bytes of a READDIR(PLUS) result we can fill, given the size the
+    /// client asked for.
+    fn readdir_budget(method: &str, maxcount: i64) -> usize {
+        let maxcount = if maxcount <= 0 {
+            DIR_PAGE_LEN
+        } else {
+            min(maxcount as usize, MAX_MSG_LEN)
+        };
+
+        maxcount.saturating_sub(wire::reply_overhead(method))
     }
 
-    fn fs_stable_write(
-        &self,
-        fid: Fid,
-        offset: usize,
-        count: usize,
-        buf: &[u8],
-    ) -> thrift::Result<usize> {
+    /// Returns the readdir cookie verifier.
+    ///
+    /// FID cookies never go stale, but the verifier changes whenever the server restarts so that
+    /// clients know to throw away anything they remember from an earlier listing.
+    fn cookie_verf(&self) -> i64 {
+        self.epoch as i64
+    }
+
+    /// Checks the arguments of a READDIR or READDIRPLUS, and returns the path of the directory
+    /// being listed.
+    fn readdir_start(&self, fsargs: &ZipReadDirArgs) -> thrift::Result<PathBuf> {
+        // Find the directory
+        let dpath = self.fs_find_by_fid(fsargs.dir.fid as usize)?;
+        debug!("Found parent at path {:?}", dpath);
+
+        // Make sure that directory exists
+        if dpath.is_none() {
+            return Err(nfs_error(ZipErrorType::NFSERR_STALE));
+        }
+
+        let dpath = dpath.unwrap();
+
+        // Make sure dpath is a directory
+        if !dpath.is_dir() {
+            return Err(nfs_error(ZipErrorType::NFSERR_NOTDIR));
+        }
+
+        // A client continuing a listing must present the verifier it got with the last page
+        if fsargs.cookie != 0 && fsargs.cookieverf != 0 && fsargs.cookieverf != self.cookie_verf() {
+            debug!("Stale cookie verifier {}", fsargs.cookieverf);
+            return Err(nfs_error(ZipErrorType::NFSERR_BAD_COOKIE));
+        }
+
+        Ok(dpath)
+    }
+
+    fn fs_stable_write(&self, fid: Fid, offset: usize, buf: &[u8]) -> thrift::Result<usize> {
         // find the file
         let fpath_numbered = self.fs_find_by_fid(fid)?;
         debug!("Found file at path {:?}", fpath_numbered);
@@ -656,38 +1124,63 @@ impl<'a, P: AsRef<Path>> ZippynfsServer<'a, P> {
 
         let fpath_numbered = fpath_numbered.unwrap();
 
-        // Create a tmp file by copying the existing file
-        //
-        // We name the tmp file after the FID and this thread's TID so
-        // as to avoid interleaving writes from different client reqs.
-        let tid = current().id();
-        let tmp_fpath = (&self.data_dir).as_ref().join(
-            format!("tmp/{}_{:?}", fid, tid),
-        );
-        copy(&fpath_numbered, &tmp_fpath)?;
+        self.with_rewrite_lock(fid, || {
+            // Create a tmp file by copying the existing file
+            //
+            // We name the tmp file after the FID and this thread's TID so
+            // as to avoid interleaving writes from different client reqs.
+            let tid = current().id();
+            let tmp_fpath = (&self.data_dir).as_ref().join(
+                format!("tmp/{}_{:?}", fid, tid),
+            );
+            copy(&fpath_numbered, &tmp_fpath)?;
 
-        {
-            // Open the file for the write
-            let mut tmp_file = OpenOptions::new().write(true).open(&tmp_fpath)?;
+            {
+                // Open the file for the write
+                let mut tmp_file = OpenOptions::new().write(true).open(&tmp_fpath)?;
 
-            // Sync the tmp file to ensure we have its contents
-            tmp_file.sync_all()?;
+                // Sync the tmp file to ensure we have its contents
+                tmp_file.sync_all()?;
 
-            // Seek to the write location
-            tmp_file.seek(SeekFrom::Start(offset as u64))?;
+                // Seek to the write location
+                tmp_file.seek(SeekFrom::Start(offset as u64))?;
 
-            // Write the data to the file
-            assert_eq!(buf.len(), count);
-            tmp_file.write_all(buf)?;
+                // Write the data to the file
+                tmp_file.write_all(buf)?;
 
-            // Sync the file
-            tmp_file.sync_all()?;
-        } // File closed
+                // Sync the file
+                tmp_file.sync_all()?;
+            } // File closed
 
-        // Atomic rename file
-        rename(tmp_fpath, fpath_numbered)?;
+            // Atomic rename file
+            rename(tmp_fpath, &fpath_numbered)?;
 
-        Ok(buf.len())
+            Ok(buf.len())
+        })
+    }
+
+    /// Run `f` while holding the lock for rewriting the file with the given FID.
+    fn with_rewrite_lock<T, F: FnOnce() -> T>(&self, fid: Fid, f: F) -> T {
+        let lock = self.rewrite_locks
+            .lock()
+            .unwrap()
+            .entry(fid)
+            .or_insert_with(|| Arc::new(Mutex::new(())))
+            .clone();
+
+        let result = {
+            let _guard = lock.lock().unwrap();
+            f()
+        };
+
+        // Forget the lock once nobody else is waiting for it, so the table doesn't keep every FID
+        // ever written. Others only get the lock from the table while holding the table's lock.
+        let mut rewrite_locks = self.rewrite_locks.lock().unwrap();
+        if Arc::strong_count(&lock) == 2 {
+            rewrite_locks.remove(&fid);
+        }
+
+        result
     }
 
     /// Get or create the `async_bufs` entry for the given FID.
@@ -712,9 +1205,147 @@ impl<'a, P: AsRef<Path>> ZippynfsServer<'a, P> {
             write_locked.get(&fid).unwrap().clone()
         }
     }
+
+    /// Check a LOCK or TESTLOCK: the file has to exist, and during the grace period, only locks
+    /// held before we restarted can be taken (unlocking is always fine).
+    fn check_lock(&self, fsargs: &ZipLockArgs) -> thrift::Result<()> {
+        if self.fs_find_by_fid(fsargs.file.fid as Fid)?.is_none() {
+            return Err(nfs_error(ZipErrorType::NFSERR_STALE));
+        }
+
+        let unlock = fsargs.lock.kind == ZipLockType::UNLOCK;
+        if !unlock && !fsargs.reclaim && self.leases.in_grace() {
+            return Err(nfs_error(ZipErrorType::NFSERR_GRACE));
+        }
+
+        // Locking also shows that the client is still around
+        self.leases.renew(fsargs.client);
+
+        Ok(())
+    }
+
+    /// The reply to a LOCK or TESTLOCK by the client, given the conflicting lock, if any. Process
+    /// IDs mean nothing on other clients, so theirs are left out.
+    fn lock_res(&self, client: i64, conflict: Option<(i64, ZipLock)>) -> ZipLockRes {
+        let conflict = conflict.map(|(holder, mut lock)| {
+            if holder != client {
+                lock.pid = 0;
+            }
+            lock
+        });
+
+        ZipLockRes::new(conflict.is_none(), conflict, self.epoch as i64)
+    }
+
+    /// Recall the delegations of the file that conflict with a request, as picked by `conflicts`
+    /// given the holder and the kind, before the request is carried out. A holder that can't be
+    /// reached in time loses its delegation anyway. No conflicting delegation is given until the
+    /// returned `Conflict` is dropped, so keep it until the request is done. `change` says whether
+    /// the request changes the file, and so conflicts with READ delegations too.
+    fn recall<F>(&self, fid: Fid, change: bool, conflicts: F) -> Conflict
+    where
+        F: Fn(i64, ZipDelegationType) -> bool,
+    {
+        let (conflict, taken) = self.delegations.conflict(fid, change, conflicts);
+        if taken.is_empty() {
+            return conflict;
+        }
+
+        for &(client, kind) in taken.iter() {
+            info!("Recalling {:?} delegation of fid={} from {:x}", kind, fid, client);
+        }
+
+        // The holders may need workers to answer (e.g. to COMMIT), so don't tie this one up
+        for (client, e) in park(|| self.callbacks.recall(fid, taken)) {
+            warn!("Unable to recall the delegation of fid={} from {:x}: {}", fid, client, e);
+        }
+        conflict
+    }
+
+    /// Note that the file was renamed by the given client to the given name in the given directory,
+    /// or removed if `new_loc` is `None`, keeping track of it if the client gave it its own silly
+    /// name.
+    ///
+    /// A client that doesn't say who it is isn't tracking its open files for us, so we can't tell
+    /// when its silly files are orphaned, and leave them alone.
+    fn note_silly(&self, fid: Fid, new_loc: Option<(Fid, &str)>, client: Option<i64>) {
+        let result = match new_loc {
+            Some((did, name)) if client.is_some() && silly_owner(name) == client => {
+                self.silly_files.insert(fid, did, name, client.unwrap())
+            }
+            _ => self.silly_files.remove(fid),
+        };
+
+        // At worst, the file is left behind (or we look for it again)
+        if let Err(e) = result {
+            warn!("Unable to record silly file fid={}: {}", fid, e);
+        }
+    }
+
+    /// Remove a file with a silly name, if it is still there under that name.
+    fn remove_silly(&self, fid: Fid, did: Fid, name: &str) -> thrift::Result<()> {
+        if let Some(dpath) = self.fs_find_by_fid(did)? {
+            let found = self.fs_find_by_name(dpath.clone(), name)?;
+            if found == Some(fid) && !dpath.join(format!("{}", fid)).is_dir() {
+                self.fs_delete_obj(dpath, fid as u64, name, true)?;
+            }
+        }
+
+        self.note_silly(fid, None, None);
+        Ok(())
+    }
+
+    /// Clean up after clients whose leases have run out: forget what they had open, drop their
+    /// locks and delegations, and remove the files they silly renamed that nobody has open any
+    /// more. This should be called every so often.
+    pub fn reap(&self) {
+        for client in self.leases.expire() {
+            info!("Client {:x} has gone away", client);
+            self.open_files.forget_client(client);
+            self.locks.forget_client(client);
+            self.delegations.forget_client(client);
+            self.callbacks.forget_client(client);
+        }
+
+        let orphans: Vec<(Fid, SillyFile)> = self.silly_files.find(|fid, client| {
+            !self.leases.is_live(client) && !self.open_files.is_open(fid)
+        });
+
+        for (fid, (did, name, _)) in orphans {
+            info!("Removing orphaned silly file {:?} (fid={}) in {}", name, fid, did);
+            if let Err(e) = self.remove_silly(fid, did, &name) {
+                warn!("Unable to remove {:?}: {:?}", name, e);
+            }
+        }
+    }
 }
 
 impl<'a, P: AsRef<Path>> ZippynfsSyncHandler for ZippynfsServer<'a, P> {
+    fn handle_hello(&self, fsargs: ZipHelloArgs) -> thrift::Result<ZipHelloRes> {
+        info!("Handling HELLO {:?}", fsargs);
+
+        // We can't speak anything older than MIN_PROTOCOL_VERSION. If the client is newer than
+        // us, it is up to the client to decide whether it can speak our version.
+        if fsargs.version < MIN_PROTOCOL_VERSION {
+            warn!("Rejecting client with protocol version {}", fsargs.version);
+            return Err(nfs_error_details(
+                ZipErrorType::NFSERR_VERSION,
+                &format!(
+                    "client speaks version {}, but server needs {} to {}",
+                    fsargs.version,
+                    MIN_PROTOCOL_VERSION,
+                    PROTOCOL_VERSION
+                ),
+            ));
+        }
+
+        Ok(ZipHelloRes::new(
+            min(fsargs.version, PROTOCOL_VERSION),
+            SERVER_CAPS,
+            self.handle_fsinfo(ZipFileHandle::new(1))?,
+        ))
+    }
+
     fn handle_null(&self) -> thrift::Result<i64> {
         info!("Handling NULL");
         Ok(self.epoch as i64)
@@ -753,6 +1384,12 @@ impl<'a, P: AsRef<Path>> ZippynfsSyncHandler for ZippynfsServer<'a, P> {
             Some(fpath_numbered) => {
                 debug!("Found file at server path {:?}", fpath_numbered);
 
+                // Nobody else can go on caching the old attributes
+                let client = fsargs.client;
+                let _conflict = self.recall(fsargs.file.fid as Fid, true, |holder, _| {
+                    Some(holder) != client
+                });
+
                 // Attempt to set attributes
                 self.fs_set_attr(
                     fpath_numbered.clone(),
@@ -793,17 +1430,31 @@ impl<'a, P: AsRef<Path>> ZippynfsSyncHandler for ZippynfsServer<'a, P> {
             return Err(nfs_error(ZipErrorType::NFSERR_NOTDIR));
         }
 
-        // Lookup the file in the directory
-        let fid = self.fs_find_by_name(dpath.clone(), &fsargs.filename)?;
+        // Lookup the file in the directory. "." and ".." are not really in the directory, but we
+        // know where they are anyway. The root is its own parent.
+        let found = match fsargs.filename.as_str() {
+            "." => Some((dir_fid(&dpath), dpath.clone())),
+            ".." if dir_fid(&dpath) == 1 => Some((1, dpath.clone())),
+            ".." => {
+                let parent = dpath.parent().unwrap().to_owned();
+                Some((dir_fid(&parent), parent))
+            }
+            name if name.len() > NAME_MAX => {
+                return Err(nfs_error(ZipErrorType::NFSERR_NAMETOOLONG));
+            }
+            name => {
+                self.fs_find_by_name(dpath.clone(), name)?.map(|fid| {
+                    (fid, dpath.join(format!("{}", fid)))
+                })
+            }
+        };
 
         // Return a result
-        match fid {
-            Some(fid) => {
+        match found {
+            Some((fid, fpath_numbered)) => {
                 debug!("File \"{}\" with fid = {}", fsargs.filename, fid);
 
                 // Get attributes of the file
-                let fpath_numbered = dpath.join(format!("{}", fid));
-
                 Ok(ZipDirOpRes::new(
                     ZipFileHandle::new(fid as i64),
                     self.fs_get_attr(fpath_numbered, fid as u64),
@@ -838,8 +1489,14 @@ impl<'a, P: AsRef<Path>> ZippynfsSyncHandler for ZippynfsServer<'a, P> {
             return Err(nfs_error(ZipErrorType::NFSERR_ISDIR));
         }
 
+        // Whoever else has a WRITE delegation has to commit what it wrote first
+        let client = fsargs.client;
+        let _conflict = self.recall(fsargs.file.fid as Fid, false, |holder, kind| {
+            Some(holder) != client && kind == ZipDelegationType::WRITE
+        });
+
         // Get file contents
-        let mut data = vec![0; min(fsargs.count as usize, MAX_BUF_LEN)];
+        let mut data = vec![0; min(fsargs.count as usize, MAX_IO_LEN)];
         {
             let f = File::open(&fpath_numbered).unwrap();
             // The underlying filesystem makes sure this works, even if another thread
@@ -868,6 +1525,44 @@ impl<'a, P: AsRef<Path>> ZippynfsSyncHandler for ZippynfsServer<'a, P> {
         );
         debug!("{}", String::from_utf8_lossy(&fsargs.data));
 
+        if fsargs.count < 0 || fsargs.count as usize != fsargs.data.len() {
+            return Err(nfs_error_details(
+                ZipErrorType::NFSERR_INVAL,
+                &format!(
+                    "count is {} but there are {} bytes of data",
+                    fsargs.count,
+                    fsargs.data.len()
+                ),
+            ));
+        }
+        if fsargs.offset < 0 {
+            return Err(nfs_error_details(
+                ZipErrorType::NFSERR_INVAL,
+                &format!("negative offset {}", fsargs.offset),
+            ));
+        }
+
+        // FSINFO told the client not to send more than this in one WRITE
+        if fsargs.data.len() > MAX_IO_LEN {
+            return Err(nfs_error_details(
+                ZipErrorType::NFSERR_INVAL,
+                &format!(
+                    "{} bytes is more than wtmax of {}",
+                    fsargs.data.len(),
+                    MAX_IO_LEN
+                ),
+            ));
+        }
+
+        // Whoever else has a WRITE delegation has to commit what it wrote first, and nobody else
+        // can go on caching the old data once this shows. Unstable writes only show once they
+        // are committed, which recalls the READ ones then.
+        let client = fsargs.client;
+        let stable = fsargs.stable != ZipWriteStable::UNSTABLE;
+        let _conflict = self.recall(fsargs.file.fid as Fid, stable, |holder, kind| {
+            Some(holder) != client && (stable || kind == ZipDelegationType::WRITE)
+        });
+
         match fsargs.stable {
             ZipWriteStable::FILE_SYNC |
             ZipWriteStable::DATA_SYNC => {
@@ -875,13 +1570,9 @@ impl<'a, P: AsRef<Path>> ZippynfsSyncHandler for ZippynfsServer<'a, P> {
                 let bytes = self.fs_stable_write(
                     fsargs.file.fid as usize,
                     fsargs.offset as usize,
-                    fsargs.count as usize,
                     &fsargs.data,
                 )?;
 
-                // Sanity
-                assert_eq!(bytes, fsargs.data.len());
-
                 // DONE!
                 Ok(ZipWriteRes::new(
                     bytes as i64,
@@ -893,9 +1584,6 @@ impl<'a, P: AsRef<Path>> ZippynfsSyncHandler for ZippynfsServer<'a, P> {
             ZipWriteStable::UNSTABLE => {
                 let size = fsargs.count as usize;
 
-                // Sanity
-                assert_eq!(fsargs.data.len(), size);
-
                 // Get or create the appropriate buffer set and append the given data to the buffer
                 let unlocked = self.get_or_create_async_bufs(fsargs.file.fid as Fid);
 
@@ -919,287 +1607,157 @@ impl<'a, P: AsRef<Path>> ZippynfsSyncHandler for ZippynfsServer<'a, P> {
     fn handle_create(&self, fsargs: ZipCreateArgs) -> thrift::Result<ZipDirOpRes> {
         info!("Handling CREATE {:?}", fsargs);
 
-        self.create_object(fsargs, true)
+        let xid = fsargs.where_.xid;
+        self.at_most_once(
+            xid,
+            || self.create_object(fsargs, true).map(|res| (res.file.fid as Fid, res)),
+            |fid| self.created_object(fid),
+        )
     }
 
     fn handle_remove(&self, fsargs: ZipDirOpArgs) -> thrift::Result<()> {
         info!("Handling REMOVE {:?}", fsargs);
 
-        // find the directory
-        let dpath = self.fs_find_by_fid(fsargs.dir.fid as usize)?;
-        debug!("Found parent at path {:?}", dpath);
-
-        // make sure that directory exists
-        if dpath.is_none() {
-            return Err(nfs_error(ZipErrorType::NFSERR_STALE));
-        }
-
-        let dpath = dpath.unwrap();
-
-        // make sure dpath is a directory
-        if !dpath.is_dir() {
-            return Err(nfs_error(ZipErrorType::NFSERR_NOTDIR));
-        }
-
-        // lookup the file in the directory
-        let fid = self.fs_find_by_name(dpath.clone(), &fsargs.filename)?;
-
-        match fid {
-            Some(fid) => {
-                debug!("File \"{}\" with fid = {}", fsargs.filename, fid);
-
-                // should make sure that it is a file
-                if dpath.join(format!("{}", fid)).is_dir() {
-                    Err(nfs_error(ZipErrorType::NFSERR_ISDIR))
-                } else {
-                    // Remove the object
-                    self.fs_delete_obj(
-                        dpath,
-                        fid as u64,
-                        &fsargs.filename,
-                        true,
-                    )?;
-                    Ok(())
-                }
-            }
-            None => {
-                debug!("File \"{}\" does not exist", fsargs.filename);
-                Err(nfs_error(ZipErrorType::NFSERR_NOENT))
-            }
-        }
+        let xid = fsargs.xid;
+        self.at_most_once(
+            xid,
+            || self.remove_object(fsargs, true).map(|()| (0, ())),
+            |_| Ok(()),
+        )
     }
 
     fn handle_rename(&self, fsargs: ZipRenameArgs) -> thrift::Result<()> {
         info!("Handling RENAME {:?}", fsargs);
 
-        // Find the old directory
-        let old_loc_dpath = self.fs_find_by_fid(fsargs.old_loc.dir.fid as usize)?;
-
-        // Make sure it exists
-        let old_loc_dpath = if let Some(path) = old_loc_dpath {
-            path
-        } else {
-            return Err(nfs_error(ZipErrorType::NFSERR_STALE));
-        };
-
-        // Find the new directory
-        let new_loc_dpath = self.fs_find_by_fid(fsargs.new_loc.dir.fid as usize)?;
-
-        // Make sure it exists
-        let new_loc_dpath = if let Some(path) = new_loc_dpath {
-            path
-        } else {
-            return Err(nfs_error(ZipErrorType::NFSERR_STALE));
-        };
-
-        // Find the file to be moved
-        let fid = self.fs_find_by_name(
-            old_loc_dpath.clone(),
-            &fsargs.old_loc.filename,
-        )?;
-
-        // make sure it exists
-        if fid.is_none() {
-            return Err(nfs_error(ZipErrorType::NFSERR_NOENT));
-        }
-
-        // Lock the name so that after we check we know we have the name
-        if !self.lock_name((new_loc_dpath.clone(), fsargs.new_loc.filename.clone())) {
-            // Could not lock == name already exists (so one else got there first)
-            return Err(nfs_error(ZipErrorType::NFSERR_EXIST));
-        }
-
-        // NOTE: We cannot use `?` until we unlock so as not to cause deadlock!
-
-        // Make sure the given filename does not exist already
-        let already = self.fs_find_by_name(new_loc_dpath.clone(), &fsargs.new_loc.filename);
-
-        // If we have some random error, then unlock
-        if already.is_err() {
-            self.unlock_name(&(new_loc_dpath.clone(), fsargs.new_loc.filename.clone()));
-            return Err(already.err().unwrap().into());
-        }
-
-        // If the name already exists, then unlock
-        if already.ok().unwrap().is_some() {
-            self.unlock_name(&(new_loc_dpath.clone(), fsargs.new_loc.filename.clone()));
-            return Err(nfs_error(ZipErrorType::NFSERR_EXIST));
-        }
-
-        // If we get to this point, we know that we own the name!
-
-        let new_loc_dir = File::open(new_loc_dpath.clone()).unwrap();
-
-        let fid = fid.unwrap();
-        let old_loc_fpath_named =
-            old_loc_dpath.join(format!("{}.{}", fid, fsargs.old_loc.filename));
-        let new_loc_fpath_named = new_loc_dpath.clone().join(format!(
-            "{}.{}",
-            fid,
-            fsargs.new_loc.filename.clone()
-        ));
-
-        // Create the new named file
-        let res = File::create(&new_loc_fpath_named);
-        if res.is_err() {
-            self.unlock_name(&(new_loc_dpath.clone(), fsargs.new_loc.filename.clone()));
-            return Err(res.err().unwrap().into());
-        }
-
-        // Sync the directory
-        let res = new_loc_dir.sync_all();
-        if res.is_err() {
-            self.unlock_name(&(new_loc_dpath.clone(), fsargs.new_loc.filename.clone()));
-            return Err(res.err().unwrap().into());
-        }
-
-        // Atomic rename numbered file to new location
-        //
-        // While we are doing the rename itself, we need to keep the `fid_cache` locked
-        let old_loc_fpath_numbered = old_loc_dpath.join(fid.to_string());
-        let new_loc_fpath_numbered = new_loc_dpath.clone().join(fid.to_string());
-        {
-            let mut fid_cache_locked = self.fid_cache.write().unwrap();
-
-            let res = rename(old_loc_fpath_numbered, new_loc_fpath_numbered);
-            if res.is_err() {
-                self.unlock_name(&(new_loc_dpath.clone(), fsargs.new_loc.filename.clone()));
-                return Err(res.err().unwrap().into());
-            }
-
-            // Sync the directory
-            let res = new_loc_dir.sync_all();
-            if res.is_err() {
-                self.unlock_name(&(new_loc_dpath.clone(), fsargs.new_loc.filename));
-                return Err(res.err().unwrap().into());
-            }
-
-            // Update the cache if the value is in it. Otherwise insert it.
-            let old = fid_cache_locked.insert(fid, fsargs.new_loc.dir.fid as usize);
-
-            // Sanity
-            if let Some(old) = old {
-                assert_eq!(old, fsargs.old_loc.dir.fid as usize);
-            }
-        } // unlock `fid_cache`
-
-        // At this point the file has been renamed... we just need to clean up
-
-        // Unlock the name
-        self.unlock_name(&(new_loc_dpath.clone(), fsargs.new_loc.filename));
-
-        // Remove the old named file... we don't even need to sync!
-        remove_file(old_loc_fpath_named)?;
-
-        // DONE!
-        Ok(())
+        let xid = fsargs.old_loc.xid;
+        self.at_most_once(
+            xid,
+            || self.rename_object(fsargs).map(|()| (0, ())),
+            |_| Ok(()),
+        )
     }
 
     fn handle_mkdir(&self, fsargs: ZipCreateArgs) -> thrift::Result<ZipDirOpRes> {
         info!("Handling MKDIR {:?}", fsargs);
 
-        self.create_object(fsargs, false)
+        let xid = fsargs.where_.xid;
+        self.at_most_once(
+            xid,
+            || self.create_object(fsargs, false).map(|res| (res.file.fid as Fid, res)),
+            |fid| self.created_object(fid),
+        )
     }
 
     fn handle_rmdir(&self, fsargs: ZipDirOpArgs) -> thrift::Result<()> {
         info!("Handling RMDIR {:?}", fsargs);
 
-        // Find the directory
-        let dpath = self.fs_find_by_fid(fsargs.dir.fid as usize)?;
-        debug!("Found parent at path {:?}", dpath);
-
-        // Make sure that directory exists
-        if dpath.is_none() {
-            return Err(nfs_error(ZipErrorType::NFSERR_STALE));
-        }
+        let xid = fsargs.xid;
+        self.at_most_once(
+            xid,
+            || self.remove_object(fsargs, false).map(|()| (0, ())),
+            |_| Ok(()),
+        )
+    }
 
-        let dpath = dpath.unwrap();
+    fn handle_readdir(&self, fsargs: ZipReadDirArgs) -> thrift::Result<ZipReadDirRes> {
+        info!("Handling READDIR {:?}", fsargs);
 
-        // Make sure dpath is a directory
-        if !dpath.is_dir() {
-            return Err(nfs_error(ZipErrorType::NFSERR_NOTDIR));
-        }
+        let dpath = self.readdir_start(&fsargs)?;
 
-        // Lookup the file in the directory
-        let fid = self.fs_find_by_name(dpath.clone(), &fsargs.filename)?;
+        // Get as many entries as fit in the reply the client asked for, measured in actual bytes
+        // on the wire.
+        let budget = Self::readdir_budget("readdir", fsargs.maxcount);
+        let verf = self.cookie_verf();
+        let mut contents = Vec::new();
+        let mut contents_len = 0;
 
-        match fid {
-            Some(fid) => {
-                debug!("File \"{}\" with fid = {}", fsargs.filename, fid);
+        let eof = self.fs_read_dir(&dpath, fsargs.cookie as Fid, |fid, fnames, ftype| {
+            let entries: Vec<ZipDirEntry> = fnames
+                .into_iter()
+                .map(|fname| ZipDirEntry::new(fid as i64, fname, ftype))
+                .collect();
+            let entries_len: usize = entries.iter().map(wire::dir_entry_len).sum();
 
-                // should make sure that it is a dir
-                if !dpath.join(format!("{}", fid)).is_dir() {
-                    Err(nfs_error(ZipErrorType::NFSERR_NOTDIR))
-                } else {
-                    // Remove the object
-                    self.fs_delete_obj(
-                        dpath,
-                        fid as u64,
-                        &fsargs.filename,
-                        false,
-                    )?;
-                    Ok(())
-                }
-            }
-            None => {
-                debug!("File \"{}\" does not exist", fsargs.filename);
-                Err(nfs_error(ZipErrorType::NFSERR_NOENT))
+            if wire::read_dir_res_len(contents.len() + entries.len(), verf) + contents_len +
+                entries_len > budget
+            {
+                return false;
             }
-        }
-    }
 
-    fn handle_readdir(&self, fsargs: ZipReadDirArgs) -> thrift::Result<ZipReadDirRes> {
-        info!("Handling READDIR {:?}", fsargs);
+            contents_len += entries_len;
+            contents.extend(entries);
+            true
+        })?;
 
-        // Find the directory
-        let dpath = self.fs_find_by_fid(fsargs.dir.fid as usize)?;
-        debug!("Found parent at path {:?}", dpath);
-
-        // Make sure that directory exists
-        if dpath.is_none() {
-            return Err(nfs_error(ZipErrorType::NFSERR_STALE));
+        // Not even one entry fits
+        if contents.is_empty() && !eof {
+            return Err(nfs_error(ZipErrorType::NFSERR_TOOSMALL));
         }
 
-        let dpath = dpath.unwrap();
-
-        // Make sure dpath is a directory
-        if !dpath.is_dir() {
-            return Err(nfs_error(ZipErrorType::NFSERR_NOTDIR));
+        if eof {
+            debug!("END OF DIR");
         }
 
-        // Get directory contents
-        let contents = self.fs_read_dir(dpath)?;
+        debug!("Returning {} dirents", contents.len());
+        debug!("Contents: {:?}", contents);
 
-        if contents.len() <= (fsargs.offset as usize) {
-            debug!("END OF DIR");
+        Ok(ZipReadDirRes::new(contents, verf, eof))
+    }
 
-            // No more entries
-            Ok(ZipReadDirRes::new(Vec::new()))
-        } else {
-            // Collect the contents into an ordered collection
-            let mut contents: Vec<_> = contents
+    fn handle_readdirplus(&self, fsargs: ZipReadDirArgs) -> thrift::Result<ZipReadDirPlusRes> {
+        info!("Handling READDIRPLUS {:?}", fsargs);
+
+        let dpath = self.readdir_start(&fsargs)?;
+
+        // Get as many entries and their attributes as fit in the reply the client asked for,
+        // measured in actual bytes on the wire.
+        let budget = Self::readdir_budget("readdirplus", fsargs.maxcount);
+        let verf = self.cookie_verf();
+        let mut contents = Vec::new();
+        let mut contents_len = 0;
+
+        let eof = self.fs_read_dir(&dpath, fsargs.cookie as Fid, |fid, fnames, _| {
+            // An entry removed since we listed it is just left out, as if we had listed the
+            // directory a moment later.
+            let fpath_numbered = dpath.join(format!("{}", fid));
+            let attr = match self.fs_try_get_attr(fpath_numbered, fid) {
+                Some(attr) => attr,
+                None => return true,
+            };
+
+            let entries: Vec<ZipDirEntryPlus> = fnames
                 .into_iter()
-                .map(|(fid, fname, ftype)| {
-                    ZipDirEntry::new(fid as i64, fname, ftype)
+                .map(|fname| {
+                    let fhandle = ZipFileHandle::new(fid as i64);
+                    ZipDirEntryPlus::new(fid as i64, fname, fhandle, attr.clone())
                 })
                 .collect();
+            let entries_len: usize = entries.iter().map(wire::dir_entry_plus_len).sum();
 
-            // Sort
-            contents.sort_unstable_by(|de1, de2| de1.fid.cmp(&de2.fid));
-
-            // Get just the offset we want.
-            //
-            // Note that we cannot return more than MAX_BUF_LEN bytes
-            let num_de = min(MAX_BUF_LEN / mem::size_of::<ZipDirEntry>(), contents.len());
+            if wire::read_dir_res_len(contents.len() + entries.len(), verf) + contents_len +
+                entries_len > budget
+            {
+                return false;
+            }
 
-            let mut contents = contents.split_off(fsargs.offset as usize);
-            contents.truncate(num_de);
+            contents_len += entries_len;
+            contents.extend(entries);
+            true
+        })?;
 
-            debug!("Returning {} dirents", contents.len());
-            debug!("Contents: {:?}", contents);
+        // Not even one entry fits
+        if contents.is_empty() && !eof {
+            return Err(nfs_error(ZipErrorType::NFSERR_TOOSMALL));
+        }
 
-            Ok(ZipReadDirRes::new(contents))
+        if eof {
+            debug!("END OF DIR");
         }
+
+        debug!("Returning {} dirents", contents.len());
+        debug!("Contents: {:?}", contents);
+
+        Ok(ZipReadDirPlusRes::new(contents, verf, eof))
     }
 
     fn handle_statfs(&self, _: ZipFileHandle) -> thrift::Result<ZipStatFsRes> {
@@ -1207,7 +1765,7 @@ impl<'a, P: AsRef<Path>> ZippynfsSyncHandler for ZippynfsServer<'a, P> {
 
         // I totally made up these numbers... maybe they are reasonable (but probably not)
         Ok(ZipStatFsRes::new(
-            2 << 20,
+            MAX_IO_LEN as i64,
             1 << 12,
             1 << 20,
             1 << 20,
@@ -1215,6 +1773,17 @@ impl<'a, P: AsRef<Path>> ZippynfsSyncHandler for ZippynfsServer<'a, P> {
         ))
     }
 
+    fn handle_fsinfo(&self, _: ZipFileHandle) -> thrift::Result<ZipFsInfoRes> {
+        info!("Handling FSINFO");
+
+        Ok(ZipFsInfoRes::new(
+            MAX_IO_LEN as i64,
+            MAX_IO_LEN as i64,
+            DIR_PAGE_LEN as i64,
+            MAX_MSG_LEN as i64,
+        ))
+    }
+
     fn handle_commit(&self, fsargs: ZipCommitArgs) -> thrift::Result<ZipCommitRes> {
         info!("Handling COMMMIT {:?}", fsargs);
 
@@ -1229,10 +1798,14 @@ impl<'a, P: AsRef<Path>> ZippynfsSyncHandler for ZippynfsServer<'a, P> {
 
         let fpath_numbered = fpath_numbered.unwrap();
 
+        // If there are changes to be committed, nobody else can go on caching the old data
+        let fid = fsargs.file.fid as Fid;
+        let pending = self.async_bufs.read().unwrap().contains_key(&fid);
+        let client = fsargs.client;
+        let _conflict = self.recall(fid, pending, |holder, _| pending && Some(holder) != client);
+
         // Grab that set of changes out of the table
-        let to_write = self.async_bufs.write().unwrap().remove(
-            &(fsargs.file.fid as Fid),
-        );
+        let to_write = self.async_bufs.write().unwrap().remove(&fid);
 
         // If there are no changes to be committed, then return success immediately
         if to_write.is_none() {
@@ -1253,45 +1826,163 @@ impl<'a, P: AsRef<Path>> ZippynfsSyncHandler for ZippynfsServer<'a, P> {
         }
 
         // Ok, so at this point we know that there is work to do, so let's do it!
+        self.with_rewrite_lock(fid, || -> thrift::Result<()> {
+            // Create a tmp file by copying the existing file
+            //
+            // We name the tmp file after the FID and this thread's TID so
+            // as to avoid interleaving writes from different client reqs.
+            let tid = current().id();
+            let tmp_fpath = (&self.data_dir).as_ref().join(
+                format!("tmp/{}_{:?}", fid, tid),
+            );
+            copy(&fpath_numbered, &tmp_fpath)?;
 
-        // Create a tmp file by copying the existing file
-        //
-        // We name the tmp file after the FID and this thread's TID so
-        // as to avoid interleaving writes from different client reqs.
-        let tid = current().id();
-        let tmp_fpath = (&self.data_dir).as_ref().join(format!(
-            "tmp/{}_{:?}",
-            fsargs.file.fid,
-            tid
-        ));
-        copy(&fpath_numbered, &tmp_fpath)?;
+            {
+                // Open the file for the write
+                let mut tmp_file = OpenOptions::new().write(true).open(&tmp_fpath)?;
+
+                // Sync the tmp file to ensure we have its contents
+                tmp_file.sync_all()?;
+
+                // Do each write onto the tmp file and sync them together
+                //
+                // NOTE: this also empties the Vec, so that future lockers will see no work to do!
+                for (offset, count, buf) in to_write.drain(..) {
+                    // Seek to the write location
+                    tmp_file.seek(SeekFrom::Start(offset as u64))?;
+
+                    // Write the data to the file
+                    assert_eq!(buf.len(), count);
+                    tmp_file.write_all(&buf)?;
+                }
 
-        {
-            // Open the file for the write
-            let mut tmp_file = OpenOptions::new().write(true).open(&tmp_fpath)?;
+                // Sync the file
+                tmp_file.sync_all()?;
+            } // File closed
 
-            // Sync the tmp file to ensure we have its contents
-            tmp_file.sync_all()?;
+            // Atomic rename file
+            rename(tmp_fpath, &fpath_numbered)?;
+            Ok(())
+        })?;
+        drop(to_write);
 
-            // Do each write onto the tmp file and sync them together
-            //
-            // NOTE: this also empties the Vec, so that future lockers will see no work to do!
-            for (offset, count, buf) in to_write.drain(..) {
-                // Seek to the write location
-                tmp_file.seek(SeekFrom::Start(offset as u64))?;
+        Ok(ZipCommitRes::new(self.epoch as i64))
+    }
 
-                // Write the data to the file
-                assert_eq!(buf.len(), count);
-                tmp_file.write_all(&buf)?;
-            }
+    fn handle_open(&self, fsargs: ZipOpenArgs) -> thrift::Result<ZipOpenRes> {
+        info!("Handling OPEN {:?}", fsargs);
+
+        let fid = fsargs.file.fid as Fid;
+        let fpath_numbered = match self.fs_find_by_fid(fid)? {
+            Some(fpath_numbered) => fpath_numbered,
+            None => return Err(nfs_error(ZipErrorType::NFSERR_STALE)),
+        };
 
-            // Sync the file
-            tmp_file.sync_all()?;
-        } // File closed
+        // Opening a file also shows that the client is still around
+        self.leases.renew(fsargs.client);
 
-        // Atomic rename file
-        rename(tmp_fpath, fpath_numbered)?;
+        let opens = self.open_files.open(fid, fsargs.client);
+        debug!("fid={} is open {} times", fid, opens);
 
-        Ok(ZipCommitRes::new(self.epoch as i64))
+        // Whoever has a WRITE delegation has to commit what it wrote, and stop counting on being
+        // alone with the file
+        let client = fsargs.client;
+        self.recall(fid, false, |holder, kind| {
+            holder != client && kind == ZipDelegationType::WRITE
+        });
+
+        Ok(ZipOpenRes::new(
+            opens as i64,
+            self.fs_get_attr(fpath_numbered, fid as u64),
+        ))
+    }
+
+    fn handle_close(&self, fsargs: ZipOpenArgs) -> thrift::Result<ZipOpenRes> {
+        info!("Handling CLOSE {:?}", fsargs);
+
+        // The file may be gone by now, which is fine
+        let fid = fsargs.file.fid as Fid;
+        let opens = self.open_files.close(fid, fsargs.client);
+        debug!("fid={} is open {} times", fid, opens);
+
+        Ok(ZipOpenRes::new(opens as i64, None))
+    }
+
+    fn handle_renew(&self, client: i64) -> thrift::Result<i64> {
+        info!("Handling RENEW {:x}", client);
+
+        self.leases.renew(client);
+
+        Ok(LEASE_SECS as i64)
+    }
+
+    fn handle_lock(&self, fsargs: ZipLockArgs) -> thrift::Result<ZipLockRes> {
+        info!("Handling LOCK {:?}", fsargs);
+
+        self.check_lock(&fsargs)?;
+
+        let fid = fsargs.file.fid as Fid;
+        let conflict = self.locks.set(fid, fsargs.client, &fsargs.lock).err();
+        debug!("fid={} lock conflicts with {:?}", fid, conflict);
+
+        Ok(self.lock_res(fsargs.client, conflict))
+    }
+
+    fn handle_testlock(&self, fsargs: ZipLockArgs) -> thrift::Result<ZipLockRes> {
+        info!("Handling TESTLOCK {:?}", fsargs);
+
+        // Even testing has to wait for the locks to be reclaimed
+        self.check_lock(&fsargs)?;
+
+        let fid = fsargs.file.fid as Fid;
+        let conflict = self.locks.test(fid, fsargs.client, &fsargs.lock);
+
+        Ok(self.lock_res(fsargs.client, conflict))
+    }
+
+    fn handle_setcallback(&self, fsargs: ZipCallbackArgs) -> thrift::Result<()> {
+        info!("Handling SETCALLBACK {:?}", fsargs);
+
+        self.leases.renew(fsargs.client);
+        self.callbacks.set(fsargs.client, fsargs.addr);
+
+        Ok(())
+    }
+
+    fn handle_delegate(&self, fsargs: ZipDelegateArgs) -> thrift::Result<ZipDelegateRes> {
+        info!("Handling DELEGATE {:?}", fsargs);
+
+        let fid = fsargs.file.fid as Fid;
+        let fpath_numbered = match self.fs_find_by_fid(fid)? {
+            Some(fpath_numbered) => fpath_numbered,
+            None => return Err(nfs_error(ZipErrorType::NFSERR_STALE)),
+        };
+
+        // During the grace period, only delegations held before we restarted can be taken, since
+        // we don't know who else might have them
+        let giving_back = fsargs.kind == ZipDelegationType::NONE;
+        if !giving_back && !fsargs.reclaim && self.leases.in_grace() {
+            return Err(nfs_error(ZipErrorType::NFSERR_GRACE));
+        }
+
+        // Asking for a delegation also shows that the client is still around
+        let client = fsargs.client;
+        self.leases.renew(client);
+
+        // A delegation can only be given to a client we can recall it from
+        let granted = if giving_back || self.callbacks.has(client) {
+            self.delegations.grant(fid, client, fsargs.kind, || {
+                self.open_files.others_open(fid, client)
+            })
+        } else {
+            ZipDelegationType::NONE
+        };
+        debug!("fid={} is delegated to {:x} for {:?}", fid, client, granted);
+
+        Ok(ZipDelegateRes::new(
+            granted,
+            self.fs_get_attr(fpath_numbered, fid as u64),
+            self.epoch as i64,
+        ))
     }
 }
@@ -0,0 +1,99 @@
+//! Scoped locks on names in a directory.
+//!
+//! We need to be sure that no two files in the system have exactly the same name in the same
+//! directory, so for the time between checking that a name is free and actually creating (or
+//! renaming) the file, that name must be locked. Locks are handed out as `NameGuard`s, which
+//! release the name when they are dropped, so an early return (or even a panic) can never leave a
+//! name locked forever.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+use super::Fid;
+
+/// Keeps track of which names are currently locked in each directory.
+#[derive(Debug)]
+pub struct NameLockManager {
+    /// Directory FID -> set of locked names in that directory.
+    ///
+    /// We key by the FID of the directory rather than its path, since the directory itself may be
+    /// concurrently renamed. Directories with no locked names are removed from the map.
+    dirs: Mutex<HashMap<Fid, HashSet<String>>>,
+}
+
+/// A lock on a single name in a single directory. The name is unlocked when this is dropped.
+#[derive(Debug)]
+pub struct NameGuard<'m> {
+    manager: &'m NameLockManager,
+    dir: Fid,
+    name: String,
+}
+
+impl NameLockManager {
+    /// Returns a new lock manager with nothing locked.
+    pub fn new() -> NameLockManager {
+        NameLockManager { dirs: Mutex::new(HashMap::new()) }
+    }
+
+    /// Attempts to lock `name` in the directory with FID `dir`.
+    ///
+    /// Returns `None` if the name is already locked by someone else.
+    pub fn try_lock(&self, dir: Fid, name: &str) -> Option<NameGuard> {
+        let mut dirs = self.dirs.lock().unwrap();
+
+        if dirs.entry(dir).or_insert_with(HashSet::new).insert(
+            name.to_owned(),
+        )
+        {
+            Some(NameGuard {
+                manager: self,
+                dir,
+                name: name.to_owned(),
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Returns true if `name` is currently locked in the directory with FID `dir`.
+    #[cfg(test)]
+    pub fn is_locked(&self, dir: Fid, name: &str) -> bool {
+        self.dirs
+            .lock()
+            .unwrap()
+            .get(&dir)
+            .map(|names| names.contains(name))
+            .unwrap_or(false)
+    }
+
+    /// Returns the total number of names locked across all directories.
+    #[cfg(test)]
+    pub fn num_locked(&self) -> usize {
+        self.dirs.lock().unwrap().values().map(|names| names.len()).sum()
+    }
+
+    /// Remove the given name from the set of locked names.
+    ///
+    /// This is only called when a `NameGuard` is dropped, so the name must be locked.
+    fn unlock(&self, dir: Fid, name: &str) {
+        let mut dirs = self.dirs.lock().unwrap();
+
+        let now_empty = {
+            let names = dirs.get_mut(&dir).expect("Unlocking name in unknown directory");
+            let present = names.remove(name);
+            assert!(present);
+            names.is_empty()
+        };
+
+        // Don't let the map grow with every directory ever touched
+        if now_empty {
+            dirs.remove(&dir);
+        }
+    }
+}
+
+impl<'m> Drop for NameGuard<'m> {
+    fn drop(&mut self) {
+        self.manager.unlock(self.dir, &self.name);
+    }
+}
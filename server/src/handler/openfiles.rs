@@ -0,0 +1,86 @@
+//! Which files clients have open.
+//!
+//! Clients with `CAP_OPEN_STATE` send an OPEN whenever a file is opened and a CLOSE whenever it
+//! is closed, so the server can tell whether a FID is still in use by anyone. Each client is
+//! identified by a number it picks when it mounts, and its opens are counted separately, so that
+//! a CLOSE from one client never undoes an OPEN from another.
+//!
+//! This is only kept in memory, so after a restart, nothing is open until clients open it again.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use super::Fid;
+
+/// The open files, and how many times each client has each one open.
+#[derive(Debug, Default)]
+pub struct OpenFiles {
+    /// FID -> client -> number of opens
+    opens: Mutex<HashMap<Fid, HashMap<i64, usize>>>,
+}
+
+impl OpenFiles {
+    pub fn new() -> OpenFiles {
+        OpenFiles::default()
+    }
+
+    /// Note that the client opened the file, and return how many opens it has by all clients.
+    pub fn open(&self, fid: Fid, client: i64) -> usize {
+        let mut opens = self.opens.lock().unwrap();
+
+        let clients = opens.entry(fid).or_insert_with(HashMap::new);
+        *clients.entry(client).or_insert(0) += 1;
+        clients.values().sum()
+    }
+
+    /// Note that the client closed the file, and return how many opens it has left by all
+    /// clients. Closing a file the client doesn't have open does nothing.
+    pub fn close(&self, fid: Fid, client: i64) -> usize {
+        let mut opens = self.opens.lock().unwrap();
+
+        let left = match opens.get_mut(&fid) {
+            Some(clients) => {
+                let closed = match clients.get_mut(&client) {
+                    Some(count) => {
+                        *count -= 1;
+                        *count == 0
+                    }
+                    None => false,
+                };
+                if closed {
+                    clients.remove(&client);
+                }
+                clients.values().sum()
+            }
+            None => 0,
+        };
+
+        if left == 0 {
+            opens.remove(&fid);
+        }
+        left
+    }
+
+    /// Return whether any client has the file open.
+    pub fn is_open(&self, fid: Fid) -> bool {
+        self.opens.lock().unwrap().contains_key(&fid)
+    }
+
+    /// Return whether any client other than the given one has the file open.
+    pub fn others_open(&self, fid: Fid, client: i64) -> bool {
+        match self.opens.lock().unwrap().get(&fid) {
+            Some(clients) => clients.keys().any(|&opener| opener != client),
+            None => false,
+        }
+    }
+
+    /// Forget everything the client has open, e.g. because it has gone away.
+    pub fn forget_client(&self, client: i64) {
+        let mut opens = self.opens.lock().unwrap();
+
+        for clients in opens.values_mut() {
+            clients.remove(&client);
+        }
+        opens.retain(|_, clients| !clients.is_empty());
+    }
+}
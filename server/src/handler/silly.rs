@@ -0,0 +1,214 @@
+//! The files that clients silly renamed, so that they can be removed if the client goes away.
+//!
+//! A client with `CAP_OPEN_STATE` says who it is when it renames a file, and if it gives the file
+//! its own silly name, we record that here. Only recorded files are ever reaped: a file that just
+//! happens to have a name like a silly one (e.g. one a user made, or one silly renamed by a client
+//! that doesn't say who it is) is left alone.
+//!
+//! The record is backed by an append-only log in `data_dir/silly`, so that the files are still
+//! reaped after the server restarts. A file is recorded after it is renamed, so a crash in between
+//! leaves it behind, which is the safe way to fail. Forgetting a file need not be durable, since
+//! a file is only reaped if it still has the recorded name, and FIDs are never reused.
+
+use std::collections::HashMap;
+use std::fs::{rename, File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use super::Fid;
+use super::dirindex::{bytes_to_u64, u32_to_bytes, u64_to_bytes};
+
+/// Log record tag for a recorded file
+const RECORD_INSERT: u8 = b'+';
+
+/// Log record tag for a forgotten file
+const RECORD_REMOVE: u8 = b'-';
+
+/// The size of a record header: tag (1B), FID (8B), directory FID (8B), client (8B), name
+/// length (4B)
+const RECORD_HEADER_LEN: usize = 1 + 8 + 8 + 8 + 4;
+
+/// Compact the log when it has this many more records than recorded files
+const COMPACT_SLACK: usize = 1024;
+
+/// A silly renamed file: (directory FID, name, client)
+pub type SillyFile = (Fid, String, i64);
+
+/// The recorded files and their log.
+#[derive(Debug)]
+struct Entries {
+    /// FID -> where it is, and whose it is
+    files: HashMap<Fid, SillyFile>,
+
+    /// The log, opened for appending
+    log: File,
+
+    /// The number of records in the log
+    records: usize,
+}
+
+/// The files that clients silly renamed.
+#[derive(Debug)]
+pub struct SillyFiles {
+    /// Where the log lives
+    log_path: PathBuf,
+
+    entries: Mutex<Entries>,
+}
+
+/// Encode a single log record. A forgotten file has no directory, name or client.
+fn encode_record(tag: u8, fid: Fid, did: Fid, name: &str, client: i64) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(RECORD_HEADER_LEN + name.len());
+    buf.push(tag);
+    buf.extend_from_slice(&u64_to_bytes(fid as u64));
+    buf.extend_from_slice(&u64_to_bytes(did as u64));
+    buf.extend_from_slice(&u64_to_bytes(client as u64));
+    buf.extend_from_slice(&u32_to_bytes(name.len() as u32));
+    buf.extend_from_slice(name.as_bytes());
+    buf
+}
+
+/// Replay the records in a log, and return the files still recorded at the end. A torn record at
+/// the end (from a crash during an append) is ignored.
+fn decode_records(bytes: &[u8]) -> Result<HashMap<Fid, SillyFile>, String> {
+    let mut files = HashMap::new();
+    let mut pos = 0;
+
+    while pos + RECORD_HEADER_LEN <= bytes.len() {
+        let tag = bytes[pos];
+        let fid = bytes_to_u64(&bytes[pos + 1..pos + 9]) as Fid;
+        let did = bytes_to_u64(&bytes[pos + 9..pos + 17]) as Fid;
+        let client = bytes_to_u64(&bytes[pos + 17..pos + 25]) as i64;
+        let len = bytes_to_u64(&bytes[pos + 25..pos + 29]) as usize;
+        let start = pos + RECORD_HEADER_LEN;
+
+        if start + len > bytes.len() {
+            break;
+        }
+
+        match tag {
+            RECORD_INSERT => {
+                let name = String::from_utf8(bytes[start..start + len].to_vec()).map_err(
+                    |_| "Corrupt silly file log".to_owned(),
+                )?;
+                files.insert(fid, (did, name, client));
+            }
+            RECORD_REMOVE => {
+                files.remove(&fid);
+            }
+            _ => return Err("Corrupt silly file log".to_owned()),
+        }
+
+        pos = start + len;
+    }
+
+    Ok(files)
+}
+
+impl SillyFiles {
+    /// Returns the record backed by the log at the given path, creating it if needed.
+    pub fn new<P: AsRef<Path>>(log_path: P) -> Result<SillyFiles, String> {
+        let log_path = log_path.as_ref().to_owned();
+
+        // Replay the log, if there is one
+        let files = if log_path.exists() {
+            let mut bytes = Vec::new();
+            File::open(&log_path)
+                .and_then(|mut f| f.read_to_end(&mut bytes))
+                .map_err(|e| format!("{}", e))?;
+            decode_records(&bytes)?
+        } else {
+            HashMap::new()
+        };
+
+        let entries = Self::rewrite_log(&log_path, files)?;
+
+        Ok(SillyFiles {
+            log_path,
+            entries: Mutex::new(entries),
+        })
+    }
+
+    /// Atomically replace the log with one recording exactly the given files, and return the
+    /// resulting entries.
+    fn rewrite_log(log_path: &Path, files: HashMap<Fid, SillyFile>) -> Result<Entries, String> {
+        let tmp_path = log_path.with_extension("tmp");
+
+        {
+            let mut tmp = File::create(&tmp_path).map_err(|e| format!("{}", e))?;
+            for (&fid, &(did, ref name, client)) in files.iter() {
+                tmp.write_all(&encode_record(RECORD_INSERT, fid, did, name, client))
+                    .map_err(|e| format!("{}", e))?;
+            }
+            tmp.sync_all().map_err(|e| format!("{}", e))?;
+        } // File closed
+
+        rename(&tmp_path, log_path).map_err(|e| format!("{}", e))?;
+
+        let log = OpenOptions::new()
+            .append(true)
+            .open(log_path)
+            .map_err(|e| format!("{}", e))?;
+
+        Ok(Entries {
+            records: files.len(),
+            files,
+            log,
+        })
+    }
+
+    /// Durably record that the client silly renamed the file to the given name in the given
+    /// directory.
+    pub fn insert(&self, fid: Fid, did: Fid, name: &str, client: i64) -> Result<(), String> {
+        let mut entries = self.entries.lock().unwrap();
+
+        entries
+            .log
+            .write_all(&encode_record(RECORD_INSERT, fid, did, name, client))
+            .and_then(|_| entries.log.sync_data())
+            .map_err(|e| format!("{}", e))?;
+        entries.records += 1;
+        entries.files.insert(fid, (did, name.to_owned(), client));
+
+        Ok(())
+    }
+
+    /// Forget the file, if it is recorded, e.g. because it has been renamed again or removed.
+    pub fn remove(&self, fid: Fid) -> Result<(), String> {
+        let mut entries = self.entries.lock().unwrap();
+
+        if entries.files.remove(&fid).is_none() {
+            return Ok(());
+        }
+
+        entries
+            .log
+            .write_all(&encode_record(RECORD_REMOVE, fid, 0, "", 0))
+            .map_err(|e| format!("{}", e))?;
+        entries.records += 1;
+
+        // Keep the log from growing forever with forgotten records
+        if entries.records > entries.files.len() * 2 + COMPACT_SLACK {
+            let files = entries.files.clone();
+            *entries = Self::rewrite_log(&self.log_path, files)?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the recorded files for which `pick` returns true, given the FID and the client.
+    pub fn find<F>(&self, pick: F) -> Vec<(Fid, SillyFile)>
+    where
+        F: Fn(Fid, i64) -> bool,
+    {
+        let entries = self.entries.lock().unwrap();
+
+        entries
+            .files
+            .iter()
+            .filter(|&(&fid, &(_, _, client))| pick(fid, client))
+            .map(|(&fid, silly)| (fid, silly.clone()))
+            .collect()
+    }
+}
@@ -4,18 +4,31 @@ use std::collections::HashSet;
 use std::process::Command;
 #[allow(unused_imports)]
 use std::error::Error as std_err;
-use std::fs::File;
+use std::fs::{metadata, read_dir, File};
 use std::io::Read;
+use std::net::TcpListener;
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Mutex;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 
 use regex::Regex;
 
+use thrift;
+use thrift::protocol::{TCompactInputProtocol, TCompactOutputProtocol};
+use thrift::server::TProcessor;
+use thrift::transport::{TIoChannel, TTcpChannel};
+
 use zippyrpc::*;
+use zippyrpc::transport::{read_transport, write_transport};
 
 use super::AtomicPersistentUsize;
-use super::ZippynfsServer;
+use super::{ZippynfsServer, LEASE_SECS, NAME_MAX};
+use super::drc::{DupCache, Outcome};
+use super::fault::FaultPoint;
+use super::leases::Leases;
+use super::namelock::NameLockManager;
 
 /// Prevent multiple concurrent test from running at the same time
 /// because we open too many file descriptors.
@@ -89,16 +102,21 @@ fn fake_sattr_args(
             size,
             atime.map(|(seconds, useconds)| ZipTimeVal { seconds, useconds }),
             mtime.map(|(seconds, useconds)| ZipTimeVal { seconds, useconds }),
-        )
+        ),
+        None,
     )
 }
 
 fn fake_dir_op_args(did: i64, filename: &str) -> ZipDirOpArgs {
-    ZipDirOpArgs::new(ZipFileHandle::new(did), filename.to_owned())
+    ZipDirOpArgs::new(ZipFileHandle::new(did), filename.to_owned(), None)
+}
+
+fn fake_dir_op_args_xid(did: i64, filename: &str, xid: i64) -> ZipDirOpArgs {
+    ZipDirOpArgs::new(ZipFileHandle::new(did), filename.to_owned(), xid)
 }
 
 fn fake_read_args(fid: i64, offset: i64, count: i64) -> ZipReadArgs {
-    ZipReadArgs::new(ZipFileHandle::new(fid), offset, count)
+    ZipReadArgs::new(ZipFileHandle::new(fid), offset, count, None)
 }
 
 fn fake_create_args(did: i64, filename: &str) -> ZipCreateArgs {
@@ -115,7 +133,49 @@ fn fake_rename_args(
 ) -> ZipRenameArgs {
     let old = fake_dir_op_args(old_did, &old_filename);
     let new = fake_dir_op_args(new_did, &new_filename);
-    ZipRenameArgs::new(old, new)
+    ZipRenameArgs::new(old, new, None)
+}
+
+/// Takes callbacks for a test client, and remembers the recalls: [(fid, kind)]. The hook is
+/// called with the FID of each recall as it comes in.
+struct RecallRecorder(Arc<Mutex<Vec<(i64, ZipDelegationType)>>>, Box<Fn(i64) + Send + Sync>);
+
+impl ZippyCallbackSyncHandler for RecallRecorder {
+    fn handle_recall(&self, fsargs: ZipRecallArgs) -> thrift::Result<()> {
+        (self.1)(fsargs.file.fid);
+        self.0.lock().unwrap().push((fsargs.file.fid, fsargs.kind));
+        Ok(())
+    }
+}
+
+/// Take callbacks on a new port in the background, and return its address along with the recalls
+/// that come in.
+fn callback_listener() -> (String, Arc<Mutex<Vec<(i64, ZipDelegationType)>>>) {
+    callback_listener_with(|_| {})
+}
+
+/// Like `callback_listener`, but calls `hook` with the FID of each recall before answering it.
+fn callback_listener_with<F>(hook: F) -> (String, Arc<Mutex<Vec<(i64, ZipDelegationType)>>>)
+where
+    F: Fn(i64) + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = format!("{}", listener.local_addr().unwrap());
+    let recalls = Arc::new(Mutex::new(Vec::new()));
+    let processor =
+        ZippyCallbackSyncProcessor::new(RecallRecorder(recalls.clone(), Box::new(hook)));
+
+    thread::spawn(move || for stream in listener.incoming() {
+        let channel = TTcpChannel::with_stream(stream.unwrap());
+        let (i_chan, o_chan) = channel.split().unwrap();
+        let mut i_prot = TCompactInputProtocol::new(read_transport(i_chan));
+        let mut o_prot = TCompactOutputProtocol::new(write_transport(o_chan));
+
+        // Until the server hangs up
+        while processor.process(&mut i_prot, &mut o_prot).is_ok() {}
+    });
+
+    (addr, recalls)
 }
 
 #[test]
@@ -892,6 +952,97 @@ fn test_nfs_remove() {
     })
 }
 
+#[test]
+fn test_retried_requests() {
+    run_with_clone_fs("test_files/test1", true, |fspath| {
+        // Do some cleanup (to get around git hackery)
+        cleanup_git_hackery_test1(fspath);
+
+        // Create a server
+        let server = ZippynfsServer::new(fspath);
+
+        let attributes = ZipSattr::new(None, None, None, None, None, None);
+
+        // A retried CREATE gets the same file, rather than NFSERR_EXIST
+        let create = ZipCreateArgs::new(fake_dir_op_args_xid(1, "new.txt", 42), attributes);
+        let created1 = server.handle_create(create.clone()).unwrap();
+        let created2 = server.handle_create(create).unwrap();
+        assert_eq!(created1.file, created2.file);
+
+        // But a new one with the same name still fails
+        let create = ZipCreateArgs::new(
+            fake_dir_op_args_xid(1, "new.txt", 43),
+            ZipSattr::new(None, None, None, None, None, None),
+        );
+        match server.handle_create(create).map_err(|e| e.into()) {
+            Err(ZipError::Nfs(ZipErrorType::NFSERR_EXIST, _)) => {}
+            _ => assert!(false),
+        }
+
+        // A retried REMOVE succeeds, rather than failing with NFSERR_NOENT
+        let remove = fake_dir_op_args_xid(1, "new.txt", 44);
+        server.handle_remove(remove.clone()).unwrap();
+        server.handle_remove(remove).unwrap();
+
+        // A retried RENAME too
+        let rename = ZipRenameArgs::new(
+            fake_dir_op_args_xid(1, "foo", 45),
+            fake_dir_op_args(1, "foo2"),
+            None,
+        );
+        server.handle_rename(rename.clone()).unwrap();
+        server.handle_rename(rename).unwrap();
+
+        // A request that failed fails the same way when retried, even if it would work now
+        let rmdir = fake_dir_op_args_xid(1, "newdir", 46);
+        assert!(server.handle_rmdir(rmdir.clone()).is_err());
+        server
+            .handle_mkdir(ZipCreateArgs::new(
+                fake_dir_op_args(1, "newdir"),
+                ZipSattr::new(None, None, None, None, None, None),
+            ))
+            .unwrap();
+        match server.handle_rmdir(rmdir).map_err(|e| e.into()) {
+            Err(ZipError::Nfs(ZipErrorType::NFSERR_NOENT, _)) => {}
+            _ => assert!(false),
+        }
+        assert!(server.handle_lookup(fake_dir_op_args(1, "newdir")).is_ok());
+    })
+}
+
+#[test]
+fn test_retried_requests_after_restart() {
+    run_with_clone_fs("test_files/test1", true, |fspath| {
+        // Do some cleanup (to get around git hackery)
+        cleanup_git_hackery_test1(fspath);
+
+        let create = ZipCreateArgs::new(
+            fake_dir_op_args_xid(1, "new.txt", 42),
+            ZipSattr::new(None, None, None, None, None, None),
+        );
+        let remove = fake_dir_op_args_xid(1, "baz.txt", 43);
+
+        let created = {
+            let server = ZippynfsServer::new(fspath);
+            server.handle_remove(remove.clone()).unwrap();
+            server.handle_create(create.clone()).unwrap()
+        };
+
+        // The server restarts, and the retries still get the original replies
+        let server = ZippynfsServer::new(fspath);
+        server.handle_remove(remove).unwrap();
+        assert_eq!(server.handle_create(create).unwrap().file, created.file);
+
+        // Without the XID, it is a new request
+        match server.handle_remove(fake_dir_op_args(1, "baz.txt")).map_err(
+            |e| e.into(),
+        ) {
+            Err(ZipError::Nfs(ZipErrorType::NFSERR_NOENT, _)) => {}
+            _ => assert!(false),
+        }
+    })
+}
+
 #[test]
 fn test_nfs_readdir() {
     run_with_clone_fs("test_files/test1", true, |fspath| {
@@ -901,12 +1052,12 @@ fn test_nfs_readdir() {
         // Create a server
         let server = ZippynfsServer::new(fspath);
 
-        // Call RMDIR
-        let readdir1 = server.handle_readdir(ZipReadDirArgs::new(ZipFileHandle::new(1), 0));
+        // Call READDIR
+        let readdir1 = server.handle_readdir(ZipReadDirArgs::new(ZipFileHandle::new(1), 0, 0, 0));
 
         // Correctness
         match readdir1 {
-            Ok(ZipReadDirRes { entries }) => {
+            Ok(ZipReadDirRes { entries, .. }) => {
                 let correct_entries: HashSet<(u64, String, ZipFtype)> =
                     vec![
                         (8, "foo", ZipFtype::NFDIR),
@@ -931,6 +1082,234 @@ fn test_nfs_readdir() {
     })
 }
 
+#[test]
+fn test_nfs_readdir_cookies() {
+    run_with_clone_fs("test_files/test1", true, |fspath| {
+        // Do some cleanup (to get around git hackery)
+        cleanup_git_hackery_test1(fspath);
+
+        // Create a server
+        let server = ZippynfsServer::new(fspath);
+
+        let fids = |res: ZipReadDirRes| -> Vec<i64> { res.entries.iter().map(|e| e.fid).collect() };
+
+        // Start a listing in the middle, as if we had already seen FID 4
+        let page1 = server
+            .handle_readdir(ZipReadDirArgs::new(ZipFileHandle::new(1), 4, 0, 0))
+            .unwrap();
+        let verf = page1.cookieverf;
+        assert_eq!(verf, 9); // server epoch
+        assert_eq!(fids(page1), vec![5, 8]);
+
+        // Concurrently, the entry with our cookie is removed, and something new is created
+        server.handle_rmdir(fake_dir_op_args(1, "bazee")).unwrap();
+        let create = server
+            .create_object(fake_create_args(1, "new.txt"), true)
+            .unwrap();
+        assert_eq!(create.file.fid, 10);
+
+        // Continuing from the removed entry neither skips nor repeats anything
+        let page2 = server
+            .handle_readdir(ZipReadDirArgs::new(ZipFileHandle::new(1), 5, verf, 0))
+            .unwrap();
+        assert_eq!(fids(page2), vec![8, 10]);
+
+        // Nothing after the last entry
+        let page3 = server
+            .handle_readdir(ZipReadDirArgs::new(ZipFileHandle::new(1), 10, verf, 0))
+            .unwrap();
+        assert!(page3.entries.is_empty());
+        assert!(page3.eof);
+
+        // A verifier from another server instance is rejected...
+        let stale = server.handle_readdir(ZipReadDirArgs::new(ZipFileHandle::new(1), 5, 1234, 0));
+        match stale.map_err(|e| e.into()) {
+            Err(ZipError::Nfs(ZipErrorType::NFSERR_BAD_COOKIE, _)) => {}
+            _ => assert!(false),
+        }
+
+        // ... but a client without a verifier can still resume from a FID
+        let page4 = server
+            .handle_readdir(ZipReadDirArgs::new(ZipFileHandle::new(1), 5, 0, 0))
+            .unwrap();
+        assert_eq!(fids(page4), vec![8, 10]);
+    })
+}
+
+#[test]
+fn test_nfs_readdirplus() {
+    run_with_clone_fs("test_files/test1", true, |fspath| {
+        // Do some cleanup (to get around git hackery)
+        cleanup_git_hackery_test1(fspath);
+
+        // Create a server
+        let server = ZippynfsServer::new(fspath);
+
+        // Make one of the files non-empty so that sizes are interesting
+        let create = server
+            .create_object(fake_create_args(1, "new.txt"), true)
+            .unwrap();
+        server
+            .handle_write(ZipWriteArgs::new(
+                create.file.clone(),
+                0,
+                5,
+                b"hello".to_vec(),
+                ZipWriteStable::FILE_SYNC,
+                None,
+            ))
+            .unwrap();
+
+        // Call READDIRPLUS
+        let res = server
+            .handle_readdirplus(ZipReadDirArgs::new(ZipFileHandle::new(1), 0, 0, 0))
+            .unwrap();
+        assert_eq!(res.cookieverf, 9); // server epoch
+
+        let names: HashSet<(i64, String)> = res.entries
+            .iter()
+            .map(|e| (e.fid, e.fname.clone()))
+            .collect();
+        let correct_names: HashSet<(i64, String)> =
+            vec![(4, "baz.txt"), (5, "bazee"), (8, "foo"), (10, "new.txt")]
+                .into_iter()
+                .map(|(fid, fname)| (fid, fname.to_owned()))
+                .collect();
+        assert_eq!(names, correct_names);
+
+        // Each entry carries the same handle and attributes as a LOOKUP would return
+        for entry in res.entries {
+            let lookup = server
+                .handle_lookup(fake_dir_op_args(1, &entry.fname))
+                .unwrap();
+            assert_eq!(entry.file, lookup.file);
+            assert_eq!(entry.attributes.fid, lookup.attributes.fid);
+            assert_eq!(entry.attributes.type_, lookup.attributes.type_);
+            assert_eq!(entry.attributes.size, lookup.attributes.size);
+        }
+
+        // Paging works the same way as READDIR
+        let page = server
+            .handle_readdirplus(ZipReadDirArgs::new(ZipFileHandle::new(1), 5, 9, 0))
+            .unwrap();
+        let fids: Vec<i64> = page.entries.iter().map(|e| e.fid).collect();
+        assert_eq!(fids, vec![8, 10]);
+    })
+}
+
+#[test]
+fn test_nfs_readdir_page_size() {
+    run_with_clone_fs("test_files/test1", true, |fspath| {
+        // Do some cleanup (to get around git hackery)
+        cleanup_git_hackery_test1(fspath);
+
+        // Create a server
+        let server = ZippynfsServer::new(fspath);
+
+        // Lots of entries with long names, so a listing takes several pages
+        for i in 0..100 {
+            server
+                .create_object(fake_create_args(1, &format!("{:0200}", i)), true)
+                .unwrap();
+        }
+
+        // The encoded size of a result
+        fn encoded_len(res: &ZipReadDirRes) -> usize {
+            let mut buf = Vec::new();
+            {
+                let mut o_prot = TCompactOutputProtocol::new(&mut buf);
+                res.write_to_out_protocol(&mut o_prot).unwrap();
+            }
+            buf.len()
+        }
+
+        // Ask for small pages
+        const PAGE_LEN: usize = 4000;
+
+        let mut seen = HashSet::new();
+        let mut cookie = 0;
+        let mut verf = 0;
+        let mut pages = 0;
+
+        loop {
+            let res = server
+                .handle_readdir(ZipReadDirArgs::new(
+                    ZipFileHandle::new(1),
+                    cookie,
+                    verf,
+                    PAGE_LEN as i64,
+                ))
+                .unwrap();
+            pages += 1;
+
+            // The size we account for is exactly the size on the wire...
+            let computed = wire::read_dir_res_len(res.entries.len(), res.cookieverf) +
+                res.entries.iter().map(wire::dir_entry_len).sum::<usize>();
+            assert_eq!(computed, encoded_len(&res));
+
+            // ... and the whole reply fits
+            assert!(computed + wire::reply_overhead("readdir") <= PAGE_LEN);
+
+            for entry in res.entries.iter() {
+                assert!(seen.insert(entry.fid));
+            }
+
+            if res.eof {
+                break;
+            }
+
+            // Only the last page may be empty
+            assert!(!res.entries.is_empty());
+            cookie = res.entries.last().unwrap().fid;
+            verf = res.cookieverf;
+        }
+
+        assert_eq!(seen.len(), 103);
+        assert!(pages > 1);
+
+        // By default, the server picks a page size that fits all of these
+        let all = server
+            .handle_readdir(ZipReadDirArgs::new(ZipFileHandle::new(1), 0, 0, 0))
+            .unwrap();
+        assert_eq!(all.entries.len(), 103);
+        assert!(all.eof);
+
+        // A smaller maxcount gives smaller pages
+        let small = server
+            .handle_readdir(ZipReadDirArgs::new(ZipFileHandle::new(1), 0, 0, 1000))
+            .unwrap();
+        assert!(!small.eof);
+        assert!(encoded_len(&small) + wire::reply_overhead("readdir") <= 1000);
+
+        // If not even one entry fits, we say so
+        let tiny = server.handle_readdir(ZipReadDirArgs::new(ZipFileHandle::new(1), 8, 0, 100));
+        match tiny.map_err(|e| e.into()) {
+            Err(ZipError::Nfs(ZipErrorType::NFSERR_TOOSMALL, _)) => {}
+            _ => assert!(false),
+        }
+
+        // READDIRPLUS pages are sized the same way
+        let plus = server
+            .handle_readdirplus(ZipReadDirArgs::new(
+                ZipFileHandle::new(1),
+                0,
+                0,
+                PAGE_LEN as i64,
+            ))
+            .unwrap();
+        let mut buf = Vec::new();
+        {
+            let mut o_prot = TCompactOutputProtocol::new(&mut buf);
+            plus.write_to_out_protocol(&mut o_prot).unwrap();
+        }
+        let computed = wire::read_dir_res_len(plus.entries.len(), plus.cookieverf) +
+            plus.entries.iter().map(wire::dir_entry_plus_len).sum::<usize>();
+        assert_eq!(computed, buf.len());
+        assert!(computed + wire::reply_overhead("readdirplus") <= PAGE_LEN);
+        assert!(!plus.eof);
+    })
+}
+
 #[test]
 fn test_nfs_rename_easy() {
     run_with_clone_fs("test_files/test1", true, |fspath| {
@@ -1125,6 +1504,377 @@ fn test_nfs_rename_concurrent() {
     remove_dir_all(&fspath).unwrap();
 }
 
+#[test]
+fn test_nfs_name_max() {
+    run_with_clone_fs("test_files/test1", true, |fspath| {
+        let server = ZippynfsServer::new(fspath);
+
+        let longest = "a".repeat(NAME_MAX);
+        let too_long = "a".repeat(NAME_MAX + 1);
+
+        // Names that are too long can't be created, renamed to, or looked up
+        let create = server.create_object(fake_create_args(1, &too_long), true);
+        let rename = server.handle_rename(fake_rename_args(2, "zee.txt", 8, &too_long));
+        let lookup = server.handle_lookup(fake_dir_op_args(1, &too_long));
+        for result in vec![create.map(|_| ()), rename, lookup.map(|_| ())] {
+            match result.map_err(|e| e.into()) {
+                Err(ZipError::Nfs(ZipErrorType::NFSERR_NAMETOOLONG, _)) => {}
+                _ => assert!(false),
+            }
+        }
+
+        // But the longest name is fine
+        let create = server
+            .create_object(fake_create_args(1, &longest), true)
+            .unwrap();
+        let lookup = server
+            .handle_lookup(fake_dir_op_args(1, &longest))
+            .unwrap();
+        assert_eq!(lookup.file.fid, create.file.fid);
+    })
+}
+
+#[test]
+fn test_name_lock_guard() {
+    let locks = NameLockManager::new();
+
+    {
+        let _guard = locks.try_lock(1, "foo").unwrap();
+
+        // Same name in the same directory is taken
+        assert!(locks.try_lock(1, "foo").is_none());
+        assert!(locks.is_locked(1, "foo"));
+
+        // Same name in another directory, or another name, is fine
+        let _other_dir = locks.try_lock(2, "foo").unwrap();
+        let _other_name = locks.try_lock(1, "bar").unwrap();
+        assert_eq!(locks.num_locked(), 3);
+    } // All guards dropped
+
+    assert_eq!(locks.num_locked(), 0);
+    assert!(!locks.is_locked(1, "foo"));
+    assert!(locks.try_lock(1, "foo").is_some());
+}
+
+#[test]
+fn test_create_releases_name_on_failure() {
+    run_with_clone_fs("test_files/test1", true, |fspath| {
+        // Create a server
+        let server = ZippynfsServer::new(fspath);
+
+        let points = [
+            FaultPoint::FindByName,
+            FaultPoint::CreateNumbered,
+            FaultPoint::CreateNamed,
+            FaultPoint::SetAttr,
+        ];
+        let entries = read_dir(fspath.join("1")).unwrap().count();
+
+        for &is_file in &[true, false] {
+            for &point in points.iter() {
+                // Fail somewhere in the middle of a CREATE/MKDIR
+                server.faults.arm(point);
+                let create = server.create_object(fake_create_args(1, "myobj"), is_file);
+                server.faults.disarm(point);
+
+                assert!(create.is_err(), "{:?} did not fail", point);
+
+                // The name must not be left locked
+                assert!(!server.name_locks.is_locked(1, "myobj"), "{:?}", point);
+                assert_eq!(server.name_locks.num_locked(), 0);
+
+                // Nothing is left behind, named or numbered
+                let find = server.fs_find_by_name(fspath.join("1"), "myobj").unwrap();
+                assert!(find.is_none(), "{:?}", point);
+                assert_eq!(read_dir(fspath.join("1")).unwrap().count(), entries, "{:?}", point);
+            }
+
+            // Now that nothing is armed, the name can be created
+            let create = server
+                .create_object(fake_create_args(1, "myobj"), is_file)
+                .unwrap();
+            assert_eq!(server.name_locks.num_locked(), 0);
+
+            // And it really exists
+            let lookup = server.handle_lookup(fake_dir_op_args(1, "myobj")).unwrap();
+            assert_eq!(lookup.file.fid, create.file.fid);
+
+            // Clean up for the next round
+            server
+                .fs_delete_obj(fspath.join("1"), create.file.fid as u64, "myobj", is_file)
+                .unwrap();
+        }
+    })
+}
+
+#[test]
+fn test_rename_releases_name_on_failure() {
+    run_with_clone_fs("test_files/test1", true, |fspath| {
+        // Create a server
+        let server = ZippynfsServer::new(fspath);
+
+        let points = [
+            FaultPoint::FindByName,
+            FaultPoint::RenameNamed,
+            FaultPoint::RenameNumbered,
+        ];
+
+        for &point in points.iter() {
+            // Fail somewhere in the middle of a RENAME
+            server.faults.arm(point);
+            let rename = server.handle_rename(fake_rename_args(2, "zee.txt", 8, "zee.mv.txt"));
+            server.faults.disarm(point);
+
+            assert!(rename.is_err(), "{:?} did not fail", point);
+
+            // The name must not be left locked
+            assert!(!server.name_locks.is_locked(8, "zee.mv.txt"), "{:?}", point);
+            assert_eq!(server.name_locks.num_locked(), 0);
+
+            // The file has not moved
+            let find_old = server
+                .fs_find_by_name(fspath.join("1/8/2"), "zee.txt")
+                .unwrap();
+            let find_new = server
+                .fs_find_by_name(fspath.join("1/8"), "zee.mv.txt")
+                .unwrap();
+            assert_eq!(find_old, Some(3));
+            assert_eq!(find_new, None);
+        }
+
+        // Now that nothing is armed, the rename goes through
+        server
+            .handle_rename(fake_rename_args(2, "zee.txt", 8, "zee.mv.txt"))
+            .unwrap();
+        assert_eq!(server.name_locks.num_locked(), 0);
+
+        let find_new = server
+            .fs_find_by_name(fspath.join("1/8"), "zee.mv.txt")
+            .unwrap();
+        assert_eq!(find_new, Some(3));
+    })
+}
+
+#[test]
+fn test_readdir_after_failed_rename() {
+    run_with_clone_fs("test_files/test1", true, |fspath| {
+        // Create a server
+        let server = ZippynfsServer::new(fspath);
+
+        // Fail a RENAME within a directory half way, after the new name was made
+        server.faults.arm(FaultPoint::RenameNumbered);
+        let rename = server.handle_rename(fake_rename_args(1, "baz.txt", 1, "baz.mv.txt"));
+        server.faults.disarm(FaultPoint::RenameNumbered);
+        assert!(rename.is_err());
+
+        // The file is there under both names, and both are listed
+        let all = server
+            .handle_readdir(ZipReadDirArgs::new(ZipFileHandle::new(1), 0, 0, 0))
+            .unwrap();
+        let names: Vec<&str> = all.entries
+            .iter()
+            .filter(|entry| entry.fid == 4)
+            .map(|entry| entry.fname.as_str())
+            .collect();
+        assert_eq!(names, vec!["baz.mv.txt", "baz.txt"]);
+
+        let all = server
+            .handle_readdirplus(ZipReadDirArgs::new(ZipFileHandle::new(1), 0, 0, 0))
+            .unwrap();
+        assert_eq!(all.entries.iter().filter(|entry| entry.fid == 4).count(), 2);
+
+        // However small the pages, a listing resumed from the last FID seen never misses a name
+        let mut listed = 0;
+        for maxcount in (100..400).filter(|maxcount| maxcount % 10 == 0) {
+            let mut seen = HashSet::new();
+            let mut cookie = 0;
+            let mut verf = 0;
+
+            loop {
+                let res = server
+                    .handle_readdir(ZipReadDirArgs::new(
+                        ZipFileHandle::new(1),
+                        cookie,
+                        verf,
+                        maxcount,
+                    ))
+                    .map_err(|e| e.into());
+                let res = match res {
+                    Ok(res) => res,
+                    Err(ZipError::Nfs(ZipErrorType::NFSERR_TOOSMALL, _)) => break,
+                    Err(e) => panic!("{:?}", e),
+                };
+
+                for entry in res.entries.iter() {
+                    assert!(seen.insert((entry.fid, entry.fname.clone())));
+                }
+                if res.eof {
+                    assert!(seen.contains(&(4, "baz.txt".to_owned())), "{}", maxcount);
+                    assert!(seen.contains(&(4, "baz.mv.txt".to_owned())), "{}", maxcount);
+                    listed += 1;
+                    break;
+                }
+
+                cookie = res.entries.last().unwrap().fid;
+                verf = res.cookieverf;
+            }
+        }
+        assert!(listed > 0);
+    })
+}
+
+#[test]
+fn test_drc_compact_in_progress() {
+    run_with_clone_fs("test_files/test1", true, |fspath| {
+        let drc = Arc::new(DupCache::new(fspath.join("drc.test"), 2).unwrap());
+
+        // Start a request, and while it is being done, finish enough others to compact the log
+        assert_eq!(drc.begin(1), None);
+
+        let compacter = {
+            let drc = drc.clone();
+            thread::spawn(move || for xid in 2..2000 {
+                assert_eq!(drc.begin(xid), None);
+                drc.finish(xid, Some(Outcome::Done(xid as usize)));
+            })
+        };
+        compacter.join().unwrap();
+
+        // A retry of the first request still waits for it, rather than doing it again
+        let (tx, rx) = mpsc::channel();
+        let retry = {
+            let drc = drc.clone();
+            thread::spawn(move || tx.send(drc.begin(1)).unwrap())
+        };
+        assert!(rx.recv_timeout(Duration::from_millis(200)).is_err());
+
+        drc.finish(1, Some(Outcome::Done(42)));
+        assert_eq!(rx.recv().unwrap(), Some(Outcome::Done(42)));
+        retry.join().unwrap();
+    })
+}
+
+#[test]
+fn test_dir_index_persistent() {
+    run_with_clone_fs("test_files/test1", true, |fspath| {
+        {
+            // Create a server
+            let server = ZippynfsServer::new(fspath);
+
+            // Create, rename and remove some things
+            let create1 = server
+                .create_object(fake_create_args(1, "myfile"), true)
+                .unwrap();
+            let create2 = server
+                .create_object(fake_create_args(8, "mydir"), false)
+                .unwrap();
+            server
+                .handle_rename(fake_rename_args(1, "myfile", 8, "myfile.mv"))
+                .unwrap();
+            server.handle_remove(fake_dir_op_args(1, "baz.txt")).unwrap();
+
+            assert_eq!(create1.file.fid, 10);
+            assert_eq!(create2.file.fid, 11);
+
+            // Leave a half-created object behind, as if we crashed before creating its named
+            // file. A CREATE that fails cleans up after itself, so this has to be done by hand.
+            let fid = server.counter.fetch_inc();
+            File::create(fspath.join("1").join(fid.to_string())).unwrap();
+            server
+                .dir_index
+                .insert(1, &fspath.join("1"), fid, "halfdone")
+                .unwrap();
+        } // Crash
+
+        // Reboot
+        let server = ZippynfsServer::new(fspath);
+
+        // Everything is where we left it
+        let find_old = server.fs_find_by_name(fspath.join("1"), "myfile").unwrap();
+        let find_mv = server
+            .fs_find_by_name(fspath.join("1/8"), "myfile.mv")
+            .unwrap();
+        let find_dir = server.fs_find_by_name(fspath.join("1/8"), "mydir").unwrap();
+        let find_rm = server.fs_find_by_name(fspath.join("1"), "baz.txt").unwrap();
+        let find_half = server.fs_find_by_name(fspath.join("1"), "halfdone").unwrap();
+
+        assert_eq!(find_old, None);
+        assert_eq!(find_mv, Some(10));
+        assert_eq!(find_dir, Some(11));
+        assert_eq!(find_rm, None);
+        assert_eq!(find_half, None);
+
+        // The half-created object was dropped from the index when it was loaded
+        let root_entries = server.dir_index.entries(1, &fspath.join("1")).unwrap();
+        assert_eq!(
+            root_entries,
+            vec![(5, "bazee".to_owned()), (8, "foo".to_owned())]
+        );
+
+        // And the name can be used
+        server
+            .create_object(fake_create_args(1, "halfdone"), true)
+            .unwrap();
+    })
+}
+
+#[test]
+fn test_dir_index_compact() {
+    run_with_clone_fs("test_files/test1", true, |fspath| {
+        let server = ZippynfsServer::new(fspath);
+        let dpath = fspath.join("1");
+
+        // A rename that failed half way leaves both names in the index
+        server.dir_index.insert(1, &dpath, 100, "old").unwrap();
+        server.dir_index.insert(1, &dpath, 100, "new").unwrap();
+
+        // Enough dead records to compact the log
+        for _ in 0..2048 {
+            server.dir_index.remove(1, &dpath, 101, "gone").unwrap();
+        }
+
+        // The log was compacted, but both names survive
+        let log_len = metadata(fspath.join("index/1")).unwrap().len();
+        assert!(log_len < 2048 * (13 + 4));
+        assert_eq!(server.dir_index.lookup(1, &dpath, "old").unwrap(), Some(100));
+        assert_eq!(server.dir_index.lookup(1, &dpath, "new").unwrap(), Some(100));
+        assert_eq!(server.dir_index.lookup(1, &dpath, "baz.txt").unwrap(), Some(4));
+    })
+}
+
+#[test]
+fn test_dir_index_rebuild() {
+    use std::fs::remove_dir_all;
+
+    run_with_clone_fs("test_files/test1", true, |fspath| {
+        {
+            // Create a server and something in the root
+            let server = ZippynfsServer::new(fspath);
+            server
+                .create_object(fake_create_args(1, "myfile"), true)
+                .unwrap();
+        }
+
+        // Lose all of the indices
+        remove_dir_all(fspath.join("index")).unwrap();
+
+        // Reboot, and the indices are rebuilt from the server files
+        let server = ZippynfsServer::new(fspath);
+
+        let find_new = server.fs_find_by_name(fspath.join("1"), "myfile").unwrap();
+        let find3 = server
+            .fs_find_by_name(fspath.join("1/8/2"), "zee.txt")
+            .unwrap();
+        let find7 = server
+            .fs_find_by_name(fspath.join("1"), "deleted.txt")
+            .unwrap();
+
+        assert_eq!(find_new, Some(10));
+        assert_eq!(find3, Some(3));
+        assert_eq!(find7, None);
+    })
+}
+
 #[test]
 fn test_nfs_statfs() {
     run_with_clone_fs("test_files/test1", true, |fspath| {
@@ -1134,6 +1884,486 @@ fn test_nfs_statfs() {
     })
 }
 
+#[test]
+fn test_nfs_fsinfo() {
+    run_with_clone_fs("test_files/test1", true, |fspath| {
+        let server = ZippynfsServer::new(fspath);
+        let fsinfo = server.handle_fsinfo(ZipFileHandle::new(1)).unwrap();
+        let statfs = server.handle_statfs(ZipFileHandle::new(1)).unwrap();
+
+        assert_eq!(fsinfo.rtmax, MAX_IO_LEN as i64);
+        assert_eq!(fsinfo.wtmax, MAX_IO_LEN as i64);
+        assert_eq!(fsinfo.maxmsg, MAX_MSG_LEN as i64);
+        assert!(fsinfo.dtpref as usize <= MAX_MSG_LEN);
+        assert_eq!(statfs.tsize, fsinfo.wtmax);
+    })
+}
+
+#[test]
+fn test_nfs_hello() {
+    run_with_clone_fs("test_files/test1", true, |fspath| {
+        let server = ZippynfsServer::new(fspath);
+
+        // Same version
+        let hello = server
+            .handle_hello(ZipHelloArgs::new(PROTOCOL_VERSION))
+            .unwrap();
+        assert_eq!(hello.version, PROTOCOL_VERSION);
+        assert!(hello.capabilities & CAP_UNSTABLE_WRITES != 0);
+        assert!(hello.capabilities & CAP_READDIRPLUS != 0);
+        assert!(hello.capabilities & CAP_SYMLINKS == 0);
+        assert!(hello.capabilities & CAP_PIPELINING != 0);
+        assert!(hello.capabilities & CAP_OPEN_STATE != 0);
+        assert!(hello.capabilities & CAP_LEASES != 0);
+        assert!(hello.capabilities & CAP_LOCKS != 0);
+        assert!(hello.capabilities & CAP_DELEGATIONS != 0);
+        assert_eq!(
+            hello.fsinfo,
+            server.handle_fsinfo(ZipFileHandle::new(1)).unwrap()
+        );
+
+        // A newer client is told which version the server speaks
+        let hello = server
+            .handle_hello(ZipHelloArgs::new(PROTOCOL_VERSION + 1))
+            .unwrap();
+        assert_eq!(hello.version, PROTOCOL_VERSION);
+
+        // A client that is too old is rejected
+        let hello = server.handle_hello(ZipHelloArgs::new(MIN_PROTOCOL_VERSION - 1));
+        match hello.map_err(|e| e.into()) {
+            Err(ZipError::Nfs(ZipErrorType::NFSERR_VERSION, msg)) => {
+                assert!(msg.contains("NFSERR_VERSION"));
+            }
+            _ => assert!(false),
+        }
+    })
+}
+
+#[test]
+fn test_nfs_open_close() {
+    run_with_clone_fs("test_files/test1", true, |fspath| {
+        let server = ZippynfsServer::new(fspath);
+
+        let open = |fid, client| ZipOpenArgs::new(ZipFileHandle::new(fid), client);
+
+        // Opens are counted across clients
+        let opened = server.handle_open(open(3, 1)).unwrap();
+        assert_eq!(opened.opens, 1);
+        assert_eq!(opened.attributes.unwrap().size, 27);
+        assert_eq!(server.handle_open(open(3, 1)).unwrap().opens, 2);
+        assert_eq!(server.handle_open(open(3, 2)).unwrap().opens, 3);
+
+        // A client can only close what it opened
+        assert_eq!(server.handle_close(open(3, 3)).unwrap().opens, 3);
+        assert_eq!(server.handle_close(open(3, 2)).unwrap().opens, 2);
+        assert_eq!(server.handle_close(open(3, 2)).unwrap().opens, 2);
+        assert_eq!(server.handle_close(open(3, 1)).unwrap().opens, 1);
+        assert_eq!(server.handle_close(open(3, 1)).unwrap().opens, 0);
+
+        // Closing a file that isn't open is harmless
+        let closed = server.handle_close(open(4, 1)).unwrap();
+        assert_eq!(closed.opens, 0);
+        assert!(closed.attributes.is_none());
+
+        // A file that doesn't exist can't be opened
+        match server.handle_open(open(1000, 1)).map_err(|e| e.into()) {
+            Err(ZipError::Nfs(ZipErrorType::NFSERR_STALE, _)) => {}
+            _ => assert!(false),
+        }
+    })
+}
+
+#[test]
+fn test_nfs_silly_files() {
+    run_with_clone_fs("test_files/test1", true, |fspath| {
+        let mut server = ZippynfsServer::new(fspath);
+        server.leases = Leases::new(Duration::from_millis(200));
+
+        let open = |fid, client| ZipOpenArgs::new(ZipFileHandle::new(fid), client);
+        let rename = |old_did, old_filename: &str, new_did, new_filename: &str, client| {
+            let mut args = fake_rename_args(old_did, old_filename, new_did, new_filename);
+            args.client = client;
+            args
+        };
+        let exists = |server: &ZippynfsServer<_>, did, filename: &str| {
+            server.handle_lookup(fake_dir_op_args(did, filename)).is_ok()
+        };
+
+        let silly = silly_name(1, 4);
+        assert_eq!(silly_owner(&silly), Some(1));
+        assert_eq!(silly_owner("baz.txt"), None);
+
+        // Client 1 unlinks baz.txt while clients 1 and 2 have it open
+        server.handle_open(open(4, 1)).unwrap();
+        server.handle_open(open(4, 2)).unwrap();
+        server
+            .handle_rename(rename(1, "baz.txt", 1, &silly, Some(1)))
+            .unwrap();
+
+        // A file that just looks like client 1's, since it wasn't renamed by a client saying who
+        // it is, is never removed
+        let lookalike = silly_name(1, 3);
+        server
+            .handle_rename(rename(2, "zee.txt", 2, &lookalike, None))
+            .unwrap();
+
+        // While client 1 is around, the file stays
+        server.reap();
+        assert!(exists(&server, 1, &silly));
+
+        // Client 1 goes away, but client 2 still has the file open
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(server.handle_renew(2).unwrap(), LEASE_SECS as i64);
+        server.reap();
+        assert!(exists(&server, 1, &silly));
+
+        // Once client 2 closes it, it goes
+        assert_eq!(server.handle_close(open(4, 2)).unwrap().opens, 0);
+        server.reap();
+        match server.handle_lookup(fake_dir_op_args(1, &silly)).map_err(|e| e.into()) {
+            Err(ZipError::Nfs(ZipErrorType::NFSERR_NOENT, _)) => {}
+            _ => assert!(false),
+        }
+        assert!(exists(&server, 2, &lookalike));
+
+        // Client 3 unlinks the lookalike, and then the server restarts
+        let silly = silly_name(3, 3);
+        server
+            .handle_rename(rename(2, &lookalike, 2, &silly, Some(3)))
+            .unwrap();
+        drop(server);
+
+        let mut server = ZippynfsServer::new(fspath);
+        server.leases = Leases::new(Duration::from_millis(200));
+
+        // Client 3 gets a lease from the restart to come back, and if it doesn't, the file goes
+        server.reap();
+        assert!(exists(&server, 2, &silly));
+        thread::sleep(Duration::from_millis(300));
+        server.reap();
+        assert!(!exists(&server, 2, &silly));
+    })
+}
+
+#[test]
+fn test_nfs_locks() {
+    run_with_clone_fs("test_files/test1", true, |fspath| {
+        let mut server = ZippynfsServer::new(fspath);
+        server.leases = Leases::new(Duration::from_millis(200));
+
+        let lock = |fid, client, owner, kind, start, end, reclaim| {
+            ZipLockArgs::new(
+                ZipFileHandle::new(fid),
+                client,
+                ZipLock::new(kind, start, end, owner, 100 + owner),
+                reclaim,
+            )
+        };
+        let (read, write, unlock) = (ZipLockType::READ, ZipLockType::WRITE, ZipLockType::UNLOCK);
+
+        // Right after a restart, only locks held before it can be taken
+        match server
+            .handle_lock(lock(4, 1, 1, write, 0, 10, false))
+            .map_err(|e| e.into()) {
+            Err(ZipError::Nfs(ZipErrorType::NFSERR_GRACE, _)) => {}
+            _ => assert!(false),
+        }
+        let reclaimed = server.handle_lock(lock(4, 1, 1, write, 0, 10, true)).unwrap();
+        assert!(reclaimed.granted);
+        assert_eq!(reclaimed.epoch, server.handle_null().unwrap());
+
+        thread::sleep(Duration::from_millis(250));
+
+        // A write lock keeps out everyone else, and only its own client sees its PID
+        let denied = server.handle_lock(lock(4, 2, 1, read, 5, 20, false)).unwrap();
+        assert!(!denied.granted);
+        let conflict = denied.conflict.unwrap();
+        assert_eq!((conflict.start, conflict.end, conflict.pid), (0, 10, 0));
+
+        let denied = server.handle_testlock(lock(4, 1, 2, read, 5, 20, false)).unwrap();
+        assert!(!denied.granted);
+        assert_eq!(denied.conflict.unwrap().pid, 101);
+
+        // After turning it into a read lock, other readers can share it, but writers can't
+        assert!(server.handle_lock(lock(4, 1, 1, read, 0, 10, false)).unwrap().granted);
+        assert!(server.handle_lock(lock(4, 2, 1, read, 5, 20, false)).unwrap().granted);
+        assert!(!server.handle_testlock(lock(4, 3, 1, write, 15, -1, false)).unwrap().granted);
+
+        // Unlocking part of a lock leaves the rest
+        assert!(server.handle_lock(lock(4, 2, 1, unlock, 0, 18, false)).unwrap().granted);
+        let conflict = server
+            .handle_testlock(lock(4, 3, 1, write, 10, -1, false))
+            .unwrap()
+            .conflict
+            .unwrap();
+        assert_eq!((conflict.start, conflict.end), (18, 20));
+
+        // The locks of a client that goes away are dropped
+        thread::sleep(Duration::from_millis(250));
+        server.handle_renew(1).unwrap();
+        server.reap();
+        assert!(server.handle_testlock(lock(4, 3, 1, write, 10, -1, false)).unwrap().granted);
+        assert!(!server.handle_testlock(lock(4, 3, 1, write, 0, -1, false)).unwrap().granted);
+
+        // A file that doesn't exist can't be locked
+        match server
+            .handle_lock(lock(1000, 1, 1, write, 0, 10, false))
+            .map_err(|e| e.into()) {
+            Err(ZipError::Nfs(ZipErrorType::NFSERR_STALE, _)) => {}
+            _ => assert!(false),
+        }
+    })
+}
+
+#[test]
+fn test_nfs_delegations() {
+    run_with_clone_fs("test_files/test1", true, |fspath| {
+        let mut server = ZippynfsServer::new(fspath);
+        server.leases = Leases::new(Duration::from_millis(200));
+
+        let delegate = |fid, client, kind, reclaim| {
+            ZipDelegateArgs::new(ZipFileHandle::new(fid), client, kind, reclaim)
+        };
+        let open = |fid, client| ZipOpenArgs::new(ZipFileHandle::new(fid), client);
+        let write = |fid| {
+            ZipWriteArgs::new(
+                ZipFileHandle::new(fid),
+                0, // offset
+                5, // count
+                b"Hello".to_vec(),
+                ZipWriteStable::FILE_SYNC,
+                None,
+            )
+        };
+        let (none, read, write_deleg) = (
+            ZipDelegationType::NONE,
+            ZipDelegationType::READ,
+            ZipDelegationType::WRITE,
+        );
+
+        let (addr1, recalls1) = callback_listener();
+        let (addr2, recalls2) = callback_listener();
+        server
+            .handle_setcallback(ZipCallbackArgs::new(1, addr1))
+            .unwrap();
+        server
+            .handle_setcallback(ZipCallbackArgs::new(2, addr2))
+            .unwrap();
+
+        // Right after a restart, only delegations held before it can be taken
+        match server
+            .handle_delegate(delegate(4, 1, read, false))
+            .map_err(|e| e.into()) {
+            Err(ZipError::Nfs(ZipErrorType::NFSERR_GRACE, _)) => {}
+            _ => assert!(false),
+        }
+        let reclaimed = server.handle_delegate(delegate(4, 1, read, true)).unwrap();
+        assert_eq!(reclaimed.granted, read);
+        assert_eq!(reclaimed.attributes.unwrap().fid, 4);
+        assert_eq!(reclaimed.epoch, server.handle_null().unwrap());
+
+        thread::sleep(Duration::from_millis(250));
+
+        // Readers share, and keep out writers
+        let granted = server.handle_delegate(delegate(4, 2, read, false)).unwrap();
+        assert_eq!(granted.granted, read);
+        let granted = server.handle_delegate(delegate(4, 2, write_deleg, false)).unwrap();
+        assert_eq!(granted.granted, read);
+
+        // A write recalls them all
+        server.handle_write(write(4)).unwrap();
+        assert_eq!(*recalls1.lock().unwrap(), vec![(4, read)]);
+        assert_eq!(*recalls2.lock().unwrap(), vec![(4, read)]);
+
+        // A WRITE delegation keeps out everyone else, and is recalled by anyone else's OPEN, but
+        // not by the holder's
+        server.handle_open(open(4, 2)).unwrap();
+        let granted = server.handle_delegate(delegate(4, 2, write_deleg, false)).unwrap();
+        assert_eq!(granted.granted, write_deleg);
+        let granted = server.handle_delegate(delegate(4, 1, read, false)).unwrap();
+        assert_eq!(granted.granted, none);
+
+        server.handle_open(open(4, 2)).unwrap();
+        assert_eq!(recalls2.lock().unwrap().len(), 1);
+        server.handle_open(open(4, 1)).unwrap();
+        assert_eq!(recalls2.lock().unwrap()[1], (4, write_deleg));
+
+        // Nobody gets a WRITE delegation of a file someone else has open
+        let granted = server.handle_delegate(delegate(4, 2, write_deleg, false)).unwrap();
+        assert_eq!(granted.granted, none);
+
+        // A delegation is only given to a client we can call back, and can be given back
+        let granted = server.handle_delegate(delegate(3, 3, read, false)).unwrap();
+        assert_eq!(granted.granted, none);
+        let granted = server.handle_delegate(delegate(3, 1, read, false)).unwrap();
+        assert_eq!(granted.granted, read);
+        let granted = server.handle_delegate(delegate(3, 1, none, false)).unwrap();
+        assert_eq!(granted.granted, none);
+        server
+            .handle_setattr(fake_sattr_args(3, Some(0), None, None))
+            .unwrap();
+        assert_eq!(recalls1.lock().unwrap().len(), 1);
+
+        // A change of attributes recalls READ delegations too
+        server.handle_delegate(delegate(3, 1, read, false)).unwrap();
+        server
+            .handle_setattr(fake_sattr_args(3, Some(5), None, None))
+            .unwrap();
+        assert_eq!(recalls1.lock().unwrap()[1], (3, read));
+
+        // The delegations of a client that goes away are dropped
+        server.handle_delegate(delegate(3, 1, read, false)).unwrap();
+        thread::sleep(Duration::from_millis(250));
+        server.handle_renew(2).unwrap();
+        server.reap();
+        server.handle_write(write(3)).unwrap();
+        assert_eq!(recalls1.lock().unwrap().len(), 2);
+
+        // A client that can't be called back loses its delegation, and gets no more until it
+        // says where it takes callbacks again
+        let gone = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            format!("{}", listener.local_addr().unwrap())
+        };
+        server
+            .handle_setcallback(ZipCallbackArgs::new(3, gone))
+            .unwrap();
+        let granted = server.handle_delegate(delegate(3, 3, read, false)).unwrap();
+        assert_eq!(granted.granted, read);
+        server.handle_write(write(3)).unwrap();
+        let granted = server.handle_delegate(delegate(3, 3, read, false)).unwrap();
+        assert_eq!(granted.granted, none);
+
+        // A file that doesn't exist can't be delegated
+        match server
+            .handle_delegate(delegate(1000, 2, read, false))
+            .map_err(|e| e.into()) {
+            Err(ZipError::Nfs(ZipErrorType::NFSERR_STALE, _)) => {}
+            _ => assert!(false),
+        }
+    })
+}
+
+#[test]
+fn test_nfs_delegation_conflicts() {
+    run_with_clone_fs("test_files/test1", true, |fspath| {
+        let mut server = ZippynfsServer::new(fspath);
+        server.leases = Leases::new(Duration::from_millis(200));
+
+        let delegate = |client, kind| {
+            ZipDelegateArgs::new(ZipFileHandle::new(3), client, kind, false)
+        };
+        let write = |client: Option<i64>| {
+            ZipWriteArgs::new(
+                ZipFileHandle::new(3),
+                0, // offset
+                5, // count
+                b"Hello".to_vec(),
+                ZipWriteStable::FILE_SYNC,
+                client,
+            )
+        };
+        let (read, write_deleg) = (ZipDelegationType::READ, ZipDelegationType::WRITE);
+
+        // Remember how long zee.txt was when each recall came in, to see that it was recalled
+        // before the change was made
+        let path = fspath.join("1/8/2/3");
+        let lengths = Arc::new(Mutex::new(Vec::new()));
+        let (addr, recalls) = {
+            let (path, lengths) = (path.clone(), lengths.clone());
+            callback_listener_with(move |_| {
+                let length = metadata(&path).ok().map(|m| m.len());
+                lengths.lock().unwrap().push(length);
+            })
+        };
+        server
+            .handle_setcallback(ZipCallbackArgs::new(1, addr))
+            .unwrap();
+
+        thread::sleep(Duration::from_millis(250));
+
+        // The holder's own requests don't recall its delegation
+        let granted = server.handle_delegate(delegate(1, write_deleg)).unwrap();
+        assert_eq!(granted.granted, write_deleg);
+        server.handle_write(write(Some(1))).unwrap();
+        let mut sattr = fake_sattr_args(3, None, None, Some((1, 0)));
+        sattr.client = Some(1);
+        server.handle_setattr(sattr).unwrap();
+        let mut read_args = fake_read_args(3, 0, 10);
+        read_args.client = Some(1);
+        server.handle_read(read_args).unwrap();
+        assert!(recalls.lock().unwrap().is_empty());
+
+        // Another client's change of attributes recalls a WRITE delegation first
+        let mut sattr = fake_sattr_args(3, Some(0), None, None);
+        sattr.client = Some(2);
+        let attrs = server.handle_setattr(sattr).unwrap().attributes;
+        assert_eq!(attrs.size, 0);
+        assert_eq!(*recalls.lock().unwrap(), vec![(3, write_deleg)]);
+        assert_eq!(*lengths.lock().unwrap(), vec![Some(27)]);
+
+        // So does another client's write
+        let granted = server.handle_delegate(delegate(1, write_deleg)).unwrap();
+        assert_eq!(granted.granted, write_deleg);
+        server.handle_write(write(Some(2))).unwrap();
+        assert_eq!(recalls.lock().unwrap()[1], (3, write_deleg));
+        assert_eq!(lengths.lock().unwrap()[1], Some(0));
+
+        // And a read that doesn't say who it's from, which leaves READ delegations alone
+        let granted = server.handle_delegate(delegate(1, write_deleg)).unwrap();
+        assert_eq!(granted.granted, write_deleg);
+        server.handle_read(fake_read_args(3, 0, 10)).unwrap();
+        assert_eq!(recalls.lock().unwrap()[2], (3, write_deleg));
+
+        let granted = server.handle_delegate(delegate(1, read)).unwrap();
+        assert_eq!(granted.granted, read);
+        server.handle_read(fake_read_args(3, 0, 10)).unwrap();
+        assert_eq!(recalls.lock().unwrap().len(), 3);
+
+        // Removing the file recalls everything, while it is still there
+        server
+            .handle_remove(fake_dir_op_args(2, "zee.txt"))
+            .unwrap();
+        assert_eq!(recalls.lock().unwrap()[3], (3, read));
+        assert_eq!(lengths.lock().unwrap()[3], Some(5));
+        assert!(!path.exists());
+    })
+}
+
+#[test]
+fn test_nfs_large_io() {
+    run_with_clone_fs("test_files/test1", true, |fspath| {
+        let server = ZippynfsServer::new(fspath);
+
+        // Much more than the old 4000B limit, all in one WRITE
+        let data: Vec<u8> = (0..MAX_IO_LEN).map(|i| (i % 251) as u8).collect();
+        let write = server
+            .handle_write(ZipWriteArgs::new(
+                ZipFileHandle::new(4),
+                0, // offset
+                data.len() as i64, // count
+                data.clone(),
+                ZipWriteStable::FILE_SYNC,
+                None,
+            ))
+            .unwrap();
+        assert_eq!(write.count as usize, MAX_IO_LEN);
+
+        // ... and back in one READ
+        let read = server
+            .handle_read(fake_read_args(4, 0, MAX_IO_LEN as i64))
+            .unwrap();
+        assert_eq!(read.attributes.size as usize, MAX_IO_LEN);
+        assert!(read.data == data);
+
+        // A READ never returns more than MAX_IO_LEN
+        let read = server
+            .handle_read(fake_read_args(4, 0, 2 * MAX_IO_LEN as i64))
+            .unwrap();
+        assert_eq!(read.data.len(), MAX_IO_LEN);
+    })
+}
+
 #[test]
 fn test_nfs_write_stable_simple() {
     run_with_clone_fs("test_files/test1", true, |fspath| {
@@ -1160,6 +2390,7 @@ fn test_nfs_write_stable_simple() {
                 data1.len() as i64, // count
                 data1.clone().into(),
                 ZipWriteStable::FILE_SYNC,
+                None,
             ))
             .unwrap();
 
@@ -1208,6 +2439,7 @@ fn test_nfs_write_stable_extend() {
                 data1.len() as i64, // count
                 data1.clone().into(),
                 ZipWriteStable::FILE_SYNC,
+                None,
             ))
             .unwrap();
 
@@ -1230,6 +2462,113 @@ fn test_nfs_write_stable_extend() {
     })
 }
 
+#[test]
+fn test_nfs_write_stable_concurrent() {
+    run_with_clone_fs("test_files/test1", true, |fspath| {
+        let server = Arc::new(ZippynfsServer::new(fspath.to_owned()));
+
+        // Each thread keeps writing its own part of the file. Each write rewrites the whole file,
+        // so unless they take turns, some of them are lost.
+        const NTHREADS: usize = 8;
+        const PART_LEN: usize = 64;
+
+        let children: Vec<_> = (0..NTHREADS)
+            .map(|i| {
+                let server = server.clone();
+                thread::spawn(move || for _ in 0..20 {
+                    let write = server
+                        .handle_write(ZipWriteArgs::new(
+                            ZipFileHandle::new(3),
+                            (i * PART_LEN) as i64,
+                            PART_LEN as i64,
+                            vec![i as u8; PART_LEN],
+                            ZipWriteStable::FILE_SYNC,
+                            None,
+                        ))
+                        .unwrap();
+                    assert_eq!(write.count as usize, PART_LEN);
+                })
+            })
+            .collect();
+
+        for child in children {
+            child.join().unwrap();
+        }
+
+        // Correctness
+        let mut buf = Vec::new();
+        File::open(fspath.join("1/8/2/3"))
+            .unwrap()
+            .read_to_end(&mut buf)
+            .unwrap();
+        assert_eq!(buf.len(), NTHREADS * PART_LEN);
+        for (i, part) in buf.chunks(PART_LEN).enumerate() {
+            assert!(part.iter().all(|&b| b == i as u8), "part {} was lost", i);
+        }
+
+        // The locks are only kept while they are wanted
+        assert!(server.rewrite_locks.lock().unwrap().is_empty());
+    })
+}
+
+#[test]
+fn test_nfs_write_invalid() {
+    run_with_clone_fs("test_files/test1", true, |fspath| {
+        // Create a server
+        let server = ZippynfsServer::new(fspath);
+
+        let fpath_numbered = fspath.join("1/8/2/3");
+        let mut buf_old = Vec::new();
+        File::open(&fpath_numbered)
+            .unwrap()
+            .read_to_end(&mut buf_old)
+            .unwrap();
+
+        // The count has to be the length of the data, whether or not the write is stable
+        for &stable in &[ZipWriteStable::FILE_SYNC, ZipWriteStable::UNSTABLE] {
+            for &count in &[5, 20, -1] {
+                let write = server.handle_write(ZipWriteArgs::new(
+                    ZipFileHandle::new(3),
+                    0, // offset
+                    count,
+                    b"Hello, World!".to_vec(),
+                    stable,
+                    None,
+                ));
+                match write.map_err(|e| e.into()) {
+                    Err(ZipError::Nfs(ZipErrorType::NFSERR_INVAL, _)) => {}
+                    _ => assert!(false),
+                }
+            }
+        }
+
+        // Nor can a write be bigger than FSINFO's wtmax
+        for &stable in &[ZipWriteStable::FILE_SYNC, ZipWriteStable::UNSTABLE] {
+            let write = server.handle_write(ZipWriteArgs::new(
+                ZipFileHandle::new(3),
+                0, // offset
+                (MAX_IO_LEN + 1) as i64,
+                vec![0; MAX_IO_LEN + 1],
+                stable,
+                None,
+            ));
+            match write.map_err(|e| e.into()) {
+                Err(ZipError::Nfs(ZipErrorType::NFSERR_INVAL, _)) => {}
+                _ => assert!(false),
+            }
+        }
+
+        // Nothing was written
+        let mut buf_new = Vec::new();
+        File::open(&fpath_numbered)
+            .unwrap()
+            .read_to_end(&mut buf_new)
+            .unwrap();
+        assert_eq!(buf_new, buf_old);
+        assert!(server.async_bufs.read().unwrap().is_empty());
+    })
+}
+
 #[test]
 fn test_nfs_write_unstable_simple() {
     run_with_clone_fs("test_files/test1", true, |fspath| {
@@ -1258,6 +2597,7 @@ fn test_nfs_write_unstable_simple() {
                 data1.len() as i64, // count
                 data1.clone().into(),
                 ZipWriteStable::UNSTABLE,
+                None,
             ))
             .unwrap();
         let write2 = server
@@ -1267,6 +2607,7 @@ fn test_nfs_write_unstable_simple() {
                 data2.len() as i64, // count
                 data2.clone().into(),
                 ZipWriteStable::UNSTABLE,
+                None,
             ))
             .unwrap();
 
@@ -1292,6 +2633,7 @@ fn test_nfs_write_unstable_simple() {
                 ZipFileHandle::new(3),
                 -1, // count
                 -1, // offset
+                None, // client
             ))
             .unwrap();
 
@@ -1337,6 +2679,7 @@ fn test_nfs_write_unstable_extend() {
                 data1.len() as i64, // count
                 data1.clone().into(),
                 ZipWriteStable::UNSTABLE,
+                None,
             ))
             .unwrap();
 
@@ -1359,6 +2702,7 @@ fn test_nfs_write_unstable_extend() {
                 ZipFileHandle::new(3),
                 -1, // count
                 -1, // offset
+                None, // client
             ))
             .unwrap();
 
@@ -1405,6 +2749,7 @@ fn test_nfs_write_unstable_overlap() {
                 data1.len() as i64, // count
                 data1.clone().into(),
                 ZipWriteStable::UNSTABLE,
+                None,
             ))
             .unwrap();
         let write2 = server
@@ -1414,6 +2759,7 @@ fn test_nfs_write_unstable_overlap() {
                 data2.len() as i64, // count
                 data2.clone().into(),
                 ZipWriteStable::UNSTABLE,
+                None,
             ))
             .unwrap();
 
@@ -1439,6 +2785,7 @@ fn test_nfs_write_unstable_overlap() {
                 ZipFileHandle::new(3),
                 -1, // count
                 -1, // offset
+                None, // client
             ))
             .unwrap();
 
@@ -1485,6 +2832,7 @@ fn test_nfs_write_unstable_crash() {
             data1.len() as i64, // count
             data1.clone().into(),
             ZipWriteStable::UNSTABLE,
+            None,
         );
         let write_args2 = ZipWriteArgs::new(
             ZipFileHandle::new(3),
@@ -1492,6 +2840,7 @@ fn test_nfs_write_unstable_crash() {
             data2.len() as i64, // count
             data2.clone().into(),
             ZipWriteStable::UNSTABLE,
+            None,
         );
 
         // Write a file multiple times
@@ -1523,6 +2872,7 @@ fn test_nfs_write_unstable_crash() {
                 ZipFileHandle::new(3),
                 -1, // count
                 -1, // offset
+                None, // client
             ))
             .unwrap();
 
@@ -1588,6 +2938,7 @@ fn test_nfs_write_unstable_crash() {
                 ZipFileHandle::new(3),
                 -1, // count
                 -1, // offset
+                None, // client
             ))
             .unwrap();
 
@@ -7,6 +7,7 @@ extern crate clap;
 extern crate log;
 extern crate env_logger;
 extern crate memmap;
+extern crate mio;
 
 #[cfg(test)]
 #[macro_use]
@@ -16,18 +17,27 @@ extern crate regex;
 extern crate thrift;
 extern crate zippyrpc;
 
+mod eventloop;
+mod frontend;
 mod handler;
+mod nfs3;
+mod p9;
+mod shared;
+
+#[cfg(test)]
+mod testutil;
 
 use std::path::Path;
 use std::process::exit;
-
-use thrift::protocol::{TCompactInputProtocolFactory, TCompactOutputProtocolFactory};
-use thrift::server::TServer;
-use thrift::transport::{TBufferedReadTransportFactory, TBufferedWriteTransportFactory};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 
 use zippyrpc::ZippynfsSyncProcessor;
 
-use handler::ZippynfsServer;
+use eventloop::Server;
+use handler::{ZippynfsServer, LEASE_SECS};
+use shared::SharedHandler;
 
 /// Checks if the given string is a valid IP:port pair.
 ///
@@ -40,11 +50,39 @@ fn is_addr(arg: String) -> Result<(), String> {
         .map(|_| ())
 }
 
+/// Checks if the given string is a positive number.
+///
+/// This is used for parsing command line args.
+fn is_positive(arg: String) -> Result<(), String> {
+    match arg.parse::<usize>() {
+        Ok(n) if n > 0 => Ok(()),
+        _ => Err("Not a positive number".to_owned()),
+    }
+}
+
+/// Where and how to serve NFSv3, if at all.
+struct Nfs3Options<'a> {
+    /// The address to serve NFSv3 (and MOUNT, unless `mount_addr` is given) at
+    nfs_addr: &'a str,
+
+    /// A separate address to serve MOUNT at
+    mount_addr: Option<&'a str>,
+
+    /// Whether to register with the local portmapper
+    portmap: bool,
+}
+
 /// The main routine of the server.
 ///
 /// The server sits around listening for RPC calls and then
 /// acts on them.
-fn run<P>(server_addr: &str, data_dir: P) -> Result<(), String>
+fn run<P>(
+    server_addr: &str,
+    data_dir: P,
+    workers: usize,
+    nfs3: Option<Nfs3Options>,
+    p9_addr: Option<&str>,
+) -> Result<(), String>
 where
     P: AsRef<Path> + Send + Sync + 'static,
 {
@@ -53,31 +91,45 @@ where
 
     info!("Hello! The server is starting!");
 
-    // Create stuff for Thrift
-    let i_tran_fact = TBufferedReadTransportFactory::new();
-    let i_prot_fact = TCompactInputProtocolFactory::new();
+    // The handler is shared by the Thrift, NFSv3, and 9P frontends
+    let handler = Arc::new(ZippynfsServer::new(data_dir));
+
+    // Clean up after clients that go away, checking a few times a lease
+    let reaper = handler.clone();
+    thread::Builder::new()
+        .name("reaper".to_owned())
+        .spawn(move || loop {
+            thread::sleep(Duration::from_secs(LEASE_SECS / 3));
+            reaper.reap();
+        })
+        .map_err(|e| format!("Unable to start the reaper: {}", e))?;
+
+    // Start serving NFSv3 in the background, if asked to
+    if let Some(nfs3) = nfs3 {
+        nfs3::serve(
+            handler.clone(),
+            nfs3.nfs_addr,
+            nfs3.mount_addr,
+            nfs3.portmap,
+        )?;
+    }
 
-    let o_tran_fact = TBufferedWriteTransportFactory::new();
-    let o_prot_fact = TCompactOutputProtocolFactory::new();
+    // Likewise for 9P
+    if let Some(p9_addr) = p9_addr {
+        p9::serve(handler.clone(), p9_addr)?;
+    }
 
     // demux incoming messages
-    let processor = ZippynfsSyncProcessor::new(ZippynfsServer::new(data_dir));
+    let processor = ZippynfsSyncProcessor::new(SharedHandler(handler));
 
-    info!("Creating a server with 10 workers");
+    info!("Creating a server with {} workers", workers);
 
     // create the server and start listening
-    let mut server = TServer::new(
-        i_tran_fact,
-        i_prot_fact,
-        o_tran_fact,
-        o_prot_fact,
-        processor,
-        10, // 10 workers
-    );
+    let server = Server::bind(server_addr, processor, workers)?;
 
     info!("Listening at {}", server_addr);
 
-    server.listen(server_addr).map_err(|e| format!("{}", e))
+    server.run()
 }
 
 /// The main entry point of the CLI client
@@ -94,6 +146,16 @@ fn main() {
                 +required +takes_value "The \"IP:Port\" address the server is listening on")
             (@arg data_dir: -d --dir
                 +required +takes_value "The directory where the server should put its FS contents")
+            (@arg nfs3: --nfs3 {is_addr} +takes_value
+                "Also serve NFSv3 and MOUNT over ONC RPC at this \"IP:Port\" address")
+            (@arg mountd: --mountd {is_addr} +takes_value requires[nfs3]
+                "Serve MOUNT at this \"IP:Port\" address instead of the NFSv3 one")
+            (@arg portmap: --portmap requires[nfs3]
+                "Register the NFSv3 and MOUNT ports with the local portmapper")
+            (@arg p9: --p9 {is_addr} +takes_value
+                "Also serve 9P2000.L at this \"IP:Port\" address")
+            (@arg workers: -w --workers {is_positive} +takes_value
+                "The number of threads doing FS work for Thrift clients (default 16)")
     }.get_matches();
 
     // Get the server address
@@ -102,7 +164,25 @@ fn main() {
     // Get the server data dir
     let data_dir = matches.value_of("data_dir").unwrap().to_owned();
 
-    if let Err(e) = run(server_addr, data_dir) {
+    // Get the number of workers
+    let workers = matches
+        .value_of("workers")
+        .map(|w| w.parse().unwrap())
+        .unwrap_or(16);
+
+    // Get the NFSv3 options
+    let nfs3 = matches.value_of("nfs3").map(|nfs_addr| {
+        Nfs3Options {
+            nfs_addr,
+            mount_addr: matches.value_of("mountd"),
+            portmap: matches.is_present("portmap"),
+        }
+    });
+
+    // Get the 9P address
+    let p9_addr = matches.value_of("p9");
+
+    if let Err(e) = run(server_addr, data_dir, workers, nfs3, p9_addr) {
         println!("Error! {}", e);
         exit(-1);
     }
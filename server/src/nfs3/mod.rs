@@ -0,0 +1,170 @@
+//! A native NFSv3 frontend, so that stock NFS clients (e.g. the Linux kernel) can mount the FS
+//! without our FUSE client.
+//!
+//! This speaks the NFS and MOUNT programs over ONC RPC on TCP, and maps every procedure onto the
+//! same handler that serves the Thrift protocol, so both frontends see the same FS at the same
+//! time. By default, both programs are served on a single fixed port and nothing is registered
+//! with a portmapper, so clients need to be told the ports, e.g.
+//!
+//! ```text
+//! mount -t nfs -o vers=3,proto=tcp,port=2049,mountport=2049,nolock server:/ /mnt
+//! ```
+//!
+//! There is no lock manager (NLM), so clients need `nolock`.
+
+mod mount;
+mod nfs;
+mod portmap;
+mod rpc;
+mod xdr;
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::io::BufReader;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+
+use zippyrpc::{ZippynfsSyncHandler, MAX_MSG_LEN};
+
+use self::mount::{MOUNT_PROGRAM, MOUNT_V3};
+use self::nfs::{NFS_PROGRAM, NFS_V3};
+use self::rpc::{AcceptStat, CallHeader};
+use self::xdr::{XdrReader, XdrWriter};
+
+/// Start serving NFSv3 at `nfs_addr`, and MOUNT at `mount_addr` (or also at `nfs_addr`, if
+/// `None`), in the background. If `portmap` is set, the ports are also registered with the local
+/// portmapper.
+pub fn serve<H>(
+    handler: Arc<H>,
+    nfs_addr: &str,
+    mount_addr: Option<&str>,
+    portmap: bool,
+) -> Result<(), String>
+where
+    H: ZippynfsSyncHandler + Send + Sync + 'static,
+{
+    let nfs_port = listen(handler.clone(), nfs_addr)?;
+    let mount_port = match mount_addr {
+        Some(mount_addr) => listen(handler, mount_addr)?,
+        None => nfs_port,
+    };
+
+    if portmap {
+        portmap::register(NFS_PROGRAM, NFS_V3, nfs_port)?;
+        portmap::register(MOUNT_PROGRAM, MOUNT_V3, mount_port)?;
+    }
+
+    Ok(())
+}
+
+/// Start accepting RPC connections at the given address in the background, and return the port
+/// we are listening on.
+fn listen<H>(handler: Arc<H>, addr: &str) -> Result<u16, String>
+where
+    H: ZippynfsSyncHandler + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind(addr).map_err(|e| {
+        format!("Unable to listen for NFSv3 at {}: {}", addr, e)
+    })?;
+    let port = listener.local_addr().map_err(|e| format!("{}", e))?.port();
+
+    info!("Listening for NFSv3 and MOUNT at {}", addr);
+
+    thread::spawn(move || for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("NFSv3: failed to accept a connection: {}", e);
+                continue;
+            }
+        };
+
+        let handler = handler.clone();
+        thread::spawn(move || if let Err(e) = handle_conn(&*handler, stream) {
+            warn!("NFSv3: connection failed: {}", e);
+        });
+    });
+
+    Ok(port)
+}
+
+/// Serve calls on the given connection, one at a time, until the client hangs up.
+fn handle_conn<H: ZippynfsSyncHandler>(handler: &H, stream: TcpStream) -> Result<(), String> {
+    let peer: Option<SocketAddr> = stream.peer_addr().ok();
+    debug!("NFSv3: new connection from {:?}", peer);
+
+    let mut writer = stream.try_clone().map_err(|e| format!("{}", e))?;
+    let mut reader = BufReader::new(stream);
+
+    while let Some(call) = rpc::read_record(&mut reader, MAX_MSG_LEN).map_err(
+        |e| format!("{}", e),
+    )?
+    {
+        if let Some(reply) = handle_call(handler, peer, &call) {
+            rpc::write_record(&mut writer, &reply).map_err(|e| format!("{}", e))?;
+        }
+    }
+
+    debug!("NFSv3: {:?} hung up", peer);
+    Ok(())
+}
+
+/// The XID that the handler's duplicate request cache knows a call by. A client resends a call it
+/// got no reply to with the same RPC xid, so the call is known by that, along with who sent it
+/// and what it is.
+fn drc_xid(peer: Option<SocketAddr>, header: &CallHeader) -> i64 {
+    let mut hasher = DefaultHasher::new();
+    (peer, header.xid, header.prog, header.vers, header.proc_).hash(&mut hasher);
+
+    // Zero means no XID
+    match hasher.finish() as i64 {
+        0 => 1,
+        xid => xid,
+    }
+}
+
+/// Handle a single RPC call from the given peer, and return the reply. Returns `None` if the call
+/// is so garbled that we can't even reply.
+fn handle_call<H: ZippynfsSyncHandler>(
+    handler: &H,
+    peer: Option<SocketAddr>,
+    call: &[u8],
+) -> Option<Vec<u8>> {
+    let mut args = XdrReader::new(call);
+
+    let header = match rpc::read_call(&mut args) {
+        Ok(header) => header,
+        Err(e) => {
+            warn!("NFSv3: dropping bad call: {}", e);
+            return None;
+        }
+    };
+
+    debug!("NFSv3: {:?}", header);
+
+    if !rpc::version_ok(&header) {
+        return Some(rpc::rpc_mismatch_reply(header.xid));
+    }
+
+    let mut results = XdrWriter::new();
+    let stat = match (header.prog, header.vers) {
+        (NFS_PROGRAM, NFS_V3) => {
+            let xid = drc_xid(peer, &header);
+            nfs::dispatch(handler, header.proc_, xid, &mut args, &mut results)
+        }
+        (MOUNT_PROGRAM, MOUNT_V3) => mount::dispatch(header.proc_, &mut args, &mut results),
+        (NFS_PROGRAM, _) => AcceptStat::ProgMismatch(NFS_V3, NFS_V3),
+        (MOUNT_PROGRAM, _) => AcceptStat::ProgMismatch(MOUNT_V3, MOUNT_V3),
+        _ => AcceptStat::ProgUnavail,
+    };
+
+    Some(rpc::accepted_reply(
+        header.xid,
+        stat,
+        &results.into_inner(),
+    ))
+}
+
+#[cfg(test)]
+mod test;
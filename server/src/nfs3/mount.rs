@@ -0,0 +1,101 @@
+//! The MOUNT program, version 3 (RFC 1813, appendix I).
+//!
+//! Clients use MOUNT to get the file handle of the root of an export. We only export one thing,
+//! the whole FS, as "/". We don't keep track of who has it mounted, so DUMP is always empty and
+//! UMNT does nothing.
+
+use zippyrpc::ZipFileHandle;
+
+use super::nfs::write_fh;
+use super::rpc::{AcceptStat, AUTH_UNIX};
+use super::xdr::{XdrReader, XdrWriter};
+
+/// The MOUNT program number
+pub const MOUNT_PROGRAM: u32 = 100005;
+
+/// The only version of MOUNT we speak
+pub const MOUNT_V3: u32 = 3;
+
+/// The longest path MOUNT allows
+const MNTPATHLEN: usize = 1024;
+
+/// The one thing we export
+const EXPORT_PATH: &'static str = "/";
+
+/// The FID of the root of the FS
+const ROOT_FID: i64 = 1;
+
+/// Procedures
+const MOUNTPROC3_NULL: u32 = 0;
+const MOUNTPROC3_MNT: u32 = 1;
+const MOUNTPROC3_DUMP: u32 = 2;
+const MOUNTPROC3_UMNT: u32 = 3;
+const MOUNTPROC3_UMNTALL: u32 = 4;
+const MOUNTPROC3_EXPORT: u32 = 5;
+
+/// Statuses (`mountstat3`)
+const MNT3_OK: u32 = 0;
+const MNT3ERR_NOENT: u32 = 2;
+
+/// Run the given MOUNT procedure, and write its results to `out`.
+pub fn dispatch(proc_: u32, args: &mut XdrReader, out: &mut XdrWriter) -> AcceptStat {
+    match proc_ {
+        MOUNTPROC3_NULL | MOUNTPROC3_UMNTALL => AcceptStat::Success,
+
+        MOUNTPROC3_MNT => {
+            let path = match args.string(MNTPATHLEN) {
+                Ok(path) => path,
+                Err(e) => {
+                    warn!("MOUNT: bad arguments to MNT: {}", e);
+                    return AcceptStat::GarbageArgs;
+                }
+            };
+
+            info!("MOUNT: MNT {:?}", path);
+
+            if path.trim_right_matches('/') != EXPORT_PATH.trim_right_matches('/') {
+                out.u32(MNT3ERR_NOENT);
+                return AcceptStat::Success;
+            }
+
+            out.u32(MNT3_OK);
+            write_fh(out, &ZipFileHandle::new(ROOT_FID));
+
+            // auth_flavors: we take anything, but clients expect AUTH_UNIX
+            out.u32(1);
+            out.u32(AUTH_UNIX);
+
+            AcceptStat::Success
+        }
+
+        // Nobody has anything mounted, as far as we know
+        MOUNTPROC3_DUMP => {
+            out.bool(false);
+            AcceptStat::Success
+        }
+
+        MOUNTPROC3_UMNT => {
+            match args.string(MNTPATHLEN) {
+                Ok(path) => {
+                    info!("MOUNT: UMNT {:?}", path);
+                    AcceptStat::Success
+                }
+                Err(e) => {
+                    warn!("MOUNT: bad arguments to UMNT: {}", e);
+                    AcceptStat::GarbageArgs
+                }
+            }
+        }
+
+        // One export, open to every host
+        MOUNTPROC3_EXPORT => {
+            out.bool(true);
+            out.string(EXPORT_PATH);
+            out.bool(false); // no groups
+            out.bool(false); // no more exports
+            AcceptStat::Success
+        }
+
+        _ => AcceptStat::ProcUnavail,
+    }
+}
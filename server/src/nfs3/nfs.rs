@@ -0,0 +1,759 @@
+//! The NFS program, version 3 (RFC 1813).
+//!
+//! Each procedure decodes its XDR arguments, makes the equivalent call on the handler, and
+//! encodes the results. NFSv3 has a few things we don't (e.g. symlinks and hard links), and those
+//! procedures just fail with `NFS3ERR_NOTSUPP`.
+//!
+//! File handles are just the 8-byte big-endian FID of the file, and readdir cookies are the FID
+//! of the last entry seen, just as in the Thrift protocol.
+
+use std::cmp::min;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use zippyrpc::*;
+
+use frontend::{Fail, Statuses};
+use handler::NAME_MAX;
+
+use super::rpc::AcceptStat;
+use super::xdr::{pad, XdrReader, XdrWriter};
+
+/// The NFS program number
+pub const NFS_PROGRAM: u32 = 100003;
+
+/// The only version of NFS we speak
+pub const NFS_V3: u32 = 3;
+
+/// The largest file handle NFSv3 allows
+pub const NFS3_FHSIZE: usize = 64;
+
+/// The longest path we accept
+const PATH_MAX: usize = 4096;
+
+/// Procedures
+const NFSPROC3_NULL: u32 = 0;
+const NFSPROC3_GETATTR: u32 = 1;
+const NFSPROC3_SETATTR: u32 = 2;
+const NFSPROC3_LOOKUP: u32 = 3;
+const NFSPROC3_ACCESS: u32 = 4;
+const NFSPROC3_READLINK: u32 = 5;
+const NFSPROC3_READ: u32 = 6;
+const NFSPROC3_WRITE: u32 = 7;
+const NFSPROC3_CREATE: u32 = 8;
+const NFSPROC3_MKDIR: u32 = 9;
+const NFSPROC3_SYMLINK: u32 = 10;
+const NFSPROC3_MKNOD: u32 = 11;
+const NFSPROC3_REMOVE: u32 = 12;
+const NFSPROC3_RMDIR: u32 = 13;
+const NFSPROC3_RENAME: u32 = 14;
+const NFSPROC3_LINK: u32 = 15;
+const NFSPROC3_READDIR: u32 = 16;
+const NFSPROC3_READDIRPLUS: u32 = 17;
+const NFSPROC3_FSSTAT: u32 = 18;
+const NFSPROC3_FSINFO: u32 = 19;
+const NFSPROC3_PATHCONF: u32 = 20;
+const NFSPROC3_COMMIT: u32 = 21;
+
+/// Statuses (`nfsstat3`)
+const NFS3_OK: u32 = 0;
+const NFS3ERR_NOENT: u32 = 2;
+const NFS3ERR_EXIST: u32 = 17;
+const NFS3ERR_NOTDIR: u32 = 20;
+const NFS3ERR_ISDIR: u32 = 21;
+const NFS3ERR_INVAL: u32 = 22;
+const NFS3ERR_NAMETOOLONG: u32 = 63;
+const NFS3ERR_NOTEMPTY: u32 = 66;
+const NFS3ERR_STALE: u32 = 70;
+const NFS3ERR_BADHANDLE: u32 = 10001;
+const NFS3ERR_NOT_SYNC: u32 = 10002;
+const NFS3ERR_BAD_COOKIE: u32 = 10003;
+const NFS3ERR_NOTSUPP: u32 = 10004;
+const NFS3ERR_TOOSMALL: u32 = 10005;
+const NFS3ERR_SERVERFAULT: u32 = 10006;
+const NFS3ERR_JUKEBOX: u32 = 10008;
+
+/// File types (`ftype3`)
+const NF3REG: u32 = 1;
+const NF3DIR: u32 = 2;
+const NF3BLK: u32 = 3;
+const NF3CHR: u32 = 4;
+const NF3LNK: u32 = 5;
+
+/// How to set a time in a `sattr3`
+const DONT_CHANGE: u32 = 0;
+const SET_TO_SERVER_TIME: u32 = 1;
+const SET_TO_CLIENT_TIME: u32 = 2;
+
+/// How to CREATE (`createmode3`)
+const UNCHECKED: u32 = 0;
+const GUARDED: u32 = 1;
+const EXCLUSIVE: u32 = 2;
+
+/// How stable a WRITE is (`stable_how`)
+const UNSTABLE: u32 = 0;
+const DATA_SYNC: u32 = 1;
+const FILE_SYNC: u32 = 2;
+
+/// FSINFO properties: all files have the same pathconf, and SETATTR can set times
+const FSF3_HOMOGENEOUS: u32 = 0x0008;
+const FSF3_CANSETTIME: u32 = 0x0010;
+
+/// The size of the encoded RPC reply header, `nfsstat3`, and `post_op_attr` of a READDIR(PLUS)
+/// reply, before the cookie verifier and entries.
+const READDIR_REPLY_OVERHEAD: usize = 24 + 4 + 4 + FATTR3_LEN;
+
+/// The size of an encoded `fattr3`
+const FATTR3_LEN: usize = 84;
+
+/// The `nfsstat3` for each failure of the handler.
+struct Nfs3;
+
+impl Statuses for Nfs3 {
+    fn nfs(error: ZipErrorType) -> u32 {
+        match error {
+            ZipErrorType::NFSERR_NOENT => NFS3ERR_NOENT,
+            ZipErrorType::NFSERR_EXIST => NFS3ERR_EXIST,
+            ZipErrorType::NFSERR_NOTDIR => NFS3ERR_NOTDIR,
+            ZipErrorType::NFSERR_ISDIR => NFS3ERR_ISDIR,
+            ZipErrorType::NFSERR_NOTEMPTY => NFS3ERR_NOTEMPTY,
+            ZipErrorType::NFSERR_STALE => NFS3ERR_STALE,
+            ZipErrorType::NFSERR_NAMETOOLONG => NFS3ERR_NAMETOOLONG,
+            ZipErrorType::NFSERR_BAD_COOKIE => NFS3ERR_BAD_COOKIE,
+            ZipErrorType::NFSERR_TOOSMALL => NFS3ERR_TOOSMALL,
+            ZipErrorType::NFSERR_VERSION => NFS3ERR_SERVERFAULT,
+            ZipErrorType::NFSERR_GRACE => NFS3ERR_JUKEBOX,
+            ZipErrorType::NFSERR_INVAL => NFS3ERR_INVAL,
+        }
+    }
+
+    fn fault() -> u32 {
+        NFS3ERR_SERVERFAULT
+    }
+}
+
+type ProcResult = Result<XdrWriter, Fail>;
+
+/// Run the given NFSv3 procedure, and write its results to `out`. Procedures that change the FS
+/// are done only once for each `xid`, so that a retransmitted call isn't done again.
+pub fn dispatch<H: ZippynfsSyncHandler>(
+    handler: &H,
+    proc_: u32,
+    xid: i64,
+    args: &mut XdrReader,
+    out: &mut XdrWriter,
+) -> AcceptStat {
+    // On failure, most procedures still send back attributes of the objects involved. We never
+    // have any to send, so we just say so, which takes this many words of zeros: one for each
+    // `post_op_attr` and two for each `wcc_data`.
+    let (result, fail_words) = match proc_ {
+        NFSPROC3_NULL => return AcceptStat::Success,
+        NFSPROC3_GETATTR => (getattr(handler, args), 0),
+        NFSPROC3_SETATTR => (setattr(handler, args), 2),
+        NFSPROC3_LOOKUP => (lookup(handler, args), 1),
+        NFSPROC3_ACCESS => (access(handler, args), 1),
+        NFSPROC3_READLINK => (Err(Fail::Status(NFS3ERR_NOTSUPP)), 1),
+        NFSPROC3_READ => (read(handler, args), 1),
+        NFSPROC3_WRITE => (write(handler, args), 2),
+        NFSPROC3_CREATE => (create(handler, xid, args), 2),
+        NFSPROC3_MKDIR => (mkdir(handler, xid, args), 2),
+        NFSPROC3_SYMLINK | NFSPROC3_MKNOD => (Err(Fail::Status(NFS3ERR_NOTSUPP)), 2),
+        NFSPROC3_REMOVE => (remove(handler, xid, args), 2),
+        NFSPROC3_RMDIR => (rmdir(handler, xid, args), 2),
+        NFSPROC3_RENAME => (rename(handler, xid, args), 4),
+        NFSPROC3_LINK => (Err(Fail::Status(NFS3ERR_NOTSUPP)), 3),
+        NFSPROC3_READDIR => (readdir(handler, args), 1),
+        NFSPROC3_READDIRPLUS => (readdirplus(handler, args), 1),
+        NFSPROC3_FSSTAT => (fsstat(handler, args), 1),
+        NFSPROC3_FSINFO => (fsinfo(handler, args), 1),
+        NFSPROC3_PATHCONF => (pathconf(handler, args), 1),
+        NFSPROC3_COMMIT => (commit(handler, args), 2),
+        _ => return AcceptStat::ProcUnavail,
+    };
+
+    match result {
+        Ok(results) => {
+            out.u32(NFS3_OK);
+            out.fixed(&results.into_inner());
+        }
+        Err(fail) => {
+            match fail.status::<Nfs3>() {
+                Ok(status) => {
+                    debug!("NFSv3: procedure {} failed with {}", proc_, status);
+                    out.u32(status);
+                    for _ in 0..fail_words {
+                        out.u32(0);
+                    }
+                }
+                Err(e) => {
+                    warn!("NFSv3: bad arguments to procedure {}: {}", proc_, e);
+                    return AcceptStat::GarbageArgs;
+                }
+            }
+        }
+    }
+
+    AcceptStat::Success
+}
+
+/// Decode a file handle.
+fn read_fh(args: &mut XdrReader) -> Result<ZipFileHandle, Fail> {
+    let fh = args.opaque(NFS3_FHSIZE)?;
+    if fh.len() != 8 {
+        return Err(Fail::Status(NFS3ERR_BADHANDLE));
+    }
+
+    let fid = fh.iter().fold(0u64, |fid, &b| fid << 8 | b as u64);
+    Ok(ZipFileHandle::new(fid as i64))
+}
+
+/// Encode a file handle.
+pub fn write_fh(out: &mut XdrWriter, fhandle: &ZipFileHandle) {
+    let mut fh = XdrWriter::new();
+    fh.u64(fhandle.fid as u64);
+    out.opaque(&fh.into_inner());
+}
+
+/// Decode a `diropargs3`.
+fn read_dirop(args: &mut XdrReader) -> Result<ZipDirOpArgs, Fail> {
+    let dir = read_fh(args)?;
+    let name = args.string(PATH_MAX)?;
+    if name.len() > NAME_MAX {
+        return Err(Fail::Status(NFS3ERR_NAMETOOLONG));
+    }
+
+    Ok(ZipDirOpArgs::new(dir, name, None))
+}
+
+/// Decode an `nfstime3`.
+fn read_time(args: &mut XdrReader) -> Result<ZipTimeVal, Fail> {
+    let seconds = args.u32()? as i64;
+    let nseconds = args.u32()? as i64;
+    Ok(ZipTimeVal::new(seconds, nseconds / 1000))
+}
+
+/// Encode an `nfstime3`.
+fn write_time(out: &mut XdrWriter, time: &ZipTimeVal) {
+    out.u32(time.seconds as u32);
+    out.u32((time.useconds * 1000) as u32);
+}
+
+/// Decode one of the times of a `sattr3`.
+fn read_set_time(args: &mut XdrReader) -> Result<Option<ZipTimeVal>, Fail> {
+    match args.u32()? {
+        DONT_CHANGE => Ok(None),
+        SET_TO_SERVER_TIME => {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+            Ok(Some(ZipTimeVal::new(
+                now.as_secs() as i64,
+                (now.subsec_nanos() / 1000) as i64,
+            )))
+        }
+        SET_TO_CLIENT_TIME => read_time(args).map(Some),
+        how => Err(Fail::Garbage(format!("bad time_how {}", how))),
+    }
+}
+
+/// Decode a `sattr3`.
+fn read_sattr(args: &mut XdrReader) -> Result<ZipSattr, Fail> {
+    let mode = if args.bool()? {
+        Some(args.u32()? as i16)
+    } else {
+        None
+    };
+    let uid = if args.bool()? {
+        Some(args.u32()? as i64)
+    } else {
+        None
+    };
+    let gid = if args.bool()? {
+        Some(args.u32()? as i64)
+    } else {
+        None
+    };
+    let size = if args.bool()? {
+        Some(args.u64()? as i64)
+    } else {
+        None
+    };
+    let atime = read_set_time(args)?;
+    let mtime = read_set_time(args)?;
+
+    Ok(ZipSattr::new(mode, uid, gid, size, atime, mtime))
+}
+
+/// Encode a `fattr3`.
+fn write_fattr(out: &mut XdrWriter, attr: &ZipFattr) {
+    out.u32(match attr.type_ {
+        ZipFtype::NFDIR => NF3DIR,
+        ZipFtype::NFBLK => NF3BLK,
+        ZipFtype::NFCHR => NF3CHR,
+        ZipFtype::NFLNK => NF3LNK,
+        ZipFtype::NFREG | ZipFtype::NFNON => NF3REG,
+    });
+    out.u32(attr.mode as u16 as u32 & 0o7777);
+    out.u32(attr.nlink as u32);
+    out.u32(attr.uid as u32);
+    out.u32(attr.gid as u32);
+    out.u64(attr.size as u64);
+    out.u64((attr.blocks * attr.blocksize) as u64);
+
+    // rdev
+    out.u32((attr.rdev >> 32) as u32);
+    out.u32(attr.rdev as u32);
+
+    out.u64(attr.fsid as u64);
+    out.u64(attr.fid as u64);
+    write_time(out, &attr.atime);
+    write_time(out, &attr.mtime);
+    write_time(out, &attr.ctime);
+}
+
+/// Encode a `post_op_attr` with the given attributes.
+fn write_post_op_attr(out: &mut XdrWriter, attr: &ZipFattr) {
+    out.bool(true);
+    write_fattr(out, attr);
+}
+
+/// Encode a `wcc_data` with the given attributes from after the operation. We never send the
+/// attributes from before, since we can't get them atomically with the operation anyway.
+fn write_wcc_data(out: &mut XdrWriter, after: Option<&ZipFattr>) {
+    out.bool(false);
+    match after {
+        Some(attr) => write_post_op_attr(out, attr),
+        None => out.bool(false),
+    }
+}
+
+/// Get the attributes of a file, if it is still there. Used to fill in the optional attributes
+/// of a result.
+fn try_getattr<H: ZippynfsSyncHandler>(handler: &H, fhandle: &ZipFileHandle) -> Option<ZipFattr> {
+    handler
+        .handle_getattr(ZipFileHandle::new(fhandle.fid))
+        .ok()
+        .map(|attrstat| attrstat.attributes)
+}
+
+fn getattr<H: ZippynfsSyncHandler>(handler: &H, args: &mut XdrReader) -> ProcResult {
+    let fhandle = read_fh(args)?;
+
+    let attrstat = handler.handle_getattr(fhandle)?;
+
+    let mut out = XdrWriter::new();
+    write_fattr(&mut out, &attrstat.attributes);
+    Ok(out)
+}
+
+fn setattr<H: ZippynfsSyncHandler>(handler: &H, args: &mut XdrReader) -> ProcResult {
+    let fhandle = read_fh(args)?;
+    let mut sattr = read_sattr(args)?;
+
+    // The guard: only do the SETATTR if the ctime is the one the client expects
+    let guard = if args.bool()? {
+        Some(read_time(args)?)
+    } else {
+        None
+    };
+
+    if let Some(guard) = guard {
+        let attrstat = handler.handle_getattr(ZipFileHandle::new(fhandle.fid))?;
+        let ctime = attrstat.attributes.ctime;
+        if ctime.seconds != guard.seconds || ctime.useconds != guard.useconds {
+            return Err(Fail::Status(NFS3ERR_NOT_SYNC));
+        }
+    }
+
+    // The handler can't set the mtime without setting the atime too, so keep the current atime.
+    if sattr.mtime.is_some() && sattr.atime.is_none() {
+        let attrstat = handler.handle_getattr(ZipFileHandle::new(fhandle.fid))?;
+        sattr.atime = Some(attrstat.attributes.atime);
+    }
+
+    let attrstat = handler.handle_setattr(ZipSattrArgs::new(fhandle, sattr, None))?;
+
+    let mut out = XdrWriter::new();
+    write_wcc_data(&mut out, Some(&attrstat.attributes));
+    Ok(out)
+}
+
+fn lookup<H: ZippynfsSyncHandler>(handler: &H, args: &mut XdrReader) -> ProcResult {
+    let dirop = read_dirop(args)?;
+    let dir = ZipFileHandle::new(dirop.dir.fid);
+
+    let res = handler.handle_lookup(dirop)?;
+
+    let mut out = XdrWriter::new();
+    write_fh(&mut out, &res.file);
+    write_post_op_attr(&mut out, &res.attributes);
+    match try_getattr(handler, &dir) {
+        Some(attr) => write_post_op_attr(&mut out, &attr),
+        None => out.bool(false),
+    }
+    Ok(out)
+}
+
+fn access<H: ZippynfsSyncHandler>(handler: &H, args: &mut XdrReader) -> ProcResult {
+    let fhandle = read_fh(args)?;
+    let access = args.u32()?;
+
+    // There are no permissions, so everybody can do everything
+    let attrstat = handler.handle_getattr(fhandle)?;
+
+    let mut out = XdrWriter::new();
+    write_post_op_attr(&mut out, &attrstat.attributes);
+    out.u32(access);
+    Ok(out)
+}
+
+fn read<H: ZippynfsSyncHandler>(handler: &H, args: &mut XdrReader) -> ProcResult {
+    let fhandle = read_fh(args)?;
+    let offset = args.u64()? as i64;
+    let count = min(args.u32()? as usize, MAX_IO_LEN) as i64;
+
+    let res = handler.handle_read(ZipReadArgs::new(fhandle, offset, count, None))?;
+    let eof = offset + res.data.len() as i64 >= res.attributes.size;
+
+    let mut out = XdrWriter::new();
+    write_post_op_attr(&mut out, &res.attributes);
+    out.u32(res.data.len() as u32);
+    out.bool(eof);
+    out.opaque(&res.data);
+    Ok(out)
+}
+
+fn write<H: ZippynfsSyncHandler>(handler: &H, args: &mut XdrReader) -> ProcResult {
+    let fhandle = read_fh(args)?;
+    let offset = args.u64()? as i64;
+    let count = args.u32()? as usize;
+    let stable = match args.u32()? {
+        UNSTABLE => ZipWriteStable::UNSTABLE,
+        DATA_SYNC => ZipWriteStable::DATA_SYNC,
+        FILE_SYNC => ZipWriteStable::FILE_SYNC,
+        how => return Err(Fail::Garbage(format!("bad stable_how {}", how))),
+    };
+    let data = args.opaque(MAX_IO_LEN)?;
+
+    // `count` is supposed to match the data, but if it doesn't, write no more than we have
+    let data = data[..min(count, data.len())].to_vec();
+    let fid = fhandle.fid;
+
+    let res = handler.handle_write(ZipWriteArgs::new(
+        fhandle,
+        offset,
+        data.len() as i64,
+        data,
+        stable,
+        None,
+    ))?;
+
+    let mut out = XdrWriter::new();
+    write_wcc_data(&mut out, try_getattr(handler, &ZipFileHandle::new(fid)).as_ref());
+    out.u32(res.count as u32);
+    out.u32(match res.committed {
+        ZipWriteStable::UNSTABLE => UNSTABLE,
+        ZipWriteStable::DATA_SYNC => DATA_SYNC,
+        ZipWriteStable::FILE_SYNC => FILE_SYNC,
+    });
+    out.u64(res.verf as u64);
+    Ok(out)
+}
+
+/// Encode the results of a CREATE or MKDIR.
+fn write_diropres<H: ZippynfsSyncHandler>(handler: &H, dir: i64, res: &ZipDirOpRes) -> XdrWriter {
+    let mut out = XdrWriter::new();
+    out.bool(true);
+    write_fh(&mut out, &res.file);
+    write_post_op_attr(&mut out, &res.attributes);
+    write_wcc_data(&mut out, try_getattr(handler, &ZipFileHandle::new(dir)).as_ref());
+    out
+}
+
+fn create<H: ZippynfsSyncHandler>(handler: &H, xid: i64, args: &mut XdrReader) -> ProcResult {
+    let mut dirop = read_dirop(args)?;
+    dirop.xid = Some(xid);
+    let dir = dirop.dir.fid;
+    let how = args.u32()?;
+
+    // We have nowhere to keep the verifier of an EXCLUSIVE create, so it is treated just like a
+    // GUARDED one. The only difference is that a retransmitted EXCLUSIVE create fails with
+    // NFS3ERR_EXIST rather than succeeding.
+    let sattr = match how {
+        UNCHECKED | GUARDED => read_sattr(args)?,
+        EXCLUSIVE => {
+            let _verf = args.fixed(8)?;
+            ZipSattr::new(None, None, None, None, None, None)
+        }
+        how => return Err(Fail::Garbage(format!("bad createmode3 {}", how))),
+    };
+
+    let res = match handler.handle_create(ZipCreateArgs::new(dirop.clone(), sattr.clone())) {
+        Ok(res) => res,
+
+        // An UNCHECKED create of an existing file just sets its attributes
+        Err(e) => {
+            match Fail::from(e) {
+                Fail::Nfs(ZipErrorType::NFSERR_EXIST) if how == UNCHECKED => {
+                    let res = handler.handle_lookup(dirop)?;
+                    if res.attributes.type_ == ZipFtype::NFDIR {
+                        return Err(Fail::Status(NFS3ERR_EXIST));
+                    }
+
+                    let attrstat = handler.handle_setattr(
+                        ZipSattrArgs::new(res.file.clone(), sattr, None),
+                    )?;
+                    ZipDirOpRes::new(res.file, attrstat.attributes)
+                }
+                fail => return Err(fail),
+            }
+        }
+    };
+
+    Ok(write_diropres(handler, dir, &res))
+}
+
+fn mkdir<H: ZippynfsSyncHandler>(handler: &H, xid: i64, args: &mut XdrReader) -> ProcResult {
+    let mut dirop = read_dirop(args)?;
+    dirop.xid = Some(xid);
+    let dir = dirop.dir.fid;
+    let sattr = read_sattr(args)?;
+
+    let res = handler.handle_mkdir(ZipCreateArgs::new(dirop, sattr))?;
+
+    Ok(write_diropres(handler, dir, &res))
+}
+
+fn remove<H: ZippynfsSyncHandler>(handler: &H, xid: i64, args: &mut XdrReader) -> ProcResult {
+    let mut dirop = read_dirop(args)?;
+    dirop.xid = Some(xid);
+    let dir = ZipFileHandle::new(dirop.dir.fid);
+
+    handler.handle_remove(dirop)?;
+
+    let mut out = XdrWriter::new();
+    write_wcc_data(&mut out, try_getattr(handler, &dir).as_ref());
+    Ok(out)
+}
+
+fn rmdir<H: ZippynfsSyncHandler>(handler: &H, xid: i64, args: &mut XdrReader) -> ProcResult {
+    let mut dirop = read_dirop(args)?;
+    dirop.xid = Some(xid);
+    let dir = ZipFileHandle::new(dirop.dir.fid);
+
+    handler.handle_rmdir(dirop)?;
+
+    let mut out = XdrWriter::new();
+    write_wcc_data(&mut out, try_getattr(handler, &dir).as_ref());
+    Ok(out)
+}
+
+fn rename<H: ZippynfsSyncHandler>(handler: &H, xid: i64, args: &mut XdrReader) -> ProcResult {
+    let mut from = read_dirop(args)?;
+    from.xid = Some(xid);
+    let to = read_dirop(args)?;
+    let from_dir = ZipFileHandle::new(from.dir.fid);
+    let to_dir = ZipFileHandle::new(to.dir.fid);
+
+    handler.handle_rename(ZipRenameArgs::new(from, to, None))?;
+
+    let mut out = XdrWriter::new();
+    write_wcc_data(&mut out, try_getattr(handler, &from_dir).as_ref());
+    write_wcc_data(&mut out, try_getattr(handler, &to_dir).as_ref());
+    Ok(out)
+}
+
+/// Decode the start of the arguments of a READDIR or READDIRPLUS.
+fn read_readdir_args(args: &mut XdrReader) -> Result<(ZipFileHandle, i64, i64), Fail> {
+    let dir = read_fh(args)?;
+    let cookie = args.u64()? as i64;
+    let cookieverf = args.u64()? as i64;
+    Ok((dir, cookie, cookieverf))
+}
+
+/// The size of an encoded `entry3` (or the common part of an `entryplus3`) with the given name,
+/// including the `value_follows` before it.
+fn entry_len(name: &str) -> usize {
+    4 + 8 + 4 + pad(name.len()) + 8
+}
+
+fn readdir<H: ZippynfsSyncHandler>(handler: &H, args: &mut XdrReader) -> ProcResult {
+    let (dir, cookie, cookieverf) = read_readdir_args(args)?;
+    let count = args.u32()? as usize;
+
+    let res = handler.handle_readdir(ZipReadDirArgs::new(
+        ZipFileHandle::new(dir.fid),
+        cookie,
+        cookieverf,
+        count as i64,
+    ))?;
+
+    // The Thrift encoding is smaller than XDR, so we may have to leave some of the entries for
+    // the next call.
+    let mut entries = XdrWriter::new();
+    let mut len = READDIR_REPLY_OVERHEAD + 8 + 4 + 4;
+    let mut eof = res.eof;
+    for entry in &res.entries {
+        len += entry_len(&entry.fname);
+        if len > count {
+            eof = false;
+            break;
+        }
+
+        entries.bool(true);
+        entries.u64(entry.fid as u64);
+        entries.string(&entry.fname);
+        entries.u64(entry.fid as u64);
+    }
+
+    if entries.is_empty() && !eof {
+        return Err(Fail::Status(NFS3ERR_TOOSMALL));
+    }
+
+    let mut out = XdrWriter::new();
+    match try_getattr(handler, &dir) {
+        Some(attr) => write_post_op_attr(&mut out, &attr),
+        None => out.bool(false),
+    }
+    out.u64(res.cookieverf as u64);
+    out.fixed(&entries.into_inner());
+    out.bool(false);
+    out.bool(eof);
+    Ok(out)
+}
+
+fn readdirplus<H: ZippynfsSyncHandler>(handler: &H, args: &mut XdrReader) -> ProcResult {
+    let (dir, cookie, cookieverf) = read_readdir_args(args)?;
+    let _dircount = args.u32()?;
+    let maxcount = args.u32()? as usize;
+
+    let res = handler.handle_readdirplus(ZipReadDirArgs::new(
+        ZipFileHandle::new(dir.fid),
+        cookie,
+        cookieverf,
+        maxcount as i64,
+    ))?;
+
+    // The Thrift encoding is smaller than XDR, so we may have to leave some of the entries for
+    // the next call. Besides the name, each entry has attributes and an 8-byte handle.
+    let mut entries = XdrWriter::new();
+    let mut len = READDIR_REPLY_OVERHEAD + 8 + 4 + 4;
+    let mut eof = res.eof;
+    for entry in &res.entries {
+        len += entry_len(&entry.fname) + 4 + FATTR3_LEN + 4 + 4 + 8;
+        if len > maxcount {
+            eof = false;
+            break;
+        }
+
+        entries.bool(true);
+        entries.u64(entry.fid as u64);
+        entries.string(&entry.fname);
+        entries.u64(entry.fid as u64);
+        write_post_op_attr(&mut entries, &entry.attributes);
+        entries.bool(true);
+        write_fh(&mut entries, &entry.file);
+    }
+
+    if entries.is_empty() && !eof {
+        return Err(Fail::Status(NFS3ERR_TOOSMALL));
+    }
+
+    let mut out = XdrWriter::new();
+    match try_getattr(handler, &dir) {
+        Some(attr) => write_post_op_attr(&mut out, &attr),
+        None => out.bool(false),
+    }
+    out.u64(res.cookieverf as u64);
+    out.fixed(&entries.into_inner());
+    out.bool(false);
+    out.bool(eof);
+    Ok(out)
+}
+
+fn fsstat<H: ZippynfsSyncHandler>(handler: &H, args: &mut XdrReader) -> ProcResult {
+    let fhandle = read_fh(args)?;
+    let attr = try_getattr(handler, &fhandle);
+
+    let res = handler.handle_statfs(fhandle)?;
+
+    let mut out = XdrWriter::new();
+    match attr {
+        Some(attr) => write_post_op_attr(&mut out, &attr),
+        None => out.bool(false),
+    }
+    out.u64((res.blocks * res.bsize) as u64);
+    out.u64((res.bfree * res.bsize) as u64);
+    out.u64((res.bavail * res.bsize) as u64);
+
+    // We don't run out of files before we run out of space
+    out.u64(res.blocks as u64);
+    out.u64(res.bfree as u64);
+    out.u64(res.bavail as u64);
+
+    // invarsec: these can change at any time
+    out.u32(0);
+    Ok(out)
+}
+
+fn fsinfo<H: ZippynfsSyncHandler>(handler: &H, args: &mut XdrReader) -> ProcResult {
+    let fhandle = read_fh(args)?;
+    let attr = try_getattr(handler, &fhandle);
+
+    let res = handler.handle_fsinfo(fhandle)?;
+
+    let mut out = XdrWriter::new();
+    match attr {
+        Some(attr) => write_post_op_attr(&mut out, &attr),
+        None => out.bool(false),
+    }
+
+    // rtmax, rtpref, rtmult
+    out.u32(res.rtmax as u32);
+    out.u32(res.rtmax as u32);
+    out.u32(1);
+
+    // wtmax, wtpref, wtmult
+    out.u32(res.wtmax as u32);
+    out.u32(res.wtmax as u32);
+    out.u32(1);
+
+    out.u32(res.dtpref as u32);
+
+    // maxfilesize
+    out.u64(i64::max_value() as u64);
+
+    // time_delta: we keep times to the microsecond
+    out.u32(0);
+    out.u32(1000);
+
+    out.u32(FSF3_HOMOGENEOUS | FSF3_CANSETTIME);
+    Ok(out)
+}
+
+fn pathconf<H: ZippynfsSyncHandler>(handler: &H, args: &mut XdrReader) -> ProcResult {
+    let fhandle = read_fh(args)?;
+
+    let attrstat = handler.handle_getattr(fhandle)?;
+
+    let mut out = XdrWriter::new();
+    write_post_op_attr(&mut out, &attrstat.attributes);
+    out.u32(1); // linkmax
+    out.u32(NAME_MAX as u32);
+    out.bool(true); // no_trunc
+    out.bool(true); // chown_restricted
+    out.bool(false); // case_insensitive
+    out.bool(true); // case_preserving
+    Ok(out)
+}
+
+fn commit<H: ZippynfsSyncHandler>(handler: &H, args: &mut XdrReader) -> ProcResult {
+    let fhandle = read_fh(args)?;
+    let offset = args.u64()? as i64;
+    let count = args.u32()? as i64;
+    let fid = fhandle.fid;
+
+    let res = handler.handle_commit(ZipCommitArgs::new(fhandle, count, offset, None))?;
+
+    let mut out = XdrWriter::new();
+    write_wcc_data(&mut out, try_getattr(handler, &ZipFileHandle::new(fid)).as_ref());
+    out.u64(res.verf as u64);
+    Ok(out)
+}
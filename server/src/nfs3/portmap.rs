@@ -0,0 +1,66 @@
+//! Registering with the local portmapper (RFC 1833, version 2).
+//!
+//! Clients normally ask the portmapper on the server host which ports NFS and MOUNT are on. This
+//! is optional: clients can also be told the ports directly (e.g. with `-o port=,mountport=`),
+//! which is handy when there is no portmapper, or we are not allowed to register with it.
+
+use std::net::TcpStream;
+
+use super::rpc;
+use super::xdr::{XdrReader, XdrWriter};
+
+/// Where the portmapper lives
+const PORTMAP_ADDR: &'static str = "127.0.0.1:111";
+
+/// The portmapper program number and version
+const PMAP_PROGRAM: u32 = 100000;
+const PMAP_V2: u32 = 2;
+
+/// Procedures
+const PMAPPROC_SET: u32 = 1;
+const PMAPPROC_UNSET: u32 = 2;
+
+/// The protocol number of TCP
+const IPPROTO_TCP: u32 = 6;
+
+/// Make a call to the portmapper about the given program, and return its answer.
+fn call(proc_: u32, xid: u32, prog: u32, vers: u32, port: u16) -> Result<bool, String> {
+    let mut stream = TcpStream::connect(PORTMAP_ADDR).map_err(|e| {
+        format!("Unable to reach the portmapper at {}: {}", PORTMAP_ADDR, e)
+    })?;
+
+    let mut mapping = XdrWriter::new();
+    mapping.u32(prog);
+    mapping.u32(vers);
+    mapping.u32(IPPROTO_TCP);
+    mapping.u32(port as u32);
+
+    let msg = rpc::call(xid, PMAP_PROGRAM, PMAP_V2, proc_, &mapping.into_inner());
+    rpc::write_record(&mut stream, &msg).map_err(|e| format!("{}", e))?;
+
+    let reply = rpc::read_record(&mut stream, 1 << 10)
+        .map_err(|e| format!("{}", e))?
+        .ok_or_else(|| "The portmapper hung up".to_owned())?;
+
+    let mut results = XdrReader::new(&reply);
+    rpc::read_reply(&mut results, xid)?;
+    results.bool()
+}
+
+/// Register the given program with the portmapper, replacing any earlier registration (e.g. from
+/// an earlier run of the server).
+pub fn register(prog: u32, vers: u32, port: u16) -> Result<(), String> {
+    call(PMAPPROC_UNSET, prog, prog, vers, port)?;
+
+    if call(PMAPPROC_SET, prog + 1, prog, vers, port)? {
+        info!("Registered program {} v{} on port {}", prog, vers, port);
+        Ok(())
+    } else {
+        Err(format!(
+            "The portmapper refused to register program {} v{} on port {}",
+            prog,
+            vers,
+            port
+        ))
+    }
+}
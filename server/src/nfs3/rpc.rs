@@ -0,0 +1,245 @@
+//! ONC RPC version 2 (RFC 5531) over TCP.
+//!
+//! On TCP, each message is sent as a record made of one or more fragments. Each fragment starts
+//! with a 4-byte header whose top bit says whether it is the last fragment of the record and
+//! whose other 31 bits are the length of the fragment.
+
+use std::io::{self, Read, Write};
+
+use super::xdr::{XdrReader, XdrWriter};
+
+/// The version of RPC we speak
+const RPC_VERSION: u32 = 2;
+
+/// Message types
+const CALL: u32 = 0;
+const REPLY: u32 = 1;
+
+/// Reply status
+const MSG_ACCEPTED: u32 = 0;
+const MSG_DENIED: u32 = 1;
+
+/// Why a call was denied
+const RPC_MISMATCH: u32 = 0;
+
+/// The auth flavor with no credentials, which we use for all of our verifiers
+pub const AUTH_NONE: u32 = 0;
+
+/// The auth flavor with a Unix uid and gids
+pub const AUTH_UNIX: u32 = 1;
+
+/// The most an auth body can be, according to the RFC
+const MAX_AUTH_LEN: usize = 400;
+
+/// The top bit of a fragment header
+const LAST_FRAGMENT: u32 = 1 << 31;
+
+/// The status of a call that we accepted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcceptStat {
+    /// The call went through; the results follow
+    Success,
+
+    /// We don't serve the requested program
+    ProgUnavail,
+
+    /// We serve the program, but only versions `low` to `high`
+    ProgMismatch(u32, u32),
+
+    /// The program has no such procedure
+    ProcUnavail,
+
+    /// We could not decode the arguments
+    GarbageArgs,
+}
+
+/// The header of an RPC call.
+#[derive(Debug)]
+pub struct CallHeader {
+    pub xid: u32,
+    pub rpcvers: u32,
+    pub prog: u32,
+    pub vers: u32,
+    pub proc_: u32,
+}
+
+/// Read a whole record from the given stream. Returns `None` if the stream was closed cleanly
+/// between records.
+///
+/// Records longer than `max_len` are rejected, since they could only be a mistake or an attack.
+pub fn read_record<R: Read>(stream: &mut R, max_len: usize) -> io::Result<Option<Vec<u8>>> {
+    let mut record = Vec::new();
+
+    loop {
+        let mut header = [0u8; 4];
+
+        // A closed connection is fine, as long as it is closed between records
+        match stream.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof && record.is_empty() => {
+                return Ok(None);
+            }
+            Err(e) => return Err(e),
+        }
+
+        let header = (header[0] as u32) << 24 | (header[1] as u32) << 16 |
+            (header[2] as u32) << 8 | (header[3] as u32);
+        let len = (header & !LAST_FRAGMENT) as usize;
+
+        if record.len() + len > max_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("RPC record is over {} bytes", max_len),
+            ));
+        }
+
+        let start = record.len();
+        record.resize(start + len, 0);
+        stream.read_exact(&mut record[start..])?;
+
+        if header & LAST_FRAGMENT != 0 {
+            return Ok(Some(record));
+        }
+    }
+}
+
+/// Write the given message as a record made of a single fragment.
+pub fn write_record<W: Write>(stream: &mut W, msg: &[u8]) -> io::Result<()> {
+    let header = msg.len() as u32 | LAST_FRAGMENT;
+    let mut record = Vec::with_capacity(msg.len() + 4);
+    record.extend_from_slice(
+        &[
+            (header >> 24) as u8,
+            (header >> 16) as u8,
+            (header >> 8) as u8,
+            header as u8,
+        ],
+    );
+    record.extend_from_slice(msg);
+    stream.write_all(&record)?;
+    stream.flush()
+}
+
+/// Parse the header of a call, leaving `args` at the start of the procedure's arguments.
+///
+/// We don't check credentials at all (just like the Thrift frontend), so they are skipped.
+pub fn read_call(args: &mut XdrReader) -> Result<CallHeader, String> {
+    let xid = args.u32()?;
+
+    let msg_type = args.u32()?;
+    if msg_type != CALL {
+        return Err(format!("RPC: expected a call, got message type {}", msg_type));
+    }
+
+    let rpcvers = args.u32()?;
+    let prog = args.u32()?;
+    let vers = args.u32()?;
+    let proc_ = args.u32()?;
+
+    // Credentials and verifier
+    for _ in 0..2 {
+        let _flavor = args.u32()?;
+        let _body = args.opaque(MAX_AUTH_LEN)?;
+    }
+
+    Ok(CallHeader {
+        xid,
+        rpcvers,
+        prog,
+        vers,
+        proc_,
+    })
+}
+
+/// Returns true if we speak the RPC version of the given call.
+pub fn version_ok(call: &CallHeader) -> bool {
+    call.rpcvers == RPC_VERSION
+}
+
+/// Build a reply to a call we accepted. `results` are only sent on success.
+pub fn accepted_reply(xid: u32, stat: AcceptStat, results: &[u8]) -> Vec<u8> {
+    let mut out = XdrWriter::new();
+    out.u32(xid);
+    out.u32(REPLY);
+    out.u32(MSG_ACCEPTED);
+
+    // Verifier
+    out.u32(AUTH_NONE);
+    out.opaque(&[]);
+
+    match stat {
+        AcceptStat::Success => out.u32(0),
+        AcceptStat::ProgUnavail => out.u32(1),
+        AcceptStat::ProgMismatch(low, high) => {
+            out.u32(2);
+            out.u32(low);
+            out.u32(high);
+        }
+        AcceptStat::ProcUnavail => out.u32(3),
+        AcceptStat::GarbageArgs => out.u32(4),
+    }
+
+    let mut out = out.into_inner();
+    if stat == AcceptStat::Success {
+        out.extend_from_slice(results);
+    }
+    out
+}
+
+/// Build a reply to a call using an RPC version we don't speak.
+pub fn rpc_mismatch_reply(xid: u32) -> Vec<u8> {
+    let mut out = XdrWriter::new();
+    out.u32(xid);
+    out.u32(REPLY);
+    out.u32(MSG_DENIED);
+    out.u32(RPC_MISMATCH);
+    out.u32(RPC_VERSION);
+    out.u32(RPC_VERSION);
+    out.into_inner()
+}
+
+/// Build a call, with no credentials. We only make calls to register with the portmapper.
+pub fn call(xid: u32, prog: u32, vers: u32, proc_: u32, args: &[u8]) -> Vec<u8> {
+    let mut out = XdrWriter::new();
+    out.u32(xid);
+    out.u32(CALL);
+    out.u32(RPC_VERSION);
+    out.u32(prog);
+    out.u32(vers);
+    out.u32(proc_);
+
+    // Credentials and verifier
+    for _ in 0..2 {
+        out.u32(AUTH_NONE);
+        out.opaque(&[]);
+    }
+
+    let mut out = out.into_inner();
+    out.extend_from_slice(args);
+    out
+}
+
+/// Parse a reply to one of our calls, leaving `results` at the start of the results. Fails
+/// unless the call was accepted and successful.
+pub fn read_reply(results: &mut XdrReader, xid: u32) -> Result<(), String> {
+    if results.u32()? != xid {
+        return Err("RPC: reply to some other call".into());
+    }
+
+    if results.u32()? != REPLY {
+        return Err("RPC: expected a reply".into());
+    }
+
+    if results.u32()? != MSG_ACCEPTED {
+        return Err("RPC: call denied".into());
+    }
+
+    // Verifier
+    let _flavor = results.u32()?;
+    let _body = results.opaque(MAX_AUTH_LEN)?;
+
+    match results.u32()? {
+        0 => Ok(()),
+        stat => Err(format!("RPC: call not successful ({})", stat)),
+    }
+}
@@ -0,0 +1,258 @@
+//! Unit tests for the NFSv3 frontend
+
+use std::io::Cursor;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+use handler::ZippynfsServer;
+use testutil::run_with_clone_fs;
+
+use super::handle_call;
+use super::rpc::{self, read_record, write_record};
+use super::xdr::{XdrReader, XdrWriter};
+
+const NFS: u32 = 100003;
+const MOUNT: u32 = 100005;
+
+/// The RPC xid of the last call, so that every call gets its own
+static LAST_XID: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Make a call, and return its results. Panics unless the call was accepted and successful.
+fn call<P: AsRef<Path>>(
+    server: &ZippynfsServer<P>,
+    prog: u32,
+    proc_: u32,
+    args: XdrWriter,
+) -> Vec<u8> {
+    let xid = LAST_XID.fetch_add(1, Ordering::SeqCst) as u32 + 1;
+    call_xid(server, xid, prog, proc_, args)
+}
+
+/// Make a call with the given RPC xid, e.g. to retransmit one, and return its results.
+fn call_xid<P: AsRef<Path>>(
+    server: &ZippynfsServer<P>,
+    xid: u32,
+    prog: u32,
+    proc_: u32,
+    args: XdrWriter,
+) -> Vec<u8> {
+    let msg = rpc::call(xid, prog, 3, proc_, &args.into_inner());
+    let reply = handle_call(server, None, &msg).unwrap();
+
+    let mut results = XdrReader::new(&reply);
+    rpc::read_reply(&mut results, xid).unwrap();
+
+    let mut rest = Vec::new();
+    while let Ok(word) = results.fixed(4) {
+        rest.extend_from_slice(word);
+    }
+    rest
+}
+
+fn fh(fid: u64) -> Vec<u8> {
+    let mut fh = XdrWriter::new();
+    fh.u64(fid);
+    fh.into_inner()
+}
+
+#[test]
+fn test_xdr_round_trip() {
+    let mut out = XdrWriter::new();
+    out.u32(0xdeadbeef);
+    out.u64(1 << 40 | 7);
+    out.bool(true);
+    out.opaque(&[1, 2, 3, 4, 5]);
+    out.string("zee.txt");
+    out.fixed(&[9]);
+
+    let buf = out.into_inner();
+    assert_eq!(buf.len(), 4 + 8 + 4 + (4 + 8) + (4 + 8) + 4);
+
+    let mut args = XdrReader::new(&buf);
+    assert_eq!(args.u32().unwrap(), 0xdeadbeef);
+    assert_eq!(args.u64().unwrap(), 1 << 40 | 7);
+    assert_eq!(args.bool().unwrap(), true);
+    assert_eq!(args.opaque(8).unwrap(), &[1, 2, 3, 4, 5]);
+    assert_eq!(args.string(8).unwrap(), "zee.txt");
+    assert_eq!(args.fixed(1).unwrap(), &[9]);
+
+    // Nothing left
+    assert!(args.u32().is_err());
+
+    // Too long
+    let mut args = XdrReader::new(&buf[16..]);
+    assert!(args.opaque(4).is_err());
+}
+
+#[test]
+fn test_record_fragments() {
+    // Two fragments, the second one last
+    let mut stream = vec![0, 0, 0, 2, b'a', b'b', 0x80, 0, 0, 1, b'c'];
+
+    // And a whole record
+    write_record(&mut stream, b"xyz").unwrap();
+
+    let mut stream = Cursor::new(stream);
+    assert_eq!(read_record(&mut stream, 16).unwrap().unwrap(), b"abc");
+    assert_eq!(read_record(&mut stream, 16).unwrap().unwrap(), b"xyz");
+    assert!(read_record(&mut stream, 16).unwrap().is_none());
+
+    // Too long
+    let mut stream = Cursor::new(vec![0x80, 0, 0, 17]);
+    assert!(read_record(&mut stream, 16).is_err());
+}
+
+#[test]
+fn test_rpc_mismatches() {
+    run_with_clone_fs("test_files/test1", |fspath| {
+        let server = ZippynfsServer::new(fspath);
+
+        // Unknown program: PROG_UNAVAIL
+        let reply = handle_call(&server, None, &rpc::call(1, 123456, 1, 0, &[])).unwrap();
+        let mut results = XdrReader::new(&reply);
+        assert!(rpc::read_reply(&mut results, 1).is_err());
+
+        // NFSv2: PROG_MISMATCH, 3 to 3
+        let reply = handle_call(&server, None, &rpc::call(2, NFS, 2, 0, &[])).unwrap();
+        assert_eq!(&reply[reply.len() - 12..], &[0, 0, 0, 2, 0, 0, 0, 3, 0, 0, 0, 3]);
+
+        // Unknown procedure: PROC_UNAVAIL
+        let reply = handle_call(&server, None, &rpc::call(3, NFS, 3, 99, &[])).unwrap();
+        assert_eq!(&reply[reply.len() - 4..], &[0, 0, 0, 3]);
+
+        // Garbage
+        assert!(handle_call(&server, None, &[1, 2, 3]).is_none());
+    })
+}
+
+#[test]
+fn test_mount_and_browse() {
+    run_with_clone_fs("test_files/test1", |fspath| {
+        let server = ZippynfsServer::new(fspath);
+
+        // MNT "/" gives the root, and anything else fails with MNT3ERR_NOENT
+        let mut args = XdrWriter::new();
+        args.string("/");
+        let results = call(&server, MOUNT, 1, args);
+        let mut results = XdrReader::new(&results);
+        assert_eq!(results.u32().unwrap(), 0);
+        assert_eq!(results.opaque(64).unwrap(), &fh(1)[..]);
+
+        let mut args = XdrWriter::new();
+        args.string("/foo");
+        let results = call(&server, MOUNT, 1, args);
+        assert_eq!(XdrReader::new(&results).u32().unwrap(), 2);
+
+        // GETATTR of the root: NF3DIR with fileid 1
+        let mut args = XdrWriter::new();
+        args.opaque(&fh(1));
+        let results = call(&server, NFS, 1, args);
+        let mut results = XdrReader::new(&results);
+        assert_eq!(results.u32().unwrap(), 0);
+        assert_eq!(results.u32().unwrap(), 2);
+        let _ = results.fixed(4 * 4 + 8 * 4).unwrap();
+        assert_eq!(results.u64().unwrap(), 1);
+
+        // A bad handle
+        let mut args = XdrWriter::new();
+        args.opaque(&[1, 2, 3]);
+        let results = call(&server, NFS, 1, args);
+        assert_eq!(XdrReader::new(&results).u32().unwrap(), 10001);
+
+        // LOOKUP foo, then bar
+        let mut args = XdrWriter::new();
+        args.opaque(&fh(1));
+        args.string("foo");
+        let results = call(&server, NFS, 3, args);
+        let mut results = XdrReader::new(&results);
+        assert_eq!(results.u32().unwrap(), 0);
+        assert_eq!(results.opaque(64).unwrap(), &fh(8)[..]);
+
+        let mut args = XdrWriter::new();
+        args.opaque(&fh(8));
+        args.string("bar");
+        let results = call(&server, NFS, 3, args);
+        let mut results = XdrReader::new(&results);
+        assert_eq!(results.u32().unwrap(), 0);
+        assert_eq!(results.opaque(64).unwrap(), &fh(2)[..]);
+
+        // LOOKUP of something that isn't there
+        let mut args = XdrWriter::new();
+        args.opaque(&fh(1));
+        args.string("nope");
+        let results = call(&server, NFS, 3, args);
+        assert_eq!(XdrReader::new(&results).u32().unwrap(), 2);
+
+        // READ all of zee.txt
+        let mut args = XdrWriter::new();
+        args.opaque(&fh(3));
+        args.u64(0);
+        args.u32(1 << 10);
+        let results = call(&server, NFS, 6, args);
+        let mut results = XdrReader::new(&results);
+        assert_eq!(results.u32().unwrap(), 0);
+        assert_eq!(results.bool().unwrap(), true);
+        let _ = results.fixed(84).unwrap();
+        assert_eq!(results.u32().unwrap(), 27);
+        assert_eq!(results.bool().unwrap(), true);
+        assert_eq!(results.opaque(1 << 10).unwrap().len(), 27);
+
+        // READDIR the root
+        let mut args = XdrWriter::new();
+        args.opaque(&fh(1));
+        args.u64(0);
+        args.u64(0);
+        args.u32(1 << 12);
+        let results = call(&server, NFS, 16, args);
+        let mut results = XdrReader::new(&results);
+        assert_eq!(results.u32().unwrap(), 0);
+        assert_eq!(results.bool().unwrap(), true);
+        let _ = results.fixed(84).unwrap();
+        let _verf = results.u64().unwrap();
+
+        let mut names = Vec::new();
+        while results.bool().unwrap() {
+            let fileid = results.u64().unwrap();
+            let name = results.string(255).unwrap();
+            assert_eq!(results.u64().unwrap(), fileid);
+            names.push((fileid, name));
+        }
+        names.sort();
+
+        assert_eq!(
+            names,
+            vec![
+                (4, "baz.txt".to_owned()),
+                (5, "bazee".to_owned()),
+                (8, "foo".to_owned()),
+            ]
+        );
+
+        // eof
+        assert_eq!(results.bool().unwrap(), true);
+    })
+}
+
+#[test]
+fn test_retransmit() {
+    run_with_clone_fs("test_files/test1", |fspath| {
+        let server = ZippynfsServer::new(fspath);
+
+        let remove = || {
+            let mut args = XdrWriter::new();
+            args.opaque(&fh(1));
+            args.string("baz.txt");
+            args
+        };
+
+        // REMOVE baz.txt, and then the same call again, as if the reply had been lost
+        let results = call_xid(&server, 1 << 30, NFS, 12, remove());
+        assert_eq!(XdrReader::new(&results).u32().unwrap(), 0);
+        let results = call_xid(&server, 1 << 30, NFS, 12, remove());
+        assert_eq!(XdrReader::new(&results).u32().unwrap(), 0);
+
+        // But a new call finds that it is gone: NFS3ERR_NOENT
+        let results = call(&server, NFS, 12, remove());
+        assert_eq!(XdrReader::new(&results).u32().unwrap(), 2);
+    })
+}
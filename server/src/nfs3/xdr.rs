@@ -0,0 +1,130 @@
+//! Just enough XDR (RFC 4506) to speak ONC RPC, MOUNT, and NFSv3.
+//!
+//! Everything in XDR is big-endian and padded to a multiple of 4 bytes.
+
+/// Decodes XDR values from a buffer, front to back.
+pub struct XdrReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> XdrReader<'a> {
+    pub fn new(buf: &'a [u8]) -> XdrReader<'a> {
+        XdrReader { buf, pos: 0 }
+    }
+
+    /// Take the next `len` bytes, plus padding.
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let padded = pad(len);
+        if self.buf.len() - self.pos < padded {
+            return Err(format!(
+                "XDR: wanted {} bytes at {}, but only {} left",
+                padded,
+                self.pos,
+                self.buf.len() - self.pos
+            ));
+        }
+
+        let bytes = &self.buf[self.pos..self.pos + len];
+        self.pos += padded;
+        Ok(bytes)
+    }
+
+    pub fn u32(&mut self) -> Result<u32, String> {
+        let b = self.take(4)?;
+        Ok(
+            (b[0] as u32) << 24 | (b[1] as u32) << 16 | (b[2] as u32) << 8 | (b[3] as u32),
+        )
+    }
+
+    pub fn u64(&mut self) -> Result<u64, String> {
+        let hi = self.u32()? as u64;
+        let lo = self.u32()? as u64;
+        Ok(hi << 32 | lo)
+    }
+
+    pub fn bool(&mut self) -> Result<bool, String> {
+        match self.u32()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            n => Err(format!("XDR: {} is not a bool", n)),
+        }
+    }
+
+    /// Fixed-length opaque data.
+    pub fn fixed(&mut self, len: usize) -> Result<&'a [u8], String> {
+        self.take(len)
+    }
+
+    /// Variable-length opaque data of at most `max` bytes.
+    pub fn opaque(&mut self, max: usize) -> Result<&'a [u8], String> {
+        let len = self.u32()? as usize;
+        if len > max {
+            return Err(format!("XDR: opaque of {} bytes is over {}", len, max));
+        }
+        self.take(len)
+    }
+
+    /// A string of at most `max` bytes. We only deal in UTF-8.
+    pub fn string(&mut self, max: usize) -> Result<String, String> {
+        let bytes = self.opaque(max)?;
+        String::from_utf8(bytes.to_vec()).map_err(|e| format!("XDR: {}", e))
+    }
+}
+
+/// Encodes XDR values into a buffer.
+pub struct XdrWriter {
+    buf: Vec<u8>,
+}
+
+impl XdrWriter {
+    pub fn new() -> XdrWriter {
+        XdrWriter { buf: Vec::new() }
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.buf
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    pub fn u32(&mut self, n: u32) {
+        self.buf.extend_from_slice(
+            &[(n >> 24) as u8, (n >> 16) as u8, (n >> 8) as u8, n as u8],
+        );
+    }
+
+    pub fn u64(&mut self, n: u64) {
+        self.u32((n >> 32) as u32);
+        self.u32(n as u32);
+    }
+
+    pub fn bool(&mut self, b: bool) {
+        self.u32(if b { 1 } else { 0 });
+    }
+
+    /// Fixed-length opaque data.
+    pub fn fixed(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+        for _ in bytes.len()..pad(bytes.len()) {
+            self.buf.push(0);
+        }
+    }
+
+    /// Variable-length opaque data.
+    pub fn opaque(&mut self, bytes: &[u8]) {
+        self.u32(bytes.len() as u32);
+        self.fixed(bytes);
+    }
+
+    pub fn string(&mut self, s: &str) {
+        self.opaque(s.as_bytes());
+    }
+}
+
+/// Rounds `len` up to a multiple of 4.
+pub fn pad(len: usize) -> usize {
+    (len + 3) & !3
+}
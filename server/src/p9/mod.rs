@@ -0,0 +1,103 @@
+//! A 9P2000.L frontend, so that VMs (e.g. QEMU guests) and sandboxes can mount the FS with their
+//! own 9P client, without our FUSE client.
+//!
+//! Like the NFSv3 frontend, this maps every message onto the same handler that serves the Thrift
+//! protocol, so all frontends see the same FS at the same time. 9P qids are just our FIDs. For
+//! example, with the Linux 9P client:
+//!
+//! ```text
+//! mount -t 9p -o trans=tcp,port=5640,version=9p2000.L,msize=1048576 server /mnt
+//! ```
+//!
+//! There are no permissions, owners, xattrs, links, or locks; messages about them fail with
+//! `EOPNOTSUPP`.
+
+mod session;
+mod wire;
+
+use std::io::{self, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+
+use zippyrpc::ZippynfsSyncHandler;
+
+use self::session::Session;
+use self::wire::HEADER_LEN;
+
+/// Start serving 9P2000.L at the given address in the background.
+pub fn serve<H>(handler: Arc<H>, addr: &str) -> Result<(), String>
+where
+    H: ZippynfsSyncHandler + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind(addr).map_err(|e| {
+        format!("Unable to listen for 9P at {}: {}", addr, e)
+    })?;
+
+    info!("Listening for 9P2000.L at {}", addr);
+
+    thread::spawn(move || for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("9P: failed to accept a connection: {}", e);
+                continue;
+            }
+        };
+
+        let handler = handler.clone();
+        thread::spawn(move || if let Err(e) = handle_conn(&*handler, stream) {
+            warn!("9P: connection failed: {}", e);
+        });
+    });
+
+    Ok(())
+}
+
+/// Read one message from the given stream. Returns `None` if the stream was closed cleanly
+/// between messages.
+fn read_msg<R: Read>(stream: &mut R, msize: usize) -> io::Result<Option<Vec<u8>>> {
+    let mut size = [0u8; 4];
+    match stream.read_exact(&mut size) {
+        Ok(()) => {}
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+
+    let len = size.iter().rev().fold(0usize, |n, &b| n << 8 | b as usize);
+    if len < HEADER_LEN || len > msize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("9P message of {} bytes (msize is {})", len, msize),
+        ));
+    }
+
+    let mut msg = vec![0u8; len];
+    msg[..4].copy_from_slice(&size);
+    stream.read_exact(&mut msg[4..])?;
+    Ok(Some(msg))
+}
+
+/// Serve messages on the given connection, one at a time, until the client hangs up.
+fn handle_conn<H: ZippynfsSyncHandler>(handler: &H, stream: TcpStream) -> Result<(), String> {
+    let peer = stream.peer_addr().ok();
+    debug!("9P: new connection from {:?}", peer);
+
+    let mut writer = stream.try_clone().map_err(|e| format!("{}", e))?;
+    let mut reader = BufReader::new(stream);
+    let mut session = Session::new(handler);
+
+    while let Some(msg) = read_msg(&mut reader, session.msize()).map_err(
+        |e| format!("{}", e),
+    )?
+    {
+        let reply = session.handle(&msg)?;
+        writer.write_all(&reply).map_err(|e| format!("{}", e))?;
+    }
+
+    debug!("9P: {:?} hung up", peer);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test;
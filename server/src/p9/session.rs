@@ -0,0 +1,732 @@
+//! A single 9P2000.L session, i.e. one connection.
+//!
+//! In 9P, the client picks a number (a "fid", not to be confused with our FIDs) for each file it
+//! is using, and refers to the file by that number from then on. A session keeps the table from
+//! 9P fids to files, and turns each message into calls on the handler.
+//!
+//! Some operations (e.g. REMOVE and RENAME) name a file only by its 9P fid, while the handler
+//! wants the directory it is in and its name. So for each 9P fid we also remember how we got to
+//! it. That is only known if the client walked to it by name; a file reached by walking ".." can
+//! not be removed or renamed through that fid.
+//!
+//! Unlike NFS, 9P never resends a message: a session lives and dies with its connection, and a
+//! client that reconnects starts a new session and can't retry what it sent on the old one. So
+//! there are no retries for the DRC to recognize, and requests that change the FS have no XID.
+
+use std::cmp::min;
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use thrift;
+
+use zippyrpc::*;
+
+use frontend::{Fail, Statuses};
+use handler::NAME_MAX;
+
+use super::wire::{string_len, P9Reader, P9Writer, Qid, HEADER_LEN, QID_LEN};
+
+/// The only version of 9P we speak
+const VERSION_9P2000_L: &'static str = "9P2000.L";
+
+/// The FID of the root of the FS
+const ROOT_FID: i64 = 1;
+
+/// The most names a client may walk in one message
+const MAXWELEM: usize = 16;
+
+/// The size of the fields before the data of an RREAD or TWRITE, including the header
+const IOHDRSZ: usize = 24;
+
+/// Message types. Replies are always the type of the request plus one.
+const RLERROR: u8 = 7;
+const TSTATFS: u8 = 8;
+const TLOPEN: u8 = 12;
+const TLCREATE: u8 = 14;
+const TRENAME: u8 = 20;
+const TGETATTR: u8 = 24;
+const TSETATTR: u8 = 26;
+const TREADDIR: u8 = 40;
+const TFSYNC: u8 = 50;
+const TMKDIR: u8 = 72;
+const TRENAMEAT: u8 = 74;
+const TUNLINKAT: u8 = 76;
+const TVERSION: u8 = 100;
+const TATTACH: u8 = 104;
+const TFLUSH: u8 = 108;
+const TWALK: u8 = 110;
+const TREAD: u8 = 116;
+const TWRITE: u8 = 118;
+const TCLUNK: u8 = 120;
+const TREMOVE: u8 = 122;
+
+/// Linux errnos, which 9P2000.L sends back in RLERROR
+const ENOENT: u32 = 2;
+const EIO: u32 = 5;
+const EBADF: u32 = 9;
+const EAGAIN: u32 = 11;
+const EEXIST: u32 = 17;
+const ENOTDIR: u32 = 20;
+const EISDIR: u32 = 21;
+const EINVAL: u32 = 22;
+const ENAMETOOLONG: u32 = 36;
+const ENOTEMPTY: u32 = 39;
+const EOPNOTSUPP: u32 = 95;
+const ESTALE: u32 = 116;
+
+/// Qid types
+const QTDIR: u8 = 0x80;
+const QTFILE: u8 = 0x00;
+
+/// Directory entry types, as in `struct dirent`
+const DT_DIR: u8 = 4;
+const DT_REG: u8 = 8;
+
+/// File types, as in `st_mode`
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+
+/// Open flags we care about
+const O_TRUNC: u32 = 0o1000;
+
+/// Flags of TUNLINKAT
+const AT_REMOVEDIR: u32 = 0x200;
+
+/// The fields of an RGETATTR we fill in (P9_GETATTR_BASIC)
+const GETATTR_BASIC: u64 = 0x7ff;
+
+/// Which fields of a TSETATTR to set
+const SETATTR_SIZE: u32 = 0x8;
+const SETATTR_ATIME: u32 = 0x10;
+const SETATTR_MTIME: u32 = 0x20;
+const SETATTR_ATIME_SET: u32 = 0x80;
+const SETATTR_MTIME_SET: u32 = 0x100;
+
+/// The `f_type` we report in RSTATFS (V9FS_MAGIC)
+const V9FS_MAGIC: u32 = 0x0102_1997;
+
+/// What a 9P fid refers to.
+#[derive(Debug, Clone)]
+struct FidState {
+    /// The file
+    fid: i64,
+
+    /// The directory the file was found in, and its name there, if we know them
+    parent: Option<(i64, String)>,
+
+    /// Whether the file is a directory
+    is_dir: bool,
+}
+
+/// The errno for each failure of the handler.
+struct Errno;
+
+impl Statuses for Errno {
+    fn nfs(error: ZipErrorType) -> u32 {
+        match error {
+            ZipErrorType::NFSERR_NOENT => ENOENT,
+            ZipErrorType::NFSERR_EXIST => EEXIST,
+            ZipErrorType::NFSERR_NOTDIR => ENOTDIR,
+            ZipErrorType::NFSERR_ISDIR => EISDIR,
+            ZipErrorType::NFSERR_NOTEMPTY => ENOTEMPTY,
+            ZipErrorType::NFSERR_STALE => ESTALE,
+            ZipErrorType::NFSERR_NAMETOOLONG => ENAMETOOLONG,
+            ZipErrorType::NFSERR_BAD_COOKIE |
+            ZipErrorType::NFSERR_TOOSMALL |
+            ZipErrorType::NFSERR_VERSION |
+            ZipErrorType::NFSERR_INVAL => EINVAL,
+            ZipErrorType::NFSERR_GRACE => EAGAIN,
+        }
+    }
+
+    fn fault() -> u32 {
+        EIO
+    }
+}
+
+type OpResult = Result<(), Fail>;
+
+/// The qid of a file with the given attributes.
+fn qid(attr: &ZipFattr) -> Qid {
+    Qid {
+        type_: if attr.type_ == ZipFtype::NFDIR {
+            QTDIR
+        } else {
+            QTFILE
+        },
+        version: 0,
+        path: attr.fid as u64,
+    }
+}
+
+/// The current time.
+fn now() -> ZipTimeVal {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    ZipTimeVal::new(now.as_secs() as i64, (now.subsec_nanos() / 1000) as i64)
+}
+
+/// One 9P session.
+pub struct Session<'h, H: 'h> {
+    handler: &'h H,
+
+    /// The largest message either side may send, as agreed in TVERSION
+    msize: usize,
+
+    /// What each 9P fid refers to
+    fids: HashMap<u32, FidState>,
+}
+
+impl<'h, H: ZippynfsSyncHandler> Session<'h, H> {
+    pub fn new(handler: &'h H) -> Session<'h, H> {
+        Session {
+            handler,
+            msize: MAX_MSG_LEN,
+            fids: HashMap::new(),
+        }
+    }
+
+    /// The largest message the client may send.
+    pub fn msize(&self) -> usize {
+        self.msize
+    }
+
+    /// Handle one message, and return the reply. Fails only if the message is so garbled that we
+    /// can't even reply.
+    pub fn handle(&mut self, msg: &[u8]) -> Result<Vec<u8>, String> {
+        let mut args = P9Reader::new(msg);
+        let _size = args.u32()?;
+        let type_ = args.u8()?;
+        let tag = args.u16()?;
+
+        debug!("9P: message {} with tag {}", type_, tag);
+
+        let mut out = P9Writer::new(type_.wrapping_add(1), tag);
+        let result = match type_ {
+            TVERSION => self.version(&mut args, &mut out),
+            TATTACH => self.attach(&mut args, &mut out),
+            // We handle one message at a time, so whatever is being flushed is already done
+            TFLUSH => Ok(()),
+            TWALK => self.walk(&mut args, &mut out),
+            TCLUNK => self.clunk(&mut args),
+            TREMOVE => self.remove(&mut args),
+            TLOPEN => self.lopen(&mut args, &mut out),
+            TLCREATE => self.lcreate(&mut args, &mut out),
+            TREAD => self.read(&mut args, &mut out),
+            TWRITE => self.write(&mut args, &mut out),
+            // Writes are always stable, so there is nothing to sync
+            TFSYNC => Ok(()),
+            TGETATTR => self.getattr(&mut args, &mut out),
+            TSETATTR => self.setattr(&mut args),
+            TREADDIR => self.readdir(&mut args, &mut out),
+            TMKDIR => self.mkdir(&mut args, &mut out),
+            TUNLINKAT => self.unlinkat(&mut args),
+            TRENAME => self.rename(&mut args),
+            TRENAMEAT => self.renameat(&mut args),
+            TSTATFS => self.statfs(&mut args, &mut out),
+
+            // Auth, xattrs, links, special files, and locks
+            _ => Err(Fail::Status(EOPNOTSUPP)),
+        };
+
+        let errno = match result.map_err(|fail| fail.status::<Errno>()) {
+            Ok(()) => return Ok(out.finish()),
+            Err(Ok(errno)) => errno,
+            Err(Err(e)) => {
+                warn!("9P: bad message {}: {}", type_, e);
+                EINVAL
+            }
+        };
+
+        debug!("9P: message {} failed with errno {}", type_, errno);
+
+        let mut out = P9Writer::new(RLERROR, tag);
+        out.u32(errno);
+        Ok(out.finish())
+    }
+
+    /// Look up a 9P fid.
+    fn fid(&self, fid: u32) -> Result<FidState, Fail> {
+        self.fids.get(&fid).cloned().ok_or(Fail::Status(EBADF))
+    }
+
+    /// Start using a new 9P fid.
+    fn add_fid(&mut self, fid: u32, state: FidState) -> OpResult {
+        if self.fids.contains_key(&fid) {
+            return Err(Fail::Status(EBADF));
+        }
+
+        self.fids.insert(fid, state);
+        Ok(())
+    }
+
+    /// The most data we move in one READ or WRITE.
+    fn iounit(&self) -> usize {
+        min(self.msize - IOHDRSZ, MAX_IO_LEN)
+    }
+
+    fn getattr_fid(&self, fid: i64) -> thrift::Result<ZipFattr> {
+        self.handler
+            .handle_getattr(ZipFileHandle::new(fid))
+            .map(|attrstat| attrstat.attributes)
+    }
+
+    fn version(&mut self, args: &mut P9Reader, out: &mut P9Writer) -> OpResult {
+        let msize = args.u32()? as usize;
+        let version = args.string()?;
+
+        info!("9P: TVERSION msize={} version={:?}", msize, version);
+
+        // A new version starts a new session
+        self.fids.clear();
+
+        if msize < IOHDRSZ + 1 {
+            return Err(Fail::Status(EINVAL));
+        }
+        self.msize = min(msize, MAX_MSG_LEN);
+
+        out.u32(self.msize as u32);
+        if version.starts_with(VERSION_9P2000_L) {
+            out.string(VERSION_9P2000_L);
+        } else {
+            out.string("unknown");
+        }
+        Ok(())
+    }
+
+    fn attach(&mut self, args: &mut P9Reader, out: &mut P9Writer) -> OpResult {
+        let fid = args.u32()?;
+        let _afid = args.u32()?;
+        let uname = args.string()?;
+        let aname = args.string()?;
+        let _n_uname = args.u32()?;
+
+        info!("9P: TATTACH uname={:?} aname={:?}", uname, aname);
+
+        let attr = self.getattr_fid(ROOT_FID)?;
+        self.add_fid(
+            fid,
+            FidState {
+                fid: ROOT_FID,
+                parent: None,
+                is_dir: true,
+            },
+        )?;
+
+        out.qid(&qid(&attr));
+        Ok(())
+    }
+
+    fn walk(&mut self, args: &mut P9Reader, out: &mut P9Writer) -> OpResult {
+        let fid = args.u32()?;
+        let newfid = args.u32()?;
+        let nwname = args.u16()? as usize;
+        if nwname > MAXWELEM {
+            return Err(Fail::Status(EINVAL));
+        }
+
+        let mut names = Vec::with_capacity(nwname);
+        for _ in 0..nwname {
+            names.push(args.string()?);
+        }
+
+        let mut cur = self.fid(fid)?;
+        if newfid != fid && self.fids.contains_key(&newfid) {
+            return Err(Fail::Status(EBADF));
+        }
+
+        // Walk as far as we can. Failing on the first name is an error, but after that, the
+        // client just gets the qids of the names we could walk.
+        let mut qids = Vec::with_capacity(nwname);
+        for name in names {
+            let res = match self.handler.handle_lookup(
+                ZipDirOpArgs::new(ZipFileHandle::new(cur.fid), name.clone(), None),
+            ) {
+                Ok(res) => res,
+                Err(e) if qids.is_empty() => return Err(e.into()),
+                Err(_) => break,
+            };
+
+            let parent = match name.as_str() {
+                "." => cur.parent.clone(),
+                ".." => None,
+                _ => Some((cur.fid, name)),
+            };
+
+            qids.push(qid(&res.attributes));
+            cur = FidState {
+                fid: res.file.fid,
+                parent,
+                is_dir: res.attributes.type_ == ZipFtype::NFDIR,
+            };
+        }
+
+        // Only a complete walk gives the new fid a file
+        if qids.len() == nwname {
+            self.fids.insert(newfid, cur);
+        }
+
+        out.u16(qids.len() as u16);
+        for qid in &qids {
+            out.qid(qid);
+        }
+        Ok(())
+    }
+
+    fn clunk(&mut self, args: &mut P9Reader) -> OpResult {
+        let fid = args.u32()?;
+
+        self.fids.remove(&fid).map(|_| ()).ok_or(Fail::Status(EBADF))
+    }
+
+    fn remove(&mut self, args: &mut P9Reader) -> OpResult {
+        let fid = args.u32()?;
+
+        // The fid is clunked even if the remove fails
+        let state = self.fids.remove(&fid).ok_or(Fail::Status(EBADF))?;
+
+        let (dir, name) = state.parent.ok_or(Fail::Status(EINVAL))?;
+        let dirop = ZipDirOpArgs::new(ZipFileHandle::new(dir), name, None);
+        if state.is_dir {
+            self.handler.handle_rmdir(dirop)?;
+        } else {
+            self.handler.handle_remove(dirop)?;
+        }
+        Ok(())
+    }
+
+    fn lopen(&mut self, args: &mut P9Reader, out: &mut P9Writer) -> OpResult {
+        let fid = args.u32()?;
+        let flags = args.u32()?;
+
+        let state = self.fid(fid)?;
+
+        let attr = if flags & O_TRUNC != 0 && !state.is_dir {
+            let sattr = ZipSattr::new(None, None, None, Some(0), None, None);
+            self.handler
+                .handle_setattr(ZipSattrArgs::new(ZipFileHandle::new(state.fid), sattr, None))?
+                .attributes
+        } else {
+            self.getattr_fid(state.fid)?
+        };
+
+        out.qid(&qid(&attr));
+        out.u32(self.iounit() as u32);
+        Ok(())
+    }
+
+    fn lcreate(&mut self, args: &mut P9Reader, out: &mut P9Writer) -> OpResult {
+        let fid = args.u32()?;
+        let name = args.string()?;
+        let _flags = args.u32()?;
+        let mode = args.u32()?;
+        let _gid = args.u32()?;
+
+        let dir = self.fid(fid)?;
+
+        let sattr = ZipSattr::new(Some(mode as i16), None, None, None, None, None);
+        let res = self.handler.handle_create(ZipCreateArgs::new(
+            ZipDirOpArgs::new(ZipFileHandle::new(dir.fid), name.clone(), None),
+            sattr,
+        ))?;
+
+        // The fid now refers to the new file
+        self.fids.insert(
+            fid,
+            FidState {
+                fid: res.file.fid,
+                parent: Some((dir.fid, name)),
+                is_dir: false,
+            },
+        );
+
+        out.qid(&qid(&res.attributes));
+        out.u32(self.iounit() as u32);
+        Ok(())
+    }
+
+    fn read(&mut self, args: &mut P9Reader, out: &mut P9Writer) -> OpResult {
+        let fid = args.u32()?;
+        let offset = args.u64()? as i64;
+        let count = min(args.u32()? as usize, self.iounit());
+
+        let state = self.fid(fid)?;
+
+        let res = self.handler.handle_read(ZipReadArgs::new(
+            ZipFileHandle::new(state.fid),
+            offset,
+            count as i64,
+            None,
+        ))?;
+
+        out.data(&res.data);
+        Ok(())
+    }
+
+    fn write(&mut self, args: &mut P9Reader, out: &mut P9Writer) -> OpResult {
+        let fid = args.u32()?;
+        let offset = args.u64()? as i64;
+        let data = args.data()?;
+
+        let state = self.fid(fid)?;
+
+        // Clients are supposed to keep to the iounit, but a short write is fine if they don't
+        let data = &data[..min(data.len(), self.iounit())];
+
+        // Other clients (and other fids) may read the file at any time, so the write has to be
+        // visible right away.
+        let res = self.handler.handle_write(ZipWriteArgs::new(
+            ZipFileHandle::new(state.fid),
+            offset,
+            data.len() as i64,
+            data.to_vec(),
+            ZipWriteStable::FILE_SYNC,
+            None,
+        ))?;
+
+        out.u32(res.count as u32);
+        Ok(())
+    }
+
+    fn getattr(&mut self, args: &mut P9Reader, out: &mut P9Writer) -> OpResult {
+        let fid = args.u32()?;
+        let _request_mask = args.u64()?;
+
+        let state = self.fid(fid)?;
+        let attr = self.getattr_fid(state.fid)?;
+
+        out.u64(GETATTR_BASIC);
+        out.qid(&qid(&attr));
+        out.u32(
+            (attr.mode as u16 as u32 & 0o7777) |
+                if attr.type_ == ZipFtype::NFDIR {
+                    S_IFDIR
+                } else {
+                    S_IFREG
+                },
+        );
+        out.u32(attr.uid as u32);
+        out.u32(attr.gid as u32);
+        out.u64(attr.nlink as u64);
+        out.u64(attr.rdev as u64);
+        out.u64(attr.size as u64);
+        out.u64(attr.blocksize as u64);
+
+        // In 512-byte blocks, like `st_blocks`
+        out.u64((attr.blocks * attr.blocksize / 512) as u64);
+
+        for time in &[&attr.atime, &attr.mtime, &attr.ctime] {
+            out.u64(time.seconds as u64);
+            out.u64((time.useconds * 1000) as u64);
+        }
+
+        // btime, gen, and data_version, which we don't have
+        for _ in 0..4 {
+            out.u64(0);
+        }
+        Ok(())
+    }
+
+    fn setattr(&mut self, args: &mut P9Reader) -> OpResult {
+        let fid = args.u32()?;
+        let valid = args.u32()?;
+        let _mode = args.u32()?;
+        let _uid = args.u32()?;
+        let _gid = args.u32()?;
+        let size = args.u64()?;
+        let atime_sec = args.u64()?;
+        let atime_nsec = args.u64()?;
+        let mtime_sec = args.u64()?;
+        let mtime_nsec = args.u64()?;
+
+        let state = self.fid(fid)?;
+
+        let size = if valid & SETATTR_SIZE != 0 {
+            Some(size as i64)
+        } else {
+            None
+        };
+
+        let atime = match (valid & SETATTR_ATIME != 0, valid & SETATTR_ATIME_SET != 0) {
+            (false, _) => None,
+            (true, false) => Some(now()),
+            (true, true) => Some(ZipTimeVal::new(
+                atime_sec as i64,
+                atime_nsec as i64 / 1000,
+            )),
+        };
+
+        let mtime = match (valid & SETATTR_MTIME != 0, valid & SETATTR_MTIME_SET != 0) {
+            (false, _) => None,
+            (true, false) => Some(now()),
+            (true, true) => Some(ZipTimeVal::new(
+                mtime_sec as i64,
+                mtime_nsec as i64 / 1000,
+            )),
+        };
+
+        // The handler can't set the mtime without setting the atime too, so keep the current
+        // atime.
+        let atime = match (atime, &mtime) {
+            (None, &Some(_)) => Some(self.getattr_fid(state.fid)?.atime),
+            (atime, _) => atime,
+        };
+
+        // There are no permissions or owners, so mode, uid, and gid are ignored
+        let sattr = ZipSattr::new(None, None, None, size, atime, mtime);
+        self.handler.handle_setattr(ZipSattrArgs::new(
+            ZipFileHandle::new(state.fid),
+            sattr,
+            None,
+        ))?;
+        Ok(())
+    }
+
+    fn readdir(&mut self, args: &mut P9Reader, out: &mut P9Writer) -> OpResult {
+        let fid = args.u32()?;
+        let offset = args.u64()? as i64;
+        let count = min(args.u32()? as usize, self.msize - HEADER_LEN - 4);
+
+        let state = self.fid(fid)?;
+
+        // Offsets are our readdir cookies, i.e. the FID of the last entry seen. They never go
+        // stale, so we don't bother with a verifier.
+        let res = self.handler.handle_readdir(ZipReadDirArgs::new(
+            ZipFileHandle::new(state.fid),
+            offset,
+            0,
+            count as i64,
+        ))?;
+
+        // Our entries are bigger than the Thrift ones, so we may not be able to send them all.
+        // Whatever doesn't fit is sent in the next READDIR.
+        let mut entries = P9Writer::new(0, 0);
+        let mut len = 0;
+        for entry in &res.entries {
+            let entry_len = QID_LEN + 8 + 1 + string_len(&entry.fname);
+            if len + entry_len > count {
+                break;
+            }
+            len += entry_len;
+
+            let is_dir = entry.type_ == ZipFtype::NFDIR;
+            entries.qid(&Qid {
+                type_: if is_dir { QTDIR } else { QTFILE },
+                version: 0,
+                path: entry.fid as u64,
+            });
+            entries.u64(entry.fid as u64);
+            entries.u8(if is_dir { DT_DIR } else { DT_REG });
+            entries.string(&entry.fname);
+        }
+
+        if len == 0 && !res.entries.is_empty() {
+            return Err(Fail::Status(EINVAL));
+        }
+
+        let entries = entries.finish();
+        out.data(&entries[HEADER_LEN..]);
+        Ok(())
+    }
+
+    fn mkdir(&mut self, args: &mut P9Reader, out: &mut P9Writer) -> OpResult {
+        let dfid = args.u32()?;
+        let name = args.string()?;
+        let mode = args.u32()?;
+        let _gid = args.u32()?;
+
+        let dir = self.fid(dfid)?;
+
+        let sattr = ZipSattr::new(Some(mode as i16), None, None, None, None, None);
+        let res = self.handler.handle_mkdir(ZipCreateArgs::new(
+            ZipDirOpArgs::new(ZipFileHandle::new(dir.fid), name, None),
+            sattr,
+        ))?;
+
+        out.qid(&qid(&res.attributes));
+        Ok(())
+    }
+
+    fn unlinkat(&mut self, args: &mut P9Reader) -> OpResult {
+        let dirfd = args.u32()?;
+        let name = args.string()?;
+        let flags = args.u32()?;
+
+        let dir = self.fid(dirfd)?;
+
+        let dirop = ZipDirOpArgs::new(ZipFileHandle::new(dir.fid), name, None);
+        if flags & AT_REMOVEDIR != 0 {
+            self.handler.handle_rmdir(dirop)?;
+        } else {
+            self.handler.handle_remove(dirop)?;
+        }
+        Ok(())
+    }
+
+    /// Rename a file, and keep track of where any fids referring to it are now.
+    fn do_rename(
+        &mut self,
+        olddir: i64,
+        oldname: String,
+        newdir: i64,
+        newname: String,
+    ) -> OpResult {
+        self.handler.handle_rename(ZipRenameArgs::new(
+            ZipDirOpArgs::new(ZipFileHandle::new(olddir), oldname.clone(), None),
+            ZipDirOpArgs::new(ZipFileHandle::new(newdir), newname.clone(), None),
+            None,
+        ))?;
+
+        let old = Some((olddir, oldname));
+        for state in self.fids.values_mut() {
+            if state.parent == old {
+                state.parent = Some((newdir, newname.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    fn rename(&mut self, args: &mut P9Reader) -> OpResult {
+        let fid = args.u32()?;
+        let dfid = args.u32()?;
+        let name = args.string()?;
+
+        let state = self.fid(fid)?;
+        let newdir = self.fid(dfid)?;
+        let (olddir, oldname) = state.parent.ok_or(Fail::Status(EINVAL))?;
+
+        self.do_rename(olddir, oldname, newdir.fid, name)
+    }
+
+    fn renameat(&mut self, args: &mut P9Reader) -> OpResult {
+        let olddirfid = args.u32()?;
+        let oldname = args.string()?;
+        let newdirfid = args.u32()?;
+        let newname = args.string()?;
+
+        let olddir = self.fid(olddirfid)?;
+        let newdir = self.fid(newdirfid)?;
+
+        self.do_rename(olddir.fid, oldname, newdir.fid, newname)
+    }
+
+    fn statfs(&mut self, args: &mut P9Reader, out: &mut P9Writer) -> OpResult {
+        let fid = args.u32()?;
+
+        let state = self.fid(fid)?;
+        let res = self.handler.handle_statfs(ZipFileHandle::new(state.fid))?;
+
+        out.u32(V9FS_MAGIC);
+        out.u32(res.bsize as u32);
+        out.u64(res.blocks as u64);
+        out.u64(res.bfree as u64);
+        out.u64(res.bavail as u64);
+
+        // We don't run out of files before we run out of space
+        out.u64(res.blocks as u64);
+        out.u64(res.bfree as u64);
+
+        out.u64(0); // fsid
+        out.u32(NAME_MAX as u32);
+        Ok(())
+    }
+}
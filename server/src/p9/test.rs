@@ -0,0 +1,285 @@
+//! Unit tests for the 9P frontend
+
+use std::path::Path;
+
+use handler::ZippynfsServer;
+use testutil::run_with_clone_fs;
+
+use super::session::Session;
+use super::wire::{P9Reader, P9Writer, Qid};
+
+const RLERROR: u8 = 7;
+const TLOPEN: u8 = 12;
+const TLCREATE: u8 = 14;
+const TGETATTR: u8 = 24;
+const TREADDIR: u8 = 40;
+const TUNLINKAT: u8 = 76;
+const TVERSION: u8 = 100;
+const TATTACH: u8 = 104;
+const TWALK: u8 = 110;
+const TREAD: u8 = 116;
+const TWRITE: u8 = 118;
+const TCLUNK: u8 = 120;
+
+const ENOENT: u32 = 2;
+const EBADF: u32 = 9;
+
+/// Send a message, and return the reply after checking its header. Replies are the type of the
+/// message plus one, unless they are errors.
+fn send<P: AsRef<Path>>(
+    session: &mut Session<ZippynfsServer<P>>,
+    msg: P9Writer,
+    expected: u8,
+) -> Vec<u8> {
+    let reply = session.handle(&msg.finish()).unwrap();
+
+    let mut reader = P9Reader::new(&reply);
+    assert_eq!(reader.u32().unwrap() as usize, reply.len());
+    assert_eq!(reader.u8().unwrap(), expected);
+    assert_eq!(reader.u16().unwrap(), 1);
+
+    reply[7..].to_vec()
+}
+
+/// Walk from `fid` to `newfid` along the given names, and return the qids.
+fn walk<P: AsRef<Path>>(
+    session: &mut Session<ZippynfsServer<P>>,
+    fid: u32,
+    newfid: u32,
+    names: &[&str],
+) -> Vec<u64> {
+    let mut msg = P9Writer::new(TWALK, 1);
+    msg.u32(fid);
+    msg.u32(newfid);
+    msg.u16(names.len() as u16);
+    for name in names {
+        msg.string(name);
+    }
+
+    let reply = send(session, msg, TWALK + 1);
+    let mut reply = P9Reader::new(&reply);
+    let nwqid = reply.u16().unwrap();
+    (0..nwqid)
+        .map(|_| {
+            let _type = reply.u8().unwrap();
+            let _version = reply.u32().unwrap();
+            reply.u64().unwrap()
+        })
+        .collect()
+}
+
+/// Agree on a version and attach fid 0 to the root.
+fn attach<P: AsRef<Path>>(session: &mut Session<ZippynfsServer<P>>) {
+    let mut msg = P9Writer::new(TVERSION, 1);
+    msg.u32(1 << 16);
+    msg.string("9P2000.L");
+    let reply = send(session, msg, TVERSION + 1);
+    let mut reply = P9Reader::new(&reply);
+    assert_eq!(reply.u32().unwrap(), 1 << 16);
+    assert_eq!(reply.string().unwrap(), "9P2000.L");
+
+    let mut msg = P9Writer::new(TATTACH, 1);
+    msg.u32(0);
+    msg.u32(!0);
+    msg.string("root");
+    msg.string("");
+    msg.u32(0);
+    let reply = send(session, msg, TATTACH + 1);
+    let mut reply = P9Reader::new(&reply);
+    assert_eq!(reply.u8().unwrap(), 0x80);
+    let _version = reply.u32().unwrap();
+    assert_eq!(reply.u64().unwrap(), 1);
+}
+
+#[test]
+fn test_wire_round_trip() {
+    let mut out = P9Writer::new(42, 7);
+    out.u8(1);
+    out.u16(0x0203);
+    out.u32(0x0405_0607);
+    out.u64(1 << 40 | 8);
+    out.string("zee.txt");
+    out.data(&[9, 10]);
+    out.qid(&Qid {
+        type_: 0x80,
+        version: 3,
+        path: 8,
+    });
+
+    let msg = out.finish();
+    assert_eq!(msg.len(), 7 + 1 + 2 + 4 + 8 + (2 + 7) + (4 + 2) + 13);
+    assert_eq!(&msg[..7], &[msg.len() as u8, 0, 0, 0, 42, 7, 0]);
+
+    let mut args = P9Reader::new(&msg[7..]);
+    assert_eq!(args.u8().unwrap(), 1);
+    assert_eq!(args.u16().unwrap(), 0x0203);
+    assert_eq!(args.u32().unwrap(), 0x0405_0607);
+    assert_eq!(args.u64().unwrap(), 1 << 40 | 8);
+    assert_eq!(args.string().unwrap(), "zee.txt");
+    assert_eq!(args.data().unwrap(), &[9, 10]);
+    assert_eq!(args.u8().unwrap(), 0x80);
+    assert_eq!(args.u32().unwrap(), 3);
+    assert_eq!(args.u64().unwrap(), 8);
+
+    // Nothing left
+    assert!(args.u8().is_err());
+}
+
+#[test]
+fn test_p9_walk_and_read() {
+    run_with_clone_fs("test_files/test1", |fspath| {
+        let server = ZippynfsServer::new(fspath);
+        let mut session = Session::new(&server);
+        attach(&mut session);
+
+        // Walk all the way to zee.txt
+        assert_eq!(walk(&mut session, 0, 1, &["foo", "bar", "zee.txt"]), vec![8, 2, 3]);
+
+        // A partial walk returns what it could, and doesn't create the new fid
+        assert_eq!(walk(&mut session, 0, 2, &["foo", "nope"]), vec![8]);
+        let mut msg = P9Writer::new(TCLUNK, 1);
+        msg.u32(2);
+        let reply = send(&mut session, msg, RLERROR);
+        assert_eq!(P9Reader::new(&reply).u32().unwrap(), EBADF);
+
+        // But failing on the first name is an error
+        let mut msg = P9Writer::new(TWALK, 1);
+        msg.u32(0);
+        msg.u32(2);
+        msg.u16(1);
+        msg.string("nope");
+        let reply = send(&mut session, msg, RLERROR);
+        assert_eq!(P9Reader::new(&reply).u32().unwrap(), ENOENT);
+
+        // Open and read it
+        let mut msg = P9Writer::new(TLOPEN, 1);
+        msg.u32(1);
+        msg.u32(0);
+        send(&mut session, msg, TLOPEN + 1);
+
+        let mut msg = P9Writer::new(TREAD, 1);
+        msg.u32(1);
+        msg.u64(0);
+        msg.u32(1 << 10);
+        let reply = send(&mut session, msg, TREAD + 1);
+        assert_eq!(P9Reader::new(&reply).data().unwrap().len(), 27);
+
+        // Its attributes: a regular file of 27 bytes
+        let mut msg = P9Writer::new(TGETATTR, 1);
+        msg.u32(1);
+        msg.u64(!0);
+        let reply = send(&mut session, msg, TGETATTR + 1);
+        let mut reply = P9Reader::new(&reply);
+        let _valid = reply.u64().unwrap();
+        assert_eq!(reply.u8().unwrap(), 0);
+        let _version = reply.u32().unwrap();
+        assert_eq!(reply.u64().unwrap(), 3);
+        assert_eq!(reply.u32().unwrap() & 0o170000, 0o100000);
+        let _uid = reply.u32().unwrap();
+        let _gid = reply.u32().unwrap();
+        let _nlink = reply.u64().unwrap();
+        let _rdev = reply.u64().unwrap();
+        assert_eq!(reply.u64().unwrap(), 27);
+
+        // List the root
+        let mut msg = P9Writer::new(TREADDIR, 1);
+        msg.u32(0);
+        msg.u64(0);
+        msg.u32(1 << 12);
+        let reply = send(&mut session, msg, TREADDIR + 1);
+        let mut reply = P9Reader::new(&reply);
+        let entries = reply.data().unwrap();
+        let mut entries = P9Reader::new(entries);
+
+        let mut names = Vec::new();
+        while let Ok(_type) = entries.u8() {
+            let _version = entries.u32().unwrap();
+            let path = entries.u64().unwrap();
+            assert_eq!(entries.u64().unwrap(), path);
+            let _dtype = entries.u8().unwrap();
+            names.push((path, entries.string().unwrap()));
+        }
+        names.sort();
+
+        assert_eq!(
+            names,
+            vec![
+                (4, "baz.txt".to_owned()),
+                (5, "bazee".to_owned()),
+                (8, "foo".to_owned()),
+            ]
+        );
+    })
+}
+
+#[test]
+fn test_p9_create_write_unlink() {
+    run_with_clone_fs("test_files/test1", |fspath| {
+        let server = ZippynfsServer::new(fspath);
+        let mut session = Session::new(&server);
+        attach(&mut session);
+
+        // Create a file in foo, through a fid for foo
+        assert_eq!(walk(&mut session, 0, 1, &["foo"]), vec![8]);
+
+        let mut msg = P9Writer::new(TLCREATE, 1);
+        msg.u32(1);
+        msg.string("new.txt");
+        msg.u32(0);
+        msg.u32(0o644);
+        msg.u32(0);
+        let reply = send(&mut session, msg, TLCREATE + 1);
+        let mut reply = P9Reader::new(&reply);
+        let _qid = (reply.u8().unwrap(), reply.u32().unwrap(), reply.u64().unwrap());
+        let iounit = reply.u32().unwrap() as usize;
+
+        // The fid now refers to the new file
+        let mut msg = P9Writer::new(TWRITE, 1);
+        msg.u32(1);
+        msg.u64(0);
+        msg.data(b"hello");
+        let reply = send(&mut session, msg, TWRITE + 1);
+        assert_eq!(P9Reader::new(&reply).u32().unwrap(), 5);
+
+        // It can be found and read through another fid
+        let qids = walk(&mut session, 0, 2, &["foo", "new.txt"]);
+        assert_eq!(qids.len(), 2);
+
+        let mut msg = P9Writer::new(TREAD, 1);
+        msg.u32(2);
+        msg.u64(0);
+        msg.u32(1 << 10);
+        let reply = send(&mut session, msg, TREAD + 1);
+        assert_eq!(P9Reader::new(&reply).data().unwrap(), b"hello");
+
+        // A write bigger than the iounit is cut short
+        let mut msg = P9Writer::new(TWRITE, 1);
+        msg.u32(1);
+        msg.u64(0);
+        msg.data(&vec![b'x'; iounit + 100]);
+        let reply = send(&mut session, msg, TWRITE + 1);
+        assert_eq!(P9Reader::new(&reply).u32().unwrap() as usize, iounit);
+
+        // Remove it by name
+        let mut msg = P9Writer::new(TUNLINKAT, 1);
+        msg.u32(0);
+        msg.string("foo");
+        msg.u32(0);
+        send(&mut session, msg, RLERROR);
+
+        assert_eq!(walk(&mut session, 0, 3, &["foo"]), vec![8]);
+        let mut msg = P9Writer::new(TUNLINKAT, 1);
+        msg.u32(3);
+        msg.string("new.txt");
+        msg.u32(0);
+        send(&mut session, msg, TUNLINKAT + 1);
+
+        let mut msg = P9Writer::new(TWALK, 1);
+        msg.u32(3);
+        msg.u32(4);
+        msg.u16(1);
+        msg.string("new.txt");
+        let reply = send(&mut session, msg, RLERROR);
+        assert_eq!(P9Reader::new(&reply).u32().unwrap(), ENOENT);
+    })
+}
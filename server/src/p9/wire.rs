@@ -0,0 +1,153 @@
+//! The 9P wire format.
+//!
+//! Every message is `size[4] type[1] tag[2]` followed by the fields of that type of message.
+//! Integers are little-endian, and strings are `len[2]` followed by that many bytes of UTF-8.
+
+/// The size of `size[4] type[1] tag[2]`
+pub const HEADER_LEN: usize = 7;
+
+/// The size of an encoded qid
+pub const QID_LEN: usize = 13;
+
+/// Identifies a file on the server: its type, version, and a unique path (which is just the FID).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Qid {
+    pub type_: u8,
+    pub version: u32,
+    pub path: u64,
+}
+
+/// Decodes 9P values from a message, front to back.
+pub struct P9Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> P9Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> P9Reader<'a> {
+        P9Reader { buf, pos: 0 }
+    }
+
+    /// Take the next `len` bytes.
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.buf.len() - self.pos < len {
+            return Err(format!(
+                "9P: wanted {} bytes at {}, but only {} left",
+                len,
+                self.pos,
+                self.buf.len() - self.pos
+            ));
+        }
+
+        let bytes = &self.buf[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    /// Decode a little-endian integer of `len` bytes.
+    fn int(&mut self, len: usize) -> Result<u64, String> {
+        let bytes = self.take(len)?;
+        Ok(bytes.iter().rev().fold(0u64, |n, &b| n << 8 | b as u64))
+    }
+
+    pub fn u8(&mut self) -> Result<u8, String> {
+        self.int(1).map(|n| n as u8)
+    }
+
+    pub fn u16(&mut self) -> Result<u16, String> {
+        self.int(2).map(|n| n as u16)
+    }
+
+    pub fn u32(&mut self) -> Result<u32, String> {
+        self.int(4).map(|n| n as u32)
+    }
+
+    pub fn u64(&mut self) -> Result<u64, String> {
+        self.int(8)
+    }
+
+    pub fn string(&mut self) -> Result<String, String> {
+        let len = self.u16()? as usize;
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|e| format!("9P: {}", e))
+    }
+
+    /// `count[4]` followed by that many bytes.
+    pub fn data(&mut self) -> Result<&'a [u8], String> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+}
+
+/// Encodes 9P values into a message.
+pub struct P9Writer {
+    buf: Vec<u8>,
+}
+
+impl P9Writer {
+    /// Start a message of the given type, in reply to the given tag.
+    pub fn new(type_: u8, tag: u16) -> P9Writer {
+        let mut out = P9Writer { buf: Vec::new() };
+
+        // Filled in by `finish`
+        out.u32(0);
+
+        out.u8(type_);
+        out.u16(tag);
+        out
+    }
+
+    /// Fill in the size of the message, and return it.
+    pub fn finish(mut self) -> Vec<u8> {
+        let len = self.buf.len() as u32;
+        self.buf[..4].copy_from_slice(
+            &[len as u8, (len >> 8) as u8, (len >> 16) as u8, (len >> 24) as u8],
+        );
+        self.buf
+    }
+
+    /// Encode a little-endian integer of `len` bytes.
+    fn int(&mut self, n: u64, len: usize) {
+        for i in 0..len {
+            self.buf.push((n >> (8 * i)) as u8);
+        }
+    }
+
+    pub fn u8(&mut self, n: u8) {
+        self.int(n as u64, 1);
+    }
+
+    pub fn u16(&mut self, n: u16) {
+        self.int(n as u64, 2);
+    }
+
+    pub fn u32(&mut self, n: u32) {
+        self.int(n as u64, 4);
+    }
+
+    pub fn u64(&mut self, n: u64) {
+        self.int(n, 8);
+    }
+
+    pub fn string(&mut self, s: &str) {
+        self.u16(s.len() as u16);
+        self.buf.extend_from_slice(s.as_bytes());
+    }
+
+    /// `count[4]` followed by the bytes.
+    pub fn data(&mut self, bytes: &[u8]) {
+        self.u32(bytes.len() as u32);
+        self.buf.extend_from_slice(bytes);
+    }
+
+    pub fn qid(&mut self, qid: &Qid) {
+        self.u8(qid.type_);
+        self.u32(qid.version);
+        self.u64(qid.path);
+    }
+}
+
+/// The size of an encoded string.
+pub fn string_len(s: &str) -> usize {
+    2 + s.len()
+}
@@ -0,0 +1,112 @@
+//! Sharing one handler between several frontends.
+//!
+//! The Thrift processor wants to own its handler, but other frontends (e.g. NFSv3) need to use
+//! the same one, since all of the server's state lives in it. So we keep the handler in an `Arc`
+//! and give the processor a `SharedHandler`, which just forwards every call.
+
+use std::sync::Arc;
+
+use thrift;
+
+use zippyrpc::*;
+
+/// A handler that forwards every call to a shared handler.
+pub struct SharedHandler<H>(pub Arc<H>);
+
+impl<H: ZippynfsSyncHandler> ZippynfsSyncHandler for SharedHandler<H> {
+    fn handle_hello(&self, fsargs: ZipHelloArgs) -> thrift::Result<ZipHelloRes> {
+        self.0.handle_hello(fsargs)
+    }
+
+    fn handle_null(&self) -> thrift::Result<i64> {
+        self.0.handle_null()
+    }
+
+    fn handle_getattr(&self, fhandle: ZipFileHandle) -> thrift::Result<ZipAttrStat> {
+        self.0.handle_getattr(fhandle)
+    }
+
+    fn handle_setattr(&self, fsargs: ZipSattrArgs) -> thrift::Result<ZipAttrStat> {
+        self.0.handle_setattr(fsargs)
+    }
+
+    fn handle_lookup(&self, fsargs: ZipDirOpArgs) -> thrift::Result<ZipDirOpRes> {
+        self.0.handle_lookup(fsargs)
+    }
+
+    fn handle_read(&self, fsargs: ZipReadArgs) -> thrift::Result<ZipReadRes> {
+        self.0.handle_read(fsargs)
+    }
+
+    fn handle_write(&self, fsargs: ZipWriteArgs) -> thrift::Result<ZipWriteRes> {
+        self.0.handle_write(fsargs)
+    }
+
+    fn handle_create(&self, fsargs: ZipCreateArgs) -> thrift::Result<ZipDirOpRes> {
+        self.0.handle_create(fsargs)
+    }
+
+    fn handle_remove(&self, fsargs: ZipDirOpArgs) -> thrift::Result<()> {
+        self.0.handle_remove(fsargs)
+    }
+
+    fn handle_rename(&self, fsargs: ZipRenameArgs) -> thrift::Result<()> {
+        self.0.handle_rename(fsargs)
+    }
+
+    fn handle_mkdir(&self, fsargs: ZipCreateArgs) -> thrift::Result<ZipDirOpRes> {
+        self.0.handle_mkdir(fsargs)
+    }
+
+    fn handle_rmdir(&self, fsargs: ZipDirOpArgs) -> thrift::Result<()> {
+        self.0.handle_rmdir(fsargs)
+    }
+
+    fn handle_readdir(&self, fsargs: ZipReadDirArgs) -> thrift::Result<ZipReadDirRes> {
+        self.0.handle_readdir(fsargs)
+    }
+
+    fn handle_readdirplus(&self, fsargs: ZipReadDirArgs) -> thrift::Result<ZipReadDirPlusRes> {
+        self.0.handle_readdirplus(fsargs)
+    }
+
+    fn handle_statfs(&self, fhandle: ZipFileHandle) -> thrift::Result<ZipStatFsRes> {
+        self.0.handle_statfs(fhandle)
+    }
+
+    fn handle_fsinfo(&self, fhandle: ZipFileHandle) -> thrift::Result<ZipFsInfoRes> {
+        self.0.handle_fsinfo(fhandle)
+    }
+
+    fn handle_commit(&self, fsargs: ZipCommitArgs) -> thrift::Result<ZipCommitRes> {
+        self.0.handle_commit(fsargs)
+    }
+
+    fn handle_open(&self, fsargs: ZipOpenArgs) -> thrift::Result<ZipOpenRes> {
+        self.0.handle_open(fsargs)
+    }
+
+    fn handle_close(&self, fsargs: ZipOpenArgs) -> thrift::Result<ZipOpenRes> {
+        self.0.handle_close(fsargs)
+    }
+
+    fn handle_renew(&self, client: i64) -> thrift::Result<i64> {
+        self.0.handle_renew(client)
+    }
+
+    fn handle_lock(&self, fsargs: ZipLockArgs) -> thrift::Result<ZipLockRes> {
+        self.0.handle_lock(fsargs)
+    }
+
+    fn handle_testlock(&self, fsargs: ZipLockArgs) -> thrift::Result<ZipLockRes> {
+        self.0.handle_testlock(fsargs)
+    }
+
+    fn handle_setcallback(&self, fsargs: ZipCallbackArgs) -> thrift::Result<()> {
+        self.0.handle_setcallback(fsargs)
+    }
+
+    fn handle_delegate(&self, fsargs: ZipDelegateArgs) -> thrift::Result<ZipDelegateRes> {
+        self.0.handle_delegate(fsargs)
+    }
+}
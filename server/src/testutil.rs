@@ -0,0 +1,39 @@
+//! Helpers shared by the tests of the frontends.
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Run `f` on a fresh copy of the given FS, since the server modifies it.
+pub fn run_with_clone_fs<P, F>(fspath: P, f: F)
+where
+    P: AsRef<Path>,
+    F: FnOnce(&Path) -> (),
+{
+    use std::fs::remove_dir_all;
+
+    lazy_static! {
+        static ref FSCOUNT: AtomicUsize = AtomicUsize::new(0);
+    }
+
+    let new_clone: PathBuf = (&format!(
+        "test_files/_frontend_clone_fs_{}",
+        FSCOUNT.fetch_add(1, Ordering::SeqCst)
+    )).into();
+
+    if new_clone.exists() {
+        remove_dir_all(&new_clone).unwrap();
+    }
+
+    assert!{
+        Command::new("cp")
+            .args(&["-r", fspath.as_ref().to_str().unwrap(), new_clone.to_str().unwrap()])
+            .status()
+            .unwrap()
+            .success()
+    };
+
+    f(&new_clone);
+
+    remove_dir_all(new_clone).unwrap();
+}